@@ -42,7 +42,8 @@ fn main()
     let client_private_key = PrivateKey(std::fs::read(client_private_key_file).unwrap());
     let client_signer = Rc::new( Ed25519Signer::new(&client_private_key).unwrap() );
     let client_facet = ProfileFacet::Persona(PersonaFacet {homes: vec![], data: vec![]});
-    let client_profile = Profile::new(&client_signer.profile_id(), &client_signer.public_key(), &client_facet);
+    let mut client_profile = Profile::new(&client_signer.profile_id(), &client_signer.public_key(), &client_facet);
+    client_profile.sign_next_version(&*client_signer);
     let client_own_profile = OwnProfile::new(&client_profile, &vec![]);
 
     // server details has to be taken from the command line
@@ -60,10 +61,10 @@ fn main()
     info!("homenode profile id: {:?}", server_id);
     let home_profile = Profile::new_home(server_id.clone(), server_key, addr);
 
+    let mut reactor = reactor::Core::new().unwrap();
     let profile_store = SimpleProfileRepo::default();
-    profile_store.insert(home_profile);
+    reactor.run( profile_store.insert(home_profile) ).unwrap();
 
-    let mut reactor = reactor::Core::new().unwrap();
     let home_connector = SimpleTcpHomeConnector::new(reactor.handle());
     let profile_gw = MyProfileImpl::new( client_own_profile.clone(), client_signer.clone(), Rc::new(profile_store),
         Rc::new(home_connector), reactor.handle() );
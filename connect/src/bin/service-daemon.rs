@@ -31,6 +31,7 @@ use tokio_codec::LinesCodec;
 use tokio_core::reactor;
 
 use mercury_connect::*;
+use mercury_connect::profile::HomeConnector;
 use mercury_connect::service::*;
 use mercury_connect::jsonrpc;
 use mercury_home_protocol::*;
@@ -39,7 +40,7 @@ use mercury_home_protocol::crypto::*;
 
 
 pub fn init_connect_service(my_profile_privkey_file: &PathBuf, home_pubkey_file: &PathBuf,
-                            home_addr_str: &str, reactor: &mut reactor::Core)
+                            home_addr_str: &str, home_connector: Rc<HomeConnector>, reactor: &mut reactor::Core)
     -> Result<(Rc<ConnectService>, ProfileId, ProfileId), Error>
 {
     use mercury_connect::service::{DummyUserInterface, MyProfileFactory, SignerFactory};
@@ -61,8 +62,9 @@ pub fn init_connect_service(my_profile_privkey_file: &PathBuf, home_pubkey_file:
     let my_private_key = PrivateKey(my_private_key_bytes);
     let my_signer = Rc::new( Ed25519Signer::new(&my_private_key).unwrap() ) as Rc<Signer>;
     let my_profile_id = my_signer.profile_id().to_owned();
-    let my_profile = Profile::new( &my_profile_id, my_signer.public_key(),
+    let mut my_profile = Profile::new( &my_profile_id, my_signer.public_key(),
         &ProfileFacet::Persona( PersonaFacet{homes: vec![], data: vec![]} ) );
+    my_profile.sign_next_version(&*my_signer);
 
     // TODO consider that client should be able to start up without being a DHT client,
     //      e.g. with having only a Home URL including hints to access Home
@@ -83,7 +85,6 @@ pub fn init_connect_service(my_profile_privkey_file: &PathBuf, home_pubkey_file:
     let my_own_profile = OwnProfile::new(&my_profile,&[]);
     let signers = vec![ ( my_profile_id.clone(), my_signer ) ].into_iter().collect();
     let signer_factory: Rc<SignerFactory> = Rc::new(SignerFactory::new(signers) );
-    let home_connector = Rc::new( SimpleTcpHomeConnector::new( reactor.handle() ) );
     let profile_client_factory = Rc::new( MyProfileFactory::new(
         signer_factory, profile_repo.clone(), home_connector, reactor.handle() ) );
 
@@ -117,6 +118,14 @@ struct Config
 //        help="Listen on this socket to serve JsonRpc clients via TCP")]
 //    tcp_address: String,
 
+    #[structopt(long="socks5-proxy", raw(value_name=r#""IP:PORT""#),
+        help="Connect to onion addresses of homes through this SOCKS5 proxy, e.g. a local Tor client")]
+    socks5_proxy: Option<SocketAddr>,
+
+    #[structopt(long="proxy-clearnet",
+        help="Connect to all addresses of homes through the SOCKS5 proxy to hide the IP address of this client")]
+    proxy_clearnet: bool,
+
     #[structopt(long="jsonrpc-uds", default_value="/tmp/jsonrpc.sock", raw(value_name=r#""FILE""#),
         parse(from_os_str), help="Socket file path to serve JsonRpc clients via Unix Domain Sockets")]
     uds_path: PathBuf,
@@ -139,8 +148,12 @@ fn main() -> Result<(), Error>
     println!("Config: {:?}", config);
 
     let mut reactor = reactor::Core::new().unwrap();
+    let home_connector: Rc<HomeConnector> = match config.socks5_proxy {
+        Some(proxy) => Rc::new( Socks5HomeConnector::new( reactor.handle(), proxy, config.proxy_clearnet ) ),
+        None => Rc::new( SimpleTcpHomeConnector::new( reactor.handle() ) ),
+    };
     let (service, _my_profile_id, _home_id) = init_connect_service(&config.my_private_key_file,
-        &config.home_public_key_file, &config.home_address, &mut reactor)?;
+        &config.home_public_key_file, &config.home_address, home_connector, &mut reactor)?;
 
     let jsonrpc = jsonrpc::UdsServer::new( &config.uds_path, reactor.handle() ).unwrap();
     let jsonrpc_fut = jsonrpc.dispatch( LinesCodec::new(), service );
@@ -32,6 +32,15 @@ pub enum ErrorKind {
     #[fail(display="failed to connect tcp stream")]
     ConnectionFailed,
 
+    #[fail(display="unsupported address protocol")]
+    UnsupportedAddress,
+
+    #[fail(display="failed to resolve domain name")]
+    DnsResolutionFailed,
+
+    #[fail(display="failed to connect through proxy")]
+    ProxyConnectionFailed,
+
     #[fail(display="failed to load profile")]
     FailedToLoadProfile,
 
@@ -59,11 +68,20 @@ pub enum ErrorKind {
     #[fail(display="profile update failed")]
     ProfileUpdateFailed,
 
+    #[fail(display="failed to revoke relation")]
+    RevokeRelationFailed,
+
     #[fail(display="call failed")]
     CallFailed,
 
-    #[fail(display="call refused")]
-    CallRefused,
+    #[fail(display="call rejected with reason {}", _0)]
+    CallRejected(u32),
+
+    #[fail(display="hangup failed")]
+    HangupFailed,
+
+    #[fail(display="failed to get presence")]
+    FailedToGetPresence,
 
     #[fail(display="lookup failed")]
     LookupFailed,
@@ -77,6 +95,9 @@ pub enum ErrorKind {
     #[fail(display="no homes found")]
     NoHomesFound,
 
+    #[fail(display="failed to follow redirect of moved profile")]
+    RedirectFailed,
+
     #[fail(display="login failed")]
     LoginFailed,
 
@@ -116,6 +137,14 @@ impl Error {
     pub fn kind(&self) -> ErrorKind {
         *self.inner.get_context()
     }
+
+    /// Kind of the error reported by a home server that caused this error, if any
+    pub fn home_error_kind(&self) -> Option<::mercury_home_protocol::error::ErrorKind> {
+        self.iter_causes()
+            .filter_map( |cause| cause.downcast_ref::<::mercury_home_protocol::error::Error>() )
+            .next()
+            .map( |home_error| home_error.kind() )
+    }
 }
 
 impl From<ErrorKind> for Error {
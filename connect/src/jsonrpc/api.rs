@@ -128,7 +128,7 @@ pub fn create(service: Rc<ConnectService>, handle: reactor::Handle)
             let (cancel_tx, cancel_rx) = oneshot::channel();
             meta.set_cancel_events(cancel_tx); // NOTE on a repeated subscribe call this drops the previous tx causing rx to be cancelled
 
-            let fwd_events_fut = dapp_session.checkin()
+            let fwd_events_fut = dapp_session.checkin(None)
                 .map_err( |e| () ) // TODO
                 .and_then( |dapp_events| dapp_events
                     .map( |event| match event {
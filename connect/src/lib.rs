@@ -1,5 +1,6 @@
 extern crate failure;
 extern crate futures;
+extern crate futures_cpupool;
 extern crate jsonrpc_core;
 extern crate jsonrpc_pubsub;
 #[macro_use]
@@ -23,6 +24,8 @@ pub mod error;
 pub use error::{Error, ErrorKind};
 pub mod net;
 pub use net::SimpleTcpHomeConnector;
+pub mod socks;
+pub use socks::Socks5HomeConnector;
 pub mod jsonrpc;
 pub mod sdk;
 pub mod service;
@@ -38,6 +41,7 @@ use futures::prelude::*;
 
 use mercury_home_protocol::*;
 use mercury_storage::async::KeyValueStore;
+use profile::MyProfile;
 
 
 
@@ -48,14 +52,66 @@ pub struct DAppPermission(Vec<u8>);
 pub trait Contact
 {
     fn proof(&self) -> &RelationProof;
-    fn call(&self, init_payload: AppMessageFrame) -> AsyncResult<DAppCall,Error>;
+    /// Call the contact, the returned id can be used to hang up the call even while it is still ringing.
+    fn call(&self, init_payload: AppMessageFrame) -> (CallId, AsyncResult<DAppCall,Error>);
+    /// Hang up a call to the contact, e.g. to stop it ringing before it was answered.
+    fn hangup(&self, call: CallId) -> AsyncResult<(),Error>;
+    /// Presence published by the contact's instance of this app, None if it is not checked in
+    fn presence(&self) -> AsyncResult<Option<AppMessageFrame>,Error>;
+    /// Current presence of the contact's instance of this app, followed by all its changes
+    fn watch_presence(&self) -> AsyncResult<AsyncStream<Option<AppMessageFrame>,String>,Error>;
 }
 
 
+/// Channels of an answered call. If the callee only replied to the call, the reply is the single
+/// message of `incoming` and messages sent to `outgoing` fail.
+/// Dropping `outgoing` ends the incoming stream of the peer, the call is over when both parties closed their side.
 pub struct DAppCall
 {
+    pub id:       CallId,
     pub outgoing: AppMsgSink,
-    pub incoming: AppMsgStream
+    pub incoming: AppMsgStream,
+    /// Profile whose home hosts the call, i.e. the callee
+    callee:       ProfileId,
+    /// Used to hang up the call, None if the call was assembled by the application from an answered incoming call
+    my_profile:   Option<Rc<MyProfile>>,
+}
+
+impl DAppCall
+{
+    pub(crate) fn new(id: CallId, callee: ProfileId, my_profile: Rc<MyProfile>,
+                      outgoing: AppMsgSink, incoming: AppMsgStream) -> Self
+        { Self{ id, outgoing, incoming, callee, my_profile: Some(my_profile) } }
+
+    /// Channels of an incoming call answered by the application. Closing such a call only drops its channels.
+    pub fn from_channels(id: CallId, callee: ProfileId, outgoing: AppMsgSink, incoming: AppMsgStream) -> Self
+        { Self{ id, outgoing, incoming, callee, my_profile: None } }
+
+    /// Hang up the call immediately, messages still on their way might be lost.
+    pub fn close(self) -> AsyncResult<(), Error>
+    {
+        let my_profile = match self.my_profile {
+            Some(my_profile) => my_profile,
+            None => return Box::new( Ok( () ).into_future() ),
+        };
+        let hangup_fut = my_profile.hangup( &self.callee, self.id )
+            // NOTE the peer might have hung up already
+            .or_else( |e| match e.home_error_kind() {
+                Some(mercury_home_protocol::error::ErrorKind::CallNotFound) => Ok( () ),
+                _ => Err(e),
+            } );
+        Box::new(hangup_fut)
+    }
+
+    /// Send a last message, then hang up the call.
+    pub fn close_with(self, last: AppMessageFrame) -> AsyncResult<(), Error>
+    {
+        let DAppCall{ id, outgoing, incoming, callee, my_profile } = self;
+        let close_fut = outgoing.send( Ok(last) )
+            .map_err( |_e| ErrorKind::CallFailed.into() )
+            .and_then( move |outgoing| DAppCall{ id, outgoing, incoming, callee, my_profile }.close() );
+        Box::new(close_fut)
+    }
 }
 
 //impl Drop for DAppCall
@@ -95,5 +151,6 @@ pub trait DAppSession
 
     fn app_storage(&self) -> AsyncResult<KeyValueStore<String,String>, Error>;
 
-    fn checkin(&self) -> AsyncResult< Box< Stream<Item=DAppEvent, Error=()> >, Error>;
+    /// `presence` is published to contacts of this app until the returned stream is dropped
+    fn checkin(&self, presence: Option<AppMessageFrame>) -> AsyncResult< Box< Stream<Item=DAppEvent, Error=()> >, Error>;
 }
@@ -1,8 +1,11 @@
-use std::net::{SocketAddr, IpAddr};
+use std::net::{SocketAddr, IpAddr, ToSocketAddrs};
 use std::rc::Rc;
+use std::time::Duration;
 
 use failure::Fail;
-use futures::{future, Future};
+use futures::{future, Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+use futures_cpupool::CpuPool;
 use multiaddr::{Multiaddr, AddrComponent};
 use tokio_core::reactor;
 use tokio_core::net::TcpStream;
@@ -49,55 +52,338 @@ impl HomeConnector for TcpHomeConnector
 
 
 
-/// Convert a TCP/IP multiaddr to a SocketAddr. For multiaddr instances that are not TCP or IP, error is returned.
-pub fn multiaddr_to_socketaddr(multiaddr: &Multiaddr) -> Result<SocketAddr, Error>
+// TODO this should come from user configuration with a reasonable default value close to this
+/// Delay between starting connection attempts to the next address of a home, see RFC 8305
+const CFG_CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+
+
+/// Host part of a multiaddr, DNS names have to be resolved before connecting
+pub(crate) enum Host
+{
+    Ip(IpAddr),
+    Dns4(String),
+    Dns6(String),
+}
+
+
+/// Split the multiaddr of a TCP endpoint into its host and port, other protocols are refused.
+// NOTE the pinned multiaddr version cannot represent /dns, only /dns4 and /dns6,
+//      so supporting names of any IP version is out of scope until multiaddr is upgraded
+pub(crate) fn parse_tcp_multiaddr(multiaddr: &Multiaddr) -> Result<(Host, u16), Error>
 {
     let mut components = multiaddr.iter();
 
-    let ip_address = match components.next()
+    let host = match components.next()
         {
-            Some( AddrComponent::IP4(address) ) => IpAddr::from(address),
-            Some( AddrComponent::IP6(address) ) => IpAddr::from(address),
-            _ => Err(ErrorKind::AddressConversionFailed)?,
+            Some( AddrComponent::IP4(address) ) => Host::Ip( IpAddr::from(address) ),
+            Some( AddrComponent::IP6(address) ) => Host::Ip( IpAddr::from(address) ),
+            Some( AddrComponent::DNS4(name) )   => Host::Dns4(name),
+            Some( AddrComponent::DNS6(name) )   => Host::Dns6(name),
+            Some(component) => return unsupported_protocol(multiaddr, &component),
+            None => Err(ErrorKind::AddressConversionFailed)?,
         };
 
-    let ip_port = match components.next()
+    let port = match components.next()
         {
             Some( AddrComponent::TCP(port) ) => port,
-            Some( AddrComponent::UDP(port) ) => port,
-            _ => return Err(ErrorKind::AddressConversionFailed)?,
+            Some(component) => return unsupported_protocol(multiaddr, &component),
+            None => Err(ErrorKind::AddressConversionFailed)?,
         };
 
-    Ok( SocketAddr::new(ip_address, ip_port) )
+    match components.next() {
+        Some(component) => unsupported_protocol(multiaddr, &component),
+        None => Ok( (host, port) ),
+    }
+}
+
+
+fn unsupported_protocol<T>(multiaddr: &Multiaddr, component: &AddrComponent) -> Result<T, Error>
+{
+    debug!("Unsupported protocol {:?} in address {}, expected /ip4, /ip6, /dns4 or /dns6 followed by /tcp",
+           component, multiaddr);
+    Err( ErrorKind::UnsupportedAddress.into() )
+}
+
+
+/// Convert a TCP/IP multiaddr to a SocketAddr. For multiaddr instances that are not TCP or IP, error is returned,
+/// use resolve_multiaddr() for addresses with DNS names.
+pub fn multiaddr_to_socketaddr(multiaddr: &Multiaddr) -> Result<SocketAddr, Error>
+{
+    match parse_tcp_multiaddr(multiaddr)? {
+        ( Host::Ip(ip_address), port ) => Ok( SocketAddr::new(ip_address, port) ),
+        _ => Err(ErrorKind::AddressConversionFailed)?,
+    }
+}
+
+
+/// All socket addresses of a TCP multiaddr, DNS names are resolved to addresses of the requested IP version
+pub fn resolve_multiaddr(multiaddr: &Multiaddr, resolver: &CpuPool) -> AsyncResult<Vec<SocketAddr>, Error>
+{
+    let (name, want_ipv6, port) = match parse_tcp_multiaddr(multiaddr) {
+        Ok( (Host::Ip(ip_address), port) ) => return Box::new( future::ok( vec![ SocketAddr::new(ip_address, port) ] ) ),
+        Ok( (Host::Dns4(name), port) ) => (name, false, port),
+        Ok( (Host::Dns6(name), port) ) => (name, true, port),
+        Err(e) => return Box::new( future::err(e) ),
+    };
+
+    // NOTE the resolver of the system is blocking, so it runs on a thread pool
+    let resolve_fut = resolver.spawn_fn( move || -> Result<Vec<SocketAddr>, Error> {
+        let addrs = (name.as_str(), port).to_socket_addrs()
+            .map_err( |e| { debug!("Failed to resolve {}: {}", name, e); e.context(ErrorKind::DnsResolutionFailed) } )?
+            .filter( |addr| addr.is_ipv6() == want_ipv6 )
+            .collect::<Vec<_>>();
+        if addrs.is_empty() {
+            debug!("Name {} has no IPv{} address", name, if want_ipv6 { 6 } else { 4 });
+            Err(ErrorKind::DnsResolutionFailed)?
+        }
+        Ok(addrs)
+    } );
+    Box::new(resolve_fut)
+}
+
+
+/// Order addresses for connection attempts alternating between IP versions,
+/// starting with the version of the first address. Duplicates are removed.
+fn interleave_ip_versions(addrs: Vec<SocketAddr>) -> Vec<SocketAddr>
+{
+    let mut unique = Vec::new();
+    for addr in addrs {
+        if ! unique.contains(&addr)
+            { unique.push(addr); }
+    }
+
+    let first_is_ipv6 = unique.first().map( |addr| addr.is_ipv6() ).unwrap_or(false);
+    let (preferred, others): (Vec<_>, Vec<_>) = unique.into_iter()
+        .partition( |addr| addr.is_ipv6() == first_is_ipv6 );
+    let (mut preferred, mut others) = ( preferred.into_iter(), others.into_iter() );
+
+    let mut result = Vec::new();
+    loop {
+        match ( preferred.next(), others.next() ) {
+            (None, None) => return result,
+            (first, second) => { result.extend(first); result.extend(second); },
+        }
+    }
+}
+
+
+/// Race TCP connections to the addresses, see race_staggered()
+pub fn connect_any(addrs: Vec<SocketAddr>, handle: &reactor::Handle) -> AsyncResult<TcpStream, Error>
+{
+    let attempts = addrs.into_iter()
+        .map( |addr| {
+            let handle = handle.clone();
+            let attempt_fut = future::lazy( move || {
+                    debug!("Connecting to socket address {}", addr);
+                    TcpStream::connect(&addr, &handle)
+                } )
+                .map_err( move |e| {
+                    debug!("Failed to connect to {}: {}", addr, e);
+                    e.context(ErrorKind::ConnectionFailed).into()
+                } );
+            Box::new(attempt_fut) as AsyncResult<TcpStream, Error>
+        } )
+        .collect();
+    race_staggered(attempts, handle)
+}
+
+
+/// Run connection attempts, each started a bit later than the previous one
+/// so a working address wins without waiting for others to time out (happy eyeballs, RFC 8305).
+/// Attempts must not start working before polled, e.g. created by future::lazy().
+/// Resolves with the first connected stream, other attempts are cancelled.
+pub fn race_staggered(attempts: Vec< AsyncResult<TcpStream, Error> >, handle: &reactor::Handle)
+    -> AsyncResult<TcpStream, Error>
+{
+    if attempts.is_empty()
+        { return Box::new( future::err( ErrorKind::AddressConversionFailed.into() ) ); }
+
+    let delayed_attempts = attempts.into_iter().enumerate()
+        .map( |(index, attempt_fut)| {
+            let delay = CFG_CONNECTION_ATTEMPT_DELAY * index as u32;
+            let delayed_fut = future::result( reactor::Timeout::new(delay, handle) )
+                .flatten()
+                .map_err( |e| e.context(ErrorKind::ConnectionFailed).into() )
+                .and_then( move |()| attempt_fut );
+            Box::new(delayed_fut) as AsyncResult<TcpStream, Error>
+        } )
+        .collect::<Vec<_>>();
+
+    let connect_fut = future::select_ok(delayed_attempts)
+        .map( |(tcp_stream, _pending_attempts)| tcp_stream );
+    Box::new(connect_fut)
+}
+
+
+/// Authenticate the home on a connected stream and build a Home proxy on top of it
+pub fn connect_home(tcp_fut: AsyncResult<TcpStream, Error>, home_id: ProfileId, signer: Rc<Signer>,
+                    handle: reactor::Handle) -> AsyncResult<Rc<Home>, Error>
+{
+    let capnp_home = tcp_fut
+        .and_then( move |tcp_stream|
+        {
+            use mercury_home_protocol::handshake::tcp_ecdh_handshake;
+            tcp_ecdh_handshake(tcp_stream, signer)
+            .map_err(|err| err.context(ErrorKind::HandshakeFailed).into())
+        })
+        .and_then( move |(reader, writer, peer_ctx)| {
+            // The handshake proved the identity of the peer, it must be the home we wanted to reach
+            if *peer_ctx.peer_id() != home_id {
+                warn!("Expected home {} but peer proved to be {}", home_id, peer_ctx.peer_id());
+                return Err( ErrorKind::HandshakeFailed.into() );
+            }
+            use mercury_home_protocol::mercury_capnp::client_proxy::HomeClientCapnProto;
+            Ok( Rc::new( HomeClientCapnProto::new(reader, writer, handle) ) as Rc<Home> )
+        });
+    Box::new(capnp_home)
 }
 
 
 
 pub struct SimpleTcpHomeConnector
 {
-    handle: reactor::Handle,
+    handle:     reactor::Handle,
+    resolver:   CpuPool,
     // TODO cache_connections: TODO,
 }
 
 
 impl SimpleTcpHomeConnector
 {
+    const RESOLVER_THREADS: usize = 2;
+
     pub fn new(handle: reactor::Handle) -> Self
-        { Self{ handle: handle} }
+        { Self{ handle: handle, resolver: CpuPool::new(Self::RESOLVER_THREADS) } }
+
 
-    pub fn connect_addr(addr: &Multiaddr, handle: &reactor::Handle) ->
-        AsyncResult<TcpStream, Error>
+    /// Resolve all addresses of the home and race connections to them,
+    /// connecting to resolved addresses without waiting for slower lookups
+    pub fn connect_addrs(&self, addrs: &[Multiaddr]) -> AsyncResult<TcpStream, Error>
     {
-        // TODO handle other multiaddresses, not only TCP
-        let tcp_addr = match multiaddr_to_socketaddr(addr)
-        {
-            Ok(res) => res,
-            Err(err) => return Box::new( future::err(err))
+        let resolve_futs = addrs.iter()
+            .map( |addr| resolve_multiaddr(addr, &self.resolver) )
+            .collect::<Vec<_>>();
+        Box::new( ResolvingConnect::new(resolve_futs, &self.handle) )
+    }
+}
+
+
+
+/// Staggered connection attempts like race_staggered(), started as soon as the addresses are resolved.
+/// Resolves with the first connected stream, other attempts and lookups are cancelled.
+struct ResolvingConnect
+{
+    handle:         reactor::Handle,
+    resolving:      FuturesUnordered< AsyncResult<Vec<SocketAddr>, Error> >,
+    /// Resolved addresses not tried yet, ordered by interleave_ip_versions()
+    pending_addrs:  Vec<SocketAddr>,
+    tried_addrs:    Vec<SocketAddr>,
+    attempts:       FuturesUnordered< AsyncResult<TcpStream, Error> >,
+    /// Head start of the last attempt, the next one may start right away if None
+    attempt_delay:  Option<reactor::Timeout>,
+    resolve_error:  Option<Error>,
+    connect_error:  Option<Error>,
+}
+
+
+impl ResolvingConnect
+{
+    fn new(resolve_futs: Vec< AsyncResult<Vec<SocketAddr>, Error> >, handle: &reactor::Handle) -> Self
+    {
+        Self{ handle: handle.clone(), resolving: resolve_futs.into_iter().collect(),
+              pending_addrs: Vec::new(), tried_addrs: Vec::new(), attempts: FuturesUnordered::new(),
+              attempt_delay: None, resolve_error: None, connect_error: None }
+    }
+
+
+    fn add_addrs(&mut self, addrs: Vec<SocketAddr>)
+    {
+        let mut pending_addrs = ::std::mem::replace( &mut self.pending_addrs, Vec::new() );
+        pending_addrs.extend( addrs.into_iter().filter( |addr| ! self.tried_addrs.contains(addr) ) );
+        self.pending_addrs = interleave_ip_versions(pending_addrs);
+    }
+
+
+    /// Next address to try, preferring the other IP version than the one of the last attempt
+    fn next_addr(&mut self) -> Option<SocketAddr>
+    {
+        if self.pending_addrs.is_empty()
+            { return None; }
+
+        let last_is_ipv6 = self.tried_addrs.last().map( |addr| addr.is_ipv6() );
+        let index = self.pending_addrs.iter()
+            .position( |addr| Some( addr.is_ipv6() ) != last_is_ipv6 )
+            .unwrap_or(0);
+        let addr = self.pending_addrs.remove(index);
+        self.tried_addrs.push(addr);
+        Some(addr)
+    }
+
+
+    fn start_attempt(&mut self, addr: SocketAddr)
+    {
+        debug!("Connecting to socket address {}", addr);
+        let attempt_fut = TcpStream::connect(&addr, &self.handle)
+            .map_err( move |e| {
+                debug!("Failed to connect to {}: {}", addr, e);
+                e.context(ErrorKind::ConnectionFailed).into()
+            } );
+        self.attempts.push( Box::new(attempt_fut) );
+        // NOTE without a working timer all attempts are started right away
+        self.attempt_delay = reactor::Timeout::new(CFG_CONNECTION_ATTEMPT_DELAY, &self.handle).ok();
+    }
+}
+
+
+impl Future for ResolvingConnect
+{
+    type Item = TcpStream;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<TcpStream, Error>
+    {
+        let resolving_done = loop {
+            match self.resolving.poll() {
+                Ok( Async::Ready( Some(addrs) ) ) => self.add_addrs(addrs),
+                Ok( Async::Ready(None) ) => break true,
+                Ok( Async::NotReady ) => break false,
+                Err(e) => { self.resolve_error.get_or_insert(e); },
+            }
         };
 
-        debug!("Connecting to socket address {}", tcp_addr);
-        let tcp_str = TcpStream::connect(&tcp_addr, handle).map_err( |err| err.context(ErrorKind::ConnectionFailed).into());
-        Box::new(tcp_str)
+        loop {
+            let may_start = match self.attempt_delay {
+                Some(ref mut delay) => match delay.poll() {
+                    Ok( Async::NotReady ) => false,
+                    _ => true,
+                },
+                None => true,
+            };
+            if may_start {
+                if let Some(addr) = self.next_addr()
+                    { self.start_attempt(addr); continue; }
+            }
+
+            match self.attempts.poll() {
+                Ok( Async::Ready( Some(tcp_stream) ) ) => return Ok( Async::Ready(tcp_stream) ),
+                Ok( Async::NotReady ) => return Ok(Async::NotReady),
+                Err(e) => {
+                    // NOTE a failed attempt does not need to give the next one a head start
+                    self.connect_error = Some(e);
+                    self.attempt_delay = None;
+                },
+                Ok( Async::Ready(None) ) => {
+                    if ! resolving_done || ! self.pending_addrs.is_empty()
+                        { return Ok(Async::NotReady); }
+                    // NOTE report why addresses could not be used, e.g. an unsupported protocol
+                    let error = self.connect_error.take()
+                        .or_else( || self.resolve_error.take() )
+                        .unwrap_or_else( || ErrorKind::AddressConversionFailed.into() );
+                    return Err(error);
+                },
+            }
+        }
     }
 }
 
@@ -112,26 +398,8 @@ impl HomeConnector for SimpleTcpHomeConnector
             _ => return Box::new(future::err(ErrorKind::HomeProfileExpected.into())),
         };
 
-        let handle_clone = self.handle.clone();
-        let tcp_conns = addrs.iter().map( move |addr| {
-            SimpleTcpHomeConnector::connect_addr(&addr, &handle_clone)
-            .map_err(|err| err.context(ErrorKind::ConnectionFailed).into()) 
-        });
-
-        let handle_clone = self.handle.clone();
-        let capnp_home = future::select_ok(tcp_conns)
-            .and_then( move |(tcp_stream, _pending_futs)|
-            {
-                use mercury_home_protocol::handshake::temp_tcp_handshake_until_tls_is_implemented;
-                temp_tcp_handshake_until_tls_is_implemented(tcp_stream, signer)
-                .map_err(|err| err.context(ErrorKind::HandshakeFailed).into())
-            }).map( |(reader, writer, _peer_ctx)| {
-                use mercury_home_protocol::mercury_capnp::client_proxy::HomeClientCapnProto;
-                Rc::new( HomeClientCapnProto::new(reader, writer, handle_clone) ) as Rc<Home>
-            });
-
-        
-        Box::new(capnp_home)
+        let tcp_fut = self.connect_addrs(&addrs);
+        connect_home( tcp_fut, home_profile.id.clone(), signer, self.handle.clone() )
     }
 }
 
@@ -154,6 +422,61 @@ mod tests
         let multiaddr = "/ip4/127.0.0.1/utp".parse::<Multiaddr>().unwrap();
         let socketaddr = multiaddr_to_socketaddr(&multiaddr);
         
-        assert_eq!(socketaddr, Result::Err(ErrorKind::AddressConversionFailed.into()));
+        assert_eq!(socketaddr, Result::Err(ErrorKind::UnsupportedAddress.into()));
+
+        let multiaddr = "/ip4/127.0.0.1/udp/22".parse::<Multiaddr>().unwrap();
+        assert_eq!(multiaddr_to_socketaddr(&multiaddr), Result::Err(ErrorKind::UnsupportedAddress.into()));
+
+        let multiaddr = "/ip4/127.0.0.1".parse::<Multiaddr>().unwrap();
+        assert_eq!(multiaddr_to_socketaddr(&multiaddr), Result::Err(ErrorKind::AddressConversionFailed.into()));
+    }
+
+
+    #[test]
+    fn test_multiaddr_resolution()
+    {
+        let resolver = CpuPool::new(1);
+        let multiaddr = "/dns4/localhost/tcp/2077".parse::<Multiaddr>().unwrap();
+        let socketaddrs = resolve_multiaddr(&multiaddr, &resolver).wait().unwrap();
+        assert!( socketaddrs.contains( &SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2077) ) );
+        assert!( socketaddrs.iter().all( |addr| addr.is_ipv4() ) );
+
+        let multiaddr = "/ip6/::1/tcp/2077".parse::<Multiaddr>().unwrap();
+        let socketaddrs = resolve_multiaddr(&multiaddr, &resolver).wait().unwrap();
+        assert_eq!( socketaddrs, vec![ "[::1]:2077".parse::<SocketAddr>().unwrap() ] );
+    }
+
+
+    #[test]
+    fn test_connect_before_all_resolved()
+    {
+        let mut reactor = reactor::Core::new().unwrap();
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listener_addr = listener.local_addr().unwrap();
+
+        // NOTE a lookup that never finishes must not hold back addresses resolved already
+        let resolve_futs = vec![
+            Box::new( future::empty::<Vec<SocketAddr>, Error>() ) as AsyncResult<Vec<SocketAddr>, Error>,
+            Box::new( future::ok( vec![listener_addr] ) ) as AsyncResult<Vec<SocketAddr>, Error>,
+        ];
+        let tcp_stream = reactor.run( ResolvingConnect::new( resolve_futs, &reactor.handle() ) ).unwrap();
+        assert_eq!( tcp_stream.peer_addr().unwrap(), listener_addr );
+
+        let resolve_futs = vec![ Box::new( future::err( ErrorKind::DnsResolutionFailed.into() ) ) as AsyncResult<Vec<SocketAddr>, Error> ];
+        let connect_res = reactor.run( ResolvingConnect::new( resolve_futs, &reactor.handle() ) );
+        assert_eq!( connect_res.err().map( |e| e.kind() ), Some(ErrorKind::DnsResolutionFailed) );
     }
-}
\ No newline at end of file
+
+
+    #[test]
+    fn test_interleave_ip_versions()
+    {
+        let addrs = ["[::1]:1", "[::2]:1", "10.0.0.1:1", "[::1]:1", "10.0.0.2:1", "10.0.0.3:1"].iter()
+            .map( |addr| addr.parse::<SocketAddr>().unwrap() )
+            .collect::<Vec<_>>();
+        let ordered = ["[::1]:1", "10.0.0.1:1", "[::2]:1", "10.0.0.2:1", "10.0.0.3:1"].iter()
+            .map( |addr| addr.parse::<SocketAddr>().unwrap() )
+            .collect::<Vec<_>>();
+        assert_eq!( interleave_ip_versions(addrs), ordered );
+    }
+}
@@ -1,5 +1,5 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::rc::{Rc, Weak};
 
 use failure::Fail;
@@ -8,10 +8,16 @@ use futures::{future, sync::mpsc};
 use tokio_core::reactor;
 
 use super::*;
+use mercury_home_protocol::crypto::CompositeValidator;
 use mercury_home_protocol::future as fut;
 
 
 
+// TODO this should come from user configuration with a reasonable default value close to this
+/// Number of recent events remembered to filter duplicates arriving from several homes
+const CFG_EVENT_DEDUP_WINDOW: usize = 64;
+
+
 pub trait HomeConnector
 {
     /// Initiate a permanent connection to the home server defined by `home_profile`, or return an
@@ -33,7 +39,9 @@ pub trait MyProfile
     // TODO we should be able to handle profile URLs and/or home address hints to avoid needing a profile repository to join the first home node
     /// `invite` is needed only if the home has a restrictive registration policy.
     fn join_home(&self, home: ProfileId, invite: Option<HomeInvitation>) -> AsyncResult<(), Error>;
-    // NOTE newhome is a profile that contains at least one HomeSchema different than this home
+    /// Unregister from `home`. If the profile of a `newhome` is given, the profile is moved there:
+    /// it's registered on the new home (unless already hosted there) and the old home will redirect
+    /// anyone looking for the profile to the new home for a while.
     fn leave_home(&self, home: ProfileId, newhome: Option<Profile>) -> AsyncResult<(), Error>;
 //    fn home_endpoint_hint(&self, home: &ProfileId, endpoint: multiaddr);
 //    fn profile_home_hint(&self, profile: &ProfileId, home: &ProfileId);
@@ -46,11 +54,26 @@ pub trait MyProfile
         -> AsyncResult<(), Error>;
     fn accept_relation(&self, half_proof: &RelationHalfProof)
         -> AsyncResult<RelationProof, Error>;
-//    fn revoke_relation(&self, relation: &RelationProof) -> AsyncResult<(), Error>;
-
-
-    fn call(&self, rel: RelationProof, app: ApplicationId, init_payload: AppMessageFrame,
-            to_caller: Option<AppMsgSink>) -> AsyncResult<Option<AppMsgSink>, Error>;
+    /// Sign a revocation of `relation` and deliver it to all homes of both parties,
+    /// after which none of them accepts calls with it anymore. Fails only if no home accepted it.
+    fn revoke_relation(&self, relation: &RelationProof) -> AsyncResult<(), Error>;
+    /// Subscribe to profile updates of the peer of `relation` at one of its homes.
+    /// New versions published by the peer are stored into our profile repository, then forwarded
+    /// as `ProfileEvent::ProfileUpdated` into the returned stream. The stream ends when the peer leaves that home.
+    fn watch_profile(&self, relation: &RelationProof) -> AsyncResult<EventStream, Error>;
+
+
+    /// Call the peer of `call_req.relation` at one of its homes. The call can be hung up
+    /// using the id of the request any time, even while it is still ringing.
+    fn call(&self, app: ApplicationId, call_req: CallRequestDetails) -> AsyncResult<CallAnswer, Error>;
+    /// Hang up a call hosted by one of the homes of `callee`, i.e. our own homes for incoming calls.
+    /// Fails with `CallNotFound` from the homes only if none of them knows the call.
+    fn hangup(&self, callee: &ProfileId, call: CallId) -> AsyncResult<(), Error>;
+    /// Presence of `app` published by the peer of `rel`, None if the app is not checked in.
+    fn presence(&self, rel: RelationProof, app: ApplicationId) -> AsyncResult<Option<AppMessageFrame>, Error>;
+    /// Current presence of `app` published by the peer of `rel`, followed by all its changes.
+    fn watch_presence(&self, rel: RelationProof, app: ApplicationId)
+        -> AsyncResult<AsyncStream<Option<AppMessageFrame>, String>, Error>;
 
 
     fn login(&self) -> AsyncResult<Rc<MyHomeSession>, Error>;
@@ -74,6 +97,16 @@ pub trait MyHomeSession
 
 
 
+/// True if `error` was caused by a home that could not serve the request at all, so another home of the profile may be tried
+fn is_home_failure(error: &Error) -> bool
+{
+    error.iter_causes()
+        .filter_map( |cause| cause.downcast_ref::<::mercury_home_protocol::error::Error>() )
+        .any( |home_error| home_error.is_home_failure() )
+}
+
+
+
 #[derive(Clone)]
 pub struct MyProfileImpl
 {
@@ -83,6 +116,7 @@ pub struct MyProfileImpl
     home_connector: Rc<HomeConnector>,
     handle:         reactor::Handle,
     session_cache:  Rc<RefCell< HashMap<ProfileId, Rc<MyHomeSession>> >>, // {home_id -> session}
+    login_session:  Rc<RefCell< Option<Rc<MyHomeSession>> >>, // merged sessions of all homes
     // on_updated:     Rc< Fn(&OwnProfile) -> AsyncResult<(),Error>,
 // TODO remove this after testing, this should be fetched from the private binary part of OwnProfile
     relations:      Rc<RefCell< Vec<RelationProof> >>,
@@ -95,7 +129,8 @@ impl MyProfileImpl
                home_connector: Rc<HomeConnector>, handle: reactor::Handle) -> Self
         { Self{ own_profile : Rc::new( RefCell::new(own_profile) ),
                 signer, profile_repo, home_connector, handle,
-                relations: Default::default(), session_cache: Default::default() } }
+                relations: Default::default(), session_cache: Default::default(),
+                login_session: Default::default() } }
 
 //    pub fn new<F>(own_profile: OwnProfile, signer: Rc<Signer>, profile_repo: Rc<ProfileRepo>,
 //                  home_connector: Rc<HomeConnector>, handle: reactor::Handle,
@@ -133,7 +168,6 @@ impl MyProfileImpl
 
         let home_id = home_profile_id.clone();
         let my_profile_id = self.signer.profile_id().to_owned();
-        let session_cache = self.session_cache.clone();
         let login_fut = self.profile_repo.load(&my_profile_id)
             .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
             .and_then( |profile|
@@ -158,33 +192,88 @@ impl MyProfileImpl
             } )
             .and_then(
             {
-                let profile_repo_clone = self.profile_repo.clone();
-                let home_connector_clone = self.home_connector.clone();
-                let signer_clone = self.signer.clone();
-                let handle = self.handle.clone();
-                let handle2 = self.handle.clone();
-                let relations_weak = Rc::downgrade(&self.relations);
+                let this = self.clone();
                 move |home_proof| {
-                    Self::connect_home2(&home_profile_id, profile_repo_clone, home_connector_clone, signer_clone)
-                        .and_then( move |home| {
-                            home.login(&home_proof)
-                                .map_err( |err| err.context(ErrorKind::LoginFailed).into() )
-                                .map( move |session| MyHomeSessionImpl::new(session, handle) )
-                                .inspect( move |my_session|
-                                {
-                                    // TODO this allows initiating several fill attempts in parallel
-                                    //      until first one succeeds, last one wins by overwriting.
-                                    //      Is this acceptable?
-                                    session_cache.borrow_mut().insert( home_profile_id.to_owned(), my_session.clone() );
-                                    Self::start_event_handler( relations_weak, my_session.clone(), &handle2 )
-                                } )
-                        } )
+                    let relations_weak = Rc::downgrade(&this.relations);
+                    let handle = this.handle.clone();
+                    this.login_home_session(home_proof)
+                        .inspect( move |my_session|
+                            Self::start_event_handler( relations_weak, my_session.clone(), &handle ) )
                 }
             });
         Box::new(login_fut)
     }
 
 
+    /// Log into a single home with `home_proof` unless a session is already cached for it.
+    /// In contrast to login_home(), profile events are not processed here.
+    fn login_home_session(&self, home_proof: RelationProof) -> AsyncResult<Rc<MyHomeSession>, Error>
+    {
+        let home_id = match home_proof.peer_id( self.signer.profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err( e.context(ErrorKind::FailedToAuthorize).into() ).into_future() ),
+        };
+
+        if let Some(ref session_rc) = self.session_cache.borrow().get(&home_id)
+            { return Box::new( Ok( Rc::clone(session_rc) ).into_future() ) }
+
+        let session_cache = self.session_cache.clone();
+        let handle = self.handle.clone();
+        let login_fut = self.connect_home(&home_id)
+            .and_then( move |home| {
+                home.login(&home_proof)
+                    .map_err( |err| err.context(ErrorKind::LoginFailed).into() )
+                    .map( move |session| MyHomeSessionImpl::new(session, handle) )
+                    .inspect( move |my_session|
+                    {
+                        // TODO this allows initiating several fill attempts in parallel
+                        //      until first one succeeds, last one wins by overwriting.
+                        //      Is this acceptable?
+                        session_cache.borrow_mut().insert( home_id, my_session.clone() );
+                    } )
+            } );
+        Box::new(login_fut)
+    }
+
+
+    /// Store our current, already signed profile in the repository and publish it on all of our homes.
+    /// Homes that cannot be reached are skipped, publishing fails only if none of them accepted the profile.
+    fn publish_profile(&self) -> AsyncResult<(), Error>
+    {
+        let own_profile = self.own_profile.borrow().to_owned();
+        let homes = match own_profile.profile.facet {
+            ProfileFacet::Persona(ref persona) => persona.homes.clone(),
+            _ => return Box::new( future::err( ErrorKind::PersonaProfileExpected.into() ) ),
+        };
+
+        let this = self.clone();
+        let publish_fut = self.profile_repo.insert( own_profile.profile.clone() )
+            .map_err( |e| e.context(ErrorKind::ProfileUpdateFailed).into() )
+            .and_then( move |()| {
+                let home_count = homes.len();
+                let update_futs = homes.into_iter()
+                    .map( |home_proof| {
+                        let own_profile = own_profile.clone();
+                        this.login_home_session(home_proof)
+                            .and_then( move |my_session| my_session.session().update(own_profile)
+                                .map_err( |e| e.context(ErrorKind::ProfileUpdateFailed).into() ) )
+                    } )
+                    .collect::<Vec<_>>();
+                fut::collect_results(update_futs)
+                    .map_err( |()| ErrorKind::ImplementationError.into() )
+                    .and_then( move |results| {
+                        let published = results.into_iter()
+                            .filter_map( |res| res.map_err( |e| warn!("Failed to publish profile on home: {}", e) ).ok() )
+                            .count();
+                        if home_count > 0 && published == 0
+                            { return Err( ErrorKind::ProfileUpdateFailed.into() ); }
+                        Ok( () )
+                    } )
+            } );
+        Box::new(publish_fut)
+    }
+
+
     pub fn any_home_of(&self, profile: &Profile)
         -> AsyncResult<(RelationProof, Rc<Home>), Error>
     {
@@ -234,6 +323,170 @@ impl MyProfileImpl
     }
 
 
+    /// Run `operation` on the first home of `profile` that can be connected and serves the request.
+    /// Homes are tried one after the other in order of the persona's preference, failing over to the next one
+    /// only if a home could not be connected or could not serve the request at all, see `is_home_failure()`.
+    /// Any other error returned by the operation is final. If all homes failed, the profile might have moved away,
+    /// so a redirect served by its old homes is followed once before giving up with the last error.
+    fn with_any_home_of2<T,F>(profile: &Profile, prof_repo: Rc<ProfileRepo>,
+                              connector: Rc<HomeConnector>, signer: Rc<Signer>, operation: F)
+        -> AsyncResult<T, Error>
+    where T: 'static,
+          F: Fn(RelationProof, Rc<Home>) -> AsyncResult<T, Error> + 'static
+    {
+        let operation = Rc::new(operation) as Rc<Fn(RelationProof, Rc<Home>) -> AsyncResult<T, Error>>;
+        let profile_clone = profile.to_owned();
+        let result = Self::try_homes_of2( profile, prof_repo.clone(), connector.clone(), signer.clone(), operation.clone() )
+            .or_else( move |(e, fail_over)| {
+                if ! fail_over
+                    { return Box::new( future::err(e) ) as AsyncResult<T, Error>; }
+
+                let redirect_fut = Self::follow_redirect2( &profile_clone, prof_repo.clone(), connector.clone(), signer.clone() )
+                    .then( move |redirect_res| match redirect_res {
+                        Ok(new_profile) => {
+                            debug!("Profile {} moved to another home, retrying there", new_profile.id);
+                            let retry_fut = Self::try_homes_of2(&new_profile, prof_repo, connector, signer, operation)
+                                .map_err( |(e, _fail_over)| e );
+                            Box::new(retry_fut) as AsyncResult<T, Error>
+                        },
+                        Err(_redirect_err) => Box::new( future::err(e) ),
+                    } );
+                Box::new(redirect_fut)
+            } );
+        Box::new(result)
+    }
+
+
+    /// Try the homes of `profile` in order, the error also tells whether all of them failed
+    /// as homes (true) or the operation was refused by one of them (false)
+    fn try_homes_of2<T>(profile: &Profile, prof_repo: Rc<ProfileRepo>, connector: Rc<HomeConnector>,
+                        signer: Rc<Signer>, operation: Rc<Fn(RelationProof, Rc<Home>) -> AsyncResult<T, Error>>)
+        -> AsyncResult<T, (Error, bool)>
+    where T: 'static
+    {
+        let homes = match profile.facet {
+            // TODO consider how to get homes/addresses for apps and smartfridges
+            ProfileFacet::Persona(ref facet) => facet.homes.clone(),
+            _ => return Box::new(future::err( (ErrorKind::HomeProfileExpected.into(), false) )),
+        };
+
+        let profile_id = profile.id.clone();
+        let no_homes_fut = Box::new( future::err( (Error::from(ErrorKind::NoHomesFound), true) ) ) as AsyncResult<T, (Error, bool)>;
+        let result = homes.into_iter().fold( no_homes_fut, move |prev_fut, home_proof|
+        {
+            let prof_repo = prof_repo.clone();
+            let connector = connector.clone();
+            let signer = signer.clone();
+            let operation = operation.clone();
+            let profile_id = profile_id.clone();
+            let next_fut = prev_fut.or_else( move |(prev_err, fail_over)|
+            {
+                if ! fail_over
+                    { return Box::new( future::err( (prev_err, false) ) ) as AsyncResult<_, (Error, bool)>; }
+
+                let home_id = match home_proof.peer_id(&profile_id) {
+                    Ok(home_id) => home_id.to_owned(),
+                    Err(e) => return Box::new( future::err( (e.context(ErrorKind::FailedToGetPeerId).into(), true) ) ),
+                };
+
+                debug!("Trying home {} of profile {}", home_id, profile_id);
+                let op_fut = Self::connect_home2(&home_id, prof_repo, connector, signer)
+                    .map_err( |e| (e, true) )
+                    .and_then( move |home| operation(home_proof, home)
+                        .map_err( |e| { let fail_over = is_home_failure(&e); (e, fail_over) } ) )
+                    .map_err( move |(e, fail_over)| {
+                        if fail_over { warn!("Failed to use home {}, failing over: {}", home_id, e); }
+                        (e, fail_over)
+                    } );
+                Box::new(op_fut)
+            } );
+            Box::new(next_fut) as AsyncResult<T, (Error, bool)>
+        } );
+        Box::new(result)
+    }
+
+
+    /// Run `operation` on all homes of `profile` at once, collecting the result of each home.
+    /// Failures of single homes are returned in the results, the future itself fails only for non-persona profiles.
+    fn with_all_homes_of2<T,F>(profile: &Profile, prof_repo: Rc<ProfileRepo>,
+                               connector: Rc<HomeConnector>, signer: Rc<Signer>, operation: F)
+        -> AsyncResult<Vec<Result<T, Error>>, Error>
+    where T: 'static,
+          F: Fn(RelationProof, Rc<Home>) -> AsyncResult<T, Error> + 'static
+    {
+        let homes = match profile.facet {
+            ProfileFacet::Persona(ref facet) => facet.homes.clone(),
+            _ => return Box::new(future::err(ErrorKind::HomeProfileExpected.into())),
+        };
+
+        let operation = Rc::new(operation);
+        let profile_id = profile.id.clone();
+        let home_futs = homes.into_iter()
+            .map( |home_proof| {
+                let home_id = match home_proof.peer_id(&profile_id) {
+                    Ok(home_id) => home_id.to_owned(),
+                    Err(e) => return Box::new( future::ok( Err( e.context(ErrorKind::FailedToGetPeerId).into() ) ) ) as AsyncResult<_, Error>,
+                };
+
+                debug!("Using home {} of profile {}", home_id, profile_id);
+                let operation = operation.clone();
+                let op_fut = Self::connect_home2( &home_id, prof_repo.clone(), connector.clone(), signer.clone() )
+                    .and_then( move |home| operation(home_proof, home) )
+                    .then( move |result| {
+                        if let Err(ref e) = result
+                            { warn!("Failed to use home {}: {}", home_id, e); }
+                        Ok::<_, Error>(result)
+                    } );
+                Box::new(op_fut)
+            } )
+            .collect::<Vec<_>>();
+        Box::new( future::join_all(home_futs) )
+    }
+
+
+    /// Ask the homes of `profile` whether it moved away and return its new profile from the first valid redirect.
+    fn follow_redirect2(profile: &Profile, prof_repo: Rc<ProfileRepo>,
+                        connector: Rc<HomeConnector>, signer: Rc<Signer>)
+        -> AsyncResult<Profile, Error>
+    {
+        let homes = match profile.facet {
+            ProfileFacet::Persona(ref facet) => facet.homes.clone(),
+            _ => return Box::new(future::err(ErrorKind::HomeProfileExpected.into())),
+        };
+
+        let redirect_futs = homes.iter()
+            .filter_map( |home_proof| home_proof.peer_id(&profile.id).ok().map( |home_id| home_id.to_owned() ) )
+            .map( |home_id|
+            {
+                let profile = profile.to_owned();
+                let connector = connector.clone();
+                let signer = signer.clone();
+                let redirect_fut = prof_repo.load(&home_id)
+                    .map_err( |e| e.context(ErrorKind::FailedToLoadProfile).into() )
+                    .and_then( move |home_profile| connector.connect(&home_profile, signer)
+                        .and_then( {
+                            let profile_id = profile.id.clone();
+                            move |home| home.redirect(&profile_id)
+                                .map_err( |e| e.context(ErrorKind::RedirectFailed).into() )
+                        } )
+                        .and_then( move |redirect| redirect
+                            .validate( &CompositeValidator::default(), &profile, &home_profile.public_key )
+                            .map( |()| redirect.new_profile )
+                            .map_err( |e| e.context(ErrorKind::RedirectFailed).into() ) ) );
+                Box::new(redirect_fut) as AsyncResult<Profile, Error>
+            } )
+            .collect::<Vec<_>>();
+
+        // NOTE needed because select_ok() panics for empty lists instead of simply returning an error
+        if redirect_futs.is_empty()
+            { return Box::new( future::err(ErrorKind::NoHomesFound.into()) ) }
+
+        let result = future::select_ok(redirect_futs)
+            .map( |(new_profile, _pending_futs)| new_profile );
+        Box::new(result)
+    }
+
+
     fn on_new_relation(relations: Weak<RefCell< Vec<RelationProof> >>, rel_proof: RelationProof)
         -> AsyncResult<(),Error>
     {
@@ -246,10 +499,73 @@ impl MyProfileImpl
             },
         };
 
-        relations_rc.borrow_mut().push(rel_proof);
+        // NOTE the same response may arrive through several homes or even several times
+        let mut relations = relations_rc.borrow_mut();
+        if ! relations.contains(&rel_proof)
+            { relations.push(rel_proof); }
         Box::new( Ok( () ).into_future() )
     }
 
+    fn on_revoked_relation(relations: Weak<RefCell< Vec<RelationProof> >>, revocation: &RelationRevocation)
+    {
+        debug!("Removing revoked relation: {:?}", revocation.relation);
+        match relations.upgrade() {
+            Some(relations_rc) => relations_rc.borrow_mut().retain( |proof| *proof != revocation.relation ),
+            None => debug!("Received revoked relation to remove, but Rc upgrade failed"),
+        }
+    }
+
+    /// Store profile updates of `watched_id` received from its home and forward them to a new event stream.
+    /// Updates refused by the profile repository, e.g. unsigned or older versions, are dropped.
+    fn start_update_handler(watched_id: ProfileId, profile_repo: Rc<SimpleProfileRepo>,
+                            updates: AsyncStream<ProfileEvent, String>, handle: &reactor::Handle) -> EventStream
+    {
+        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+
+        handle.spawn(
+            updates.for_each( move |item| {
+                let profile = match item {
+                    Ok( ProfileEvent::ProfileUpdated(profile) ) => profile,
+                    Ok(event) => {
+                        debug!("Ignoring unexpected event on profile subscription: {:?}", event);
+                        return Box::new( Ok( () ).into_future() ) as AsyncResult<_,_>
+                    },
+                    Err(e) => {
+                        warn!("Profile subscription reported error: {}", e);
+                        return Box::new( Ok( () ).into_future() )
+                    },
+                };
+
+                if profile.id != watched_id || ! profile.is_signed() {
+                    warn!("Dropping invalid update of watched profile {}", watched_id);
+                    return Box::new( Ok( () ).into_future() )
+                }
+
+                debug!("Got version {} of watched profile {}, storing it", profile.version, profile.id);
+                let sender = sender.clone();
+                let event = ProfileEvent::ProfileUpdated( profile.clone() );
+                let update_fut = profile_repo.insert(profile)
+                    .then( move |insert_res| match insert_res {
+                        Ok(()) => Box::new( sender.send(event)
+                            .map( |_sender| () )
+                            .map_err( |_e| debug!("Watcher of profile updates was dropped, unsubscribing") ) )
+                            as AsyncResult<_,_>,
+                        Err(e) => {
+                            warn!("Refused update of watched profile: {}", e);
+                            Box::new( Ok( () ).into_future() )
+                        },
+                    } );
+                Box::new(update_fut)
+            } )
+            .then( |res| {
+                debug!("Profile update handler read all updates from stream, stopping with: {:?}", res);
+                Ok( () )
+            } )
+        );
+
+        receiver
+    }
+
     fn start_event_handler(relations: Weak<RefCell< Vec<RelationProof> >>,
                            session: Rc<MyHomeSession>, handle: &reactor::Handle)
     {
@@ -266,6 +582,11 @@ impl MyProfileImpl
                             .map_err( |e| error!("Notification on new relation failed: {}", e) );
                         Box::new(not_fut) as AsyncResult<_,_>
                     },
+                    ProfileEvent::RelationRevoked(revocation) => {
+                        debug!("Got relation revocation, removing relation");
+                        Self::on_revoked_relation( relations.clone(), &revocation );
+                        Box::new( Ok( () ).into_future() )
+                    },
                     _ => Box::new( Ok( () ).into_future() ),
                 }
             } )
@@ -326,34 +647,76 @@ impl MyProfile for MyProfileImpl
     {
         let half_proof = RelationHalfProof::new(RelationProof::RELATION_TYPE_HOSTED_ON_HOME, &home_id, &*self.signer);
 
-        let own_profile_cell = self.own_profile.clone();
         let own_profile_dataclone = self.own_profile.borrow().to_owned();
-        let profile_repo = self.profile_repo.clone();
+        let this = self.clone();
         let reg_fut = self.connect_home(&home_id)
             .and_then( move |home| {
                 home.register(own_profile_dataclone, half_proof, invite)
                     .map_err(|(_own_prof, err)| err.context(ErrorKind::RegistrationFailed).into() )
             } )
             // TODO we should also notify the AdminSession here to update its profile_store
-            //.and_then( |own_profile| ... )
-            .map( move |own_profile| {
-                own_profile_cell.replace( own_profile.clone() );
-                // TODO remove this after testing
-                profile_repo.insert(own_profile.profile);
+            .and_then( move |mut own_profile| {
+                // NOTE the home added its proof to our profile, only we can sign the new version
+                own_profile.profile.sign_next_version(&*this.signer);
+                this.own_profile.replace(own_profile);
+                this.publish_profile()
             } );
         Box::new(reg_fut)
     }
 
 
-    fn leave_home(&self, home_id: ProfileId, newhome_id: Option<Profile>) -> AsyncResult<(), Error>
+    fn leave_home(&self, home_id: ProfileId, newhome: Option<Profile>) -> AsyncResult<(), Error>
     {
-        let unreg_fut = self.login_home(home_id)
-            .map_err(|err| err.context(ErrorKind::LoginFailed).into())
-            .and_then( move |my_session|
-                my_session.session()
-                    .unregister(newhome_id)
-                    .map_err(|err| err.context(ErrorKind::DeregistrationFailed).into())
-            )
+        let my_id = self.signer.profile_id().to_owned();
+        let hosted_on = |profile: &Profile, home: &ProfileId| match profile.facet {
+            ProfileFacet::Persona(ref persona) => persona.homes.iter()
+                .any( |proof| proof.peer_id(&my_id).map( |id| id == home ).unwrap_or(false) ),
+            _ => false,
+        };
+
+        let migrating = newhome.is_some();
+        let join_fut = match newhome {
+            Some(ref newhome) if ! hosted_on( &self.own_profile.borrow().profile, &newhome.id ) => {
+                debug!("Moving profile from home {} to {}", home_id, newhome.id);
+                let this = self.clone();
+                let newhome_id = newhome.id.clone();
+                // NOTE the new home must be known by the profile repository to be able to connect it
+                let join_fut = self.profile_repo.insert( newhome.to_owned() )
+                    .map_err( |e| e.context(ErrorKind::FailedToLoadProfile).into() )
+                    .and_then( move |()| this.join_home(newhome_id, None) );
+                Box::new(join_fut) as AsyncResult<(), Error>
+            },
+            _ => Box::new( future::ok( () ) ),
+        };
+
+        let this = self.clone();
+        let unreg_fut = join_fut
+            .and_then( move |()|
+            {
+                // Our profile as it will be after leaving, this is what the old home redirects to
+                let mut new_profile = this.own_profile.borrow().profile.clone();
+                let my_id = new_profile.id.clone();
+                if let ProfileFacet::Persona(ref mut persona) = new_profile.facet {
+                    persona.homes.retain( |proof| proof.peer_id(&my_id).map( |id| *id != home_id ).unwrap_or(true) );
+                }
+                new_profile.sign_next_version(&*this.signer);
+                let redirect_to = if migrating { Some( new_profile.clone() ) } else { None };
+
+                this.login_home( home_id.clone() )
+                    .map_err(|err| err.context(ErrorKind::LoginFailed).into())
+                    .and_then( move |my_session|
+                        my_session.session()
+                            .unregister(redirect_to)
+                            .map_err(|err| err.context(ErrorKind::DeregistrationFailed).into())
+                    )
+                    .and_then( move |()| {
+                        this.session_cache.borrow_mut().remove(&home_id);
+                        // NOTE merged session contains the old home, a new login is needed
+                        this.login_session.replace(None);
+                        this.own_profile.borrow_mut().profile = new_profile;
+                        this.publish_profile()
+                    } )
+            } )
             // TODO we should also notify the AdminSession here to update its profile_store
             // .and_then( || ... )
             ;
@@ -400,11 +763,12 @@ impl MyProfile for MyProfileImpl
             {
                 //let half_proof = MyProfileImpl::new_half_proof(rel_type_clone.as_str(), &profile.id, signer_clone.clone() );
                 let half_proof = RelationHalfProof::new(&rel_type_clone, &profile.id, &*signer_clone.clone() );
-                MyProfileImpl::any_home_of2(&profile, profile_repo_clone, home_connector_clone, signer_clone)
-                    .and_then( move |(_home_proof, home)| {
+                MyProfileImpl::with_any_home_of2(&profile, profile_repo_clone, home_connector_clone, signer_clone,
+                    move |_home_proof, home| {
                         debug!("Contacted home of target profile, sending pairing request");
-                        home.pair_request(half_proof)
-                            .map_err(|err| err.context(ErrorKind::PairRequestFailed).into())
+                        let pair_fut = home.pair_request( half_proof.clone() )
+                            .map_err(|err| err.context(ErrorKind::PairRequestFailed).into());
+                        Box::new(pair_fut) as AsyncResult<_,_>
                     })
             } );
 
@@ -432,13 +796,14 @@ impl MyProfile for MyProfileImpl
                 let profile_repo = self.profile_repo.clone();
                 let connector = self.home_connector.clone();
                 let signer = self.signer.clone();
-                move |profile| Self::any_home_of2(&profile, profile_repo, connector, signer)
+                move |profile| Self::with_any_home_of2(&profile, profile_repo, connector, signer,
+                    move |_home_proof, home| {
+                        debug!("Contacted home of target profile, sending pairing response");
+                        let pair_fut = home.pair_response( proof.clone() )
+                            .map_err(|err| err.context(ErrorKind::PeerResponseFailed).into());
+                        Box::new(pair_fut) as AsyncResult<_,_>
+                    })
             } )
-            .and_then( move |(_home_proof, home)| {
-                debug!("Contacted home of target profile, sending pairing response");
-                home.pair_response(proof)
-                    .map_err(|err| err.context(ErrorKind::PeerResponseFailed).into())
-            })
             .and_then( {
                 let relations = Rc::downgrade(&self.relations);
                 move |()| Self::on_new_relation( relations, proof_clone.clone() )
@@ -449,11 +814,83 @@ impl MyProfile for MyProfileImpl
     }
 
 
-    fn call(&self, proof: RelationProof, app: ApplicationId, init_payload: AppMessageFrame,
-            to_caller: Option<AppMsgSink>) ->
-        AsyncResult<Option<AppMsgSink>, Error>
+    fn revoke_relation(&self, relation: &RelationProof) -> AsyncResult<(), Error>
     {
-        let peer_id = match proof.peer_id( self.signer.profile_id() ) {
+        let revocation = match RelationRevocation::new( relation, self.signer() ) {
+            Ok(revocation) => revocation,
+            Err(e) => return Box::new( Err( e.context(ErrorKind::FailedToAuthorize).into() ).into_future() ),
+        };
+        let peer_id = match revocation.peer_id() {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err( e.context(ErrorKind::LookupFailed).into() ).into_future() ),
+        };
+
+        // NOTE we stop using the relation right away, even if its revocation cannot be delivered
+        Self::on_revoked_relation( Rc::downgrade(&self.relations), &revocation );
+
+        let send_revocation = move |_home_proof: RelationProof, home: Rc<Home>| {
+            let revoke_fut = home.revoke_relation( revocation.clone() )
+                .map_err( |err| err.context(ErrorKind::RevokeRelationFailed).into() );
+            Box::new(revoke_fut) as AsyncResult<_,_>
+        };
+
+        let own_profile = self.own_profile.borrow().profile.clone();
+        let own_homes_fut = Self::with_all_homes_of2( &own_profile, self.profile_repo.clone(),
+            self.home_connector.clone(), self.signer.clone(), send_revocation.clone() );
+        let peer_homes_fut = self.profile_repo.load(&peer_id)
+            .map_err( |err| err.context(ErrorKind::FailedToLoadProfile).into() )
+            .and_then( {
+                let profile_repo = self.profile_repo.clone();
+                let connector = self.home_connector.clone();
+                let signer = self.signer.clone();
+                move |profile| Self::with_all_homes_of2(&profile, profile_repo, connector, signer, send_revocation)
+            } )
+            // NOTE homes of our own might still accept the revocation
+            .or_else( |e| { warn!("Failed to reach the homes of the peer: {}", e); Ok( vec![ Err(e) ] ) } );
+
+        debug!("Sending revocation of relation with {} to all homes of both parties", peer_id);
+        let revoke_fut = own_homes_fut.join(peer_homes_fut)
+            .and_then( |(own_results, peer_results)| {
+                let mut last_err = None;
+                for result in own_results.into_iter().chain(peer_results) {
+                    match result {
+                        Ok(()) => return Ok( () ),
+                        Err(e) => last_err = Some(e),
+                    }
+                }
+                Err( last_err.unwrap_or_else( || ErrorKind::NoHomesFound.into() ) )
+            } );
+        Box::new(revoke_fut)
+    }
+
+
+    fn watch_profile(&self, relation: &RelationProof) -> AsyncResult<EventStream, Error>
+    {
+        let peer_id = match relation.peer_id( self.signer.profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err(e.context(ErrorKind::LookupFailed).into()).into_future() ),
+        };
+
+        let relation = relation.to_owned();
+        let profile_repo = self.profile_repo.clone();
+        let home_connector = self.home_connector.clone();
+        let signer = self.signer.clone();
+        let handle = self.handle.clone();
+        let watch_fut = self.profile_repo.load(&peer_id)
+            .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
+            .and_then( move |profile| Self::with_any_home_of2(&profile, profile_repo.clone(), home_connector, signer,
+                    move |_home_proof, home| {
+                        debug!("Connected to home, subscribing to profile updates");
+                        Box::new( Ok( home.subscribe( relation.clone() ) ).into_future() ) as AsyncResult<_,_>
+                    } )
+                .map( move |updates| Self::start_update_handler(peer_id, profile_repo, updates, &handle) ) );
+        Box::new(watch_fut)
+    }
+
+
+    fn call(&self, app: ApplicationId, call_req: CallRequestDetails) -> AsyncResult<CallAnswer, Error>
+    {
+        let peer_id = match call_req.relation.peer_id( self.signer.profile_id() ) {
             Ok(id) => id.to_owned(),
             Err(e) => return Box::new( Err(e.context(ErrorKind::LookupFailed).into()).into_future() ),
         };
@@ -463,58 +900,127 @@ impl MyProfile for MyProfileImpl
         let signer = self.signer.clone();
         let call_fut = self.profile_repo.load(&peer_id)
             .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
-            .and_then( |profile| Self::any_home_of2(&profile, profile_repo, home_connector, signer) )
-            .and_then( move |(_home_proof, home)| {
-                debug!("Connected to home, calling target profile");
-                home.call(app, CallRequestDetails { relation: proof, init_payload: init_payload, to_caller: to_caller } )
-                    .map_err(|err| err.context(ErrorKind::CallFailed).into())
-            });
+            .and_then( |profile| Self::with_any_home_of2(&profile, profile_repo, home_connector, signer,
+                move |_home_proof, home| {
+                    debug!("Connected to home, calling target profile");
+                    let call_fut = home.call( app.clone(), call_req.clone() )
+                        .map_err(|err| err.context(ErrorKind::CallFailed).into());
+                    Box::new(call_fut) as AsyncResult<_,_>
+                } ) );
         Box::new(call_fut)
     }
 
 
-    // TODO this should try connecting to ALL of our homes, using our collect_results() future util function
+    fn hangup(&self, callee: &ProfileId, call: CallId) -> AsyncResult<(), Error>
+    {
+        let profile_repo = self.profile_repo.clone();
+        let home_connector = self.home_connector.clone();
+        let signer = self.signer.clone();
+        // NOTE the call is hosted by a single home of the callee, but not necessarily by the first one,
+        //      e.g. after call() failed over to another home, so all of them are asked
+        let hangup_fut = self.profile_repo.load(callee)
+            .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
+            .and_then( |profile| Self::with_all_homes_of2(&profile, profile_repo, home_connector, signer,
+                move |_home_proof, home| {
+                    let hangup_fut = home.hangup( call.clone() )
+                        .map_err(|err| err.context(ErrorKind::HangupFailed).into());
+                    Box::new(hangup_fut) as AsyncResult<_,_>
+                } ) )
+            .and_then( |results| {
+                if results.iter().any( |result| result.is_ok() )
+                    { return Ok( () ); }
+                // Homes not knowing the call are expected, report the failure of the one hosting it if any
+                let mut errors = results.into_iter().filter_map( |result| result.err() ).collect::<Vec<_>>();
+                let hosting_index = errors.iter()
+                    .position( |e| e.home_error_kind() != Some(::mercury_home_protocol::error::ErrorKind::CallNotFound) )
+                    .unwrap_or(0);
+                if errors.is_empty()
+                    { Err(ErrorKind::NoHomesFound)? }
+                Err( errors.swap_remove(hosting_index) )
+            } );
+        Box::new(hangup_fut)
+    }
+
+
+    fn presence(&self, rel: RelationProof, app: ApplicationId) -> AsyncResult<Option<AppMessageFrame>, Error>
+    {
+        let peer_id = match rel.peer_id( self.signer.profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err(e.context(ErrorKind::LookupFailed).into()).into_future() ),
+        };
+
+        let profile_repo = self.profile_repo.clone();
+        let home_connector = self.home_connector.clone();
+        let signer = self.signer.clone();
+        let presence_fut = self.profile_repo.load(&peer_id)
+            .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
+            .and_then( |profile| Self::with_any_home_of2(&profile, profile_repo, home_connector, signer,
+                move |_home_proof, home| {
+                    let presence_fut = home.presence( rel.clone(), app.clone() )
+                        .map_err(|err| err.context(ErrorKind::FailedToGetPresence).into());
+                    Box::new(presence_fut) as AsyncResult<_,_>
+                } ) );
+        Box::new(presence_fut)
+    }
+
+
+    fn watch_presence(&self, rel: RelationProof, app: ApplicationId)
+        -> AsyncResult<AsyncStream<Option<AppMessageFrame>, String>, Error>
+    {
+        let peer_id = match rel.peer_id( self.signer.profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err(e.context(ErrorKind::LookupFailed).into()).into_future() ),
+        };
+
+        let profile_repo = self.profile_repo.clone();
+        let home_connector = self.home_connector.clone();
+        let signer = self.signer.clone();
+        let watch_fut = self.profile_repo.load(&peer_id)
+            .map_err(|err| err.context(ErrorKind::FailedToLoadProfile).into())
+            .and_then( |profile| Self::with_any_home_of2(&profile, profile_repo, home_connector, signer,
+                move |_home_proof, home| {
+                    debug!("Connected to home, subscribing to presence of app {:?}", app);
+                    Box::new( Ok( home.subscribe_presence( rel.clone(), app.clone() ) ).into_future() ) as AsyncResult<_,_>
+                } ) );
+        Box::new(watch_fut)
+    }
+
+
+    /// Log into all homes of this persona. Homes that cannot be reached are skipped,
+    /// login fails only if none of them could be used.
     fn login(&self) -> AsyncResult<Rc<MyHomeSession>, Error>
     {
-        if let Some(ref session_rc) = self.session_cache.borrow().values().next()
+        if let Some(ref session_rc) = *self.login_session.borrow()
             { return Box::new( Ok( Rc::clone(session_rc) ).into_future() ) }
 
-        let my_profile_id = self.signer.profile_id().to_owned();
-        let session_cache = self.session_cache.clone();
-        let handle = self.handle.clone();
-        let handle2 = self.handle.clone();
-        let relations_weak = Rc::downgrade(&self.relations);
+        let this = self.clone();
         let log_fut = self.profile_repo.load( self.signer.profile_id() )
             .map_err( |err| err.context(ErrorKind::LoginFailed).into() )
-            .and_then( {
-                let profile_repo_clone = self.profile_repo.clone();
-                let home_conn_clone = self.home_connector.clone();
-                let signer_clone = self.signer.clone();
-                move |profile| {
-                    debug!("Client profile was loaded for login, connecting home");
-                    MyProfileImpl::any_home_of2(
-                        &profile, profile_repo_clone, home_conn_clone, signer_clone)
-                }
+            .and_then( |profile| match profile.facet {
+                ProfileFacet::Persona(persona) => Ok(persona.homes),
+                _ => Err( ErrorKind::PersonaProfileExpected.into() ),
             } )
-            .and_then( move |(home_proof, home)| {
-                debug!("Home connection established, logging in");
-                let home_id = match home_proof.peer_id(&my_profile_id) {
-                    Ok(id) => id.to_owned(),
-                    Err(e) => return Box::new( Err( e.context(ErrorKind::FailedToAuthorize).into() ).into_future() ) as AsyncResult<_,_>,
-                };
-                let login_fut = home.login(&home_proof)
-                    .map_err(|err| err.context(ErrorKind::LoginFailed).into())
-                    .map( move |session| MyHomeSessionImpl::new(session, handle) )
-                    .inspect( move |my_session|
-                    {
-                        // TODO this allows initiating several fill attempts in parallel
-                        //      until first one succeeds, last one wins by overwriting.
-                        //      Is this acceptable?
-                        session_cache.borrow_mut().insert( home_id, my_session.clone() );
-                        Self::start_event_handler( relations_weak, my_session.clone(), &handle2 )
-                    } );
-                Box::new(login_fut)
-            });
+            .and_then( move |homes| {
+                debug!("Client profile was loaded for login, connecting {} homes", homes.len());
+                let session_futs = homes.into_iter()
+                    .map( |home_proof| this.login_home_session(home_proof) )
+                    .collect::<Vec<_>>();
+                fut::collect_results(session_futs)
+                    .map_err( |()| ErrorKind::ImplementationError.into() )
+                    .and_then( move |results| {
+                        let sessions = results.into_iter()
+                            .filter_map( |res| res.map_err( |e| warn!("Failed to log into home: {}", e) ).ok() )
+                            .collect::<Vec<_>>();
+                        if sessions.is_empty()
+                            { return Err( ErrorKind::NoHomesFound.into() ); }
+
+                        debug!("Logged into {} homes", sessions.len());
+                        let my_session = MyMultiHomeSession::new(sessions, &this.handle);
+                        *this.login_session.borrow_mut() = Some( my_session.clone() );
+                        Self::start_event_handler( Rc::downgrade(&this.relations), my_session.clone(), &this.handle );
+                        Ok(my_session)
+                    } )
+            } );
 
         Box::new(log_fut)
     }
@@ -617,6 +1123,75 @@ impl Drop for MyHomeSessionImpl
     { fn drop(&mut self) { debug!("MyHomeSessionImpl was dropped"); } }
 
 
+
+/// Sessions with all homes of a persona, merging their events without duplicates.
+pub struct MyMultiHomeSession
+{
+    sessions:       Vec<Rc<MyHomeSession>>,
+    event_listeners:Rc<RefCell< Vec<EventSink> >>,
+}
+
+
+impl MyMultiHomeSession
+{
+    fn new(sessions: Vec<Rc<MyHomeSession>>, handle: &reactor::Handle) -> Rc<MyHomeSession>
+    {
+        let this = Rc::new( Self{ sessions, event_listeners: Default::default() } );
+
+        debug!("Created MyMultiHomeSession, start merging profile events of {} homes", this.sessions.len());
+        let recent_events = Rc::new( RefCell::new( VecDeque::with_capacity(CFG_EVENT_DEDUP_WINDOW) ) );
+        for session in &this.sessions
+        {
+            let listeners = Rc::downgrade(&this.event_listeners);
+            let recent_events = recent_events.clone();
+            handle.spawn(
+                session.events().for_each( move |event| {
+                    if Self::is_duplicate( &recent_events, &event ) {
+                        debug!("Dropping event {:?} already received from another home", event);
+                        return Box::new( Ok( () ).into_future() ) as AsyncResult<(), ()>
+                    }
+                    MyHomeSessionImpl::forward_event_safe( listeners.clone(), Ok(event) )
+                } )
+            );
+        }
+
+        this
+    }
+
+
+    fn is_duplicate(recent_events: &RefCell< VecDeque<ProfileEvent> >, event: &ProfileEvent) -> bool
+    {
+        let mut recent_events = recent_events.borrow_mut();
+        if recent_events.contains(event)
+            { return true; }
+
+        if recent_events.len() >= CFG_EVENT_DEDUP_WINDOW
+            { recent_events.pop_front(); }
+        recent_events.push_back( event.to_owned() );
+        false
+    }
+}
+
+
+impl Drop for MyMultiHomeSession
+    { fn drop(&mut self) { debug!("MyMultiHomeSession was dropped"); } }
+
+
+impl MyHomeSession for MyMultiHomeSession
+{
+    // NOTE sessions are in order of the persona's home preference, so this is the most preferred reachable home
+    fn session(&self) -> Rc<HomeSession>
+        { self.sessions[0].session() }
+
+    fn events(&self) -> EventStream
+    {
+        let (listener, events) = mpsc::channel(CHANNEL_CAPACITY);
+        MyHomeSessionImpl::add_listener( self.event_listeners.clone(), listener );
+        events
+    }
+}
+
+
 impl MyHomeSession for MyHomeSessionImpl
 {
     fn session(&self) -> Rc<HomeSession>
@@ -1,5 +1,6 @@
 use std::rc::Rc;
 
+use failure::Fail;
 use futures::prelude::*;
 use futures::sync::mpsc;
 
@@ -25,26 +26,61 @@ impl Contact for RelationImpl
 {
     fn proof(&self) -> &RelationProof { &self.relation_proof }
 
-    fn call(&self, init_payload: AppMessageFrame) -> AsyncResult<DAppCall, Error>
+    fn call(&self, init_payload: AppMessageFrame) -> (CallId, AsyncResult<DAppCall, Error>)
     {
         let (to_caller, from_callee) = mpsc::channel(CHANNEL_CAPACITY);
 
-        let call_fut = self.my_profile.call( self.relation_proof.clone(),
-                self.app_id.clone(), init_payload, Some(to_caller) )
-            .and_then( |to_callee_opt|
+        let call_req = CallRequestDetails::new( self.relation_proof.clone(), init_payload, Some(to_caller) );
+        let call_id = call_req.id.clone();
+        let callee = match self.relation_proof.peer_id( self.my_profile.signer().profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return ( call_id, Box::new( Err( e.context(ErrorKind::LookupFailed).into() ).into_future() ) ),
+        };
+
+        let id = call_id.clone();
+        let my_profile = self.my_profile.clone();
+        let call_fut = self.my_profile.call( self.app_id.clone(), call_req )
+            .and_then( move |answer|
             {
                 debug!("Call was answered, processing response");
-                match to_callee_opt {
-                    None => Err( Error::from(ErrorKind::CallRefused) ),
-                    Some(to_callee) => {
+                match answer {
+                    CallAnswer::Rejected(reason) => Err( Error::from( ErrorKind::CallRejected(reason) ) ),
+                    CallAnswer::Accepted(to_callee) => {
                         info!("Call with duplex channel established");
-                        Ok( DAppCall{ outgoing: to_callee, incoming: from_callee } )
-                    }
+                        Ok( DAppCall::new(id, callee, my_profile, to_callee, from_callee) )
+                    },
+                    CallAnswer::Replied(reply) => {
+                        info!("Call was replied without establishing a channel");
+                        // NOTE the reply is the only incoming message and nobody listens to outgoing ones
+                        let (outgoing, _dropped) = mpsc::channel(CHANNEL_CAPACITY);
+                        let (mut reply_sink, incoming) = mpsc::channel(CHANNEL_CAPACITY);
+                        reply_sink.try_send( Ok(reply) )
+                            .map_err( |_e| Error::from(ErrorKind::ImplementationError) )?;
+                        Ok( DAppCall::new(id, callee, my_profile, outgoing, incoming) )
+                    },
                 }
             } );
 
-        Box::new(call_fut)
+        ( call_id, Box::new(call_fut) )
+    }
+
+
+    fn hangup(&self, call: CallId) -> AsyncResult<(), Error>
+    {
+        let callee = match self.relation_proof.peer_id( self.my_profile.signer().profile_id() ) {
+            Ok(id) => id.to_owned(),
+            Err(e) => return Box::new( Err( e.context(ErrorKind::LookupFailed).into() ).into_future() ),
+        };
+        self.my_profile.hangup(&callee, call)
     }
+
+
+    fn presence(&self) -> AsyncResult<Option<AppMessageFrame>, Error>
+        { self.my_profile.presence( self.relation_proof.clone(), self.app_id.clone() ) }
+
+
+    fn watch_presence(&self) -> AsyncResult<AsyncStream<Option<AppMessageFrame>, String>, Error>
+        { self.my_profile.watch_presence( self.relation_proof.clone(), self.app_id.clone() ) }
 }
 
 
@@ -108,7 +144,7 @@ impl DAppSession for DAppSessionImpl
         { unimplemented!(); }
 
 
-    fn checkin(&self) -> AsyncResult<Box<Stream<Item=DAppEvent, Error=()>>, Error>
+    fn checkin(&self, presence: Option<AppMessageFrame>) -> AsyncResult<Box<Stream<Item=DAppEvent, Error=()>>, Error>
     {
         let app = self.app_id.clone();
         let my_profile = self.my_profile.clone();
@@ -116,7 +152,7 @@ impl DAppSession for DAppSessionImpl
             .map( move |my_session|
             {
                 let app1 = app.clone();
-                let calls_stream = my_session.session().checkin_app(&app)
+                let calls_stream = my_session.session().checkin_app(&app, presence)
                     .inspect( move |_| debug!("Checked in app {:?} to receive incoming calls", app1) )
                     // Filter stream elements, keep only successful calls, drop errors
                     .filter_map( |inc_call_res| inc_call_res.ok() )
@@ -1,20 +1,24 @@
 extern crate futures;
 
 use std::cell::RefCell;
-//use std::collections::HashMap;
+use std::collections::BTreeSet;
 use std::rc::Rc;
 
 use failure::Fail;
-use futures::prelude::*;
+use futures::{future, prelude::*};
 
-use mercury_home_protocol::{*, error::*};
-use mercury_storage::async::{KeyValueStore, imp::InMemoryStore};
+use mercury_home_protocol::{*, crypto::CompositeValidator, error::*};
+use mercury_storage::async::{optional, KeyValueStore, imp::InMemoryStore};
 use ::AsyncResult;
+use profile::HomeConnector;
 
 
 
 pub struct SimpleProfileRepo {
-    profiles : Rc<RefCell< KeyValueStore<ProfileId, Profile> >>
+    profiles : Rc<RefCell< KeyValueStore<ProfileId, Profile> >>,
+    // NOTE KeyValueStore cannot enumerate its keys, so we remember what was inserted through this repo
+    known_ids: Rc<RefCell< BTreeSet<ProfileId> >>,
+    validator: Rc<Validator>,
 }
 
 impl Default for SimpleProfileRepo {
@@ -22,40 +26,135 @@ impl Default for SimpleProfileRepo {
 }
 
 impl<T: KeyValueStore<ProfileId,Profile> + 'static> From<T> for SimpleProfileRepo {
-    fn from(src: T) -> Self{ Self{ profiles: Rc::new( RefCell::new(src) ) } }
+    fn from(src: T) -> Self{ Self{ profiles: Rc::new( RefCell::new(src) ),
+                                   known_ids: Rc::new( RefCell::new( BTreeSet::new() ) ),
+                                   validator: Rc::new( CompositeValidator::default() ) } }
 }
 
 
 impl SimpleProfileRepo {
-    pub fn insert(&self, profile: Profile)
-        -> AsyncResult<(), ::mercury_storage::error::StorageError>
+    /// Unsigned profiles are accepted here only as local hints, e.g. home profiles read from the configuration.
+    /// Signed ones must be valid and must not roll back an already stored version of the profile.
+    pub fn insert(&self, profile: Profile) -> AsyncResult<(), Error>
     {
-        self.profiles.borrow_mut().set( profile.id.clone(), profile.clone() )
+        if profile.is_signed() {
+            if let Err(e) = profile.validate(&*self.validator)
+                { return Box::new( future::err(e) ) }
+        }
+        Self::store(self.profiles.clone(), self.known_ids.clone(), profile)
+    }
+
+
+    fn store(profiles: Rc<RefCell< KeyValueStore<ProfileId, Profile> >>,
+             known_ids: Rc<RefCell< BTreeSet<ProfileId> >>, profile: Profile) -> AsyncResult<(), Error>
+    {
+        let store_fut = profiles.borrow().get( profile.id.clone() )
+            // NOTE a missing key simply means that the profile is not known yet
+            //      but any other failure must not let an older version through unchecked
+            .then(optional)
+            .map_err( |e| Error::from( e.context(ErrorKind::StorageFailed) ) )
+            .and_then( move |known_opt| {
+                if let Some(known) = known_opt
+                    { profile.ensure_not_older_than(&known)?; }
+                Ok(profile)
+            } )
+            .and_then( move |profile| {
+                known_ids.borrow_mut().insert( profile.id.clone() );
+                return profiles.borrow_mut().set( profile.id.clone(), profile )
+                    .map_err( |e| e.context(ErrorKind::StorageFailed).into() );
+            } );
+        Box::new(store_fut)
+    }
+
+
+    /// Same as resolve(), but if the profile is not known locally, the home server hinted
+    /// in the url is asked for it and the result is cached in this repository.
+    pub fn resolve_with_hints(&self, url: &str, connector: Rc<HomeConnector>, signer: Rc<Signer>)
+        -> AsyncResult<Profile, Error>
+    {
+        let profile_url = match ProfileUrl::parse(url) {
+            Ok(profile_url) => profile_url,
+            Err(e) => return Box::new( future::err(e) ),
+        };
+
+        let url = url.to_owned();
+        let profile_id = profile_url.profile_id.clone();
+        let profiles = self.profiles.clone();
+        let known_ids = self.known_ids.clone();
+        let validator = self.validator.clone();
+        let res_fut = self.load(&profile_url.profile_id)
+            .or_else( move |e|
+            {
+                let home_profile = match profile_url.home_hint() {
+                    Some(home_profile) => home_profile,
+                    None => return Box::new( future::err(e) ) as AsyncResult<_,_>,
+                };
+
+                debug!("Profile {} is not known locally, asking its home {}", profile_url.profile_id, home_profile.id);
+                let remote_fut = connector.connect(&home_profile, signer)
+                    .map_err( |e| e.context(ErrorKind::FailedToResolveUrl).into() )
+                    .and_then( move |home| home.resolve(&url) )
+                    // NOTE profiles received from others must always be signed
+                    .and_then( move |profile| profile.validate(&*validator).map( |()| profile ) )
+                    // NOTE any other validly signed profile must not be cached as the requested one
+                    .and_then( move |profile| {
+                        if profile.id != profile_id {
+                            warn!("Home was asked for profile {} but returned {}", profile_id, profile.id);
+                            Err(ErrorKind::FailedToResolveUrl)?
+                        }
+                        Ok(profile)
+                    } )
+                    .and_then( move |profile|
+                        Self::store( profiles, known_ids, profile.clone() )
+                            .map( |()| profile )
+                            .map_err( |e| e.context(ErrorKind::FailedToResolveUrl).into() ) );
+                Box::new(remote_fut)
+            } );
+        Box::new(res_fut)
     }
 }
 
 
 impl ProfileRepo for SimpleProfileRepo
 {
-//    /// List all profiles that can be load()'ed or resolve()'d.
-//    fn list(&self, /* TODO what filter criteria should we have here? */ ) ->
-//        HomeStream<Profile,String>
-//    {
-//        unimplemented!()
-//    }
+    /// List all profiles that can be load()'ed or resolve()'d, ordered by their ids.
+    /// Only profiles added with insert() or resolved with hints are known here.
+    fn list(&self, filter: &ProfileListFilter) -> AsyncResult<Vec<Profile>, Error>
+    {
+        let filter = filter.to_owned();
+        let profile_futs = self.known_ids.borrow().iter()
+            .map( |id| self.profiles.borrow().get( id.to_owned() ) )
+            .collect::<Vec<_>>();
+        let fut = future::join_all(profile_futs)
+            .map( move |profiles| filter.apply(profiles) )
+            .map_err( |e| e.context(ErrorKind::FailedToListProfiles).into() );
+        Box::new(fut)
+    }
 
     /// Look for specified `id` and return. This might involve searching for the latest version
     /// of the profile in the dht, but if it's the profile's home server, could come from memory, too.
     fn load(&self, id: &ProfileId) -> AsyncResult<Profile, Error>
     {
+        // NOTE the storage might be shared with others, so signatures are checked again, see insert() for unsigned profiles
+        let validator = self.validator.clone();
         let fut = self.profiles.borrow().get( id.to_owned() )
-            .map_err( |e| e.context(ErrorKind::ProfileLookupFailed).into() );
+            .map_err( |e| e.context(ErrorKind::ProfileLookupFailed).into() )
+            .and_then( move |profile|
+                if profile.is_signed() { profile.validate(&*validator).map( |()| profile ) }
+                else { Ok(profile) } );
         Box::new(fut)
     }
 
 
-//    fn resolve(&self, _url: &str) -> AsyncResult<Profile, Error>
-//    {
-//        unimplemented!()
-//    }
+    /// Only profiles known locally are resolved here, see resolve_with_hints() for asking their home, too.
+    fn resolve(&self, url: &str) -> AsyncResult<Profile, Error>
+    {
+        let profile_url = match ProfileUrl::parse(url) {
+            Ok(profile_url) => profile_url,
+            Err(e) => return Box::new( future::err(e) ),
+        };
+        let fut = self.load(&profile_url.profile_id)
+            .map_err( |e| e.context(ErrorKind::FailedToResolveUrl).into() );
+        Box::new(fut)
+    }
 }
\ No newline at end of file
@@ -0,0 +1,381 @@
+//! Connecting to homes through a SOCKS5 proxy (RFC 1928), e.g. a local Tor client.
+//!
+//! Onion services are reachable only through such a proxy. Clearnet addresses can be routed
+//! through it as well to hide the IP address of the client from its home.
+//! Homes list their onion addresses in `HomeFacetData::onion_addrs`.
+
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+
+use failure::Fail;
+use futures::{future, Future};
+use multiaddr::Multiaddr;
+use tokio_core::{net::TcpStream, reactor};
+use tokio_io::io::{read_exact, write_all};
+
+use super::*;
+use net::{self, Host, SimpleTcpHomeConnector};
+use profile::HomeConnector;
+
+
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTHENTICATION: u8 = 0;
+const COMMAND_CONNECT: u8 = 1;
+const ADDRESS_TYPE_IPV4: u8 = 1;
+const ADDRESS_TYPE_DOMAIN: u8 = 3;
+const ADDRESS_TYPE_IPV6: u8 = 4;
+const REPLY_SUCCEEDED: u8 = 0;
+
+
+
+/// Destination of a connection through the proxy
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ProxyTarget
+{
+    Ip(SocketAddr),
+    /// Resolved by the proxy, so no DNS query leaks from the client and onion services are reachable
+    Domain(String, u16),
+}
+
+
+impl ProxyTarget
+{
+    /// Target of a TCP multiaddr, also accepting /onion ones
+    pub fn from_multiaddr(multiaddr: &Multiaddr) -> Result<Self, Error>
+    {
+        if let Some(onion_target) = parse_onion(&multiaddr.to_string())?
+            { return Ok(onion_target); }
+
+        match net::parse_tcp_multiaddr(multiaddr)? {
+            ( Host::Ip(ip_address), port ) => Ok( ProxyTarget::Ip( SocketAddr::new(ip_address, port) ) ),
+            ( Host::Dns4(name), port ) | ( Host::Dns6(name), port ) => Ok( ProxyTarget::Domain(name, port) ),
+        }
+    }
+
+
+    /// Target of an onion address in text form like "/onion3/<service id>:<port>"
+    pub fn from_onion_addr(onion_addr: &str) -> Result<Self, Error>
+    {
+        match parse_onion(onion_addr)? {
+            Some(target) => Ok(target),
+            None => {
+                debug!("Unsupported onion address {}, expected /onion or /onion3", onion_addr);
+                Err( ErrorKind::UnsupportedAddress.into() )
+            },
+        }
+    }
+
+
+    /// Onion services can be reached only through the proxy
+    pub fn is_onion(&self) -> bool
+    {
+        match *self {
+            ProxyTarget::Domain(ref name, _port) => name.ends_with(".onion"),
+            ProxyTarget::Ip(_) => false,
+        }
+    }
+}
+
+
+/// Onion service address of an /onion or /onion3 multiaddr in text form, None for other protocols
+// NOTE parsed from text because the pinned multiaddr version has no component for /onion3,
+//      so such addresses are not listed in HomeFacet::addrs but in HomeFacetData::onion_addrs
+fn parse_onion(multiaddr: &str) -> Result<Option<ProxyTarget>, Error>
+{
+    // Length of the base32 encoded service id of onion services version 2 and 3
+    let (address, service_id_len) =
+        if multiaddr.starts_with("/onion/") { ( &multiaddr["/onion/".len()..], 16 ) }
+        else if multiaddr.starts_with("/onion3/") { ( &multiaddr["/onion3/".len()..], 56 ) }
+        else { return Ok(None) };
+
+    let mut parts = address.splitn(2, ':');
+    let service_id = parts.next().unwrap_or_default();
+    let port = parts.next().and_then( |port| port.parse::<u16>().ok() );
+    match port {
+        Some(port) if service_id.len() == service_id_len && service_id.chars().all( |c| c.is_ascii_alphanumeric() ) =>
+            Ok( Some( ProxyTarget::Domain( format!( "{}.onion", service_id.to_lowercase() ), port ) ) ),
+        _ => {
+            debug!("Invalid onion address {}, expected /onion/<service id>:<port> or /onion3/<service id>:<port>", multiaddr);
+            Err( ErrorKind::AddressConversionFailed.into() )
+        },
+    }
+}
+
+
+fn connect_request(target: &ProxyTarget) -> Result<Vec<u8>, Error>
+{
+    let mut request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0];
+    let port = match *target {
+        ProxyTarget::Ip( SocketAddr::V4(ref addr) ) => {
+            request.push(ADDRESS_TYPE_IPV4);
+            request.extend_from_slice( &addr.ip().octets() );
+            addr.port()
+        },
+        ProxyTarget::Ip( SocketAddr::V6(ref addr) ) => {
+            request.push(ADDRESS_TYPE_IPV6);
+            request.extend_from_slice( &addr.ip().octets() );
+            addr.port()
+        },
+        ProxyTarget::Domain(ref name, port) => {
+            if name.is_empty() || name.len() > 255
+                { Err(ErrorKind::AddressConversionFailed)? }
+            request.push(ADDRESS_TYPE_DOMAIN);
+            request.push( name.len() as u8 );
+            request.extend_from_slice( name.as_bytes() );
+            port
+        },
+    };
+    request.push( (port >> 8) as u8 );
+    request.push( port as u8 );
+    Ok(request)
+}
+
+
+fn proxy_error(message: String) -> io::Error
+    { io::Error::new(io::ErrorKind::Other, message) }
+
+
+/// Open a TCP connection to the target through a SOCKS5 proxy not requiring authentication
+pub fn connect_through_proxy(proxy: &SocketAddr, target: ProxyTarget, handle: &reactor::Handle)
+    -> AsyncResult<TcpStream, Error>
+{
+    let request = match connect_request(&target) {
+        Ok(request) => request,
+        Err(e) => return Box::new( future::err(e) ),
+    };
+
+    debug!("Connecting to {:?} through proxy {}", target, proxy);
+    let connect_fut = TcpStream::connect(proxy, handle)
+        .and_then( |stream| write_all( stream, [SOCKS_VERSION, 1, METHOD_NO_AUTHENTICATION] ) )
+        .and_then( |(stream, _greeting)| read_exact( stream, [0u8; 2] ) )
+        .and_then( |(stream, choice)| {
+            if choice != [SOCKS_VERSION, METHOD_NO_AUTHENTICATION]
+                { return Err( proxy_error( format!("proxy refused connecting without authentication: {:?}", choice) ) ); }
+            Ok(stream)
+        } )
+        .and_then( move |stream| write_all(stream, request) )
+        .and_then( |(stream, _request)| read_exact( stream, [0u8; 4] ) )
+        .and_then( |(stream, reply)| -> Box< Future<Item=(TcpStream, usize), Error=io::Error> > {
+            if reply[0] != SOCKS_VERSION || reply[1] != REPLY_SUCCEEDED
+                { return Box::new( future::err( proxy_error( format!("proxy failed to connect, reply code {}", reply[1]) ) ) ); }
+            match reply[3] {
+                ADDRESS_TYPE_IPV4 => Box::new( future::ok( (stream, 4) ) ),
+                ADDRESS_TYPE_IPV6 => Box::new( future::ok( (stream, 16) ) ),
+                ADDRESS_TYPE_DOMAIN => Box::new( read_exact( stream, [0u8; 1] )
+                    .map( |(stream, name_len)| ( stream, name_len[0] as usize ) ) ),
+                address_type => Box::new( future::err( proxy_error( format!("invalid address type {} in proxy reply", address_type) ) ) ),
+            }
+        } )
+        // NOTE the address bound by the proxy is not needed, skip it with its port
+        .and_then( |(stream, address_len)| read_exact( stream, vec![0u8; address_len + 2] ) )
+        .map( |(stream, _bound_address)| stream )
+        .map_err( |e| {
+            debug!("Connecting through proxy failed: {}", e);
+            e.context(ErrorKind::ProxyConnectionFailed).into()
+        } );
+    Box::new(connect_fut)
+}
+
+
+
+/// Connects to homes through a SOCKS5 proxy, e.g. a local Tor client.
+/// Onion addresses always use the proxy, other addresses only if clearnet routing is enabled,
+/// otherwise they are connected directly.
+pub struct Socks5HomeConnector
+{
+    handle:         reactor::Handle,
+    proxy:          SocketAddr,
+    route_clearnet: bool,
+    direct:         SimpleTcpHomeConnector,
+}
+
+
+impl Socks5HomeConnector
+{
+    pub fn new(handle: reactor::Handle, proxy: SocketAddr, route_clearnet: bool) -> Self
+        { Self{ direct: SimpleTcpHomeConnector::new( handle.clone() ), handle, proxy, route_clearnet } }
+
+
+    /// Race connections to all addresses of the home, directly connected ones first
+    pub fn connect_addrs(&self, addrs: &[Multiaddr], onion_addrs: &[String]) -> AsyncResult<TcpStream, Error>
+    {
+        let mut proxied_targets = Vec::new();
+        let mut direct_addrs = Vec::new();
+        let mut first_error = None;
+        for addr in addrs {
+            match ProxyTarget::from_multiaddr(addr) {
+                Ok(ref target) if ! self.route_clearnet && ! target.is_onion() => direct_addrs.push( addr.to_owned() ),
+                Ok(target) => proxied_targets.push(target),
+                Err(e) => { first_error.get_or_insert(e); },
+            }
+        }
+        for onion_addr in onion_addrs {
+            match ProxyTarget::from_onion_addr(onion_addr) {
+                Ok(target) => proxied_targets.push(target),
+                Err(e) => { first_error.get_or_insert(e); },
+            }
+        }
+
+        // NOTE report why addresses could not be used, e.g. an unsupported protocol
+        if proxied_targets.is_empty() && direct_addrs.is_empty() {
+            let error = first_error.unwrap_or_else( || ErrorKind::AddressConversionFailed.into() );
+            return Box::new( future::err(error) );
+        }
+
+        let mut attempts = Vec::new();
+        if ! direct_addrs.is_empty()
+            { attempts.push( self.direct.connect_addrs(&direct_addrs) ); }
+        for target in proxied_targets {
+            let proxy = self.proxy;
+            let handle = self.handle.clone();
+            let attempt_fut = future::lazy( move || connect_through_proxy(&proxy, target, &handle) );
+            attempts.push( Box::new(attempt_fut) as AsyncResult<TcpStream, Error> );
+        }
+        net::race_staggered(attempts, &self.handle)
+    }
+}
+
+
+impl HomeConnector for Socks5HomeConnector
+{
+    fn connect(&self, home_profile: &Profile, signer: Rc<Signer>) ->
+        AsyncResult<Rc<Home>, Error>
+    {
+        let (addrs, home_data) = match home_profile.facet {
+            ProfileFacet::Home(ref home_facet) => ( home_facet.addrs.clone(), home_facet.home_data() ),
+            _ => return Box::new(future::err(ErrorKind::HomeProfileExpected.into())),
+        };
+        // NOTE addresses in the facet itself are still usable if its data is invalid
+        let onion_addrs = home_data
+            .map( |home_data| home_data.onion_addrs )
+            .unwrap_or_else( |e| { debug!("Ignoring invalid data of home {}: {}", home_profile.id, e); Vec::new() } );
+
+        let tcp_fut = self.connect_addrs(&addrs, &onion_addrs);
+        net::connect_home( tcp_fut, home_profile.id.clone(), signer, self.handle.clone() )
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{Read, Write};
+    use std::thread;
+
+
+    /// Accept a single client, let it connect anywhere and serve it as the target, returns the connect request
+    fn socks5_stand_in<F>(serve_target: F) -> (SocketAddr, thread::JoinHandle< Vec<u8> >)
+        where F: FnOnce(::std::net::TcpStream) + Send + 'static
+    {
+        let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy_addr = listener.local_addr().unwrap();
+        let stand_in = thread::spawn( move || {
+            let (mut stream, _client_addr) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!( greeting, [SOCKS_VERSION, 1, METHOD_NO_AUTHENTICATION] );
+            stream.write_all( &[SOCKS_VERSION, METHOD_NO_AUTHENTICATION] ).unwrap();
+
+            let mut request = vec![0u8; 5];
+            stream.read_exact(&mut request).unwrap();
+            let mut domain_and_port = vec![0u8; request[4] as usize + 2];
+            stream.read_exact(&mut domain_and_port).unwrap();
+            request.extend(domain_and_port);
+            stream.write_all( &[SOCKS_VERSION, REPLY_SUCCEEDED, 0, ADDRESS_TYPE_IPV4, 127, 0, 0, 1, 0, 0] ).unwrap();
+
+            serve_target(stream);
+            request
+        } );
+        (proxy_addr, stand_in)
+    }
+
+
+    fn echo_ping(mut stream: ::std::net::TcpStream)
+    {
+        let mut ping = [0u8; 4];
+        stream.read_exact(&mut ping).unwrap();
+        stream.write_all(&ping).unwrap();
+    }
+
+
+    #[test]
+    fn test_connect_through_proxy()
+    {
+        let mut reactor = reactor::Core::new().unwrap();
+        let (proxy_addr, stand_in) = socks5_stand_in(echo_ping);
+
+        let target = ProxyTarget::Domain( "abcdefghijklmnop.onion".to_owned(), 2077 );
+        let echo_fut = connect_through_proxy( &proxy_addr, target, &reactor.handle() )
+            .and_then( |stream| write_all( stream, b"ping" )
+                .and_then( |(stream, _ping)| read_exact( stream, [0u8; 4] ) )
+                .map_err( |e| e.context(ErrorKind::ProxyConnectionFailed).into() ) );
+        let (_stream, echo) = reactor.run(echo_fut).unwrap();
+        assert_eq!( &echo, b"ping" );
+
+        let mut expected_request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_DOMAIN, 22];
+        expected_request.extend_from_slice(b"abcdefghijklmnop.onion");
+        expected_request.extend_from_slice( &[0x08, 0x1d] );
+        assert_eq!( stand_in.join().unwrap(), expected_request );
+    }
+
+
+    #[test]
+    fn test_connect_onion_home()
+    {
+        use mercury_home_protocol::crypto::Ed25519Signer;
+        use mercury_home_protocol::handshake::tcp_ecdh_handshake;
+
+        let home_private_key = PrivateKey( vec![1; 32] );
+        let home_signer = Ed25519Signer::new(&home_private_key).unwrap();
+        let client_signer = Rc::new( Ed25519Signer::new( &PrivateKey( vec![2; 32] ) ).unwrap() ) as Rc<Signer>;
+
+        let service_id = "a".repeat(56);
+        let mut home_facet = HomeFacet{ addrs: Vec::new(), data: Vec::new() };
+        let home_data = HomeFacetData{ onion_addrs: vec![ format!("/onion3/{}:2077", service_id) ] };
+        home_facet.set_home_data(&home_data).unwrap();
+        let home_profile = Profile::new( home_signer.profile_id(), home_signer.public_key(), &ProfileFacet::Home(home_facet) );
+
+        // NOTE the home behind the proxy only has to prove its identity in the handshake
+        let (proxy_addr, stand_in) = socks5_stand_in( move |stream| {
+            let mut reactor = reactor::Core::new().unwrap();
+            let home_signer = Rc::new( Ed25519Signer::new(&home_private_key).unwrap() ) as Rc<Signer>;
+            let stream = TcpStream::from_stream( stream, &reactor.handle() ).unwrap();
+            reactor.run( tcp_ecdh_handshake(stream, home_signer) ).ok().unwrap();
+        } );
+
+        let mut reactor = reactor::Core::new().unwrap();
+        let connector = Socks5HomeConnector::new( reactor.handle(), proxy_addr, false );
+        reactor.run( connector.connect(&home_profile, client_signer) ).unwrap();
+
+        let mut expected_request = vec![SOCKS_VERSION, COMMAND_CONNECT, 0, ADDRESS_TYPE_DOMAIN, 62];
+        expected_request.extend_from_slice( format!("{}.onion", service_id).as_bytes() );
+        expected_request.extend_from_slice( &[0x08, 0x1d] );
+        assert_eq!( stand_in.join().unwrap(), expected_request );
+    }
+
+
+    #[test]
+    fn test_proxy_targets()
+    {
+        let onion = parse_onion("/onion/ABCDEFGHIJKLMNOP:2077").unwrap().unwrap();
+        assert_eq!( onion, ProxyTarget::Domain( "abcdefghijklmnop.onion".to_owned(), 2077 ) );
+        assert!( onion.is_onion() );
+        let onion3 = format!( "/onion3/{}:443", "a".repeat(56) );
+        assert!( parse_onion(&onion3).unwrap().unwrap().is_onion() );
+        assert!( parse_onion("/onion/tooshort:2077").is_err() );
+        assert_eq!( parse_onion("/ip4/127.0.0.1/tcp/2077").unwrap(), None );
+        assert_eq!( ProxyTarget::from_onion_addr("/ip4/127.0.0.1/tcp/2077"), Err( ErrorKind::UnsupportedAddress.into() ) );
+
+        let multiaddr = "/dns4/home.example.com/tcp/2077".parse::<Multiaddr>().unwrap();
+        let target = ProxyTarget::from_multiaddr(&multiaddr).unwrap();
+        assert_eq!( target, ProxyTarget::Domain( "home.example.com".to_owned(), 2077 ) );
+        assert!( ! target.is_onion() );
+
+        let multiaddr = "/ip4/127.0.0.1/udp/2077".parse::<Multiaddr>().unwrap();
+        assert_eq!( ProxyTarget::from_multiaddr(&multiaddr), Err( ErrorKind::UnsupportedAddress.into() ) );
+    }
+}
@@ -0,0 +1,79 @@
+#!/usr/bin/env python3
+"""Independent reference implementation of the canonical encoding of signed Mercury data.
+
+Prints the test vectors listed in doc/canonical-encoding.md and asserted by home-protocol/src/canonical.rs.
+Requires the `cryptography` package for the Ed25519 signature vector.
+"""
+
+from cryptography.hazmat.primitives.asymmetric.ed25519 import Ed25519PrivateKey
+from cryptography.hazmat.primitives.serialization import Encoding, PublicFormat
+
+
+def uint(value):
+    out = bytearray()
+    while True:
+        byte = value & 0x7f
+        value >>= 7
+        if value == 0:
+            out.append(byte)
+            return bytes(out)
+        out.append(byte | 0x80)
+
+def blob(value):
+    return uint(len(value)) + value
+
+def text(value):
+    return blob(value.encode('utf-8'))
+
+def option(value, encode):
+    return b'\x00' if value is None else b'\x01' + encode(value)
+
+def listof(items, encode):
+    return uint(len(items)) + b''.join(encode(item) for item in items)
+
+def message(domain, *fields):
+    return text(domain) + b''.join(fields)
+
+
+def metadata(version, nonce, created_at, expires_at):
+    return uint(version) + blob(nonce) + uint(created_at) + option(expires_at, uint)
+
+def relation_proof(relation_type, a_id, a_sig, b_id, b_sig, meta):
+    return text(relation_type) + blob(a_id) + blob(a_sig) + blob(b_id) + blob(b_sig) + meta
+
+def home_facet(addrs, data):
+    return uint(0) + listof(addrs, text) + blob(data)
+
+def profile(profile_id, public_key, facet, version, signature):
+    return blob(profile_id) + blob(public_key) + facet + uint(version) + blob(signature)
+
+
+A_ID = bytes([0x0a] * 3)
+B_ID = bytes([0x0b] * 3)
+NONCE = bytes([0xaa] * 4)
+META = metadata(1, NONCE, 1500000000, 1600000000)
+
+VECTORS = [
+    ("primitives", text("t") + uint(0) + uint(127) + uint(128) + uint(300) + uint(2**64 - 1)
+        + blob(b"") + text("é") + option(None, uint) + option(5, uint) + listof(["a", "bc"], text)),
+    ("relation", message("mercury.relation", uint(1), text("friend"), blob(A_ID), blob(B_ID),
+        blob(NONCE), uint(1500000000), option(1600000000, uint))),
+    ("invitation", message("mercury.invitation", blob(A_ID), text("voucher"), uint(1600000000))),
+    ("revocation", message("mercury.revocation",
+        relation_proof("friend", A_ID, bytes([1, 1]), B_ID, bytes([2, 2]), META), blob(B_ID), uint(1550000000))),
+    ("profile", message("mercury.profile", blob(A_ID), blob(bytes([0x0c] * 2)),
+        home_facet(["/ip4/127.0.0.1/tcp/2077"], b""), uint(1))),
+    ("redirect", message("mercury.redirect", blob(A_ID),
+        profile(A_ID, bytes([0x0c] * 2), home_facet(["/ip4/127.0.0.1/tcp/2077"], b""), 1, bytes([0x0d] * 2)),
+        blob(B_ID), uint(1600000000))),
+]
+
+
+if __name__ == '__main__':
+    for name, encoded in VECTORS:
+        print("{}: {}".format(name, encoded.hex()))
+
+    key = Ed25519PrivateKey.from_private_bytes(bytes([1] * 32))
+    invitation = dict(VECTORS)["invitation"]
+    print("public key: {}".format(key.public_key().public_bytes(Encoding.Raw, PublicFormat.Raw).hex()))
+    print("invitation signature: {}".format(key.sign(invitation).hex()))
@@ -68,7 +68,7 @@ impl Client
                     match relations.pop() {
                         Some(relation) => Box::new( Ok(relation).into_future() ) as AsyncResult<_,_>,
                         None => {
-                            let rel_fut = dapp_session.checkin()
+                            let rel_fut = dapp_session.checkin(None)
                                 .and_then( |events| init_rel_fut.map( |()| events ) )
                                 .and_then( |events| Self::wait_for_pairing_response(events, client_id, handle) );
                             Box::new(rel_fut)
@@ -98,8 +98,8 @@ impl IntoFuture for Client
             .and_then( |contact|
             {
                 info!("Contact is available, start calling");
-                contact.call( AppMessageFrame(vec![]) )
-                    .map_err(|err| { error!("call failed: {:?}", err); err } )
+                let (_call_id, call_fut) = contact.call( AppMessageFrame(vec![]) );
+                call_fut.map_err(|err| { error!("call failed: {:?}", err); err } )
             } )
             .and_then( |call|
             {
@@ -32,8 +32,9 @@ pub fn init_connect_service(my_private_profilekey_file: &str, home_id_str: &str,
     let my_private_key = PrivateKey(my_private_key_bytes);
     let my_signer = Rc::new( Ed25519Signer::new(&my_private_key).unwrap() ) as Rc<Signer>;
     let my_profile_id = my_signer.profile_id().to_owned();
-    let my_profile = Profile::new( &my_profile_id, my_signer.public_key(),
+    let mut my_profile = Profile::new( &my_profile_id, my_signer.public_key(),
         &ProfileFacet::Persona( PersonaFacet{homes: vec![], data: vec![]} ) );
+    my_profile.sign_next_version(&*my_signer);
 
     // TODO consider that client should be able to start up without being a DHT client,
     //      e.g. with having only a Home URL including hints to access Home
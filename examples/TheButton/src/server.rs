@@ -36,9 +36,12 @@ impl IntoFuture for Server
         let dapp_events_fut = self.appctx.service.dapp_session(&self.appctx.app_id, None)
             .inspect( |_| debug!("dApp session was initialized, checking in") )
             .map_err( |err| { error!("Failed to create dApp session: {:?}", err); err } )
-            .and_then(|dapp_session| dapp_session.checkin() )
+            .and_then( |dapp_session| {
+                let my_id = dapp_session.selected_profile().to_owned();
+                dapp_session.checkin(None).map( move |dapp_events| (my_id, dapp_events) )
+            } )
             .inspect( |_call_stream| debug!("Call stream received with successful checkin, listening for calls") )
-            .and_then(move |dapp_events|
+            .and_then(move |(my_id, dapp_events)|
             {
                 dapp_events
                     .map_err( |()| Error::from(ErrorKind::ConnectionFailed) )
@@ -48,10 +51,11 @@ impl IntoFuture for Server
                         {
                             DAppEvent::Call(incoming_call) => {
                                 let (to_me, from_caller) = mpsc::channel(1);
-                                let to_caller_opt = incoming_call.answer(Some(to_me)).to_caller;
-                                if let Some(to_caller) = to_caller_opt
+                                let call_req = incoming_call.answer( CallAnswer::Accepted(to_me) );
+                                let callee = my_id.clone();
+                                if let Some(to_caller) = call_req.to_caller
                                     { active_calls_rc.borrow_mut().push(
-                                        DAppCall{incoming: from_caller, outgoing: to_caller} ); }
+                                        DAppCall::from_channels(call_req.id, callee, to_caller, from_caller) ); }
                                 Ok( debug!("Answered incoming call, saving channel to caller") )
                             },
 
@@ -0,0 +1,243 @@
+//! Administration interface of a running home node.
+//!
+//! The home listens on a local Unix socket for requests of the `mercury-home-admin` tool.
+//! Requests and responses are JSON documents, one per line. Each connection starts with a random
+//! challenge sent by the home that must be signed with the private key of the home, so only
+//! operators having access to the key of the node can manage it.
+
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use futures::{future, Future, Sink, Stream};
+use rand::{RngCore, rngs::OsRng};
+use serde::Serialize;
+use tokio_codec::{Framed, LinesCodec};
+use tokio_core::reactor;
+use tokio_uds::{UnixListener, UnixStream};
+
+use mercury_home_protocol::*;
+use mercury_home_protocol::canonical::{CanonicalEncoder, DOMAIN_ADMIN};
+use mercury_home_protocol::error::Error;
+use server::HomeServer;
+
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AdminRequest
+{
+    /// Answer to the challenge of the home, must be the first request of a connection
+    Authenticate(Signature),
+    ListProfiles,
+    ListSessions,
+    QueuedEvents(ProfileId),
+    Logout(ProfileId),
+    Unregister(ProfileId),
+    StorageUsage,
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum AdminResponse
+{
+    /// Sent by the home when a connection is opened, see `challenge_signable_part()`
+    Challenge(Vec<u8>),
+    Authenticated,
+    Profiles(Vec<ProfileId>),
+    Sessions(Vec<SessionInfo>),
+    QueuedEvents(Vec<ProfileEvent>),
+    LoggedOut(usize),
+    Unregistered,
+    StorageUsage(Vec<StorageUsage>),
+    Error(String),
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SessionInfo
+{
+    pub profile_id: ProfileId,
+    pub sessions:   Vec<Vec<ApplicationId>>, // apps checked in on each live session of the profile
+}
+
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct StorageUsage
+{
+    pub name:   String,
+    pub path:   String,
+    pub files:  u64,
+    pub bytes:  u64,
+}
+
+
+/// Bytes to be signed with the key of the home to answer an admin challenge
+pub fn challenge_signable_part(challenge: &[u8]) -> Vec<u8>
+    { CanonicalEncoder::new(DOMAIN_ADMIN).bytes(challenge).finish() }
+
+
+pub fn to_line<T: Serialize>(message: &T) -> String
+    { ::serde_json::to_string(message).expect("Admin messages are always serializable") }
+
+
+
+pub struct AdminServer
+{
+    server:         Rc<HomeServer>,
+    signer:         Rc<Signer>,
+    validator:      Rc<Validator>,
+    storage_paths:  Vec<(String, PathBuf)>,
+}
+
+
+impl AdminServer
+{
+    const CHALLENGE_SIZE: usize = 32;
+
+    pub fn new(server: Rc<HomeServer>, signer: Rc<Signer>, validator: Rc<Validator>,
+               storage_paths: Vec<(String, PathBuf)>) -> Self
+        { Self{ server, signer, validator, storage_paths } }
+
+
+    /// Serve admin clients on a Unix socket accessible only by the user running the home
+    pub fn listen(admin: Rc<AdminServer>, socket_path: &Path, handle: &reactor::Handle)
+        -> Result< Box< Future<Item=(), Error=io::Error> >, io::Error >
+    {
+        remove_stale_socket(socket_path)?;
+        let listener = UnixListener::bind(socket_path, handle)?;
+        fs::set_permissions( socket_path, fs::Permissions::from_mode(0o600) )?;
+        debug!("Admin interface listening on {:?}", socket_path);
+
+        let handle = handle.clone();
+        let serve_fut = listener.incoming().for_each( move |(connection, _peer_addr)|
+        {
+            handle.spawn( Self::serve( admin.clone(), connection ) );
+            Ok( () )
+        } );
+        Ok( Box::new(serve_fut) )
+    }
+
+
+    fn serve(admin: Rc<AdminServer>, connection: UnixStream) -> Box< Future<Item=(), Error=()> >
+    {
+        let mut challenge = vec![0u8; Self::CHALLENGE_SIZE];
+        OsRng.fill_bytes(&mut challenge);
+        let (lines_sink, lines_stream) = Framed::new( connection, LinesCodec::new() ).split();
+
+        let serve_fut = lines_sink.send( to_line( &AdminResponse::Challenge( challenge.clone() ) ) )
+            .and_then( move |lines_sink| lines_stream.into_future()
+                .map( move |(auth_line, lines_stream)| (auth_line, lines_stream, lines_sink) )
+                .map_err( |(e, _lines_stream)| e ) )
+            .and_then( move |(auth_line, lines_stream, lines_sink)|
+            {
+                if ! admin.is_authenticated(&challenge, auth_line) {
+                    warn!("Admin client failed to authenticate, closing connection");
+                    let refuse_fut = lines_sink.send( to_line( &AdminResponse::Error( "Authentication failed".to_owned() ) ) )
+                        .map( |_lines_sink| () );
+                    return Box::new(refuse_fut) as Box< Future<Item=(), Error=io::Error> >;
+                }
+
+                let responses = lines_stream.and_then( move |line| admin.respond(&line)
+                    .then( |response_res| {
+                        let response = response_res.unwrap_or_else( |e| AdminResponse::Error( e.to_string() ) );
+                        Ok( to_line(&response) )
+                    } ) );
+                let respond_fut = lines_sink.send( to_line(&AdminResponse::Authenticated) )
+                    .and_then( |lines_sink| responses.forward(lines_sink) )
+                    .map( |(_responses, _lines_sink)| () );
+                Box::new(respond_fut)
+            } )
+            .map_err( |e| warn!("Serving admin client failed: {}", e) );
+        Box::new(serve_fut)
+    }
+
+
+    fn is_authenticated(&self, challenge: &[u8], auth_line: Option<String>) -> bool
+    {
+        let request = auth_line.and_then( |line| ::serde_json::from_str(&line).ok() );
+        match request {
+            Some( AdminRequest::Authenticate(signature) ) => self.validator
+                .validate_signature( self.signer.public_key(), &challenge_signable_part(challenge), &signature )
+                .unwrap_or(false),
+            _ => false,
+        }
+    }
+
+
+    fn respond(&self, line: &str) -> Box< Future<Item=AdminResponse, Error=Error> >
+    {
+        let request: AdminRequest = match ::serde_json::from_str(line) {
+            Ok(request) => request,
+            Err(e) => return Box::new( future::ok( AdminResponse::Error( format!("Invalid request: {}", e) ) ) ),
+        };
+        debug!("Serving admin request {:?}", request);
+
+        let home_id = self.signer.profile_id().to_owned();
+        match request
+        {
+            AdminRequest::Authenticate(_signature) =>
+                Box::new( future::ok( AdminResponse::Error( "Already authenticated".to_owned() ) ) ),
+            AdminRequest::ListProfiles =>
+                Box::new( self.server.hosted_profiles(&home_id).map(AdminResponse::Profiles) ),
+            AdminRequest::ListSessions => {
+                let sessions = self.server.live_session_apps().into_iter()
+                    .map( |(profile_id, sessions)| SessionInfo{ profile_id, sessions } )
+                    .collect();
+                Box::new( future::ok( AdminResponse::Sessions(sessions) ) )
+            },
+            AdminRequest::QueuedEvents(profile_id) =>
+                Box::new( self.server.queued_events(&profile_id).map(AdminResponse::QueuedEvents) ),
+            AdminRequest::Logout(profile_id) =>
+                Box::new( future::ok( AdminResponse::LoggedOut( self.server.logout(&profile_id) ) ) ),
+            AdminRequest::Unregister(profile_id) =>
+                Box::new( HomeServer::force_unregister( self.server.clone(), home_id, profile_id )
+                    .map( |()| AdminResponse::Unregistered ) ),
+            AdminRequest::StorageUsage =>
+                Box::new( future::ok( AdminResponse::StorageUsage( self.storage_usage() ) ) ),
+        }
+    }
+
+
+    fn storage_usage(&self) -> Vec<StorageUsage>
+    {
+        self.storage_paths.iter()
+            .map( |&(ref name, ref path)| {
+                let (files, bytes) = directory_usage(path)
+                    .unwrap_or_else( |e| { warn!("Failed to measure storage {:?}: {}", path, e); (0, 0) } );
+                StorageUsage{ name: name.to_owned(), path: path.to_string_lossy().into_owned(), files, bytes }
+            } )
+            .collect()
+    }
+}
+
+
+/// Remove the socket file left by a previous run, it is not removed on shutdown and would prevent binding.
+/// Other kinds of files are kept, binding over them fails.
+pub fn remove_stale_socket(socket_path: &Path) -> io::Result<()>
+{
+    let stale_socket = fs::symlink_metadata(socket_path)
+        .map( |metadata| metadata.file_type().is_socket() )
+        .unwrap_or(false);
+    if stale_socket
+        { fs::remove_file(socket_path)?; }
+    Ok( () )
+}
+
+
+/// Number of files and their total size in bytes in a directory, including its subdirectories
+fn directory_usage(path: &Path) -> io::Result<(u64, u64)>
+{
+    let mut usage = (0, 0);
+    for entry in fs::read_dir(path)?
+    {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let (files, bytes) = if metadata.is_dir() { directory_usage( &entry.path() )? }
+                             else { (1, metadata.len()) };
+        usage.0 += files;
+        usage.1 += bytes;
+    }
+    Ok(usage)
+}
@@ -0,0 +1,156 @@
+extern crate mercury_home_node;
+extern crate mercury_home_protocol;
+extern crate serde_json;
+#[macro_use]
+extern crate structopt;
+
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
+
+use structopt::StructOpt;
+
+use mercury_home_protocol::{*, crypto::*};
+use mercury_home_node::admin::*;
+
+
+
+#[derive(Debug, StructOpt)]
+#[structopt(name="mercury-home-admin", about="Inspect and manage a running Mercury home node")]
+struct Cli
+{
+    #[structopt(long="server-key", default_value="../etc/homenode.id", parse(from_os_str), raw(value_name=r#""FILE""#),
+        help="Private key file of the home, used to authenticate to it")]
+    private_key_file: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="admin-socket", default_value="/tmp/mercury/home/admin.sock", parse(from_os_str),
+        help="Unix socket path the home serves admin clients on", raw(value_name=r#""path/to/socket""#) )]
+    admin_socket_path: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+
+#[derive(Debug, StructOpt)]
+enum Command
+{
+    #[structopt(name="profiles", about="List profiles hosted on the home")]
+    Profiles,
+
+    #[structopt(name="sessions", about="List logged in profiles with the apps checked in on each of their sessions")]
+    Sessions,
+
+    #[structopt(name="events", about="Show events queued for a profile until it logs in")]
+    Events{ profile_id: String },
+
+    #[structopt(name="logout", about="Close all sessions of a profile")]
+    Logout{ profile_id: String },
+
+    #[structopt(name="unregister", about="Remove a profile and all data stored for it from the home")]
+    Unregister{ profile_id: String },
+
+    #[structopt(name="storage", about="Show the disk usage of the storages of the home")]
+    Storage,
+}
+
+
+
+fn main()
+{
+    if let Err(e) = run( Cli::from_args() ) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+
+fn run(cli: Cli) -> Result<(), String>
+{
+    let request = match cli.command {
+        Command::Profiles                   => AdminRequest::ListProfiles,
+        Command::Sessions                   => AdminRequest::ListSessions,
+        Command::Events{ profile_id }       => AdminRequest::QueuedEvents( parse_profile_id(&profile_id)? ),
+        Command::Logout{ profile_id }       => AdminRequest::Logout( parse_profile_id(&profile_id)? ),
+        Command::Unregister{ profile_id }   => AdminRequest::Unregister( parse_profile_id(&profile_id)? ),
+        Command::Storage                    => AdminRequest::StorageUsage,
+    };
+
+    let private_key = PrivateKey( fs::read(&cli.private_key_file)
+        .map_err( |e| format!("Failed to read key file {:?}: {}", cli.private_key_file, e) )? );
+    let signer = Ed25519Signer::new(&private_key)
+        .map_err( |e| format!("Invalid private key: {}", e) )?;
+
+    let mut writer = UnixStream::connect(&cli.admin_socket_path)
+        .map_err( |e| format!("Failed to connect to home at {:?}: {}", cli.admin_socket_path, e) )?;
+    let mut reader = BufReader::new( writer.try_clone().map_err( |e| e.to_string() )? );
+
+    let challenge = match receive(&mut reader)? {
+        AdminResponse::Challenge(challenge) => challenge,
+        response => return Err( format!("Unexpected response: {:?}", response) ),
+    };
+    send( &mut writer, &AdminRequest::Authenticate( signer.sign( &challenge_signable_part(&challenge) ) ) )?;
+    match receive(&mut reader)? {
+        AdminResponse::Authenticated => {},
+        AdminResponse::Error(e) => return Err(e),
+        response => return Err( format!("Unexpected response: {:?}", response) ),
+    }
+
+    send(&mut writer, &request)?;
+    print_response( receive(&mut reader)? )
+}
+
+
+fn parse_profile_id(src: &str) -> Result<ProfileId, String>
+    { ProfileId::try_from(src).map_err( |e| format!("Invalid profile id '{}': {}", src, e) ) }
+
+
+fn send(writer: &mut Write, request: &AdminRequest) -> Result<(), String>
+{
+    writeln!( writer, "{}", to_line(request) )
+        .map_err( |e| format!("Failed to send request: {}", e) )
+}
+
+
+fn receive(reader: &mut BufRead) -> Result<AdminResponse, String>
+{
+    let mut line = String::new();
+    let read_bytes = reader.read_line(&mut line)
+        .map_err( |e| format!("Failed to receive response: {}", e) )?;
+    if read_bytes == 0
+        { return Err( "Connection closed by the home".to_owned() ); }
+    serde_json::from_str(&line)
+        .map_err( |e| format!("Invalid response: {}", e) )
+}
+
+
+fn print_response(response: AdminResponse) -> Result<(), String>
+{
+    match response
+    {
+        AdminResponse::Profiles(profile_ids) =>
+            for profile_id in profile_ids { println!("{}", profile_id); },
+        AdminResponse::Sessions(infos) =>
+            for info in infos {
+                let apps = info.sessions.iter()
+                    .map( |apps| apps.iter().map( |app| app.0.as_str() ).collect::<Vec<_>>().join(",") )
+                    .map( |apps| format!("[{}]", apps) )
+                    .collect::<Vec<_>>();
+                println!( "{} {}", info.profile_id, apps.join(" ") );
+            },
+        AdminResponse::QueuedEvents(events) =>
+            for event in events { println!( "{}", to_line(&event) ); },
+        AdminResponse::LoggedOut(count) => println!("Closed {} session(s)", count),
+        AdminResponse::Unregistered => println!("Profile unregistered"),
+        AdminResponse::StorageUsage(usages) =>
+            for usage in usages
+                { println!( "{:<16} {:>8} files {:>12} bytes  {}", usage.name, usage.files, usage.bytes, usage.path ); },
+        AdminResponse::Error(e) => return Err(e),
+        response => return Err( format!("Unexpected response: {:?}", response) ),
+    }
+    Ok( () )
+}
@@ -8,18 +8,29 @@ extern crate mercury_home_protocol;
 extern crate mercury_home_node;
 extern crate mercury_storage;
 extern crate multiaddr;
+extern crate serde_json;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_signal;
+extern crate tokio_uds;
 
 
 use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::rc::Rc;
+use std::sync::Arc;
 
-use futures::{Future, Stream};
+use futures::{future, Future, Stream};
 use tokio_core::{reactor, net::TcpListener};
+use tokio_io::AsyncRead;
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+use tokio_uds::UnixListener;
 
-use mercury_home_protocol::{crypto::*, handshake, mercury_capnp::server_dispatcher::HomeDispatcherCapnProto};
-use mercury_home_node::{config::*, server::*};
+use mercury_home_protocol::{AsyncResult, HomeInvitation, PeerContext, crypto::*, handshake};
+use mercury_home_protocol::{error::Error, mercury_capnp::server_dispatcher::HomeDispatcherCapnProto};
+use mercury_home_node::{admin::{self, AdminServer}, config::*, server::*};
+use mercury_home_node::metrics::{self, MetricsRegistry, TimedStore};
 use mercury_storage::async::{KeyAdapter, fs::FileStore, imp::InMemoryStore};
 
 
@@ -29,46 +40,161 @@ fn main()
     log4rs::init_file( "log4rs.yml", Default::default() ).unwrap();
     let config = Config::new();
 
+    if let Some(validity) = config.issue_invitation() {
+        let invitation = HomeInvitation::issue( validity, &*config.signer() );
+        println!( "{}", serde_json::to_string(&invitation).expect("Failed to serialize invitation") );
+        return;
+    }
+
     let mut core = reactor::Core::new().unwrap();
     let handle = core.handle();
 
     // TODO use some kind of persistent storage for public distributed storage
     //let distributed_storage = Box::new( Ipfs::new( "localhost", 5001, &handle1.clone() )? )
-    let distributed_storage = Rc::new( RefCell::new( InMemoryStore::new() ) );
-    let local_storage = Rc::new( RefCell::new( KeyAdapter::new(
-        FileStore::new( config.storage_path() ).unwrap() ) ) );
+    let registry = Arc::new( MetricsRegistry::default() );
+    let distributed_storage = Rc::new( RefCell::new( TimedStore::new( "public-profiles",
+        InMemoryStore::new(), registry.clone() ) ) );
+    let local_storage = Rc::new( RefCell::new( TimedStore::new( "hosted-profiles", KeyAdapter::new(
+        FileStore::new( config.storage_path() ).unwrap() ), registry.clone() ) ) );
+    let event_storage = Rc::new( RefCell::new( TimedStore::new( "offline-events", KeyAdapter::new(
+        FileStore::new( config.event_storage_path() ).unwrap() ), registry.clone() ) ) );
+    let index_storage = Rc::new( RefCell::new( TimedStore::new( "profile-index", KeyAdapter::new(
+        FileStore::new( config.index_storage_path() ).unwrap() ), registry.clone() ) ) );
+    let redirect_storage = Rc::new( RefCell::new( TimedStore::new( "redirects", KeyAdapter::new(
+        FileStore::new( config.redirect_storage_path() ).unwrap() ), registry.clone() ) ) );
+    let voucher_storage = Rc::new( RefCell::new( TimedStore::new( "used-vouchers",
+        FileStore::new( config.voucher_storage_path() ).unwrap(), registry.clone() ) ) );
+    let ban_storage = Rc::new( RefCell::new( TimedStore::new( "ban-lists", KeyAdapter::new(
+        FileStore::new( config.ban_storage_path() ).unwrap() ), registry.clone() ) ) );
+    let revocation_storage = Rc::new( RefCell::new( TimedStore::new( "revocations", KeyAdapter::new(
+        FileStore::new( config.revocation_storage_path() ).unwrap() ), registry.clone() ) ) );
     let signer = config.signer();
     let validator = Rc::new( CompositeValidator::default() );
-    let server = Rc::new( HomeServer::new(&handle, validator, distributed_storage, local_storage) );
+    let server = Rc::new( HomeServer::new(&handle, validator.clone(), distributed_storage, local_storage,
+        event_storage, index_storage, redirect_storage, voucher_storage, ban_storage, revocation_storage, config.registration(), config.session_limits(), config.session_policy(), registry.clone()) );
+
+    info!( "Opening socket {:?} for admin clients", config.admin_socket_path() );
+    let admin = Rc::new( AdminServer::new( server.clone(), signer.clone(), validator, config.storage_paths() ) );
+    let admin_fut = AdminServer::listen( admin, config.admin_socket_path(), &handle )
+        .expect("Failed to bind admin socket");
+    handle.spawn( admin_fut.map_err( |e| warn!("Admin interface failed: {}", e) ) );
+
+    info!( "Opening socket {} for metrics and health checks", config.metrics_socket() );
+    let metrics_fut = metrics::serve_http( server.clone(), config.metrics_socket(), &handle )
+        .expect("Failed to bind metrics socket");
+    handle.spawn( metrics_fut.map_err( |e| warn!("Serving metrics failed: {}", e) ) );
 
-    info!( "Opening socket {} for incoming TCP clients", config.listen_socket() );
-    let socket = TcpListener::bind( config.listen_socket(), &handle )
-        .expect("Failed to bind socket");
+    let public_addrs = config.public_addresses();
+    if public_addrs.is_empty()
+        { warn!("No address to publish in the profile of the home, listening on unspecified IP addresses needs --public-address"); }
+    else if public_addrs.len() < config.listen_addresses().len()
+        { info!("Local sockets and unspecified IP addresses are not published in the profile of the home"); }
+    let publish_fut = HomeServer::publish_home_profile( server.clone(), signer.clone(), public_addrs )
+        .map( |profile| info!("Published version {} of the home profile", profile.version) )
+        .map_err( |e| warn!("Failed to publish the home profile: {}", e) );
+    handle.spawn(publish_fut);
+
+    let listeners = config.listen_addresses().iter()
+        .map( |address| {
+            info!("Opening socket {:?} for incoming clients", address);
+            listen( address, server.clone(), signer.clone(), registry.clone(), &handle )
+                .expect("Failed to bind socket")
+        } )
+        .collect::<Vec<_>>();
 
     info!("Server started, waiting for clients");
-    let done = socket.incoming().for_each( move |(socket, _addr)|
-    {
-        info!("Accepted client connection, serving requests");
+    let serve_fut = future::select_all(listeners)
+        .map( |((), _index, _others)| () )
+        .map_err( |(e, _index, _others)| e );
+    // NOTE dropping the listeners stops accepting new connections
+    let done = serve_fut.select( shutdown_signal(&handle) )
+        .map( |((), _pending)| () )
+        .map_err( |(e, _pending)| e );
+    let res = core.run(done);
+    debug!("Accept loop finished with result: {:?}", res);
+
+    for address in config.listen_addresses() {
+        if let ListenAddress::Unix(ref path) = *address {
+            if let Err(e) = fs::remove_file(path)
+                { warn!("Failed to remove socket {:?}: {}", path, e); }
+        }
+    }
+
+    info!("Shutting down, closing sessions and waiting for pending operations");
+    let _res = core.run( HomeServer::shutdown( server, config.shutdown_timeout() ) );
+    info!("Server shutdown");
+}
+
+
+/// Resolves when the process is asked to terminate, i.e. receives SIGINT or SIGTERM
+fn shutdown_signal(handle: &reactor::Handle) -> Box< Future<Item=(), Error=io::Error> >
+{
+    let signals = [SIGINT, SIGTERM].iter()
+        .map( |&signal| Signal::new(signal, handle)
+            .flatten_stream()
+            .into_future()
+            .map( |(signal, _stream)| info!("Received signal {:?}", signal) )
+            .map_err( |(e, _stream)| e ) )
+        .collect::<Vec<_>>();
+    let signal_fut = future::select_all(signals)
+        .map( |((), _index, _others)| () )
+        .map_err( |(e, _index, _others)| e );
+    Box::new(signal_fut)
+}
+
 
-        let handle_clone = handle.clone();
-        let server_clone = server.clone();
+fn listen(address: &ListenAddress, server: Rc<HomeServer>, signer: Rc<Signer>, registry: Arc<MetricsRegistry>,
+          handle: &reactor::Handle) -> Result< Box< Future<Item=(), Error=io::Error> >, io::Error >
+{
+    let handle = handle.clone();
+    match *address
+    {
+        ListenAddress::Tcp(ref addr) => {
+            let listener = TcpListener::bind(addr, &handle)?;
+            let serve_fut = listener.incoming().for_each( move |(socket, _addr)|
+            {
+                info!("Accepted TCP client connection, serving requests");
+                let handshake_fut = handshake::tcp_ecdh_handshake( socket, signer.clone() );
+                handle.spawn( serve_client( handshake_fut, server.clone(), registry.clone(), handle.clone() ) );
+                Ok( () )
+            } );
+            Ok( Box::new(serve_fut) )
+        },
 
-        // TODO fill this in properly for each connection based on TLS authentication info
-        let handshake_fut = handshake::temp_tcp_handshake_until_tls_is_implemented( socket, signer.clone() )
-            .map_err( |e| warn!("Client handshake failed: {:?}", e) )
-            .and_then( move |(reader, writer, client_context)|
+        ListenAddress::Unix(ref path) => {
+            admin::remove_stale_socket(path)?;
+            let listener = UnixListener::bind(path, &handle)?;
+            let serve_fut = listener.incoming().for_each( move |(connection, _addr)|
             {
-                let home = HomeConnectionServer::new( Rc::new(client_context), server_clone.clone() )
-                    .map_err( |e| warn!("Failed to create server instance: {:?}", e) )?;
-                HomeDispatcherCapnProto::dispatch( Rc::new(home), reader, writer, handle_clone.clone() );
+                info!("Accepted local client connection, serving requests");
+                let (reader, writer) = connection.split();
+                let handshake_fut = handshake::ecdh_handshake( reader, writer, signer.clone() );
+                handle.spawn( serve_client( handshake_fut, server.clone(), registry.clone(), handle.clone() ) );
                 Ok( () )
             } );
+            Ok( Box::new(serve_fut) )
+        },
+    }
+}
 
-        handle.spawn(handshake_fut);
-        Ok( () )
-    } );
 
-    let res = core.run(done);
-    debug!("Reactor finished with result: {:?}", res);
-    info!("Server shutdown");
+fn serve_client<R, W>(handshake_fut: AsyncResult<(R, W, PeerContext), Error>, server: Rc<HomeServer>,
+                      registry: Arc<MetricsRegistry>, handle: reactor::Handle) -> Box< Future<Item=(), Error=()> >
+    where R: Read + 'static,
+          W: Write + 'static
+{
+    registry.inc(&metrics::CONNECTIONS_ACCEPTED);
+    let serve_fut = handshake_fut
+        .map_err( move |e| {
+            registry.inc(&metrics::HANDSHAKES_FAILED);
+            warn!("Client handshake failed: {:?}", e)
+        } )
+        .and_then( move |(reader, writer, client_context)|
+        {
+            let home = HomeConnectionServer::new( Rc::new(client_context), server )
+                .map_err( |e| warn!("Failed to create server instance: {:?}", e) )?;
+            HomeDispatcherCapnProto::dispatch( Rc::new(home), reader, writer, handle );
+            Ok( () )
+        } );
+    Box::new(serve_fut)
 }
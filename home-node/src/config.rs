@@ -1,9 +1,64 @@
 use std::fs;
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+
+use multiaddr::{AddrComponent, Multiaddr, ToMultiaddr};
 
 use mercury_home_protocol::{*, crypto::*};
+use server::{OverflowPolicy, RegistrationPolicy, SessionLimits, SessionPolicy};
+
+
+
+/// An address the home serves clients on
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ListenAddress
+{
+    Tcp(SocketAddr),
+    /// Local Unix domain socket, e.g. for clients on the same machine
+    Unix(PathBuf),
+}
+
+impl ListenAddress
+{
+    const UNIX_PREFIX: &'static str = "/unix";
+
+    /// The address to be published for remote clients, None for local sockets or unspecified IP addresses
+    pub fn public_multiaddr(&self) -> Option<Multiaddr>
+    {
+        match *self {
+            ListenAddress::Tcp(ref addr) if ! addr.ip().is_unspecified() => addr.to_multiaddr().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for ListenAddress
+{
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err>
+    {
+        // NOTE the path of a Unix socket contains slashes, so it is taken as is instead of parsing a multiaddr
+        if src.starts_with( &format!("{}/", Self::UNIX_PREFIX) )
+            { return Ok( ListenAddress::Unix( PathBuf::from( &src[Self::UNIX_PREFIX.len()..] ) ) ); }
+
+        let multiaddr = src.parse::<Multiaddr>()
+            .map_err( |e| format!("Invalid listen address '{}': {}", src, e) )?;
+        let mut components = multiaddr.iter();
+        let ip = match components.next() {
+            Some( AddrComponent::IP4(address) ) => IpAddr::from(address),
+            Some( AddrComponent::IP6(address) ) => IpAddr::from(address),
+            _ => return Err( format!("Listen address '{}' must start with /ip4, /ip6 or /unix", src) ),
+        };
+        match ( components.next(), components.next() ) {
+            ( Some( AddrComponent::TCP(port) ), None ) => Ok( ListenAddress::Tcp( SocketAddr::new(ip, port) ) ),
+            _ => Err( format!("Listen address '{}' must be a TCP address, e.g. /ip4/0.0.0.0/tcp/2077", src) ),
+        }
+    }
+}
 
 
 
@@ -19,9 +74,86 @@ struct CliConfig
         help="Directory path to store hosted profiles in", raw(value_name=r#""path/to/dir""#) )]
     storage_path: PathBuf,
 
-    #[structopt(long="tcp", default_value="0.0.0.0:2077", raw(value_name=r#""IP:Port""#),
-        help="Listen on this socket to serve TCP clients")]
-    socket_addr: String,
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="event-storage", default_value="/tmp/mercury/home/offline-events", parse(from_os_str),
+        help="Directory path to store events for offline profiles in", raw(value_name=r#""path/to/dir""#) )]
+    event_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="index-storage", default_value="/tmp/mercury/home/profile-index", parse(from_os_str),
+        help="Directory path to store the index of hosted profiles in", raw(value_name=r#""path/to/dir""#) )]
+    index_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="redirect-storage", default_value="/tmp/mercury/home/redirects", parse(from_os_str),
+        help="Directory path to store redirects of profiles moved to other homes in", raw(value_name=r#""path/to/dir""#) )]
+    redirect_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="voucher-storage", default_value="/tmp/mercury/home/used-vouchers", parse(from_os_str),
+        help="Directory path to store vouchers of already used invitations in", raw(value_name=r#""path/to/dir""#) )]
+    voucher_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="ban-storage", default_value="/tmp/mercury/home/ban-lists", parse(from_os_str),
+        help="Directory path to store the lists of profiles banned by hosted profiles in", raw(value_name=r#""path/to/dir""#) )]
+    ban_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="revocation-storage", default_value="/tmp/mercury/home/revocations", parse(from_os_str),
+        help="Directory path to store relations revoked by or for hosted profiles in", raw(value_name=r#""path/to/dir""#) )]
+    revocation_storage_path: PathBuf,
+
+    // TODO default value is only for testing, make this platform-dependent
+    #[structopt(long="admin-socket", default_value="/tmp/mercury/home/admin.sock", parse(from_os_str),
+        help="Unix socket path to serve the mercury-home-admin tool on", raw(value_name=r#""path/to/socket""#) )]
+    admin_socket_path: PathBuf,
+
+    #[structopt(long="registration", default_value="open", raw(value_name=r#""open|invite-only""#),
+        help="Allow anyone to register or only personas presenting an invitation issued by this home")]
+    registration: RegistrationPolicy,
+
+    #[structopt(long="sessions", default_value="single", raw(value_name=r#""single|multi-device""#),
+        help="Close the previous session of a profile on login or keep sessions of all its devices open")]
+    session_policy: SessionPolicy,
+
+    #[structopt(long="channel-capacity", default_value="1", raw(value_name=r#""COUNT""#),
+        help="Number of items sent to a client in advance on each of its event, call and message streams")]
+    channel_capacity: usize,
+
+    #[structopt(long="event-buffer-size", default_value="256", raw(value_name=r#""COUNT""#),
+        help="Maximum number of events buffered for a session until the client consumes them")]
+    event_buffer_size: usize,
+
+    #[structopt(long="call-buffer-size", default_value="16", raw(value_name=r#""COUNT""#),
+        help="Maximum number of calls buffered for each app of a session until the app consumes them")]
+    call_buffer_size: usize,
+
+    #[structopt(long="buffer-overflow", default_value="reject-new", raw(value_name=r#""drop-oldest|reject-new|spill-to-storage""#),
+        help="What to do with new events and calls when the buffer of a session is full")]
+    buffer_overflow: OverflowPolicy,
+
+    #[structopt(long="shutdown-timeout", default_value="10", raw(value_name=r#""SECONDS""#),
+        help="Maximum time to wait for pending calls and storage operations when shutting down")]
+    shutdown_timeout: u64,
+
+    #[structopt(long="issue-invitation", raw(value_name=r#""SECONDS""#),
+        help="Print a new invitation valid for the given number of seconds as JSON, then exit")]
+    issue_invitation: Option<u64>,
+
+    #[structopt(long="listen", default_value="/ip4/0.0.0.0/tcp/2077", raw(use_delimiter="true"), raw(value_name=r#""MULTIADDR""#),
+        help="Listen on these addresses to serve clients, e.g. /ip4/0.0.0.0/tcp/2077,/ip6/::1/tcp/2077,/unix/path/to/socket. \
+              TCP addresses with a specific IP are published in the profile of the home unless --public-address is given")]
+    listen_addresses: Vec<ListenAddress>,
+
+    #[structopt(long="public-address", raw(use_delimiter="true"), raw(value_name=r#""MULTIADDR""#),
+        help="Publish these addresses in the profile of the home instead of the listen addresses, \
+              e.g. /ip4/192.0.2.1/tcp/2077 if listening on an unspecified IP address or behind NAT")]
+    public_addresses: Vec<Multiaddr>,
+
+    #[structopt(long="metrics", default_value="127.0.0.1:2078", raw(value_name=r#""IP:Port""#),
+        help="Listen on this socket to serve metrics on /metrics and a health check on /health over HTTP")]
+    metrics_addr: String,
 }
 
 impl CliConfig
@@ -37,8 +169,22 @@ impl CliConfig
 pub struct Config
 {
     storage_path: String,
+    event_storage_path: String,
+    index_storage_path: String,
+    redirect_storage_path: String,
+    voucher_storage_path: String,
+    ban_storage_path: String,
+    revocation_storage_path: String,
+    admin_socket_path: PathBuf,
+    registration: RegistrationPolicy,
+    session_limits: SessionLimits,
+    session_policy: SessionPolicy,
+    issue_invitation: Option<Duration>,
+    shutdown_timeout: Duration,
     signer: Rc<Signer>,
-    listen_socket: SocketAddr, // TODO consider using Vec if listening on several network devices is needed
+    listen_addresses: Vec<ListenAddress>,
+    public_addresses: Vec<Multiaddr>,
+    metrics_socket: SocketAddr,
 }
 
 impl Config
@@ -49,6 +195,23 @@ impl Config
 
         let storage_path = cli.storage_path.to_str()
             .expect("Storage path should have a default value").to_owned();
+        let event_storage_path = cli.event_storage_path.to_str()
+            .expect("Event storage path should have a default value").to_owned();
+        let index_storage_path = cli.index_storage_path.to_str()
+            .expect("Index storage path should have a default value").to_owned();
+        let redirect_storage_path = cli.redirect_storage_path.to_str()
+            .expect("Redirect storage path should have a default value").to_owned();
+        let voucher_storage_path = cli.voucher_storage_path.to_str()
+            .expect("Voucher storage path should have a default value").to_owned();
+        let ban_storage_path = cli.ban_storage_path.to_str()
+            .expect("Ban storage path should have a default value").to_owned();
+        let revocation_storage_path = cli.revocation_storage_path.to_str()
+            .expect("Revocation storage path should have a default value").to_owned();
+        let issue_invitation = cli.issue_invitation.map(Duration::from_secs);
+        let shutdown_timeout = Duration::from_secs(cli.shutdown_timeout);
+        let session_limits = SessionLimits{ channel_capacity: cli.channel_capacity,
+            event_buffer_size: cli.event_buffer_size, call_buffer_size: cli.call_buffer_size,
+            overflow: cli.buffer_overflow };
 
         // TODO support hardware wallets
         // TODO consider supporting base64 and/or multibase parsing
@@ -60,13 +223,50 @@ impl Config
         info!("homenode public key: {}", signer.public_key());
         info!("homenode profile id: {}", signer.profile_id());
 
-        let listen_socket = cli.socket_addr
-            .to_socket_addrs().unwrap().next().expect("Failed to parse socket address");
+        let metrics_socket = cli.metrics_addr
+            .to_socket_addrs().unwrap().next().expect("Failed to parse metrics socket address");
 
-        Self{storage_path, signer, listen_socket}
+        Self{storage_path, event_storage_path, index_storage_path, redirect_storage_path, voucher_storage_path,
+             ban_storage_path, revocation_storage_path, admin_socket_path: cli.admin_socket_path, registration: cli.registration, session_limits,
+             session_policy: cli.session_policy, issue_invitation, shutdown_timeout, signer,
+             listen_addresses: cli.listen_addresses, public_addresses: cli.public_addresses, metrics_socket}
     }
 
     pub fn storage_path(&self) -> &str { &self.storage_path }
+    pub fn event_storage_path(&self) -> &str { &self.event_storage_path }
+    pub fn index_storage_path(&self) -> &str { &self.index_storage_path }
+    pub fn redirect_storage_path(&self) -> &str { &self.redirect_storage_path }
+    pub fn voucher_storage_path(&self) -> &str { &self.voucher_storage_path }
+    pub fn ban_storage_path(&self) -> &str { &self.ban_storage_path }
+    pub fn revocation_storage_path(&self) -> &str { &self.revocation_storage_path }
+    pub fn admin_socket_path(&self) -> &Path { &self.admin_socket_path }
+    pub fn registration(&self) -> RegistrationPolicy { self.registration }
+    pub fn session_limits(&self) -> SessionLimits { self.session_limits }
+    pub fn session_policy(&self) -> SessionPolicy { self.session_policy }
+    pub fn issue_invitation(&self) -> Option<Duration> { self.issue_invitation }
+    pub fn shutdown_timeout(&self) -> Duration { self.shutdown_timeout }
     pub fn signer(&self) -> Rc<Signer> { self.signer.clone() }
-    pub fn listen_socket(&self) -> &SocketAddr { &self.listen_socket }
+    pub fn listen_addresses(&self) -> &[ListenAddress] { &self.listen_addresses }
+    pub fn metrics_socket(&self) -> &SocketAddr { &self.metrics_socket }
+
+    /// Addresses to publish in the profile of the home so clients can find it,
+    /// the ones configured explicitly or all listen addresses that can be published otherwise
+    pub fn public_addresses(&self) -> Vec<Multiaddr>
+    {
+        if ! self.public_addresses.is_empty()
+            { return self.public_addresses.clone(); }
+        self.listen_addresses.iter().filter_map(ListenAddress::public_multiaddr).collect()
+    }
+
+    /// Directories of all local storages with a short name to show their usage
+    pub fn storage_paths(&self) -> Vec<(String, PathBuf)>
+    {
+        vec![ ("hosted-profiles", &self.storage_path), ("offline-events", &self.event_storage_path),
+              ("profile-index", &self.index_storage_path), ("redirects", &self.redirect_storage_path),
+              ("used-vouchers", &self.voucher_storage_path), ("ban-lists", &self.ban_storage_path),
+              ("revocations", &self.revocation_storage_path) ]
+            .into_iter()
+            .map( |(name, path)| ( name.to_owned(), PathBuf::from(path) ) )
+            .collect()
+    }
 }
@@ -7,12 +7,21 @@ extern crate log;
 extern crate mercury_home_protocol;
 extern crate mercury_storage;
 extern crate multiaddr;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate structopt;
+extern crate tokio_codec;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_uds;
 extern crate toml;
 
+pub mod admin;
 pub mod config;
+pub mod metrics;
 pub mod server;
 
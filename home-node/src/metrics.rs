@@ -0,0 +1,351 @@
+//! Metrics of a running home node and a health check of its storages.
+//!
+//! Both are served by a minimal HTTP listener meant to be reachable only locally or by a monitoring system:
+//! `/metrics` returns all metrics in the Prometheus text format,
+//! `/health` returns status 200 if all storage backends responded in time and 503 otherwise.
+
+use std::collections::BTreeMap;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Async, Future, Stream};
+use tokio_core::{net::TcpListener, reactor};
+use tokio_io::io::write_all;
+
+use mercury_storage::{async::KeyValueStore, error::StorageError};
+use server::HomeServer;
+
+
+
+/// Clients not sending a complete request within this time are disconnected
+const CFG_HTTP_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request line or header line accepted, including the line ending
+const CFG_HTTP_MAX_LINE_LENGTH: usize = 8 * 1024;
+/// Most headers accepted in a request
+const CFG_HTTP_MAX_HEADERS: usize = 100;
+
+
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MetricKind
+{
+    Counter,
+    Gauge,
+}
+
+impl MetricKind
+{
+    fn as_str(&self) -> &'static str
+    {
+        match *self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge   => "gauge",
+        }
+    }
+}
+
+
+#[derive(Clone, Copy, Debug)]
+pub struct Metric
+{
+    pub name:   &'static str,
+    pub help:   &'static str,
+    pub kind:   MetricKind,
+}
+
+
+pub const CONNECTIONS_ACCEPTED: Metric = Metric{ name: "mercury_home_connections_accepted_total",
+    help: "TCP connections accepted from clients", kind: MetricKind::Counter };
+pub const HANDSHAKES_FAILED: Metric = Metric{ name: "mercury_home_handshakes_failed_total",
+    help: "Client connections closed because their handshake failed", kind: MetricKind::Counter };
+pub const CONNECTIONS_OPEN: Metric = Metric{ name: "mercury_home_connections_open",
+    help: "Authenticated client connections currently served", kind: MetricKind::Gauge };
+pub const SESSIONS_OPENED: Metric = Metric{ name: "mercury_home_sessions_opened_total",
+    help: "Sessions opened by logging in", kind: MetricKind::Counter };
+pub const SESSIONS_LIVE: Metric = Metric{ name: "mercury_home_sessions_live",
+    help: "Sessions of logged in profiles currently alive", kind: MetricKind::Gauge };
+pub const EVENTS_PUSHED: Metric = Metric{ name: "mercury_home_events_pushed_total",
+    help: "Events sent to hosted profiles", kind: MetricKind::Counter };
+pub const EVENTS_QUEUED_OFFLINE: Metric = Metric{ name: "mercury_home_events_queued_offline_total",
+    help: "Events saved into the mailbox of a profile that was not logged in", kind: MetricKind::Counter };
+pub const EVENTS_BUFFERED: Metric = Metric{ name: "mercury_home_events_buffered",
+    help: "Events buffered in live sessions until their clients consume them", kind: MetricKind::Gauge };
+pub const BUFFER_OVERFLOWS: Metric = Metric{ name: "mercury_home_buffer_overflows_total",
+    help: "Items dropped, rejected or spilled to storage by full session buffers", kind: MetricKind::Counter };
+pub const CALLS_STARTED: Metric = Metric{ name: "mercury_home_calls_started_total",
+    help: "Calls started by hosted profiles", kind: MetricKind::Counter };
+pub const CALLS_ACTIVE: Metric = Metric{ name: "mercury_home_calls_active",
+    help: "Calls ringing or answered and not finished yet", kind: MetricKind::Gauge };
+pub const STORAGE_OPERATIONS: Metric = Metric{ name: "mercury_home_storage_operations_total",
+    help: "Operations completed by the storage backends", kind: MetricKind::Counter };
+pub const STORAGE_PENDING: Metric = Metric{ name: "mercury_home_storage_operations_pending",
+    help: "Operations started on the storage backends and not completed yet", kind: MetricKind::Gauge };
+pub const STORAGE_SECONDS: Metric = Metric{ name: "mercury_home_storage_seconds_total",
+    help: "Total time spent waiting for operations of the storage backends", kind: MetricKind::Counter };
+
+
+
+struct Family
+{
+    metric:     Metric,
+    samples:    BTreeMap<String, f64>, // values by their rendered label set
+}
+
+
+/// Current values of all metrics of the home.
+// NOTE storage futures must be Send, so the registry is shared by an Arc instead of an Rc
+#[derive(Default)]
+pub struct MetricsRegistry
+{
+    families: Mutex< BTreeMap<&'static str, Family> >,
+}
+
+
+impl MetricsRegistry
+{
+    pub fn inc(&self, metric: &Metric)
+        { self.add(metric, &[], 1.0) }
+
+
+    pub fn add(&self, metric: &Metric, labels: &[(&str, &str)], value: f64)
+        { self.update( metric, labels, |sample| *sample += value ) }
+
+
+    pub fn set(&self, metric: &Metric, labels: &[(&str, &str)], value: f64)
+        { self.update( metric, labels, |sample| *sample = value ) }
+
+
+    /// Current value of a metric with the given labels, 0 if it was never updated
+    pub fn value(&self, metric: &Metric, labels: &[(&str, &str)]) -> f64
+    {
+        let families = self.families.lock().expect("Metrics registry lock poisoned");
+        families.get(metric.name)
+            .and_then( |family| family.samples.get( &render_labels(labels) ) )
+            .cloned()
+            .unwrap_or(0.0)
+    }
+
+
+    fn update<F: FnOnce(&mut f64)>(&self, metric: &Metric, labels: &[(&str, &str)], update: F)
+    {
+        let mut families = self.families.lock().expect("Metrics registry lock poisoned");
+        let family = families.entry(metric.name)
+            .or_insert_with( || Family{ metric: *metric, samples: BTreeMap::new() } );
+        update( family.samples.entry( render_labels(labels) ).or_insert(0.0) );
+    }
+
+
+    /// All metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String
+    {
+        let families = self.families.lock().expect("Metrics registry lock poisoned");
+        let mut result = String::new();
+        for family in families.values()
+        {
+            result += &format!( "# HELP {} {}\n", family.metric.name, family.metric.help );
+            result += &format!( "# TYPE {} {}\n", family.metric.name, family.metric.kind.as_str() );
+            for (labels, value) in &family.samples
+                { result += &format!( "{}{} {}\n", family.metric.name, labels, value ); }
+        }
+        result
+    }
+}
+
+
+fn render_labels(labels: &[(&str, &str)]) -> String
+{
+    if labels.is_empty()
+        { return String::new(); }
+
+    let pairs = labels.iter()
+        .map( |&(name, value)| {
+            let escaped = value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+            format!("{}=\"{}\"", name, escaped)
+        } )
+        .collect::<Vec<_>>();
+    format!( "{{{}}}", pairs.join(",") )
+}
+
+
+
+/// Storage decorator measuring the number and duration of operations of the wrapped storage
+pub struct TimedStore<T>
+{
+    store:      T,
+    name:       &'static str,
+    registry:   Arc<MetricsRegistry>,
+}
+
+impl<T> TimedStore<T>
+{
+    pub fn new(name: &'static str, store: T, registry: Arc<MetricsRegistry>) -> Self
+        { Self{ store, name, registry } }
+
+
+    fn timed<I: 'static + Send>(&self, operation: &'static str, fut: Box< Future<Item=I, Error=StorageError> + Send >)
+        -> Box< Future<Item=I, Error=StorageError> + Send >
+    {
+        let registry = self.registry.clone();
+        let name = self.name;
+        let started = Instant::now();
+        let pending = PendingOperation::new( registry.clone() );
+        let timed_fut = fut.then( move |result| {
+            drop(pending);
+            let elapsed = started.elapsed();
+            let labels = [ ("storage", name), ("operation", operation) ];
+            registry.add(&STORAGE_OPERATIONS, &labels, 1.0);
+            registry.add( &STORAGE_SECONDS, &labels,
+                elapsed.as_secs() as f64 + f64::from( elapsed.subsec_nanos() ) / 1e9 );
+            result
+        } );
+        Box::new(timed_fut)
+    }
+}
+
+/// Counts a storage operation as pending until it completes or its future is dropped, e.g. by a timeout
+struct PendingOperation
+{
+    registry: Arc<MetricsRegistry>,
+}
+
+impl PendingOperation
+{
+    fn new(registry: Arc<MetricsRegistry>) -> Self
+    {
+        registry.add(&STORAGE_PENDING, &[], 1.0);
+        Self{ registry }
+    }
+}
+
+impl Drop for PendingOperation
+{
+    fn drop(&mut self)
+        { self.registry.add(&STORAGE_PENDING, &[], -1.0); }
+}
+
+impl<K, V, T> KeyValueStore<K, V> for TimedStore<T>
+    where V: 'static + Send,
+          T: KeyValueStore<K, V>
+{
+    fn set(&mut self, key: K, value: V) -> Box< Future<Item=(), Error=StorageError> + Send >
+    {
+        let set_fut = self.store.set(key, value);
+        self.timed("set", set_fut)
+    }
+
+    fn get(&self, key: K) -> Box< Future<Item=V, Error=StorageError> + Send >
+        { self.timed( "get", self.store.get(key) ) }
+
+    fn clear_local(&mut self, key: K) -> Box< Future<Item=(), Error=StorageError> + Send >
+    {
+        let clear_fut = self.store.clear_local(key);
+        self.timed("clear_local", clear_fut)
+    }
+}
+
+
+
+/// Serve `/metrics` and `/health` of the home to HTTP clients, one request per connection
+pub fn serve_http(server: Rc<HomeServer>, addr: &SocketAddr, handle: &reactor::Handle)
+    -> Result< Box< Future<Item=(), Error=io::Error> >, io::Error >
+{
+    let listener = TcpListener::bind(addr, handle)?;
+    debug!("Serving metrics on {}", addr);
+
+    let handle = handle.clone();
+    let serve_fut = listener.incoming().for_each( move |(socket, _addr)|
+    {
+        let server = server.clone();
+        let timeout_fut = future::result( reactor::Timeout::new(CFG_HTTP_REQUEST_TIMEOUT, &handle) )
+            .flatten()
+            .and_then( |()| Err::<(BufReader<_>, String), _>( io::Error::new(io::ErrorKind::TimedOut, "incomplete request") ) );
+        let respond_fut = read_request_path( BufReader::new(socket) )
+            .select(timeout_fut)
+            .map( |(request, _timeout)| request )
+            .map_err( |(e, _request)| e )
+            .and_then( move |(reader, path)| {
+                let response_fut = match path.as_str() {
+                    "/metrics" => Box::new( future::ok( ( "200 OK", server.render_metrics() ) ) )
+                        as Box< Future<Item=(&'static str, String), Error=io::Error> >,
+                    "/health" => Box::new( server.check_storage().then( |probes_res| {
+                        let probes = probes_res.unwrap_or_default();
+                        let status = if probes.iter().all( |&(_name, healthy)| healthy ) { "200 OK" }
+                                     else { "503 Service Unavailable" };
+                        let body = probes.iter()
+                            .map( |&(name, healthy)| format!( "{} {}\n", name, if healthy { "ok" } else { "unavailable" } ) )
+                            .collect::<String>();
+                        Ok( (status, body) )
+                    } ) ),
+                    _ => Box::new( future::ok( ( "404 Not Found", "Not found\n".to_owned() ) ) ),
+                };
+                response_fut.and_then( move |(status, body)| {
+                    let response = format!( "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                             Content-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body );
+                    write_all( reader.into_inner(), response.into_bytes() )
+                } )
+            } )
+            .map( |_written| () )
+            .map_err( |e| debug!("Failed to serve metrics request: {}", e) );
+        handle.spawn(respond_fut);
+        Ok( () )
+    } );
+    Ok( Box::new(serve_fut) )
+}
+
+
+/// Read the request line and all headers of an HTTP request, resolves with the requested path
+fn read_request_path<R: 'static + io::BufRead + ::tokio_io::AsyncRead>(reader: R)
+    -> Box< Future<Item=(R, String), Error=io::Error> >
+{
+    let request_fut = read_line(reader)
+        .and_then( |(reader, request_line)| {
+            let path = String::from_utf8_lossy(&request_line).split_whitespace()
+                .nth(1).unwrap_or_default().to_owned();
+            // NOTE unread headers would make closing the connection reset it before the client reads the response
+            future::loop_fn( (reader, 0), |(reader, header_count)| read_line(reader)
+                .and_then( move |(reader, line)| {
+                    if line.is_empty() || line == b"\r\n" || line == b"\n" { Ok( future::Loop::Break(reader) ) }
+                    else if header_count >= CFG_HTTP_MAX_HEADERS
+                        { Err( io::Error::new(io::ErrorKind::InvalidData, "too many headers") ) }
+                    else { Ok( future::Loop::Continue( (reader, header_count + 1) ) ) }
+                } ) )
+                .map( move |reader| (reader, path) )
+        } );
+    Box::new(request_fut)
+}
+
+
+/// Read a line including its ending like tokio_io::io::read_until(), failing for lines longer than
+/// CFG_HTTP_MAX_LINE_LENGTH. Resolves with an empty line at the end of the stream.
+fn read_line<R: 'static + io::BufRead>(reader: R) -> Box< Future<Item=(R, Vec<u8>), Error=io::Error> >
+{
+    let mut state = Some( ( reader, Vec::new() ) );
+    let line_fut = future::poll_fn( move || {
+        {
+            let &mut (ref mut reader, ref mut line) = state.as_mut().expect("line polled after it was read");
+            loop {
+                let (used, complete) = {
+                    let available = match reader.fill_buf() {
+                        Ok(available) => available,
+                        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                        Err(e) => return Err(e),
+                    };
+                    match available.iter().position( |&byte| byte == b'\n' ) {
+                        Some(index) => { line.extend_from_slice( &available[..index + 1] ); (index + 1, true) },
+                        None => { line.extend_from_slice(available); ( available.len(), available.is_empty() ) },
+                    }
+                };
+                reader.consume(used);
+                if line.len() > CFG_HTTP_MAX_LINE_LENGTH
+                    { return Err( io::Error::new(io::ErrorKind::InvalidData, "line too long") ); }
+                if complete
+                    { break; }
+            }
+        }
+        Ok( Async::Ready( state.take().unwrap() ) )
+    } );
+    Box::new(line_fut)
+}
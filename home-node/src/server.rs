@@ -1,20 +1,149 @@
-use std::{cell::RefCell, rc::Rc, rc::Weak};
-use std::collections::HashMap;
-use std::time::Duration;
+use std::{cell::Cell, cell::RefCell, rc::Rc, rc::Weak};
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use failure::Fail;
-use futures::{future, stream, Future, Sink};
+use futures::{future, stream, Async, Future, Poll, Sink, Stream};
 use futures::sync::{mpsc, oneshot};
 use tokio_core::reactor::{self, Timeout};
 
 use mercury_home_protocol::*;
 use mercury_home_protocol::error::*;
-use mercury_storage::{async::KeyValueStore, error::StorageError};
+use mercury_storage::{async::{optional, KeyValueStore}, error::StorageError};
 
+use metrics::{self, MetricsRegistry};
 
 
+
+// TODO this should come from user configuration with a reasonable default value close to this
+/// Upper limit of the ring timeout requested by callers
+const CFG_CALL_MAX_RING_TIMEOUT: Duration = Duration::from_secs(60);
 // TODO this should come from user configuration with a reasonable default value close to this
-const CFG_CALL_ANSWER_TIMEOUT: Duration = Duration::from_secs(30);
+const CFG_REDIRECT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// Storage backends not responding within this time are reported unhealthy
+const CFG_HEALTH_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often shutdown checks whether pending calls and storage operations are finished
+const CFG_DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Maximum number of profiles returned by a single `list()` call, clients have to page through the rest
+const CFG_LIST_MAX_PAGE_SIZE: u32 = 100;
+/// How often checked in apps are checked whether they still listen to their calls stream
+const CFG_PRESENCE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+
+/// Whether anyone can register on this home or only personas holding an invitation issued by this home
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegistrationPolicy
+{
+    Open,
+    InviteOnly,
+}
+
+impl FromStr for RegistrationPolicy
+{
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err>
+    {
+        match src {
+            "open"          => Ok(RegistrationPolicy::Open),
+            "invite-only"   => Ok(RegistrationPolicy::InviteOnly),
+            _ => Err( format!("Unknown registration policy '{}', expected 'open' or 'invite-only'", src) ),
+        }
+    }
+}
+
+
+/// Whether a profile can be logged in with a single session at a time or from several devices concurrently
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SessionPolicy
+{
+    /// A new login closes the previous session of the profile, notifying it on its streams
+    Single,
+    /// All sessions stay open, events are pushed to all of them and calls ring all of them until one answers
+    MultiDevice,
+}
+
+impl FromStr for SessionPolicy
+{
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err>
+    {
+        match src {
+            "single"        => Ok(SessionPolicy::Single),
+            "multi-device"  => Ok(SessionPolicy::MultiDevice),
+            _ => Err( format!("Unknown session policy '{}', expected 'single' or 'multi-device'", src) ),
+        }
+    }
+}
+
+
+/// What to do with an item pushed to a session buffer that is already full
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy
+{
+    /// Drop the oldest buffered item to make room for the new one
+    DropOldest,
+    /// Refuse the new item, the peer pushing it receives an error
+    RejectNew,
+    /// Save events into the mailbox of the profile, delivered on its next events() call.
+    /// Calls carry live channels and cannot be saved, so they are refused.
+    SpillToStorage,
+}
+
+impl FromStr for OverflowPolicy
+{
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err>
+    {
+        match src {
+            "drop-oldest"       => Ok(OverflowPolicy::DropOldest),
+            "reject-new"        => Ok(OverflowPolicy::RejectNew),
+            "spill-to-storage"  => Ok(OverflowPolicy::SpillToStorage),
+            _ => Err( format!("Unknown overflow policy '{}', expected 'drop-oldest', 'reject-new' or 'spill-to-storage'", src) ),
+        }
+    }
+}
+
+
+/// Size limits of the event and call streams of sessions
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SessionLimits
+{
+    /// Number of items the channel of a listening client holds before further items are buffered
+    pub channel_capacity:   usize,
+    /// Maximum number of events buffered for a session
+    pub event_buffer_size:  usize,
+    /// Maximum number of calls buffered for each app of a session
+    pub call_buffer_size:   usize,
+    pub overflow:           OverflowPolicy,
+}
+
+impl Default for SessionLimits
+{
+    fn default() -> Self
+    {
+        Self{ channel_capacity: CHANNEL_CAPACITY, event_buffer_size: 256, call_buffer_size: 16,
+              overflow: OverflowPolicy::RejectNew }
+    }
+}
+
+
+/// Number of items that session buffers of a home could not deliver because they were full
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct BufferMetrics
+{
+    pub dropped_events:     u64,
+    pub dropped_calls:      u64,
+    pub rejected_events:    u64,
+    pub rejected_calls:     u64,
+    pub spilled_events:     u64,
+}
+
 
 
 pub struct HomeServer
@@ -23,7 +152,33 @@ pub struct HomeServer
     validator:          Rc<Validator>,
     public_profile_dht: Rc<RefCell< KeyValueStore<ProfileId, Profile> >>,
     hosted_profile_db:  Rc<RefCell< KeyValueStore<ProfileId, OwnProfile> >>,
-    sessions:           Rc<RefCell< HashMap<ProfileId, Weak<HomeSessionServer>> >>,
+    /// Events received for hosted profiles without a live session, delivered on their next login
+    offline_events_db:  Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileEvent>> >>,
+    /// Ids of all hosted profiles stored under the id of this home, needed for listing profiles
+    profile_index_db:   Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileId>> >>,
+    /// Forwarding records of profiles moved away from this home, served until they expire
+    redirect_db:        Rc<RefCell< KeyValueStore<ProfileId, ProfileRedirect> >>,
+    /// Vouchers of invitations already used for registration, mapped to the profile that used them
+    used_vouchers_db:   Rc<RefCell< KeyValueStore<String, ProfileId> >>,
+    /// Profiles banned by hosted profiles, stored under the id of the banning profile
+    ban_db:             Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileId>> >>,
+    /// Revoked relations of hosted profiles, stored under the id of the hosted party
+    revocation_db:      Rc<RefCell< KeyValueStore<ProfileId, Vec<RelationRevocation>> >>,
+    registration:       RegistrationPolicy,
+    limits:             SessionLimits,
+    metrics:            RefCell<BufferMetrics>,
+    registry:           Arc<MetricsRegistry>,
+    session_policy:     SessionPolicy,
+    /// Sessions of logged in profiles, there is at most one per profile unless multiple devices are allowed
+    sessions:           Rc<RefCell< HashMap<ProfileId, Vec<Weak<HomeSessionServer>>> >>,
+    /// Live channels of profiles watching a hosted profile, stored under the id of the watched profile
+    subscriptions:      Rc<RefCell< HashMap<ProfileId, Vec<Subscription<ProfileEvent>>> >>,
+    /// Live channels of contacts watching the presence of an app of a hosted profile
+    presence_subscriptions: Rc<RefCell< HashMap<(ProfileId, ApplicationId), Vec<Subscription<Option<AppMessageFrame>>>> >>,
+    /// Calls to hosted profiles that are still ringing or already answered
+    calls:              Rc<RefCell< HashMap<CallId, CallEntry> >>,
+    /// Set on shutdown, new logins and registrations are refused from then on
+    closing:            Cell<bool>,
 }
 
 impl HomeServer
@@ -31,10 +186,624 @@ impl HomeServer
     pub fn new(handle: &reactor::Handle,
                validator: Rc<Validator>,
                public_dht: Rc<RefCell< KeyValueStore<ProfileId, Profile> >>,
-               private_db: Rc<RefCell< KeyValueStore<ProfileId, OwnProfile> >>) -> Self
+               private_db: Rc<RefCell< KeyValueStore<ProfileId, OwnProfile> >>,
+               offline_events_db: Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileEvent>> >>,
+               profile_index_db: Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileId>> >>,
+               redirect_db: Rc<RefCell< KeyValueStore<ProfileId, ProfileRedirect> >>,
+               used_vouchers_db: Rc<RefCell< KeyValueStore<String, ProfileId> >>,
+               ban_db: Rc<RefCell< KeyValueStore<ProfileId, Vec<ProfileId>> >>,
+               revocation_db: Rc<RefCell< KeyValueStore<ProfileId, Vec<RelationRevocation>> >>,
+               registration: RegistrationPolicy,
+               limits: SessionLimits,
+               session_policy: SessionPolicy,
+               registry: Arc<MetricsRegistry>) -> Self
     { Self{ handle: handle.clone(), validator: validator,
-            public_profile_dht: public_dht, hosted_profile_db: private_db,
-            sessions: Rc::new( RefCell::new( HashMap::new() ) ) } }
+            public_profile_dht: public_dht, hosted_profile_db: private_db, offline_events_db, profile_index_db,
+            redirect_db, used_vouchers_db, ban_db, revocation_db, registration,
+            limits, metrics: RefCell::new( BufferMetrics::default() ), registry, session_policy,
+            sessions: Rc::new( RefCell::new( HashMap::new() ) ), subscriptions: Rc::new( RefCell::new( HashMap::new() ) ),
+            presence_subscriptions: Rc::new( RefCell::new( HashMap::new() ) ),
+            calls: Rc::new( RefCell::new( HashMap::new() ) ), closing: Cell::new(false) } }
+
+
+    /// Items dropped, rejected or spilled to storage by full session buffers since the server started
+    pub fn buffer_metrics(&self) -> BufferMetrics
+        { *self.metrics.borrow() }
+
+
+    pub fn metrics_registry(&self) -> Arc<MetricsRegistry>
+        { self.registry.clone() }
+
+
+    /// All metrics in the Prometheus text format, gauges of the current state are updated first
+    pub fn render_metrics(&self) -> String
+    {
+        let sessions = self.sessions.borrow().values()
+            .flat_map( |sessions| sessions.iter() )
+            .filter_map( |weak| weak.upgrade() )
+            .collect::<Vec<_>>();
+        let buffered_events = sessions.iter().map( |session| session.events.borrow().len() ).sum::<usize>();
+        self.registry.set( &metrics::SESSIONS_LIVE, &[], sessions.len() as f64 );
+        self.registry.set( &metrics::EVENTS_BUFFERED, &[], buffered_events as f64 );
+        self.registry.set( &metrics::CALLS_ACTIVE, &[], self.calls.borrow().len() as f64 );
+
+        let buffers = self.buffer_metrics();
+        let overflows = [ ("event", "dropped", buffers.dropped_events), ("event", "rejected", buffers.rejected_events),
+                          ("event", "spilled", buffers.spilled_events), ("call", "dropped", buffers.dropped_calls),
+                          ("call", "rejected", buffers.rejected_calls) ];
+        for &(item, action, count) in overflows.iter()
+            { self.registry.set( &metrics::BUFFER_OVERFLOWS, &[ ("item", item), ("action", action) ], count as f64 ); }
+
+        self.registry.render()
+    }
+
+
+    /// Probe all storage backends by reading a key that is never written,
+    /// resolves with the name of each storage and whether it responded in time without failing.
+    // NOTE the key is missing, so only errors other than StorageError::InvalidKey mean a backend is unavailable
+    pub fn check_storage(&self) -> Box< Future<Item=Vec<(&'static str, bool)>, Error=()> >
+    {
+        let probe_id = ProfileId( b"mercury-health-probe".to_vec() );
+        let probes: Vec<(&'static str, Box< Future<Item=(), Error=StorageError> >)> = vec![
+            ( "public-profiles", Box::new( self.public_profile_dht.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "hosted-profiles", Box::new( self.hosted_profile_db.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "offline-events", Box::new( self.offline_events_db.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "profile-index", Box::new( self.profile_index_db.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "redirects", Box::new( self.redirect_db.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "used-vouchers", Box::new( self.used_vouchers_db.borrow().get( String::from(&probe_id) ).then(optional).map( |_| () ) ) ),
+            ( "ban-lists", Box::new( self.ban_db.borrow().get( probe_id.clone() ).then(optional).map( |_| () ) ) ),
+            ( "revocations", Box::new( self.revocation_db.borrow().get(probe_id).then(optional).map( |_| () ) ) ),
+        ];
+
+        let handle = self.handle.clone();
+        let checks = probes.into_iter().map( move |(name, probe_fut)|
+        {
+            let responded_fut = probe_fut.then( move |probe_res| {
+                if let Err(ref e) = probe_res
+                    { warn!("Storage {} failed to respond to health probe: {}", name, e); }
+                Ok::<_,()>( probe_res.is_ok() )
+            } );
+            let check_fut = match Timeout::new(CFG_HEALTH_PROBE_TIMEOUT, &handle) {
+                Ok(timeout) => Box::new( responded_fut
+                    .select( timeout.then( |_res| Ok(false) ) )
+                    .map( |(healthy,_pending)| healthy )
+                    .map_err( |(e,_pending)| e ) ) as Box< Future<Item=bool, Error=()> >,
+                Err(e) => {
+                    warn!("Failed to set up timeout of storage probe: {}", e);
+                    Box::new( future::ok(false) )
+                },
+            };
+            check_fut.map( move |healthy| (name, healthy) )
+        } ).collect::<Vec<_>>();
+        Box::new( future::join_all(checks) )
+    }
+
+
+    /// Ids of all profiles registered on the home with the given id
+    pub fn hosted_profiles(&self, home_id: &ProfileId) -> Box< Future<Item=Vec<ProfileId>, Error=Error> >
+    {
+        let profiles_fut = self.hosted_profile_ids(home_id)
+            .map_err( |e| e.context(ErrorKind::StorageFailed).into() );
+        Box::new(profiles_fut)
+    }
+
+
+    /// Logged in profiles with the applications checked in on each of their live sessions
+    pub fn live_session_apps(&self) -> Vec<( ProfileId, Vec<Vec<ApplicationId>> )>
+    {
+        let profile_ids = self.sessions.borrow().keys().cloned().collect::<Vec<_>>();
+        profile_ids.into_iter()
+            .map( |profile_id| {
+                let apps = self.live_sessions(&profile_id).iter()
+                    .map( |session| session.apps.borrow().keys().cloned().collect() )
+                    .collect::<Vec<_>>();
+                (profile_id, apps)
+            } )
+            .filter( |&(_, ref apps)| ! apps.is_empty() )
+            .collect()
+    }
+
+
+    /// Events waiting in the mailbox of a profile until it logs in
+    pub fn queued_events(&self, profile_id: &ProfileId) -> Box< Future<Item=Vec<ProfileEvent>, Error=Error> >
+    {
+        let events_fut = load_list( &self.offline_events_db, profile_id )
+            .map_err( |e| e.context(ErrorKind::StorageFailed).into() );
+        Box::new(events_fut)
+    }
+
+
+    /// Close all sessions of a profile, returns the number of sessions closed
+    pub fn logout(&self, profile_id: &ProfileId) -> usize
+        { self.close_sessions(profile_id, HomeSessionServer::LOGGED_OUT_NOTICE) }
+
+
+    /// Stop serving clients: refuse new logins and registrations, close all sessions with a notice
+    /// saving their undelivered events, then wait until pending calls and storage operations finish.
+    /// Resolves when everything is finished or the deadline passed.
+    pub fn shutdown(server: Rc<HomeServer>, deadline: Duration) -> Box< Future<Item=(), Error=()> >
+    {
+        server.closing.set(true);
+        let profile_ids = server.sessions.borrow().keys().cloned().collect::<Vec<_>>();
+        let closed_count = profile_ids.iter()
+            .map( |profile_id| server.close_sessions(profile_id, HomeSessionServer::SHUTDOWN_NOTICE) )
+            .sum::<usize>();
+        debug!("Closed {} sessions for shutdown, waiting for pending operations", closed_count);
+
+        let started = Instant::now();
+        // NOTE the first check is delayed so spawned tasks, e.g. saving undelivered events, can start
+        let drain_fut = future::loop_fn( server, move |server| {
+            let handle = server.handle.clone();
+            future::result( Timeout::new(CFG_DRAIN_POLL_INTERVAL, &handle) )
+                .flatten()
+                .map_err( |e| warn!("Failed to wait for pending operations: {}", e) )
+                .map( move |()| {
+                    let (calls, storage_ops) = server.pending_operations();
+                    if calls == 0 && storage_ops == 0 {
+                        debug!("All pending operations finished");
+                        future::Loop::Break( () )
+                    } else if started.elapsed() >= deadline {
+                        warn!("Shutdown deadline passed with {} calls and {} storage operations pending", calls, storage_ops);
+                        future::Loop::Break( () )
+                    }
+                    else { future::Loop::Continue(server) }
+                } )
+        } );
+        Box::new(drain_fut)
+    }
+
+
+    /// Number of calls not finished yet and of storage operations not completed yet
+    // NOTE storage operations are counted by storages wrapped into a metrics::TimedStore only
+    fn pending_operations(&self) -> (usize, usize)
+    {
+        let storage_ops = self.registry.value(&metrics::STORAGE_PENDING, &[]);
+        ( self.calls.borrow().len(), storage_ops.max(0.0) as usize )
+    }
+
+
+    /// Close all sessions of a profile with a notice, returns the number of sessions closed
+    fn close_sessions(&self, profile_id: &ProfileId, notice: &'static str) -> usize
+    {
+        let sessions = self.live_sessions(profile_id);
+        self.sessions.borrow_mut().remove(profile_id);
+        // NOTE the map must not be borrowed here, evicting and dropping sessions access it
+        for session in &sessions
+            { session.evict(notice); }
+        sessions.len()
+    }
+
+
+    /// Remove a profile hosted on the home with the given id without its consent,
+    /// closing its sessions and deleting all data stored for it
+    pub fn force_unregister(server: Rc<HomeServer>, home_id: ProfileId, profile_id: ProfileId)
+        -> Box< Future<Item=(), Error=Error> >
+    {
+        let hosted_fut = server.hosted_profile_db.borrow().get( profile_id.clone() );
+        let unreg_fut = hosted_fut
+            .map_err( |e| e.context(ErrorKind::PeerNotHostedHere).into() )
+            .and_then( move |_own_profile| {
+                debug!("Unregistering profile {} by the administrator of the home", profile_id);
+                server.logout(&profile_id);
+                server.subscriptions.borrow_mut().remove(&profile_id);
+                server.remove_profile_data(home_id, profile_id)
+                    .map_err( |e| e.context(ErrorKind::UnregisterFailed).into() )
+            } );
+        Box::new(unreg_fut)
+    }
+
+
+    /// Publish the profile of the home itself with the addresses it serves clients on.
+    /// A new version is signed only if the addresses changed since the last publication known.
+    /// An empty list is refused, it would make the home unreachable for clients knowing only its profile.
+    pub fn publish_home_profile(server: Rc<HomeServer>, signer: Rc<Signer>, addrs: Vec<Multiaddr>)
+        -> Box< Future<Item=Profile, Error=Error> >
+    {
+        if addrs.is_empty()
+            { return Box::new( future::err( ErrorKind::ProfileUpdateFailed.into() ) ); }
+
+        let home_id = signer.profile_id().to_owned();
+        let known_fut = server.public_profile_dht.borrow().get(home_id);
+        let publish_fut = known_fut
+            // NOTE a missing key simply means that the home was not published yet
+            .then(optional)
+            .map_err( |e| Error::from( e.context(ErrorKind::StorageFailed) ) )
+            .and_then( move |known_opt| {
+                let mut profile = known_opt.unwrap_or_else( || Profile::new( signer.profile_id(), signer.public_key(),
+                    &ProfileFacet::Home( HomeFacet{ addrs: Vec::new(), data: Vec::new() } ) ) );
+                let unchanged = match profile.facet {
+                    ProfileFacet::Home(ref facet) => facet.addrs == addrs && profile.is_signed(),
+                    _ => false,
+                };
+                if unchanged
+                    { return Box::new( future::ok(profile) ) as Box< Future<Item=_, Error=Error> >; }
+
+                debug!("Publishing addresses {:?} in the profile of the home", addrs);
+                let data = match profile.facet {
+                    ProfileFacet::Home(ref mut facet) => ::std::mem::replace( &mut facet.data, Vec::new() ),
+                    _ => Vec::new(),
+                };
+                profile.facet = ProfileFacet::Home( HomeFacet{ addrs, data } );
+                // NOTE the last publication is not known after a restart if the distributed storage is not persistent,
+                //      so the version is derived from the current time to be newer than any version published before
+                profile.version = ::std::cmp::max( profile.version, unix_timestamp_millis().saturating_sub(1) );
+                profile.sign_next_version(&*signer);
+                Box::new( server.publish_profile( profile.clone() ).map( move |()| profile ) )
+            } );
+        Box::new(publish_fut)
+    }
+
+
+    fn live_sessions(&self, profile_id: &ProfileId) -> Vec<Rc<HomeSessionServer>>
+    {
+        self.sessions.borrow().get(profile_id)
+            .map( |sessions| sessions.iter().filter_map( |weak| weak.upgrade() ).collect() )
+            .unwrap_or_default()
+    }
+
+
+    /// Register a new session of a logged in profile, evicting its other sessions unless multiple devices are allowed
+    fn add_session(&self, profile_id: ProfileId, session: &Rc<HomeSessionServer>)
+    {
+        let evicted = {
+            let mut sessions = self.sessions.borrow_mut();
+            let profile_sessions = sessions.entry(profile_id).or_insert_with(Vec::new);
+            let evicted = match self.session_policy {
+                SessionPolicy::Single => profile_sessions.drain(..).filter_map( |weak| weak.upgrade() ).collect(),
+                SessionPolicy::MultiDevice => {
+                    profile_sessions.retain( |weak| weak.upgrade().is_some() );
+                    Vec::new()
+                },
+            };
+            profile_sessions.push( Rc::downgrade(session) );
+            evicted
+        };
+
+        // NOTE the map must not be borrowed here, evicting and dropping sessions access it
+        for old_session in evicted
+            { old_session.evict(HomeSessionServer::EVICTED_NOTICE); }
+    }
+
+
+    /// Forget sessions of a profile that were already dropped
+    fn remove_dropped_sessions(&self, profile_id: &ProfileId)
+    {
+        let mut sessions = self.sessions.borrow_mut();
+        let all_dropped = match sessions.get_mut(profile_id) {
+            Some(profile_sessions) => {
+                profile_sessions.retain( |weak| weak.upgrade().is_some() );
+                profile_sessions.is_empty()
+            },
+            None => false,
+        };
+        if all_dropped
+            { sessions.remove(profile_id); }
+    }
+
+
+    /// Presence of an app on any device of a profile
+    fn presence_of(&self, profile_id: &ProfileId, app: &ApplicationId) -> Option<AppMessageFrame>
+    {
+        self.live_sessions(profile_id).iter()
+            .filter_map( |session| session.presence(app) )
+            .next()
+    }
+
+
+    // NOTE read-modify-write of the mailbox is not atomic, but the reactor is single-threaded
+    //      and all our storages resolve get() immediately, so no events can be lost in between
+    fn enqueue_offline_event(&self, to_profile: ProfileId, event: ProfileEvent)
+        -> Box< Future<Item=(), Error=Error> >
+    {
+        debug!("Profile {} is offline, saving event into its mailbox", to_profile);
+        self.registry.inc(&metrics::EVENTS_QUEUED_OFFLINE);
+        let mailbox = self.offline_events_db.clone();
+        let enqueue_fut = self.offline_events_db.borrow().get( to_profile.clone() )
+            // NOTE a missing key simply means that the mailbox is empty
+            .then(optional)
+            .map( |events_opt| events_opt.unwrap_or_default() )
+            .and_then( move |mut events| {
+                events.push(event);
+                return mailbox.borrow_mut().set(to_profile, events);
+            } )
+            .map_err( |e| e.context(ErrorKind::FailedToPushEvent).into() );
+        Box::new(enqueue_fut)
+    }
+
+
+    /// Returns the redirect of a profile moved away from here, or None if it did not move or its redirect already expired
+    fn active_redirect(&self, profile_id: &ProfileId) -> Box< Future<Item=Option<ProfileRedirect>, Error=Error> >
+    {
+        let redirect_db = self.redirect_db.clone();
+        let expired_id = profile_id.to_owned();
+        let redirect_fut = self.redirect_db.borrow().get( profile_id.to_owned() )
+            .then( move |get_res| {
+                match get_res {
+                    Ok(ref redirect) if redirect.is_expired() => {
+                        debug!("Redirect of profile {} expired, removing it", expired_id);
+                        let clear_fut = redirect_db.borrow_mut().clear_local(expired_id)
+                            .map( |()| None )
+                            .map_err( |e| e.context(ErrorKind::StorageFailed).into() );
+                        Box::new(clear_fut) as Box< Future<Item=_, Error=Error> >
+                    },
+                    Ok(redirect) => Box::new( future::ok( Some(redirect) ) ),
+                    // NOTE a missing key simply means that the profile did not move
+                    Err(StorageError::InvalidKey) => Box::new( future::ok(None) ),
+                    Err(e) => Box::new( future::err( e.context(ErrorKind::StorageFailed).into() ) ),
+                }
+            } );
+        Box::new(redirect_fut)
+    }
+
+
+    fn ensure_not_moved(&self, profile_id: &ProfileId) -> Box< Future<Item=(), Error=Error> >
+    {
+        let check_fut = self.active_redirect(profile_id)
+            .and_then( |redirect_opt| match redirect_opt {
+                Some(_redirect) => Err( ErrorKind::ProfileMoved.into() ),
+                None => Ok( () ),
+            } );
+        Box::new(check_fut)
+    }
+
+
+    /// Store a profile document signed by its owner in the distributed storage.
+    /// Unsigned documents and rollbacks to an older version than the one already stored are refused.
+    fn publish_profile(&self, profile: Profile) -> Box< Future<Item=(), Error=Error> >
+    {
+        if let Err(e) = profile.validate(&*self.validator)
+            { return Box::new( future::err(e) ) }
+
+        let dht = self.public_profile_dht.clone();
+        let publish_fut = self.public_profile_dht.borrow().get( profile.id.clone() )
+            // NOTE a missing key simply means that the profile was not published yet
+            //      but any other failure must not let an older version through unchecked
+            .then(optional)
+            .map_err( |e| Error::from( e.context(ErrorKind::StorageFailed) ) )
+            .and_then( move |known_opt| {
+                if let Some(known) = known_opt
+                    { profile.ensure_not_older_than(&known)?; }
+                Ok(profile)
+            } )
+            .and_then( move |profile| dht.borrow_mut().set( profile.id.clone(), profile )
+                .map_err( |e| e.context(ErrorKind::StorageFailed).into() ) );
+        Box::new(publish_fut)
+    }
+
+
+    /// Push a new version of a hosted profile to all its subscribers
+    fn notify_subscribers(&self, profile: &Profile)
+    {
+        debug!("Notifying subscribers about version {} of profile {}", profile.version, profile.id);
+        notify_all( &self.subscriptions, &profile.id, ProfileEvent::ProfileUpdated( profile.to_owned() ) );
+    }
+
+
+    /// Stop pushing updates and presence of a hosted profile to a contact it banned
+    fn drop_banned_subscriptions(&self, banner_id: &ProfileId, banned_id: &ProfileId)
+    {
+        debug!("Dropping subscriptions of {} to {}", banned_id, banner_id);
+        drop_subscriptions( &self.subscriptions, |profile_id, subscription|
+            profile_id == banner_id && subscription.subscriber == *banned_id );
+        drop_subscriptions( &self.presence_subscriptions, |&(ref profile_id, _), subscription|
+            profile_id == banner_id && subscription.subscriber == *banned_id );
+    }
+
+
+    /// Stop pushing updates and presence to subscribers that were allowed only by a revoked relation
+    fn drop_revoked_subscriptions(&self, relation: &RelationProof)
+    {
+        drop_subscriptions( &self.subscriptions, |_profile_id, subscription| subscription.relation == *relation );
+        drop_subscriptions( &self.presence_subscriptions, |_key, subscription| subscription.relation == *relation );
+    }
+
+
+    /// Forward messages of a call to one of its parties through the returned sink until the call is finished.
+    /// Closing one direction, e.g. by dropping a channel, ends the incoming stream of the other party.
+    /// An answered call ends when both directions are closed.
+    fn forward_call_messages(&self, id: CallId, to_party: AppMsgSink) -> AppMsgSink
+    {
+        let (sender, receiver) = mpsc::channel(self.limits.channel_capacity);
+        let (hangup_send, hangup_recv) = oneshot::channel();
+        // NOTE if the call is already finished, hangup_recv is cancelled and nothing is forwarded
+        if let Some(entry) = self.calls.borrow_mut().get_mut(&id) {
+            entry.hangups.push(hangup_send);
+            entry.open_channels += 1;
+        }
+
+        let calls = self.calls.clone();
+        let forward_fut = receiver.forward( to_party.sink_map_err( |_e| () ) )
+            .map( |_done| () )
+            .select( hangup_recv.then( |_res| Ok::<_,()>( () ) ) )
+            .then( move |_res| {
+                let ended = match calls.borrow_mut().get_mut(&id) {
+                    Some(entry) => {
+                        entry.open_channels -= 1;
+                        entry.open_channels == 0 && entry.state == CallState::Answered
+                    },
+                    None => false,
+                };
+                if ended { finish_call( &calls, &id, CallState::Ended ); }
+                Ok( () )
+            } );
+        self.handle.spawn(forward_fut);
+        sender
+    }
+
+
+    fn hosted_profile_ids(&self, home_id: &ProfileId) -> Box< Future<Item=Vec<ProfileId>, Error=StorageError> >
+        { load_list( &self.profile_index_db, home_id ) }
+
+
+    fn update_profile_index<F>(&self, home_id: ProfileId, modify: F)
+        -> Box< Future<Item=(), Error=StorageError> >
+        where F: FnOnce(&mut Vec<ProfileId>) + 'static
+        { update_list( self.profile_index_db.clone(), home_id, modify ) }
+
+
+    /// Delete the public profile, the private data and all lists stored for a profile leaving the home
+    fn remove_profile_data(&self, home_id: ProfileId, profile_id: ProfileId)
+        -> Box< Future<Item=(), Error=StorageError> >
+    {
+        // NOTE an empty mailbox has no stored entry, so a missing key is not an error when clearing it
+        let mailbox_fut = self.offline_events_db.borrow_mut().clear_local( profile_id.clone() )
+            .then(optional).map( |_| () );
+        let ban_list_fut = self.ban_db.borrow_mut().clear_local( profile_id.clone() )
+            .then(optional).map( |_| () );
+        let revocations_fut = self.revocation_db.borrow_mut().clear_local( profile_id.clone() )
+            .then(optional).map( |_| () );
+        let dht_fut = self.public_profile_dht.borrow_mut().clear_local( profile_id.clone() );
+        let local_fut = self.hosted_profile_db.borrow_mut().clear_local( profile_id.clone() );
+        let index_fut = self.update_profile_index( home_id, move |ids| ids.retain( |id| *id != profile_id ) );
+        let remove_fut = dht_fut
+            .and_then( |_| local_fut )
+            .and_then( |_| index_fut )
+            .and_then( |_| mailbox_fut )
+            .and_then( |_| ban_list_fut )
+            .and_then( |_| revocations_fut );
+        Box::new(remove_fut)
+    }
+
+
+    fn banned_profiles(&self, profile_id: &ProfileId) -> Box< Future<Item=Vec<ProfileId>, Error=StorageError> >
+        { load_list( &self.ban_db, profile_id ) }
+
+
+    fn update_ban_list<F>(&self, profile_id: ProfileId, modify: F)
+        -> Box< Future<Item=(), Error=StorageError> >
+        where F: FnOnce(&mut Vec<ProfileId>) + 'static
+        { update_list( self.ban_db.clone(), profile_id, modify ) }
+
+
+    fn ensure_not_banned(&self, profile_id: &ProfileId, requester_id: &ProfileId) -> Box< Future<Item=(), Error=Error> >
+    {
+        let requester_id = requester_id.to_owned();
+        let check_fut = self.banned_profiles(profile_id)
+            .map_err( |e| e.context(ErrorKind::StorageFailed).into() )
+            .and_then( move |banned| {
+                if banned.contains(&requester_id) { Err( ErrorKind::ProfileBanned.into() ) }
+                else { Ok( () ) }
+            } );
+        Box::new(check_fut)
+    }
+
+
+    fn store_revocation(&self, profile_id: ProfileId, revocation: RelationRevocation)
+        -> Box< Future<Item=(), Error=StorageError> >
+    {
+        update_list( self.revocation_db.clone(), profile_id, move |revocations|
+            if ! revocations.contains(&revocation) { revocations.push(revocation) } )
+    }
+
+
+    fn ensure_not_revoked(&self, profile_id: &ProfileId, relation: &RelationProof) -> Box< Future<Item=(), Error=Error> >
+    {
+        let relation = relation.to_owned();
+        let check_fut = load_list( &self.revocation_db, profile_id )
+            .map_err( |e| e.context(ErrorKind::StorageFailed).into() )
+            .and_then( move |revocations| {
+                if revocations.iter().any( |revocation| revocation.relation == relation )
+                    { Err( ErrorKind::RelationRevoked.into() ) }
+                else { Ok( () ) }
+            } );
+        Box::new(check_fut)
+    }
+}
+
+
+fn load_list<T: 'static>(db: &Rc<RefCell< KeyValueStore<ProfileId, Vec<T>> >>, key: &ProfileId)
+    -> Box< Future<Item=Vec<T>, Error=StorageError> >
+{
+    // NOTE a missing key simply means that the list is empty
+    let ids_fut = db.borrow().get( key.to_owned() )
+        .then(optional)
+        .map( |ids_opt| ids_opt.unwrap_or_default() );
+    Box::new(ids_fut)
+}
+
+
+/// Live channel of a contact watching a hosted profile, kept with the relation it was allowed by
+struct Subscription<T>
+{
+    subscriber: ProfileId,
+    relation:   RelationProof,
+    sender:     AsyncSink<T, String>,
+}
+
+
+/// Push `item` to all subscribers stored under `key`, dropping subscriptions closed meanwhile
+fn notify_all<K,T>(subscriptions: &RefCell< HashMap<K, Vec<Subscription<T>>> >, key: &K, item: T)
+    where K: Clone + Eq + Hash,
+          T: Clone
+{
+    let subscribers = match subscriptions.borrow_mut().remove(key) {
+        Some(subscribers) => subscribers,
+        None => return,
+    };
+
+    // NOTE a subscriber with a full channel misses this item, but it can query the current state anytime
+    let live_subscribers = subscribers.into_iter()
+        .filter_map( |mut subscription|
+            match subscription.sender.try_send( Ok( item.clone() ) ) {
+                Err(ref e) if e.is_disconnected() => None,
+                _ => Some(subscription),
+            } )
+        .collect::<Vec<_>>();
+    if ! live_subscribers.is_empty()
+        { subscriptions.borrow_mut().insert( key.to_owned(), live_subscribers ); }
+}
+
+
+/// Drop all subscriptions matching `drop`, their streams end as soon as their senders are dropped
+fn drop_subscriptions<K,T,F>(subscriptions: &RefCell< HashMap<K, Vec<Subscription<T>>> >, drop: F)
+    where K: Eq + Hash,
+          F: Fn(&K, &Subscription<T>) -> bool
+{
+    let mut subscriptions = subscriptions.borrow_mut();
+    for (key, subscribers) in subscriptions.iter_mut()
+        { subscribers.retain( |subscription| ! drop(key, subscription) ); }
+    subscriptions.retain( |_key, subscribers| ! subscribers.is_empty() );
+}
+
+
+/// State of a call, removed when the call is finished, i.e. rejected, cancelled or ended
+struct CallEntry
+{
+    caller:     ProfileId,
+    callee:     ProfileId,
+    state:      CallState,
+    /// Fired when the call is finished to stop waiting for an answer and forwarding messages
+    hangups:    Vec<oneshot::Sender<()>>,
+    /// Number of directions still forwarding messages
+    open_channels: usize,
+}
+
+
+/// Finish a call, notifying both parties by closing their channels of the call
+fn finish_call(calls: &RefCell< HashMap<CallId, CallEntry> >, id: &CallId, state: CallState)
+{
+    let entry = match calls.borrow_mut().remove(id) {
+        Some(entry) => entry,
+        None => return,
+    };
+
+    debug!("Call {:?} changed from {:?} to {:?}", id, entry.state, state);
+    for hangup in entry.hangups
+        { let _ = hangup.send( () ); } // NOTE the receiver might be already done, e.g. the call was answered
+}
+
+
+// NOTE same read-modify-write considerations apply as with HomeServer::enqueue_offline_event()
+fn update_list<T: 'static, F>(db: Rc<RefCell< KeyValueStore<ProfileId, Vec<T>> >>, key: ProfileId, modify: F)
+    -> Box< Future<Item=(), Error=StorageError> >
+    where F: FnOnce(&mut Vec<T>) + 'static
+{
+    let update_fut = load_list(&db, &key)
+        .and_then( move |mut ids| {
+            modify(&mut ids);
+            return db.borrow_mut().set(key, ids);
+        } );
+    Box::new(update_fut)
 }
 
 
@@ -52,27 +821,21 @@ impl HomeConnectionServer
     pub fn new(context: Rc<PeerContext>, server: Rc<HomeServer>) -> Result<Self, Error>
     {
         context.validate(&*server.validator).map_err(|err| err.context(ErrorKind::ContextValidationFailed))?;
+        server.registry.add(&metrics::CONNECTIONS_OPEN, &[], 1.0);
         Ok( Self{ context: context, server: server } )
     }
 
     /// Returns Error if the profile is not hosted on this home server
-    /// Returns None if the profile is not online
-    fn get_live_session(server: Rc<HomeServer>, to_profile: ProfileId)
-        -> Box< Future<Item=Option<Rc<HomeSessionServer>>, Error=Error> >
+    /// Returns an empty list if the profile is not online
+    fn get_live_sessions(server: Rc<HomeServer>, to_profile: ProfileId)
+        -> Box< Future<Item=Vec<Rc<HomeSessionServer>>, Error=Error> >
     {
-        let sessions_clone = server.sessions.clone();
+        let server_clone = server.clone();
 
         // Check if this profile is hosted on this server
         let session_fut = server.hosted_profile_db.borrow().get( to_profile.clone() )
-            .and_then( move |_profile_data|
-            {
-                // Seperate variable needed, see https://stackoverflow.com/questions/50391668/running-asynchronous-mutable-operations-with-rust-futures
-                let sessions = sessions_clone.borrow();
-                // If hosted here, check if profile is in reach with an online session
-                let session_rc = sessions.get(&to_profile)
-                    .and_then( |weak| weak.upgrade() );
-                future::ok(session_rc)
-            } )
+            // If hosted here, check if profile is in reach with online sessions
+            .map( move |_profile_data| server_clone.live_sessions(&to_profile) )
             .map_err(|err| err.context(ErrorKind::FailedToGetSession).into());
 
         Box::new(session_fut)
@@ -82,44 +845,188 @@ impl HomeConnectionServer
     fn push_event(server: Rc<HomeServer>, to_profile: ProfileId, event: ProfileEvent)
         -> Box< Future<Item=(), Error=Error> >
     {
-        let push_fut = Self::get_live_session(server, to_profile)
-            .and_then( |session_rc_opt|
+        server.registry.inc(&metrics::EVENTS_PUSHED);
+        let push_fut = Self::get_live_sessions( server.clone(), to_profile.clone() )
+            .and_then( move |sessions|
             {
-                match session_rc_opt
+                if sessions.is_empty()
+                    { return server.enqueue_offline_event(to_profile, event); }
+
+                // TODO if push to session fails, consider just dropping the session
+                //      (is anything manual needed using weak pointers?) and requiring a reconnect
+                let pushes = sessions.iter()
+                    .map( |session| session.push_event( event.clone() ).then( |res| Ok::<_,Error>(res) ) )
+                    .collect::<Vec<_>>();
+                // The event is delivered if any device of the profile accepted it
+                let push_all_fut = future::join_all(pushes)
+                    .and_then( |results| {
+                        let mut errors = Vec::new();
+                        for result in results {
+                            match result {
+                                Ok( () ) => return Ok( () ),
+                                Err(e) => errors.push(e),
+                            }
+                        }
+                        Err( errors.remove(0) )
+                    } );
+                Box::new(push_all_fut)
+            } );
+
+        Box::new(push_fut)
+    }
+
+
+    /// Ring all devices of the callee, resolves with the first answer.
+    /// Each device gets its own channel to the caller, only the one of the answering device is connected.
+    fn push_call(server: Rc<HomeServer>, to_profile: ProfileId, to_app: ApplicationId, call_req: CallRequestDetails)
+        -> Box< Future<Item=CallAnswer, Error=Error> >
+    {
+        let handle = server.handle.clone();
+        let capacity = server.limits.channel_capacity;
+        let push_fut = Self::get_live_sessions(server, to_profile)
+            .and_then( move |sessions|
+            {
+                // NOTE a call carries live channels to the caller that cannot be persisted,
+                //      so let the caller know immediately instead of waiting for an answer timeout
+                if sessions.is_empty()
+                    { return Box::new( future::err( ErrorKind::PeerOffline.context(ErrorKind::CallFailed).into() ) )
+                        as Box< Future<Item=CallAnswer, Error=Error> >; }
+
+                let mut from_devices = Vec::new();
+                let rings = sessions.iter().enumerate().map( |(device, session)|
                 {
+                    let mut device_req = call_req.clone();
+                    if call_req.to_caller.is_some() {
+                        let (sender, receiver) = mpsc::channel(capacity);
+                        device_req.to_caller = Some(sender);
+                        from_devices.push( Some(receiver) );
+                    }
+                    let (send, recv) = oneshot::channel();
+                    let call = Box::new( Call::new(device_req, send) );
+
                     // TODO if push to session fails, consider just dropping the session
                     //      (is anything manual needed using weak pointers?) and requiring a reconnect
-                    Some(ref session) => session.push_event(event),
-                    // TODO save event into persistent storage and delegate it when profile is online again
-                    None => { Box::new( future::ok( () ) ) },
-                }
+                    session.push_call( to_app.clone(), call )
+                        .map_err( |err| err.context(ErrorKind::CallFailed).into() )
+                        .and_then( |()| recv.map_err( |e| e.context(ErrorKind::FailedToReadResponse).into() ) )
+                        .map( move |answer| (device, answer) )
+                } ).collect::<Vec<_>>();
+
+                // NOTE other devices get their answer and caller channels closed when they are dropped here
+                let to_caller = call_req.to_caller;
+                let answer_fut = future::select_ok(rings)
+                    .map( move |( (device, answer), _ringing )| {
+                        if let (Some(to_caller), Some(Some(from_device))) = (to_caller, from_devices.get_mut(device).map(Option::take)) {
+                            handle.spawn( from_device.forward( to_caller.sink_map_err( |_e| () ) )
+                                .map( |_| () ) );
+                        }
+                        answer
+                    } );
+                Box::new(answer_fut)
             } );
 
         Box::new(push_fut)
     }
 
 
-    fn push_call(server: Rc<HomeServer>, to_profile: ProfileId, to_app: ApplicationId, call: Box<IncomingCall>)
-        -> Box< Future<Item=(), Error=Error> >
+    /// Register a call already validated, push it to the callee and wait until it is answered or cancelled
+    fn ring<T>(server: Rc<HomeServer>, caller: ProfileId, callee: ProfileId, app: ApplicationId,
+               mut call_req: CallRequestDetails, timeout_fut: T) -> Box< Future<Item=CallAnswer, Error=Error> >
+        where T: Future<Item=CallAnswer, Error=Error> + 'static
     {
-        let push_fut = Self::get_live_session(server, to_profile)
-            .and_then( |session_rc_opt|
+        let id = call_req.id.clone();
+        if server.calls.borrow().contains_key(&id)
+            { return Box::new( future::err( ErrorKind::CallFailed.into() ) ); }
+
+        let (cancel_send, cancel_recv) = oneshot::channel();
+        server.calls.borrow_mut().insert( id.clone(), CallEntry{ caller, callee: callee.clone(),
+            state: CallState::Ringing, hangups: vec![cancel_send], open_channels: 0 } );
+        server.registry.inc(&metrics::CALLS_STARTED);
+
+        // NOTE messages are forwarded through the home, so it can close the channels when the call is finished
+        call_req.to_caller = call_req.to_caller.map( |to_caller| server.forward_call_messages( id.clone(), to_caller ) );
+
+        let cancel_fut = cancel_recv.then( |_res| Err::<CallAnswer,Error>( ErrorKind::CallCancelled.into() ) );
+        let calls = server.calls.clone();
+        // Wait for answer with specified timeout, unless the call is hung up by the caller
+        let ring_fut = Self::push_call( server.clone(), callee, app, call_req )
+            .select(timeout_fut)
+            .map( |(done,_pending)| done )
+            .map_err( |(e,_pending)| e )
+            .select(cancel_fut)
+            .map( |(done,_pending)| done )
+            .map_err( |(e,_pending)| e )
+            .then( move |answer_res| match answer_res
             {
-                match session_rc_opt
-                {
-                    Some(ref session) =>
-                    {
-                        // TODO if push to session fails, consider just dropping the session
-                        //      (is anything manual needed using weak pointers?) and requiring a reconnect
-                        let push_fut = session.push_call(to_app, call);
-                        Box::new(push_fut) as Box< Future<Item=(), Error=Error> >
-                    },
-                    // TODO save event into persistent storage and delegate it when profile is online again
-                    None => { Box::new( future::ok( () ) ) },
-                }
+                Ok( CallAnswer::Accepted(to_callee) ) => {
+                    match calls.borrow_mut().get_mut(&id) {
+                        Some(entry) => entry.state = CallState::Answered,
+                        None => return Err( ErrorKind::CallCancelled.into() ),
+                    };
+                    debug!("Call {:?} changed from {:?} to {:?}", id, CallState::Ringing, CallState::Answered);
+                    Ok( CallAnswer::Accepted( server.forward_call_messages(id, to_callee) ) )
+                },
+                Ok( CallAnswer::Replied(reply) ) => {
+                    finish_call( &calls, &id, CallState::Ended );
+                    Ok( CallAnswer::Replied(reply) )
+                },
+                Ok( CallAnswer::Rejected(reason) ) => {
+                    finish_call( &calls, &id, CallState::Rejected );
+                    Ok( CallAnswer::Rejected(reason) )
+                },
+                Err(e) => {
+                    finish_call( &calls, &id, CallState::Cancelled );
+                    Err(e)
+                },
             } );
+        Box::new(ring_fut)
+    }
 
-        Box::new(push_fut)
+
+    /// Returns the id of the profile hosted here that the caller is allowed to contact with `relation`
+    fn validate_contact(&self, relation: &RelationProof) -> Box< Future<Item=ProfileId, Error=Error> >
+    {
+        let to_profile = match relation.peer_id( self.context.peer_id() ) {
+            Ok(profile_id) => profile_id.to_owned(),
+            Err(e) => return Box::new( future::err( e.context(ErrorKind::ProfileMismatch).into() ) ),
+        };
+
+        let validator = self.server.validator.clone();
+        let relation = relation.to_owned();
+        let peer_id = self.context.peer_id().to_owned();
+        let peer_pubkey = self.context.peer_pubkey().to_owned();
+        let hosted_fut = self.server.hosted_profile_db.borrow().get( to_profile.clone() );
+        let ban_fut = self.server.ensure_not_banned( &to_profile, self.context.peer_id() );
+        let revoked_fut = self.server.ensure_not_revoked( &to_profile, &relation );
+        let contact_fut = self.server.ensure_not_moved(&to_profile)
+            .and_then( |()| ban_fut )
+            .and_then( |()| revoked_fut )
+            .and_then( |()| hosted_fut
+                .map_err( |e| e.context(ErrorKind::PeerNotHostedHere).into() ) )
+            .and_then( move |profile_data| validator.validate_relation_proof( &relation,
+                    &peer_id, &peer_pubkey, &profile_data.profile.id, &profile_data.profile.public_key )
+                .map( |()| profile_data.profile.id )
+                .map_err( |e| e.context(ErrorKind::InvalidRelationProof).into() ) );
+        Box::new(contact_fut)
+    }
+
+
+    /// Hand over `sender` to `subscribe` if `relation` is valid, otherwise send the reason of refusal into it
+    fn spawn_subscription<T,F>(&self, relation: &RelationProof, sender: AsyncSink<T, String>, subscribe: F)
+        where T: 'static,
+              F: FnOnce(ProfileId, AsyncSink<T, String>) + 'static
+    {
+        let subscribe_fut = self.validate_contact(relation)
+            .then( move |contact_res| match contact_res {
+                Ok(profile_id) => {
+                    subscribe(profile_id, sender);
+                    Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=()> >
+                },
+                Err(e) => Box::new( sender.send( Err( format!("Subscription refused: {}", e) ) )
+                    .map( |_sender| () )
+                    .map_err( |_e| () ) ),
+            } );
+        self.server.handle.spawn(subscribe_fut);
     }
 }
 
@@ -127,26 +1034,67 @@ impl HomeConnectionServer
 
 impl ProfileRepo for HomeConnectionServer
 {
-//    fn list(&self, /* TODO what filter criteria should we have here? */ ) ->
-//        HomeStream<Profile, String>
-//    {
-//        unimplemented!()
-//    }
+    // NOTE only profiles hosted on this home are listed
+    fn list(&self, filter: &ProfileListFilter) ->
+        Box< Future<Item=Vec<Profile>, Error=Error> >
+    {
+        let filter = filter.to_owned();
+        let filter_offset = filter.offset;
+        let limit = filter.limit.map_or( CFG_LIST_MAX_PAGE_SIZE, |limit| ::std::cmp::min(limit, CFG_LIST_MAX_PAGE_SIZE) );
+        let dht = self.server.public_profile_dht.clone();
+        let validator = self.server.validator.clone();
+        let list_fut = self.server.hosted_profile_ids( self.context.my_signer().profile_id() )
+            .map_err( |e| Error::from( e.context(ErrorKind::FailedToListProfiles) ) )
+            .and_then( move |mut ids| {
+                // NOTE profiles are loaded one by one in the order of their ids only until the page is filled
+                ids.sort_by( |a, b| a.0.cmp(&b.0) );
+                stream::iter_ok::<_, Error>(ids)
+                    .and_then( move |id| dht.borrow().get(id)
+                        .then(optional)
+                        .map_err( |e| Error::from( e.context(ErrorKind::FailedToListProfiles) ) ) )
+                    // NOTE profiles missing from the distributed storage or not signed properly
+                    //      are skipped instead of failing the whole list
+                    .filter_map( move |profile_opt| profile_opt.and_then( |profile|
+                        profile.validate(&*validator).ok().map( |()| profile ) ) )
+                    .filter( move |profile| filter.matches(profile) )
+                    .skip( u64::from(filter_offset) )
+                    .take( u64::from(limit) )
+                    .collect()
+            } );
+        Box::new(list_fut)
+    }
 
     fn load(&self, id: &ProfileId) ->
         Box< Future<Item=Profile, Error=Error> >
     {
+        let server = self.server.clone();
+        let profile_id = id.to_owned();
+        let validator = self.server.validator.clone();
         let profile_fut = self.server.public_profile_dht.borrow().get( id.to_owned() )
-            .map_err( |e| e.context(ErrorKind::DhtLookupFailed).into() );
+            // NOTE anyone might write the distributed storage, so only documents signed by the profile are served
+            .map_err( |e| e.context(ErrorKind::DhtLookupFailed).into() )
+            .and_then( move |profile: Profile| profile.validate(&*validator).map( |()| profile ) )
+            .or_else( move |e| server.active_redirect(&profile_id)
+                .and_then( |redirect_opt| match redirect_opt {
+                    // NOTE serve the profile from its new home(s) instead, it was validated when the redirect was created
+                    Some(redirect) => Ok(redirect.new_profile),
+                    None => Err(e),
+                } ) );
         Box::new(profile_fut)
     }
 
-//    fn resolve(&self, _url: &str) ->
-//        Box< Future<Item=Profile, Error=Error> >
-//    {
-//        // TODO parse URL and fetch profile accordingly
-//        unimplemented!()
-//    }
+    // NOTE we are the home server to be asked, so hints about the home are not needed here
+    fn resolve(&self, url: &str) ->
+        Box< Future<Item=Profile, Error=Error> >
+    {
+        let profile_url = match ProfileUrl::parse(url) {
+            Ok(profile_url) => profile_url,
+            Err(e) => return Box::new( future::err(e) ),
+        };
+        let profile_fut = self.load(&profile_url.profile_id)
+            .map_err( |e| e.context(ErrorKind::FailedToResolveUrl).into() );
+        Box::new(profile_fut)
+    }
 }
 
 
@@ -165,9 +1113,12 @@ impl Home for HomeConnectionServer
     }
 
 
-    fn register(&self, own_prof: OwnProfile, half_proof: RelationHalfProof, _invite: Option<HomeInvitation>) ->
+    fn register(&self, own_prof: OwnProfile, half_proof: RelationHalfProof, invite: Option<HomeInvitation>) ->
         Box< Future<Item=OwnProfile, Error=(OwnProfile,Error)> >
     {
+        if self.server.closing.get()
+            { return Box::new( future::err( (own_prof, ErrorKind::ShuttingDown.into()) ) ); }
+
         if own_prof.profile.id != *self.context.peer_id() { 
             return Box::new( future::err( (own_prof, ErrorKind::ProfileMismatch.into()))) 
         }
@@ -190,13 +1141,29 @@ impl Home for HomeConnectionServer
             return Box::new( future::err( (own_prof,ErrorKind::RelationTypeMismatch.into())))
         }
 
+        if let Err(e) = own_prof.profile.validate(&*self.server.validator) {
+            return Box::new( future::err( (own_prof, e) ) )
+        }
+
         if self.server.validator.validate_half_proof(&half_proof, &self.context.peer_pubkey()).is_err() { 
             return Box::new( future::err( (own_prof, ErrorKind::InvalidSignature.into())))
         }
 
+        let voucher = match self.server.registration {
+            RegistrationPolicy::Open => None,
+            RegistrationPolicy::InviteOnly => match invite {
+                None => return Box::new( future::err( (own_prof, ErrorKind::InvitationRequired.into()) ) ),
+                Some(invite) => match invite.validate( &*self.server.validator,
+                        self.context.my_signer().profile_id(), self.context.my_signer().public_key() ) {
+                    Err(e) => return Box::new( future::err( (own_prof, e) ) ),
+                    Ok(()) => Some(invite.voucher),
+                },
+            },
+        };
+
         let own_prof_original = own_prof.clone();
         let error_mapper = |e: StorageError| ( own_prof_original, ErrorKind::StorageFailed.into() );
-        let error_mapper_clone = error_mapper.clone();
+        let publish_failed_prof = own_prof.clone();
 
         let home_proof = match RelationProof::sign_remaining_half( &half_proof, self.context.my_signer() )
         {
@@ -204,16 +1171,29 @@ impl Home for HomeConnectionServer
             Ok(proof) => proof,
         };
 
+        // NOTE we cannot sign the profile with the new home added, the client has to sign it and publish it with update()
         let mut own_prof_modified = own_prof.clone();
         if let ProfileFacet::Persona(ref mut profile_facet) = own_prof_modified.profile.facet {
             profile_facet.homes.push(home_proof)
         } else {
             return Box::new( future::err( (own_prof, ErrorKind::PersonaExpected.into())))
         }
+        own_prof_modified.profile.signature = Signature::default();
 
-        let pub_prof_modified = own_prof_modified.profile.clone();
+        let pub_prof = own_prof.profile.clone();
+        let server = self.server.clone();
+        let server_publish = self.server.clone();
+        let server_clone = self.server.clone();
+        let home_id = self.context.my_signer().profile_id().to_owned();
+        let redirect_store = self.server.redirect_db.clone();
+        let redirected_id = own_prof.profile.id.clone();
+        let redirect_failed_prof = own_prof.clone();
+        let voucher_store = self.server.used_vouchers_db.clone();
+        let voucher_used_prof = own_prof.clone();
+        let voucher_clone = voucher.clone();
+        let registered_id = own_prof.profile.id.clone();
+        let indexed_id = own_prof.profile.id.clone();
         let local_store = self.server.hosted_profile_db.clone();
-        let distributed_store = self.server.public_profile_dht.clone();
         let reg_fut = self.server.hosted_profile_db.borrow().get( own_prof.profile.id.clone() )
             .then( |get_res|
             {
@@ -222,18 +1202,45 @@ impl Home for HomeConnectionServer
                         debug!("Profile was already registered");
                         Err( ( own_prof, ErrorKind::AlreadyRegistered.into() ))
                     },
-                    // TODO only errors like NotFound should be accepted here but other (e.g. I/O) errors should be delegated
-                    Err(_e) => Ok( () ),
+                    // NOTE a missing key simply means that the profile is not registered yet
+                    Err(StorageError::InvalidKey) => Ok( () ),
+                    Err(e) => Err( ( own_prof, e.context(ErrorKind::StorageFailed).into() ) ),
                 }
             } )
+            .and_then( move |_| { // Each invitation can be used only once
+                let voucher = match voucher_clone {
+                    None => return Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=(OwnProfile,Error)> >,
+                    Some(voucher) => voucher,
+                };
+                let check_fut = voucher_store.borrow().get(voucher)
+                    .then( |get_res| match get_res {
+                        Ok(_profile_id) => Err( (voucher_used_prof, ErrorKind::InvitationAlreadyUsed.into()) ),
+                        // NOTE a missing key simply means that the voucher was not used yet
+                        Err(StorageError::InvalidKey) => Ok( () ),
+                        Err(e) => Err( (voucher_used_prof, e.context(ErrorKind::StorageFailed).into()) ),
+                    } );
+                Box::new(check_fut)
+            } )
             // NOTE Block with "return" is needed, see https://stackoverflow.com/questions/50391668/running-asynchronous-mutable-operations-with-rust-futures
+            .and_then( move |_| { // A profile returning here must not be redirected anymore
+                return redirect_store.borrow_mut().clear_local( redirected_id )
+                    .then(optional)
+                    .map( |_| () )
+                    .map_err( move |e| (redirect_failed_prof, e.context(ErrorKind::StorageFailed).into()) ); } )
             .and_then( move |_| { // Store public profile parts in distributed storage (e.g. DHT)
                 debug!("Saving public profile info into distributed storage");
-                return distributed_store.borrow_mut().set( pub_prof_modified.id.clone(), pub_prof_modified )
-                    .map_err(error_mapper_clone ); } )
+                return server_publish.publish_profile(pub_prof)
+                    .map_err( move |e| (publish_failed_prof, e) ); } )
             .and_then( move |_| { // Store private profile info in local storage only (e.g. SQL)
                 debug!("Saving private profile info into local storage");
                 return local_store.borrow_mut().set( own_prof_modified.profile.id.clone(), own_prof_modified.clone() )
+                    .and_then( move |_| {
+                        server.update_profile_index( home_id, move |ids| ids.push(indexed_id) )
+                    } )
+                    .and_then( move |_| match voucher {
+                        None => Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=StorageError> >,
+                        Some(voucher) => server_clone.used_vouchers_db.borrow_mut().set(voucher, registered_id),
+                    } )
                     .map( |_| own_prof_modified )
                     .map_err(error_mapper); } );
 
@@ -244,6 +1251,9 @@ impl Home for HomeConnectionServer
     fn login(&self, proof_of_home: &RelationProof) ->
         Box< Future<Item=Rc<HomeSession>, Error=Error> >
     {
+        if self.server.closing.get()
+            { return Box::new( future::err( ErrorKind::ShuttingDown.into() ) ); }
+
         if *proof_of_home.relation_type != *RelationProof::RELATION_TYPE_HOSTED_ON_HOME { 
             return Box::new(future::err(ErrorKind::RelationTypeMismatch.into())); 
         }
@@ -258,10 +1268,9 @@ impl Home for HomeConnectionServer
             .map( {
                 let context_clone = self.context.clone();
                 let server_clone = self.server.clone();
-                let sessions_clone = self.server.sessions.clone();
                 move |_own_profile| {
-                    let session = Rc::new( HomeSessionServer::new(context_clone, server_clone) );
-                    sessions_clone.borrow_mut().entry(profile_id).or_insert( Rc::downgrade(&session) );
+                    let session = Rc::new( HomeSessionServer::new( context_clone, server_clone.clone() ) );
+                    server_clone.add_session(profile_id, &session);
                     session as Rc<HomeSession>
                 }
             } )
@@ -283,7 +1292,12 @@ impl Home for HomeConnectionServer
         }
 
         let to_profile = half_proof.peer_id.clone();
-        Self::push_event(self.server.clone(), to_profile, ProfileEvent::PairingRequest(half_proof) )
+        let server = self.server.clone();
+        let ban_fut = self.server.ensure_not_banned( &to_profile, self.context.peer_id() );
+        let pair_fut = self.server.ensure_not_moved(&to_profile)
+            .and_then( |()| ban_fut )
+            .and_then( move |()| Self::push_event(server, to_profile, ProfileEvent::PairingRequest(half_proof) ) );
+        Box::new(pair_fut)
     }
 
 
@@ -320,7 +1334,7 @@ impl Home for HomeConnectionServer
 
 
     fn call(&self, app: ApplicationId, call_req: CallRequestDetails) ->
-        Box< Future<Item=Option<AppMsgSink>, Error=Error> >
+        Box< Future<Item=CallAnswer, Error=Error> >
     {
         // TODO add error case for calling self
         let to_profile = match call_req.relation.peer_id( self.context.peer_id() )
@@ -330,24 +1344,34 @@ impl Home for HomeConnectionServer
                 
         };
 
+        // NOTE calls are stored by their id, so ids must not be left empty to collide with each other
+        if ! call_req.id.is_valid()
+            { return Box::new( future::err( ErrorKind::InvalidCallId.into() ) ); }
+
         let server_clone = self.server.clone();
         let server_clone2 = self.server.clone();
         let peer_id_clone = self.context.peer_id().clone();
         let peer_pubkey_clone = self.context.peer_pubkey().clone();
         let relation = call_req.relation.clone();
-        let (send, recv) = oneshot::channel();
-        let call = Box::new( Call::new(call_req, send) );
         let handle = self.server.handle.clone();
 
-        let timeout_fut = match Timeout::new(CFG_CALL_ANSWER_TIMEOUT, &handle) {
+        let ring_timeout = ::std::cmp::min(call_req.ring_timeout, CFG_CALL_MAX_RING_TIMEOUT);
+        let timeout_fut = match Timeout::new(ring_timeout, &handle) {
+            // NOTE a missing answer must not be mistaken for a rejected call
             Ok(timeout_fut) => timeout_fut
-                .map( |_| None)
-                .map_err( |e| e.context(ErrorKind::TimeoutFailed).into() ),
+                .map_err( |e| e.context(ErrorKind::TimeoutFailed).into() )
+                .and_then( |()| Err::<CallAnswer,Error>( ErrorKind::CallTimedOut.into() ) ),
             Err(err) => return Box::new(future::err(err.context(ErrorKind::TimeoutFailed).into())),
         };
 
-        let answer_fut = self.server.hosted_profile_db.borrow().get( to_profile.clone() )
-            .map_err(|e| e.context(ErrorKind::PeerNotHostedHere).into())
+        let hosted_fut = self.server.hosted_profile_db.borrow().get( to_profile.clone() );
+        let ban_fut = self.server.ensure_not_banned( &to_profile, self.context.peer_id() );
+        let revoked_fut = self.server.ensure_not_revoked( &to_profile, &call_req.relation );
+        let answer_fut = self.server.ensure_not_moved(&to_profile)
+            .and_then( |()| ban_fut )
+            .and_then( |()| revoked_fut )
+            .and_then( |()| hosted_fut
+                .map_err(|e| e.context(ErrorKind::PeerNotHostedHere).into()) )
             .and_then(move |profile_data|
             {
                 server_clone.validator.validate_relation_proof(
@@ -355,22 +1379,131 @@ impl Home for HomeConnectionServer
                     &profile_data.profile.id, &profile_data.profile.public_key
                 )
                 .map_err(|err| err.context(ErrorKind::InvalidRelationProof).into())
+                .map( |()| peer_id_clone )
             })            
-            .and_then(|_| 
-                Self::push_call(server_clone2, to_profile, app, call)
-                .map_err(|err| err.context(ErrorKind::CallFailed).into())
-            )
-            .and_then( move |_void|
-            {
-                let answer_fut = recv
-                    .map_err( |e| e.context(ErrorKind::FailedToReadResponse).into() );
+            .and_then( move |caller_id|
+                Self::ring(server_clone2, caller_id, to_profile, app, call_req, timeout_fut) );
+        Box::new(answer_fut)
+    }
+
+
+    fn hangup(&self, call: CallId) -> Box< Future<Item=(), Error=Error> >
+    {
+        // NOTE calls of others are reported as missing to not reveal them
+        let state = match self.server.calls.borrow().get(&call) {
+            Some(entry) if entry.caller == *self.context.peer_id() || entry.callee == *self.context.peer_id() =>
+                entry.state,
+            _ => return Box::new( future::err( ErrorKind::CallNotFound.into() ) ),
+        };
+
+        let finished_state = match state {
+            CallState::Ringing => CallState::Cancelled,
+            _ => CallState::Ended,
+        };
+        finish_call(&self.server.calls, &call, finished_state);
+        Box::new( future::ok( () ) )
+    }
+
 
-                // Wait for answer with specified timeout
-                answer_fut.select(timeout_fut)
-                    .map( |(done,_pending)| done )
-                    .map_err( |(e,_pending)| e )
+    fn revoke_relation(&self, revocation: RelationRevocation) ->
+        Box< Future<Item=(), Error=Error> >
+    {
+        if revocation.revoker_id != *self.context.peer_id()
+            { return Box::new( future::err( ErrorKind::ProfileMismatch.into() ) ) }
+
+        if let Err(e) = revocation.validate( &*self.server.validator, self.context.peer_pubkey() )
+            { return Box::new( future::err( e.context(ErrorKind::RevokeRelationFailed).into() ) ) }
+
+        let peer_id = match revocation.peer_id() {
+            Ok(peer_id) => peer_id.to_owned(),
+            Err(e) => return Box::new( future::err( e.context(ErrorKind::ProfileMismatch).into() ) ),
+        };
+
+        // Store the revocation for whichever parties are hosted here and notify the peer
+        let server = self.server.clone();
+        let revoker_id = revocation.revoker_id.clone();
+        let revoker_fut = self.server.hosted_profile_db.borrow().get( revoker_id.clone() );
+        let peer_fut = self.server.hosted_profile_db.borrow().get( peer_id.clone() );
+        let revoke_fut = revoker_fut.then( |res| Ok::<_,Error>( res.is_ok() ) )
+            .join( peer_fut.then( |res| Ok( res.is_ok() ) ) )
+            .and_then( move |(revoker_hosted, peer_hosted)|
+            {
+                if ! revoker_hosted && ! peer_hosted
+                    { return Box::new( future::err( ErrorKind::PeerNotHostedHere.into() ) ) as Box< Future<Item=_, Error=Error> > }
+
+                debug!("Profile {} revokes its relation with {}", revoker_id, peer_id);
+                let no_store_fut = || Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=StorageError> >;
+                let revoker_store_fut = if revoker_hosted
+                    { server.store_revocation( revoker_id, revocation.clone() ) } else { no_store_fut() };
+                let peer_store_fut = if peer_hosted
+                    { server.store_revocation( peer_id.clone(), revocation.clone() ) } else { no_store_fut() };
+                let store_fut = revoker_store_fut
+                    .and_then( |()| peer_store_fut )
+                    .map_err( |e| e.context(ErrorKind::StorageFailed).into() )
+                    .and_then( move |()| {
+                        server.drop_revoked_subscriptions(&revocation.relation);
+                        if peer_hosted { Self::push_event( server, peer_id, ProfileEvent::RelationRevoked(revocation) ) }
+                        else { Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=Error> > }
+                    } );
+                Box::new(store_fut)
             } );
-        Box::new(answer_fut)
+        Box::new(revoke_fut)
+    }
+
+
+    fn redirect(&self, profile: &ProfileId) ->
+        Box< Future<Item=ProfileRedirect, Error=Error> >
+    {
+        let redirect_fut = self.server.active_redirect(profile)
+            .and_then( |redirect_opt| redirect_opt.ok_or( ErrorKind::FailedToGetRedirect.into() ) );
+        Box::new(redirect_fut)
+    }
+
+
+    fn subscribe(&self, relation: RelationProof) -> AsyncStream<ProfileEvent, String>
+    {
+        let (sender, receiver) = mpsc::channel(self.server.limits.channel_capacity);
+        let subscriptions = self.server.subscriptions.clone();
+        let subscriber_id = self.context.peer_id().to_owned();
+        let subscribed_relation = relation.clone();
+        // NOTE the subscription is dropped when the subscriber gets banned or the relation is revoked
+        self.spawn_subscription( &relation, sender, move |profile_id, sender| {
+            debug!("Profile {} subscribed to updates of {}", subscriber_id, profile_id);
+            subscriptions.borrow_mut().entry(profile_id)
+                .or_insert_with(Vec::new)
+                .push( Subscription{ subscriber: subscriber_id, relation: subscribed_relation, sender } );
+        } );
+        receiver
+    }
+
+
+    fn presence(&self, rel: RelationProof, app: ApplicationId) ->
+        Box< Future<Item=Option<AppMessageFrame>, Error=Error> >
+    {
+        let server = self.server.clone();
+        let presence_fut = self.validate_contact(&rel)
+            .map( move |profile_id| server.presence_of(&profile_id, &app) );
+        Box::new(presence_fut)
+    }
+
+
+    fn subscribe_presence(&self, rel: RelationProof, app: ApplicationId) ->
+        AsyncStream<Option<AppMessageFrame>, String>
+    {
+        let (sender, receiver) = mpsc::channel(self.server.limits.channel_capacity);
+        let server = self.server.clone();
+        let subscriber_id = self.context.peer_id().to_owned();
+        let subscribed_relation = rel.clone();
+        self.spawn_subscription( &rel, sender, move |profile_id, mut sender| {
+            // Subscribers start with the current presence, then receive all its changes
+            let current = server.presence_of(&profile_id, &app);
+            if sender.try_send( Ok(current) ).is_ok() {
+                server.presence_subscriptions.borrow_mut().entry( (profile_id, app) )
+                    .or_insert_with(Vec::new)
+                    .push( Subscription{ subscriber: subscriber_id, relation: subscribed_relation, sender } );
+            }
+        } );
+        receiver
     }
 }
 
@@ -379,23 +1512,23 @@ impl Home for HomeConnectionServer
 struct Call
 {
     request: CallRequestDetails,
-    sender:  oneshot::Sender< Option<AppMsgSink> >,
+    sender:  oneshot::Sender<CallAnswer>,
 }
 
 impl Call
 {
-    pub fn new(request: CallRequestDetails, sender: oneshot::Sender< Option<AppMsgSink> >) -> Self
+    pub fn new(request: CallRequestDetails, sender: oneshot::Sender<CallAnswer>) -> Self
         { Self{ request: request, sender: sender } }
 }
 
 impl IncomingCall for Call
 {
     fn request_details(&self) -> &CallRequestDetails { &self.request }
-    fn answer(self: Box<Self>, to_callee: Option<AppMsgSink>) -> CallRequestDetails
+    fn answer(self: Box<Self>, answer: CallAnswer) -> CallRequestDetails
     {
         // NOTE needed to dereference Box because otherwise the whole self is moved at its first dereference
         let this = *self;
-        if let Err(e) = this.sender.send(to_callee)
+        if let Err(e) = this.sender.send(answer)
             { } // TODO We should at least log the error here.
                 //      To solve this better, the function probably should return a Result<T,E> instead of T.
         this.request
@@ -404,51 +1537,352 @@ impl IncomingCall for Call
 
 
 
-enum ServerSink<T,E>
+/// Bounded queue of items for a stream of a session, pumped into the channel of the client while it is listening
+struct SessionBuffer<T,E>
 {
-    // TODO message buffer should be persistent on the long run
-    Buffer(Vec<Result<T,E>>),   // Temporary buffer, sink is not initialized
-    Sender(AsyncSink<T,E>)       // Initialized sink end of channel, user is listening on the other half
+    queue:      VecDeque<Result<T,E>>,
+    limit:      usize,
+    /// Sink end of the channel the client is listening on, None until it starts listening or after it dropped the stream
+    sender:     Option<AsyncSink<T,E>>,
+    /// A pump task is delivering the queue, new items must wait behind it to keep their order
+    pumping:    bool,
+    /// Incremented for each new channel, so pumps of replaced channels can stop
+    epoch:      u64,
 }
 
+impl<T,E> SessionBuffer<T,E>
+{
+    fn new(limit: usize) -> Self
+        { Self{ queue: VecDeque::new(), limit, sender: None, pumping: false, epoch: 0 } }
+
+    fn len(&self) -> usize
+        { self.queue.len() }
+
+
+    /// Sends the item right away if possible, buffers it otherwise.
+    /// Returns the item that did not fit into the buffer: the oldest one when dropping old items, the new one otherwise.
+    fn push(&mut self, item: Result<T,E>, policy: OverflowPolicy) -> Option< Result<T,E> >
+    {
+        let item = if self.queue.is_empty() && ! self.pumping {
+            match self.sender.take() {
+                Some(mut sender) => match sender.try_send(item) {
+                    Ok( () ) => { self.sender = Some(sender); return None; },
+                    Err(e) => {
+                        // NOTE a disconnected client dropped its stream, buffer until it listens again
+                        if e.is_full() { self.sender = Some(sender); }
+                        e.into_inner()
+                    }
+                },
+                None => item,
+            }
+        } else { item };
+
+        self.queue.push_back(item);
+        if self.queue.len() <= self.limit
+            { return None; }
+        match policy {
+            OverflowPolicy::DropOldest => self.queue.pop_front(),
+            _ => self.queue.pop_back(),
+        }
+    }
+
+
+    /// Put items in front of all buffered ones regardless of the limit, e.g. events already accepted into storage
+    fn prepend<I: IntoIterator<Item=Result<T,E>>>(&mut self, items: I)
+    {
+        let mut queue = items.into_iter().collect::<VecDeque<_>>();
+        queue.append(&mut self.queue);
+        self.queue = queue;
+    }
+
+
+    /// Start delivering into a new channel, returns the channel used so far.
+    /// The caller has to start a pump afterwards.
+    fn attach(&mut self, sender: AsyncSink<T,E>) -> Option< AsyncSink<T,E> >
+    {
+        self.epoch += 1;
+        self.pumping = true;
+        ::std::mem::replace( &mut self.sender, Some(sender) )
+    }
+
+
+    /// Stop delivering items, returns the channel and all undelivered items
+    fn close(&mut self) -> ( Option< AsyncSink<T,E> >, VecDeque< Result<T,E> > )
+    {
+        self.epoch += 1;
+        self.pumping = false;
+        ( self.sender.take(), ::std::mem::replace( &mut self.queue, VecDeque::new() ) )
+    }
+
+
+    /// Returns true if a pump has to be started to deliver buffered items
+    fn needs_pump(&mut self) -> bool
+    {
+        if self.pumping || self.sender.is_none() || self.queue.is_empty()
+            { return false; }
+        self.pumping = true;
+        true
+    }
+
+
+    fn is_listening(&self) -> bool
+    {
+        // NOTE a new clone of the sender can only fail to be ready if the receiver was dropped
+        self.sender.as_ref()
+            .map( |sender| sender.clone().poll_ready().is_ok() )
+            .unwrap_or(false)
+    }
+
+
+    fn poll_flush(&mut self, epoch: u64) -> Poll<(), ()>
+    {
+        // A newer pump took over with another channel
+        if epoch != self.epoch
+            { return Ok(Async::Ready( () )); }
+
+        while let Some(item) = self.queue.pop_front()
+        {
+            let send_res = match self.sender {
+                Some(ref mut sender) => sender.start_send(item),
+                None => { self.queue.push_front(item); break; }
+            };
+            match send_res {
+                Ok( ::futures::AsyncSink::Ready ) => {},
+                Ok( ::futures::AsyncSink::NotReady(item) ) => {
+                    self.queue.push_front(item);
+                    return Ok(Async::NotReady);
+                },
+                Err(e) => {
+                    debug!("Client stopped listening, buffering {} items", self.queue.len() + 1);
+                    self.queue.push_front( e.into_inner() );
+                    self.sender = None;
+                },
+            }
+        }
+
+        self.pumping = false;
+        Ok(Async::Ready( () ))
+    }
+}
+
+
+/// Milliseconds since the unix epoch
+fn unix_timestamp_millis() -> u64
+{
+    ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+        .map( |elapsed| elapsed.as_secs() * 1000 + u64::from( elapsed.subsec_millis() ) )
+        .unwrap_or(0)
+}
+
+
+/// Send a last error item to a client listening on a channel that is closed afterwards
+fn send_notice<T: 'static>(sender: AsyncSink<T,String>, notice: &str) -> Box< Future<Item=(), Error=()> >
+{
+    let send_fut = sender.send( Err( notice.to_owned() ) )
+        .map( |_sender| () )
+        .map_err( |_e| () );
+    Box::new(send_fut)
+}
+
+
+/// Deliver buffered items into the channel of the buffer as fast as the client consumes them
+fn pump_session_buffer<T: 'static, E: 'static>(handle: &reactor::Handle, buffer: Rc<RefCell< SessionBuffer<T,E> >>)
+{
+    let epoch = buffer.borrow().epoch;
+    handle.spawn( future::poll_fn( move || buffer.borrow_mut().poll_flush(epoch) ) );
+}
+
+
+
 pub struct HomeSessionServer
 {
     // TODO consider using Weak<Ptrs> instead of Rc<Ptrs> if a closed Home connection cannot
     //      drop all related session automatically
     context:    Rc<PeerContext>,
     server:     Rc<HomeServer>,
-    events:     RefCell< ServerSink<ProfileEvent, String> >,
-    apps:       RefCell< HashMap< ApplicationId, ServerSink<Box<IncomingCall>, String> > > // {appId->sender<call>}
+    events:     Rc<RefCell< SessionBuffer<ProfileEvent, String> >>,
+    apps:       RefCell< HashMap< ApplicationId, Rc<RefCell< SessionBuffer<Box<IncomingCall>, String> >> > >,
+    presences:  RefCell< HashMap<ApplicationId, AppMessageFrame> >, // published by apps on checkin
+    closed:     Cell<Option<&'static str>>, // notice of a session closed by a newer login or the home admin
 }
 
 
 impl HomeSessionServer
 {
+    const EVICTED_NOTICE: &'static str = "Session closed because the profile logged in with a new session";
+    const LOGGED_OUT_NOTICE: &'static str = "Session closed by the administrator of the home";
+    const SHUTDOWN_NOTICE: &'static str = "Session closed because the home is shutting down";
+
     // TODO consider if validating the context is needed here, e.g. as an assert()
     pub fn new(context: Rc<PeerContext>, server: Rc<HomeServer>) -> Self
     {
-        Self{ context: context, server: server,
-              events:  RefCell::new(ServerSink::Buffer( Vec::new() ) ),
-              apps:    RefCell::new( HashMap::new() ) }
+        let events = Rc::new( RefCell::new( SessionBuffer::new(server.limits.event_buffer_size) ) );
+        server.registry.inc(&metrics::SESSIONS_OPENED);
+        Self{ context: context, server: server, events,
+              apps:    RefCell::new( HashMap::new() ),
+              presences: RefCell::new( HashMap::new() ), closed: Cell::new(None) }
+    }
+
+
+    /// Presence of a checked in app, None if it is not checked in or its calls stream was dropped since then
+    fn presence(&self, app: &ApplicationId) -> Option<AppMessageFrame>
+    {
+        let listening = self.apps.borrow().get(app)
+            .map( |buffer| buffer.borrow().is_listening() )
+            .unwrap_or(false);
+        if ! listening
+            { self.set_presence(app, None); }
+        self.presences.borrow().get(app).cloned()
+    }
+
+
+    fn set_presence(&self, app: &ApplicationId, presence: Option<AppMessageFrame>)
+    {
+        // NOTE contacts see the presence of the app on any device, this session taking precedence
+        let other_device_presence = self.server.live_sessions( self.context.peer_id() ).iter()
+            .filter( |session| ! ::std::ptr::eq( &***session, self ) )
+            .filter_map( |session| session.presences.borrow().get(app).cloned() )
+            .next();
+        let old_presence = match presence {
+            Some(ref presence) => self.presences.borrow_mut().insert( app.to_owned(), presence.to_owned() ),
+            None => self.presences.borrow_mut().remove(app),
+        };
+
+        let old_presence = old_presence.or( other_device_presence.clone() );
+        let presence = presence.or(other_device_presence);
+        if old_presence != presence {
+            let key = ( self.context.peer_id().to_owned(), app.to_owned() );
+            notify_all(&self.server.presence_subscriptions, &key, presence);
+        }
+    }
+
+
+    /// Clear the presence of an app as soon as it drops its calls stream, so contacts are notified.
+    /// Senders are not notified about a dropped receiver, so the channel is checked periodically
+    /// until the app checks in again or the session is closed.
+    fn watch_app(&self, app: ApplicationId, buffer: Rc<RefCell< SessionBuffer<Box<IncomingCall>, String> >>)
+    {
+        let epoch = buffer.borrow().epoch;
+        let server = self.server.clone();
+        let profile_id = self.context.peer_id().to_owned();
+        let handle = self.server.handle.clone();
+        let watch_fut = future::loop_fn( (), move |()| {
+            let server = server.clone();
+            let profile_id = profile_id.clone();
+            let app = app.clone();
+            let buffer = buffer.clone();
+            future::result( Timeout::new(CFG_PRESENCE_CHECK_INTERVAL, &handle) )
+                .flatten()
+                .map_err( |e| warn!("Failed to wait for checking presence: {}", e) )
+                .map( move |()| {
+                    if buffer.borrow().epoch != epoch
+                        { return future::Loop::Break( () ); }
+                    // NOTE dropped sessions already cleared the presences of their apps
+                    let session = server.live_sessions(&profile_id).into_iter()
+                        .find( |session| session.apps.borrow().get(&app)
+                            .map( |app_buffer| Rc::ptr_eq(app_buffer, &buffer) )
+                            .unwrap_or(false) );
+                    let session = match session {
+                        Some(session) => session,
+                        None => return future::Loop::Break( () ),
+                    };
+                    if buffer.borrow().is_listening()
+                        { return future::Loop::Continue( () ); }
+
+                    debug!("App {:?} of profile {} dropped its calls stream", app, profile_id);
+                    session.set_presence(&app, None);
+                    future::Loop::Break( () )
+                } )
+        } );
+        self.server.handle.spawn(watch_fut);
+    }
+
+
+    /// Close the streams of this session with a notice, e.g. after the profile logged in with a new one.
+    /// Undelivered events are saved into the mailbox, so the next session receives them.
+    fn evict(&self, notice: &'static str)
+    {
+        debug!("Closing session of profile {}: {}", self.context.peer_id(), notice);
+        self.closed.set( Some(notice) );
+
+        let (events_sender, undelivered) = self.events.borrow_mut().close();
+        if let Some(sender) = events_sender
+            { self.server.handle.spawn( send_notice(sender, notice) ); }
+        let server = self.server.clone();
+        let profile_id = self.context.peer_id().to_owned();
+        let save_fut = stream::iter_ok( undelivered.into_iter().filter_map( |item| item.ok() ) )
+            .for_each( move |event| server.enqueue_offline_event( profile_id.clone(), event ) )
+            .map_err( |e| warn!("Failed to save undelivered events of closed session: {}", e) );
+        self.server.handle.spawn(save_fut);
+
+        // NOTE dropping undelivered calls closes their answer channel, so their callers are notified
+        for buffer in self.apps.borrow().values() {
+            if let (Some(sender), _undelivered) = buffer.borrow_mut().close()
+                { self.server.handle.spawn( send_notice(sender, notice) ); }
+        }
+
+        let present_apps = self.presences.borrow().keys().cloned().collect::<Vec<_>>();
+        for app in present_apps
+            { self.set_presence(&app, None); }
+    }
+
+
+    /// The profile after migration must be ours and hosted by at least one other home that we agreed to
+    fn validate_new_home(&self, new_profile: &Profile) -> Result<(), Error>
+    {
+        if new_profile.id != *self.context.peer_id()
+            { Err(ErrorKind::ProfileMismatch)? }
+        if new_profile.public_key != *self.context.peer_pubkey()
+            { Err(ErrorKind::PublicKeyMismatch)? }
+        new_profile.validate(&*self.server.validator)?;
+
+        let homes = match new_profile.facet {
+            ProfileFacet::Persona(ref persona) => &persona.homes,
+            _ => Err(ErrorKind::PersonaExpected)?,
+        };
+
+        let my_home_id = self.context.my_signer().profile_id();
+        let new_home_found = homes.iter().any( |home_proof|
+            home_proof.relation_type == RelationProof::RELATION_TYPE_HOSTED_ON_HOME &&
+            home_proof.peer_id( &new_profile.id ).map( |home_id| home_id != my_home_id ).unwrap_or(false) &&
+            home_proof.validate_half( &*self.server.validator, &new_profile.id, &new_profile.public_key ).is_ok() );
+        if ! new_home_found
+            { Err(ErrorKind::InvalidRelationProof)? }
+        Ok( () )
     }
 
 
     fn push_event(&self, event: ProfileEvent) -> Box< Future<Item=(),Error=Error> >
     {
-        match *self.events.borrow_mut()
+        let policy = self.server.limits.overflow;
+        let overflow = self.events.borrow_mut().push( Ok(event), policy );
+        if self.events.borrow_mut().needs_pump()
+            { pump_session_buffer( &self.server.handle, self.events.clone() ); }
+
+        let overflow = match overflow {
+            None => return Box::new( future::ok( () ) ),
+            Some(item) => item,
+        };
+
+        let peer_id = self.context.peer_id().to_owned();
+        let mut metrics = self.server.metrics.borrow_mut();
+        match (policy, overflow)
         {
-            ServerSink::Buffer(ref mut bufvec) =>
-            {
-                bufvec.push( Ok(event) ); // TODO consider size constraints
+            (OverflowPolicy::DropOldest, _) => {
+                warn!("Event buffer of profile {} is full, dropped its oldest event", peer_id);
+                metrics.dropped_events += 1;
                 Box::new( future::ok( () ) )
             },
-            ServerSink::Sender(ref mut sender) => Box::new
-            (
-                sender.clone().send( Ok(event) )
-                    .map( |_sender| () )
-                    // TODO if call dispatch fails we probably should replace the sender with a buffer
-                    .map_err( |e| e.context(ErrorKind::FailedToSend).into())
-            ),
+            (OverflowPolicy::SpillToStorage, Ok(event)) => {
+                debug!("Event buffer of profile {} is full, saving event into its mailbox", peer_id);
+                metrics.spilled_events += 1;
+                self.server.enqueue_offline_event(peer_id, event)
+            },
+            _ => {
+                warn!("Event buffer of profile {} is full, rejected event", peer_id);
+                metrics.rejected_events += 1;
+                Box::new( future::err( ErrorKind::SessionBufferFull.into() ) )
+            },
         }
     }
 
@@ -456,31 +1890,52 @@ impl HomeSessionServer
     fn push_call(&self, app: ApplicationId, call: Box<IncomingCall>)
         -> Box< Future<Item=(), Error=Error> >
     {
-        let mut apps = self.apps.borrow_mut();
-        let sink = apps.entry(app).or_insert( ServerSink::Buffer( Vec::new() ) );
-        match *sink
+        let limits = self.server.limits;
+        let buffer = self.apps.borrow_mut().entry( app.clone() )
+            .or_insert_with( || Rc::new( RefCell::new( SessionBuffer::new(limits.call_buffer_size) ) ) )
+            .clone();
+        let overflow = buffer.borrow_mut().push( Ok(call), limits.overflow );
+        if buffer.borrow_mut().needs_pump()
+            { pump_session_buffer( &self.server.handle, buffer.clone() ); }
+
+        if overflow.is_none()
+            { return Box::new( future::ok( () ) ); }
+
+        // NOTE dropping a call closes its answer channel, so its caller is notified of the failure
+        let peer_id = self.context.peer_id();
+        let mut metrics = self.server.metrics.borrow_mut();
+        match limits.overflow
         {
-            ServerSink::Buffer(ref mut bufvec) =>
-            {
-                bufvec.push( Ok(call) ); // TODO consider size constraints
+            OverflowPolicy::DropOldest => {
+                warn!("Call buffer of app {:?} of profile {} is full, dropped its oldest call", app, peer_id);
+                metrics.dropped_calls += 1;
                 Box::new( future::ok( () ) )
             },
-            ServerSink::Sender(ref mut sender) => Box::new
-            (
-                sender.clone().send( Ok(call) )
-                    .map( |_sender| () )
-                    // TODO if call dispatch fails we probably should replace the sender with a buffer
-                    .map_err( |e| ErrorKind::FailedToSend.into() )
-            ),
+            _ => {
+                warn!("Call buffer of app {:?} of profile {} is full, rejected call", app, peer_id);
+                metrics.rejected_calls += 1;
+                Box::new( future::err( ErrorKind::SessionBufferFull.into() ) )
+            },
         }
     }
 }
 
+impl Drop for HomeConnectionServer
+{
+    fn drop(&mut self)
+        { self.server.registry.add(&metrics::CONNECTIONS_OPEN, &[], -1.0); }
+}
+
+
 impl Drop for HomeSessionServer {
     fn drop(&mut self) {
         let peer_id = self.context.peer_id();
         debug!("dropping session {}", peer_id);
-        self.server.sessions.borrow_mut().remove(peer_id);
+        self.server.remove_dropped_sessions(peer_id);
+
+        let present_apps = self.presences.borrow().keys().cloned().collect::<Vec<_>>();
+        for app in present_apps
+            { self.set_presence(&app, None); }
     }   
 }
 
@@ -496,35 +1951,59 @@ impl HomeSession for HomeSessionServer
             return Box::new( future::err( ErrorKind::PublicKeyMismatch.into())) 
         }
 
+        if let Err(e) = own_prof.profile.validate(&*self.server.validator) {
+            return Box::new( future::err(e) )
+        }
+
         let upd_fut = self.server.hosted_profile_db.borrow().get( own_prof.profile.id.clone() )
-            // NOTE Block with "return" is needed, see https://stackoverflow.com/questions/50391668/running-asynchronous-mutable-operations-with-rust-futures
+            // TODO: fix it after storage error refactorings
+            .map_err( |_e| ErrorKind::ProfileUpdateFailed.into() )
             .and_then( {
-                let distributed_store = self.server.public_profile_dht.clone();
+                let server = self.server.clone();
                 let pub_prof = own_prof.profile.clone();
                 move |_own_prof_orig| { // Update public profile parts in distributed storage (e.g. DHT)
-                    return distributed_store.borrow_mut().set( pub_prof.id.clone(), pub_prof );
+                    server.publish_profile(pub_prof)
                 }
             } )
+            // NOTE Block with "return" is needed, see https://stackoverflow.com/questions/50391668/running-asynchronous-mutable-operations-with-rust-futures
             .and_then( {
                 let local_store = self.server.hosted_profile_db.clone();
+                let pub_prof = own_prof.profile.clone();
                 move |_| { // Update private profile info in local storage only (e.g. SQL)
-                    return local_store.borrow_mut().set( own_prof.profile.id.clone(), own_prof );
+                    return local_store.borrow_mut().set( own_prof.profile.id.clone(), own_prof )
+                        .map( |()| pub_prof )
+                        .map_err( |e| e.context(ErrorKind::ProfileUpdateFailed).into() );
                 }
             } )
-            // TODO: fix it after storage error refactorings
-            .map_err( |e| ErrorKind::ProfileUpdateFailed.into() );
+            .map( {
+                let server = self.server.clone();
+                move |pub_prof| server.notify_subscribers(&pub_prof)
+            } );
 
         Box::new(upd_fut)
     }
 
 
-    // TODO is the ID of the new home enough here or do we need the whole profile?
-    // TODO newhome should be stored and some special redirect to new home should be sent when someone looking for the profile
-    fn unregister(&self, _newhome: Option<Profile>) ->
+    fn unregister(&self, newhome: Option<Profile>) ->
         Box< Future<Item=(), Error=Error> >
     {
         let profile_id = self.context.peer_id().to_owned();
 
+        let redirect_fut = match newhome {
+            None => Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=StorageError> >,
+            Some(new_profile) => {
+                if let Err(e) = self.validate_new_home(&new_profile)
+                    { return Box::new( future::err( e.context(ErrorKind::InvalidRedirect).into() ) ); }
+                debug!("Profile {} moves away, storing redirect", profile_id);
+                self.server.notify_subscribers(&new_profile);
+                let redirect = ProfileRedirect::new( &new_profile, CFG_REDIRECT_RETENTION, self.context.my_signer() );
+                self.server.redirect_db.borrow_mut().set( profile_id.clone(), redirect )
+            },
+        };
+
+        // Subscribers were told about the new home above, the profile cannot be watched here anymore
+        self.server.subscriptions.borrow_mut().remove(&profile_id);
+
         // TODO is it the caller's responsibility to remove this home from the persona facet's homelist
         //      or should we do it here and save the results into the distributed public db?
         // TODO how to delete profile from self.server.hosted_profiles_db? We'll probably need a remove operation
@@ -535,45 +2014,38 @@ impl HomeSession for HomeSessionServer
         // TODO force close/drop session connection after successful unregister().
         //      Ideally self would be consumed here, but that'd require binding to self: Box<Self> or Rc<Self> to compile within a trait.
 
-        let local_fut = self.server.hosted_profile_db.borrow_mut().clear_local( profile_id.clone() );
-        let unreg_fut = self.server.public_profile_dht.borrow_mut().clear_local(profile_id)
-            .and_then( |_| local_fut )
+        let remove_fut = self.server.remove_profile_data( self.context.my_signer().profile_id().to_owned(), profile_id );
+        let unreg_fut = redirect_fut
+            .and_then( |_| remove_fut )
             .map_err( |e| e.context(ErrorKind::UnregisterFailed).into());
 
         Box::new(unreg_fut)
     }
 
 
-    // TODO add argument in a later milestone, presence: Option<AppMessageFrame>) ->
-    fn checkin_app(&self, app: &ApplicationId) -> AsyncStream<Box<IncomingCall>, String>
+    fn checkin_app(&self, app: &ApplicationId, presence: Option<AppMessageFrame>) -> AsyncStream<Box<IncomingCall>, String>
     {
-        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let limits = self.server.limits;
+        let (sender, receiver) = mpsc::channel(limits.channel_capacity);
+        if let Some(notice) = self.closed.get() {
+            self.server.handle.spawn( send_notice(sender, notice) );
+            return receiver;
+        }
 
-        match self.apps.borrow_mut().insert( app.to_owned(), ServerSink::Sender( sender.clone() ) )
+        let buffer = self.apps.borrow_mut().entry( app.to_owned() )
+            .or_insert_with( || Rc::new( RefCell::new( SessionBuffer::new(limits.call_buffer_size) ) ) )
+            .clone();
+        if let Some(old_sender) = buffer.borrow_mut().attach(sender)
         {
-            Some( ServerSink::Sender(old_sender) ) =>
-            {
-                // NOTE consuming the calls stream multiple times is likely a client implementation error
-                self.server.handle.spawn(
-                    old_sender.send( Err( "WARNING: Repeated call of HomeSession::checkin_app() detected, this channel is dropped, using the new one".to_owned() ) )
-                        .map( |_sender| () )
-                        .map_err( |_e| () )
-                )
-            },
-            Some( ServerSink::Buffer(call_vec) ) =>
-            {
-                // Send all collected calls from buffer as we now finally have a channel to the app
-                // TODO use persistent storage for calls when profile is offline and delegate them here
-                self.server.handle.spawn(
-                    sender.send_all( stream::iter_ok(call_vec) )
-                        .map( |_sender| () )
-                        .map_err( |_e| () )
-                )
-            },
-            None => {},
+            // NOTE consuming the calls stream multiple times is likely a client implementation error
+            self.server.handle.spawn( send_notice( old_sender,
+                "WARNING: Repeated call of HomeSession::checkin_app() detected, this channel is dropped, using the new one" ) );
         }
+        // Send all collected calls from buffer as we now finally have a channel to the app
+        pump_session_buffer( &self.server.handle, buffer.clone() );
 
-        // TODO how to detect dropped stream and remove the sink from the session?
+        self.set_presence(app, presence);
+        self.watch_app( app.to_owned(), buffer );
         receiver
     }
 
@@ -583,30 +2055,52 @@ impl HomeSession for HomeSessionServer
     //      has been processed via the old_sender?
     fn events(&self) -> AsyncStream<ProfileEvent, String>
     {
-        let (sender, receiver) = mpsc::channel(CHANNEL_CAPACITY);
+        let (sender, receiver) = mpsc::channel(self.server.limits.channel_capacity);
+        if let Some(notice) = self.closed.get() {
+            self.server.handle.spawn( send_notice(sender, notice) );
+            return receiver;
+        }
 
         // Set up events with the new channel and check the old event sink
-        match self.events.replace( ServerSink::Sender( sender.clone() ) )
+        match self.events.borrow_mut().attach(sender)
         {
             // We already had another channel properly set up
-            ServerSink::Sender(old_sender) =>
+            Some(old_sender) =>
             {
                 // NOTE consuming the events stream multiple times is likely a client implementation error
-                self.server.handle.spawn(
-                    old_sender.send( Err( "WARNING: Repeated call of HomeSession::events() detected, this channel is dropped, using the new one".to_owned() ) )
-                        .map( |_sender| () )
-                        .map_err( |_e| () )
-                )
+                self.server.handle.spawn( send_notice( old_sender,
+                    "WARNING: Repeated call of HomeSession::events() detected, this channel is dropped, using the new one" ) );
+                pump_session_buffer( &self.server.handle, self.events.clone() );
             },
-            // The client was not listening to events so far, the channel is brand new
-            ServerSink::Buffer(msg_vec) =>
+            // The client was not listening to events so far or dropped its last stream
+            None =>
             {
-                // Send all collected messages from buffer as we now finally have a channel to the user
-                // TODO use persistent storage for events when profile is offline and delegate them here
+                // Send all events saved into the mailbox, then all collected messages from buffer
+                // as we now finally have a channel to the user.
+                // The mailbox is emptied only after its contents were moved into the buffer.
+                let profile_id = self.context.peer_id().to_owned();
+                let mailbox = self.server.offline_events_db.clone();
+                let buffer = self.events.clone();
+                let handle = self.server.handle.clone();
                 self.server.handle.spawn(
-                    sender.send_all( stream::iter_ok(msg_vec) )
-                        .map( |_sender| () )
-                        .map_err( |_e| () )
+                    self.server.offline_events_db.borrow().get( profile_id.clone() )
+                        .then(optional)
+                        .map( |events_opt| events_opt.unwrap_or_default() )
+                        // NOTE live events must still be delivered, the mailbox is kept intact for a later session
+                        .or_else( |e| { warn!("Failed to load events from mailbox: {}", e); Ok( Vec::new() ) } )
+                        .map_err( |_e: StorageError| () )
+                        .and_then( move |stored_events| {
+                            debug!("Delivering {} events from mailbox", stored_events.len());
+                            let has_stored_events = ! stored_events.is_empty();
+                            buffer.borrow_mut().prepend( stored_events.into_iter().map(Ok) );
+                            pump_session_buffer(&handle, buffer);
+
+                            if ! has_stored_events
+                                { return Box::new( future::ok( () ) ) as Box< Future<Item=(), Error=()> >; }
+                            let clear_fut = mailbox.borrow_mut().clear_local(profile_id)
+                                .map_err( |e| warn!("Failed to clear delivered events from mailbox: {}", e) );
+                            Box::new(clear_fut)
+                        } )
                 )
             }
         }
@@ -622,4 +2116,38 @@ impl HomeSession for HomeSessionServer
         debug!("Ping received `{}`, sending it back", txt);
         Box::new( future::ok( txt.to_owned() ) )
     }
+
+
+    fn banned_profiles(&self) -> Box< Future<Item=Vec<ProfileId>, Error=Error> >
+    {
+        let banned_fut = self.server.banned_profiles( self.context.peer_id() )
+            .map_err( |e| e.context(ErrorKind::FailedToGetBannedProfiles).into() );
+        Box::new(banned_fut)
+    }
+
+
+    fn ban(&self, profile: &ProfileId) -> Box< Future<Item=(), Error=Error> >
+    {
+        debug!("Profile {} bans {}", self.context.peer_id(), profile);
+        let server = self.server.clone();
+        let banner_id = self.context.peer_id().to_owned();
+        let banned_id = profile.to_owned();
+        let stored_id = banned_id.clone();
+        let ban_fut = self.server.update_ban_list( banner_id.clone(), move |ids|
+                if ! ids.contains(&stored_id) { ids.push(stored_id) } )
+            .map( move |()| server.drop_banned_subscriptions(&banner_id, &banned_id) )
+            .map_err( |e| e.context(ErrorKind::BanFailed).into() );
+        Box::new(ban_fut)
+    }
+
+
+    fn unban(&self, profile: &ProfileId) -> Box< Future<Item=(), Error=Error> >
+    {
+        debug!("Profile {} unbans {}", self.context.peer_id(), profile);
+        let unbanned_id = profile.to_owned();
+        let unban_fut = self.server.update_ban_list( self.context.peer_id().to_owned(), move |ids|
+                ids.retain( |id| *id != unbanned_id ) )
+            .map_err( |e| e.context(ErrorKind::UnbanFailed).into() );
+        Box::new(unban_fut)
+    }
 }
@@ -0,0 +1,250 @@
+//! Canonical binary encoding of all data signed by the protocol.
+//!
+//! Signatures must be reproducible by clients written in any language, so the signed bytes
+//! cannot depend on Rust-specific serializers. The format is specified in `doc/canonical-encoding.md`,
+//! in short:
+//!
+//! * every signed message starts with a domain tag (encoded as text) naming the signed structure
+//! * unsigned integers are unsigned LEB128 varints using the minimal number of bytes
+//! * byte strings and UTF-8 text are a varint length followed by the raw bytes
+//! * optional values are a `0x00` byte if absent, a `0x01` byte followed by the value otherwise
+//! * lists are a varint item count followed by the items
+//! * structs are the concatenation of their fields in the documented order, without field names
+//! * enums are a varint variant index followed by the fields of the variant
+
+use multiaddr::Multiaddr;
+
+use super::*;
+
+
+
+pub const DOMAIN_PROFILE:       &'static str = "mercury.profile";
+pub const DOMAIN_RELATION:      &'static str = "mercury.relation";
+pub const DOMAIN_INVITATION:    &'static str = "mercury.invitation";
+pub const DOMAIN_REDIRECT:      &'static str = "mercury.redirect";
+pub const DOMAIN_REVOCATION:    &'static str = "mercury.revocation";
+pub const DOMAIN_HANDSHAKE:     &'static str = "mercury.handshake";
+pub const DOMAIN_ADMIN:         &'static str = "mercury.admin";
+
+
+
+/// Types that have a canonical encoding when part of a signed message.
+pub trait CanonicalEncode
+{
+    fn encode(&self, encoder: &mut CanonicalEncoder);
+}
+
+
+/// Builds the canonical bytes of a signed message field by field. Encoding cannot fail.
+pub struct CanonicalEncoder
+{
+    buffer: Vec<u8>,
+}
+
+impl CanonicalEncoder
+{
+    /// Start a new message with the domain tag of the signed structure.
+    pub fn new(domain: &str) -> Self
+    {
+        let mut result = Self{ buffer: Vec::new() };
+        result.text(domain);
+        result
+    }
+
+    pub fn uint(&mut self, mut value: u64) -> &mut Self
+    {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 { self.buffer.push(byte); break; }
+            self.buffer.push(byte | 0x80);
+        }
+        self
+    }
+
+    pub fn bytes(&mut self, value: &[u8]) -> &mut Self
+    {
+        self.uint( value.len() as u64 );
+        self.buffer.extend_from_slice(value);
+        self
+    }
+
+    pub fn text(&mut self, value: &str) -> &mut Self
+        { self.bytes( value.as_bytes() ) }
+
+    pub fn item<T: CanonicalEncode + ?Sized>(&mut self, value: &T) -> &mut Self
+    {
+        value.encode(self);
+        self
+    }
+
+    pub fn option<T: CanonicalEncode>(&mut self, value: Option<&T>) -> &mut Self
+    {
+        match value {
+            None => self.buffer.push(0),
+            Some(value) => { self.buffer.push(1); value.encode(self); }
+        }
+        self
+    }
+
+    pub fn list<T: CanonicalEncode>(&mut self, items: &[T]) -> &mut Self
+    {
+        self.uint( items.len() as u64 );
+        for item in items { item.encode(self); }
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8>
+        { ::std::mem::replace( &mut self.buffer, Vec::new() ) }
+}
+
+
+
+impl CanonicalEncode for u64
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.uint(*self); } }
+
+impl CanonicalEncode for str
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.text(self); } }
+
+impl CanonicalEncode for String
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.text(self); } }
+
+impl CanonicalEncode for ProfileId
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.bytes(&self.0); } }
+
+impl CanonicalEncode for PublicKey
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.bytes(&self.0); } }
+
+impl CanonicalEncode for Signature
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.bytes(&self.0); } }
+
+// NOTE multiaddresses are encoded in their textual form, e.g. "/ip4/127.0.0.1/tcp/2077"
+impl CanonicalEncode for Multiaddr
+    { fn encode(&self, encoder: &mut CanonicalEncoder) { encoder.text( &self.to_string() ); } }
+
+
+impl CanonicalEncode for RelationMetadata
+{
+    fn encode(&self, encoder: &mut CanonicalEncoder)
+    {
+        encoder.uint( u64::from(self.version) )
+            .bytes(&self.nonce)
+            .uint(self.created_at)
+            .option( self.expires_at.as_ref() );
+    }
+}
+
+impl CanonicalEncode for RelationProof
+{
+    fn encode(&self, encoder: &mut CanonicalEncoder)
+    {
+        encoder.text(&self.relation_type)
+            .item(&self.a_id).item(&self.a_signature)
+            .item(&self.b_id).item(&self.b_signature)
+            .item(&self.metadata);
+    }
+}
+
+impl CanonicalEncode for ProfileFacet
+{
+    fn encode(&self, encoder: &mut CanonicalEncoder)
+    {
+        match *self {
+            ProfileFacet::Home(ref home) =>
+                { encoder.uint(0).list(&home.addrs).bytes(&home.data); }
+            ProfileFacet::Persona(ref persona) =>
+                { encoder.uint(1).list(&persona.homes).bytes(&persona.data); }
+            ProfileFacet::Application(ref app) =>
+                { encoder.uint(2).text(&app.id.0).bytes(&app.data); }
+            ProfileFacet::Unknown(ref raw) =>
+                { encoder.uint(3).bytes(&raw.data); }
+        }
+    }
+}
+
+impl CanonicalEncode for Profile
+{
+    fn encode(&self, encoder: &mut CanonicalEncoder)
+        { encoder.item(&self.id).item(&self.public_key).item(&self.facet).uint(self.version).item(&self.signature); }
+}
+
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crypto::{CompositeValidator, Ed25519Signer};
+    use multiaddr::ToMultiaddr;
+
+    // NOTE these vectors are generated by doc/canonical-encoding-vectors.py, keep them in sync
+    fn hex(bytes: &[u8]) -> String
+        { bytes.iter().map( |b| format!("{:02x}", b) ).collect() }
+
+    fn sample_metadata() -> RelationMetadata
+        { RelationMetadata{ version: 1, nonce: vec![0xaa; 4], created_at: 1_500_000_000, expires_at: Some(1_600_000_000) } }
+
+    #[test]
+    fn primitive_vectors()
+    {
+        let encoded = CanonicalEncoder::new("t")
+            .uint(0).uint(127).uint(128).uint(300).uint( u64::max_value() )
+            .bytes(&[]).text("\u{e9}")
+            .option::<u64>(None).option( Some(&5u64) )
+            .list( &[ "a".to_owned(), "bc".to_owned() ] )
+            .finish();
+        assert_eq!( hex(&encoded), "0174007f8001ac02ffffffffffffffffff010002c3a9000105020161026263" );
+    }
+
+    #[test]
+    fn signable_vectors()
+    {
+        let a_id = ProfileId( vec![0x0a; 3] );
+        let b_id = ProfileId( vec![0x0b; 3] );
+
+        let relation = RelationSignablePart::new("friend", &a_id, &b_id, &sample_metadata());
+        assert_eq!( hex( &relation.serialized() ),
+            "106d6572637572792e72656c6174696f6e0106667269656e64030a0a0a030b0b0b04aaaaaaaa80dea0cb050180a0f8fa05" );
+
+        let invitation = HomeInvitation::new( &a_id, "voucher", 1_600_000_000, &Signature( Vec::new() ) );
+        assert_eq!( hex( &invitation.signable_part() ),
+            "126d6572637572792e696e7669746174696f6e030a0a0a07766f756368657280a0f8fa05" );
+
+        let proof = RelationProof::new( "friend", &a_id, &Signature( vec![1; 2] ),
+                                        &b_id, &Signature( vec![2; 2] ), &sample_metadata() );
+        let revocation = RelationRevocation{ relation: proof, revoker_id: b_id.clone(),
+                                             revoked_at: 1_550_000_000, signature: Signature( Vec::new() ) };
+        assert_eq!( hex( &revocation.signable_part() ),
+            "126d6572637572792e7265766f636174696f6e06667269656e64030a0a0a020101030b0b0b02020201\
+             04aaaaaaaa80dea0cb050180a0f8fa05030b0b0b80bf8ce305" );
+
+        let home_facet = ProfileFacet::Home( HomeFacet{
+            addrs: vec![ "/ip4/127.0.0.1/tcp/2077".to_multiaddr().unwrap() ], data: vec![] } );
+        let mut profile = Profile::new( &a_id, &PublicKey( vec![0x0c; 2] ), &home_facet );
+        profile.version = 1;
+        assert_eq!( hex( &profile.signable_part() ),
+            "0f6d6572637572792e70726f66696c65030a0a0a020c0c0001172f6970342f3132372e302e302e312f7463702f323037370001" );
+
+        profile.signature = Signature( vec![0x0d; 2] );
+        let redirect = ProfileRedirect{ profile_id: a_id.clone(), new_profile: profile, old_home_id: b_id.clone(),
+                                        expires_at: 1_600_000_000, signature: Signature( Vec::new() ) };
+        assert_eq!( hex( &redirect.signable_part() ),
+            "106d6572637572792e7265646972656374030a0a0a030a0a0a020c0c0001172f6970342f3132372e302e302e312f7463702f3230373700\
+             01020d0d030b0b0b80a0f8fa05" );
+    }
+
+
+    #[test]
+    fn signature_vector()
+    {
+        let signer = Ed25519Signer::new( &PrivateKey( vec![1; 32] ) ).unwrap();
+        let invitation = HomeInvitation::new( &ProfileId( vec![0x0a; 3] ), "voucher", 1_600_000_000, &Signature( Vec::new() ) );
+        let signature = signer.sign( &invitation.signable_part() );
+        assert_eq!( hex( &signer.public_key().0 ), "8a88e3dd7409f195fd52db2d3cba5d72ca6709bf1d94121bf3748801b40f6f5c" );
+        assert_eq!( hex( &signature.0 ),
+            "c2e3e4e73082c418b1de4d52ca749842e8a1a8175b513d7761e0a597d03d5c93\
+             93ba71f7c1650caa4bf93391d82c8982d2f0ddf193373dba52951933bb7bd306" );
+        assert!( CompositeValidator::default().validate_signature(
+            signer.public_key(), &invitation.signable_part(), &signature ).unwrap() );
+    }
+}
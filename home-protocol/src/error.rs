@@ -59,6 +59,50 @@ pub enum ErrorKind {
     SignerMismatch,
     #[fail(display="peer not hosted here")]
     PeerNotHostedHere,
+    #[fail(display="peer is offline")]
+    PeerOffline,
+    #[fail(display="profile moved to another home")]
+    ProfileMoved,
+    #[fail(display="redirect expired")]
+    RedirectExpired,
+    #[fail(display="invalid redirect")]
+    InvalidRedirect,
+    #[fail(display="failed to get redirect")]
+    FailedToGetRedirect,
+    #[fail(display="home accepts registrations only with an invitation")]
+    InvitationRequired,
+    #[fail(display="invalid invitation")]
+    InvalidInvitation,
+    #[fail(display="invitation expired")]
+    InvitationExpired,
+    #[fail(display="invitation was already used")]
+    InvitationAlreadyUsed,
+    #[fail(display="requester is banned by the profile")]
+    ProfileBanned,
+    #[fail(display="failed to get banned profiles")]
+    FailedToGetBannedProfiles,
+    #[fail(display="failed to ban profile")]
+    BanFailed,
+    #[fail(display="failed to unban profile")]
+    UnbanFailed,
+    #[fail(display="relation was revoked")]
+    RelationRevoked,
+    #[fail(display="failed to revoke relation")]
+    RevokeRelationFailed,
+    #[fail(display="unsupported relation version")]
+    UnsupportedRelationVersion,
+    #[fail(display="relation is not valid yet")]
+    RelationNotYetValid,
+    #[fail(display="relation expired")]
+    RelationExpired,
+    #[fail(display="profile is not signed")]
+    ProfileNotSigned,
+    #[fail(display="invalid profile signature")]
+    InvalidProfileSignature,
+    #[fail(display="profile version is older than the one already known")]
+    StaleProfileVersion,
+    #[fail(display="failed to get presence")]
+    FailedToGetPresence,
     #[fail(display="invalid relation proof")]
     InvalidRelationProof,
     #[fail(display="timeout failed")]
@@ -71,18 +115,30 @@ pub enum ErrorKind {
     FailedToLoadProfile,
     #[fail(display="call failed")]
     CallFailed,
+    #[fail(display="call timed out without answer")]
+    CallTimedOut,
+    #[fail(display="call cancelled")]
+    CallCancelled,
+    #[fail(display="call not found")]
+    CallNotFound,
+    #[fail(display="hangup failed")]
+    HangupFailed,
     #[fail(display="failed to push event")]
     FailedToPushEvent,
     #[fail(display= "connection to home failed")]
     ConnectionToHomeFailed,
     #[fail(display="failed to send")]
     FailedToSend,
+    #[fail(display="session buffer is full")]
+    SessionBufferFull,
     #[fail(display="context validation failed")]
     ContextValidationFailed,
     #[fail(display="failed to get session")]
     FailedToGetSession,
     #[fail(display="failed to resolve URL")]
     FailedToResolveUrl,
+    #[fail(display="failed to list profiles")]
+    FailedToListProfiles,
     #[fail(display="pair request failed")]
     PairRequestFailed,
     #[fail(display="pair response failed")]
@@ -99,6 +155,104 @@ pub enum ErrorKind {
     PingFailed,
     #[fail(display="login failed")]
     LoginFailed,
+    #[fail(display="home is shutting down")]
+    ShuttingDown,
+    #[fail(display="invalid call id")]
+    InvalidCallId,
+}
+
+// NOTE codes are part of the protocol, never change or reuse the code of a kind, only append new ones
+const ERROR_CODES: &'static [(ErrorKind, u16)] = &[
+    (ErrorKind::ProfileLookupFailed,            1),
+    (ErrorKind::ProfileUpdateFailed,            2),
+    (ErrorKind::HashDecodeFailed,               3),
+    (ErrorKind::HashEncodeFailed,               4),
+    (ErrorKind::SignerCreationFailed,           5),
+    (ErrorKind::SignatureValidationFailed,      6),
+    (ErrorKind::TlsHandshakeFailed,             7),
+    (ErrorKind::RelationSigningFailed,          8),
+    (ErrorKind::RelationValidationFailed,       9),
+    (ErrorKind::ProfileValidationFailed,        10),
+    (ErrorKind::MultiaddrSerializationFailed,   11),
+    (ErrorKind::MultiaddrDeserializationFailed, 12),
+    (ErrorKind::PeerIdRetreivalFailed,          13),
+    (ErrorKind::FailedToClaimProfile,           14),
+    (ErrorKind::PersonaExpected,                15),
+    (ErrorKind::AlreadyRegistered,              16),
+    (ErrorKind::HomeIdMismatch,                 17),
+    (ErrorKind::RelationTypeMismatch,           18),
+    (ErrorKind::InvalidSignature,               19),
+    (ErrorKind::StorageFailed,                  20),
+    (ErrorKind::ProfileMismatch,                21),
+    (ErrorKind::PublicKeyMismatch,              22),
+    (ErrorKind::SignerMismatch,                 23),
+    (ErrorKind::PeerNotHostedHere,              24),
+    (ErrorKind::PeerOffline,                    25),
+    (ErrorKind::ProfileMoved,                   26),
+    (ErrorKind::RedirectExpired,                27),
+    (ErrorKind::InvalidRedirect,                28),
+    (ErrorKind::FailedToGetRedirect,            29),
+    (ErrorKind::InvitationRequired,             30),
+    (ErrorKind::InvalidInvitation,              31),
+    (ErrorKind::InvitationExpired,              32),
+    (ErrorKind::InvitationAlreadyUsed,          33),
+    (ErrorKind::ProfileBanned,                  34),
+    (ErrorKind::FailedToGetBannedProfiles,      35),
+    (ErrorKind::BanFailed,                      36),
+    (ErrorKind::UnbanFailed,                    37),
+    (ErrorKind::RelationRevoked,                38),
+    (ErrorKind::RevokeRelationFailed,           39),
+    (ErrorKind::UnsupportedRelationVersion,     40),
+    (ErrorKind::RelationNotYetValid,            41),
+    (ErrorKind::RelationExpired,                42),
+    (ErrorKind::ProfileNotSigned,               43),
+    (ErrorKind::InvalidProfileSignature,        44),
+    (ErrorKind::StaleProfileVersion,            45),
+    (ErrorKind::FailedToGetPresence,            46),
+    (ErrorKind::InvalidRelationProof,           47),
+    (ErrorKind::TimeoutFailed,                  48),
+    (ErrorKind::FailedToReadResponse,           49),
+    (ErrorKind::ProfileDeregistered,            50),
+    (ErrorKind::FailedToLoadProfile,            51),
+    (ErrorKind::CallFailed,                     52),
+    (ErrorKind::CallTimedOut,                   53),
+    (ErrorKind::CallCancelled,                  54),
+    (ErrorKind::CallNotFound,                   55),
+    (ErrorKind::HangupFailed,                   56),
+    (ErrorKind::FailedToPushEvent,              57),
+    (ErrorKind::ConnectionToHomeFailed,         58),
+    (ErrorKind::FailedToSend,                   59),
+    (ErrorKind::SessionBufferFull,              60),
+    (ErrorKind::ContextValidationFailed,        61),
+    (ErrorKind::FailedToGetSession,             62),
+    (ErrorKind::FailedToResolveUrl,             63),
+    (ErrorKind::FailedToListProfiles,           64),
+    (ErrorKind::PairRequestFailed,              65),
+    (ErrorKind::PairResponseFailed,             66),
+    (ErrorKind::RegisterFailed,                 67),
+    (ErrorKind::UnregisterFailed,               68),
+    (ErrorKind::FailedToCreateSession,          69),
+    (ErrorKind::DhtLookupFailed,                70),
+    (ErrorKind::PingFailed,                     71),
+    (ErrorKind::LoginFailed,                    72),
+    (ErrorKind::ShuttingDown,                   73),
+    (ErrorKind::InvalidCallId,                  74),
+];
+
+impl ErrorKind {
+    /// Stable code of the kind used to transfer errors to remote peers, 0 is reserved for unknown kinds
+    pub fn code(&self) -> u16 {
+        ERROR_CODES.iter()
+            .find( |&&(kind, _code)| kind == *self )
+            .map( |&(_kind, code)| code )
+            .unwrap_or(0)
+    }
+
+    pub fn from_code(code: u16) -> Option<ErrorKind> {
+        ERROR_CODES.iter()
+            .find( |&&(_kind, known_code)| known_code == code )
+            .map( |&(kind, _code)| kind )
+    }
 }
 
 impl PartialEq for Error {
@@ -127,6 +281,21 @@ impl Error {
     pub fn kind(&self) -> ErrorKind {
         *self.inner.get_context()
     }
+
+    /// True if the home could not serve the request at all, e.g. it was unreachable, the connection broke
+    /// or it does not host the profile anymore, so another home of the same profile may still succeed.
+    /// Errors refusing the request itself, e.g. a rejected call or an invalid signature, are final.
+    pub fn is_home_failure(&self) -> bool {
+        match self.kind() {
+            ErrorKind::ConnectionToHomeFailed | ErrorKind::TlsHandshakeFailed | ErrorKind::ShuttingDown |
+            ErrorKind::PeerNotHostedHere | ErrorKind::ProfileMoved => return true,
+            _ => {},
+        }
+        // NOTE transport failures are reported with the kind of the failed operation, only their cause tells them apart
+        self.inner.iter_causes()
+            .filter_map( |cause| cause.downcast_ref::<::capnp::Error>() )
+            .any( |e| e.kind == ::capnp::ErrorKind::Disconnected || e.kind == ::capnp::ErrorKind::Overloaded )
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -1,7 +1,10 @@
 use std::iter;
+use std::time::Duration;
 
 use futures::prelude::*;
 use futures::future::{self, loop_fn, poll_fn, Loop};
+use futures::sync::mpsc;
+use tokio_core::reactor;
 
 use ::AsyncResult;
 
@@ -136,12 +139,30 @@ where I: IntoIterator,
     })
 }
 
+/// Resolves when the receiver of the channel of `sender` was dropped.
+/// Senders are not notified about that until they try sending, so the channel is checked every `interval`.
+pub fn receiver_dropped<T: 'static>(sender: mpsc::Sender<T>, interval: Duration, handle: &reactor::Handle)
+    -> AsyncResult<(), ()>
+{
+    let handle = handle.clone();
+    let dropped_fut = loop_fn( sender, move |sender|
+        future::result( reactor::Timeout::new(interval, &handle) )
+            .flatten()
+            .map_err( |e| warn!("Failed to wait for checking a channel: {}", e) )
+            .map( |()| {
+                // NOTE a new clone of the sender can only fail to be ready if the receiver was dropped
+                if sender.clone().poll_ready().is_err() { Loop::Break( () ) }
+                else { Loop::Continue(sender) }
+            } ) );
+    Box::new(dropped_fut)
+}
+
+
+
 #[cfg(test)]
 mod test
 {
     use super::*;
-    use futures::sync::mpsc;
-    use tokio_core::reactor;
 
     #[test]
     fn test_collect_empty()
@@ -175,4 +196,15 @@ mod test
         let result = reactor.run(collect_fut).unwrap();
         assert_eq!( result, [Ok(1), Ok(2)] );
     }
+
+
+    #[test]
+    fn test_receiver_dropped()
+    {
+        let mut reactor = reactor::Core::new().unwrap();
+        let (sink, stream) = mpsc::channel::<()>(1);
+        let dropped_fut = receiver_dropped( sink, Duration::from_millis(10), &reactor.handle() );
+        drop(stream);
+        assert_eq!( reactor.run(dropped_fut), Ok( () ) );
+    }
 }
@@ -1,36 +1,128 @@
 use bytes::{Buf, BufMut, BytesMut, IntoBuf};
+use std::cmp;
+use std::io::{Read, Write};
 use std::mem;
 use std::rc::Rc;
 
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce, aead::{Aead, NewAead}};
 use failure::Fail;
-use futures::{future, Future};
+use futures::{future, Async, Future, Poll};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
 use serde_json::{from_slice, to_vec};
-//bincode::{deserialize, serialize};
+use sha2::{Digest, Sha256};
 use tokio_core::net::TcpStream;
 use tokio_io::io;
+use x25519_dalek::{EphemeralSecret, PublicKey as DhPublicKey};
 
 use super::*;
+use canonical::{CanonicalEncoder, DOMAIN_HANDSHAKE};
+use crypto::CompositeValidator;
+
+
+
+// NOTE Protocol overview (both sides run exactly the same steps, there is no initiator role):
+//      1. send own identity (profile id, public key) and a fresh X25519 ephemeral public key
+//      2. receive the same from the peer
+//      3. send a signature of the handshake transcript (both ephemeral keys and both profile ids)
+//         created with the long-term profile key through `Signer`
+//      4. receive and validate the signature of the peer, proving that it owns the claimed profile
+//      5. derive a separate key for each direction from the ephemeral Diffie-Hellman secret,
+//         all further traffic is encrypted and authenticated with ChaCha20-Poly1305
+const HANDSHAKE_PROTOCOL_ID: &'static [u8] = b"mercury-handshake-x25519-chachapoly-v1";
+const MAX_HANDSHAKE_MESSAGE_SIZE: u32 = 64 * 1024;
+
+/// Maximum size of plaintext encrypted into a single frame.
+pub const MAX_FRAME_PAYLOAD_SIZE: usize = 16 * 1024;
+const FRAME_TAG_SIZE: usize = 16;
+const FRAME_HEADER_SIZE: usize = 4;
 
 
 
 #[derive(Deserialize, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 struct AuthenticationInfo
 {
-    profile_id: ProfileId,
-    public_key: PublicKey,
+    profile_id:     ProfileId,
+    public_key:     PublicKey,
+    ephemeral_key:  Vec<u8>,
+}
+
+#[derive(Deserialize, Clone, Debug, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+struct AuthenticationProof
+{
+    signature:      Signature,
 }
 
 
-pub fn temp_handshake_until_tls_is_implemented<R,W>(reader: R, writer: W, signer: Rc<Signer>)
-    -> AsyncResult<(R, W, PeerContext), Error>
+
+/// Perform a mutually authenticated key exchange over `reader` and `writer`.
+/// On success, returns an encrypted channel to the peer together with a `PeerContext`
+/// whose identity was proven by a signature of the peer's profile key.
+pub fn ecdh_handshake<R,W>(reader: R, writer: W, signer: Rc<Signer>)
+    -> AsyncResult<(EncryptedReader<R>, EncryptedWriter<W>, PeerContext), Error>
 where R: std::io::Read + tokio_io::AsyncRead + 'static,
       W: std::io::Write + tokio_io::AsyncWrite + 'static
 {
     debug!("Starting handshake with peer");
-    let auth_info = AuthenticationInfo{
-        profile_id: signer.profile_id().to_owned(), public_key: signer.public_key().to_owned() };
+    let my_secret = EphemeralSecret::new(OsRng);
+    let my_ephemeral = DhPublicKey::from(&my_secret);
+    let my_auth = AuthenticationInfo{
+        profile_id: signer.profile_id().to_owned(), public_key: signer.public_key().to_owned(),
+        ephemeral_key: my_ephemeral.as_bytes().to_vec() };
+    trace!("Sending auth info of myself: {:?}", my_auth);
+
+    let handshake_fut = write_message(writer, &my_auth)
+        .and_then( |writer| read_message::<_, AuthenticationInfo>(reader)
+            .map( |(reader, peer_auth)| (reader, writer, peer_auth) ) )
+        .and_then( move |(reader, writer, peer_auth)|
+        {
+            trace!("Received peer identity: {:?}", peer_auth);
+            let transcript = transcript_hash(&my_auth, &peer_auth);
+            let my_proof = AuthenticationProof{ signature: signer.sign( &signable_transcript(&transcript, &my_auth) ) };
+            write_message(writer, &my_proof)
+                .and_then( |writer| read_message::<_, AuthenticationProof>(reader)
+                    .map( |(reader, peer_proof)| (reader, writer, peer_proof) ) )
+                .and_then( move |(reader, writer, peer_proof)|
+                {
+                    trace!("Validating peer signature over handshake transcript");
+                    let peer_ctx = PeerContext::new( signer, peer_auth.public_key.clone(), peer_auth.profile_id.clone() );
+                    let validator = CompositeValidator::default();
+                    peer_ctx.validate(&validator)?;
+                    validator.validate_signature( &peer_auth.public_key,
+                        &signable_transcript(&transcript, &peer_auth), &peer_proof.signature )?;
+
+                    let (send_key, recv_key) = derive_session_keys(my_secret, &my_auth, &peer_auth, &transcript)?;
+                    debug!("Handshake succeeded with peer {}", peer_ctx.peer_id());
+                    Ok( ( EncryptedReader::new(reader, &recv_key), EncryptedWriter::new(writer, &send_key), peer_ctx ) )
+                } )
+        } )
+        .map_err( |err: Error| err.context(ErrorKind::TlsHandshakeFailed).into() );
+    Box::new(handshake_fut)
+}
+
+
+
+pub fn tcp_ecdh_handshake(socket: TcpStream, signer: Rc<Signer>)
+    -> AsyncResult<(impl std::io::Read, impl std::io::Write, PeerContext), Error>
+{
+    use tokio_io::AsyncRead;
+
+    match socket.set_nodelay(true) {
+        Ok(_) => {},
+        Err(e) => return Box::new( future::err(e.context(ErrorKind::TlsHandshakeFailed).into())),
+    };
+
+    let (reader, writer) = socket.split();
+    ecdh_handshake(reader, writer, signer)
+}
+
 
-    let out_bytes = match to_vec(&auth_info) {
+
+fn write_message<W,T>(writer: W, message: &T) -> AsyncResult<W, Error>
+where W: std::io::Write + tokio_io::AsyncWrite + 'static,
+      T: serde::Serialize
+{
+    let out_bytes = match to_vec(message) {
         Ok(data) => data,
         Err(e) => return Box::new( future::err( e.context(ErrorKind::TlsHandshakeFailed).into()) ),
     };
@@ -38,53 +130,326 @@ where R: std::io::Read + tokio_io::AsyncRead + 'static,
 
     let mut size_out_bytes = BytesMut::with_capacity( mem::size_of_val(&bufsize) );
     size_out_bytes.put_u32_le(bufsize);
-    trace!("Sending auth info of myself: {:?}", auth_info);
 
-    let handshake_fut = io::write_all(writer, size_out_bytes)
-        .and_then( move |(writer, _buf)| { io::write_all(writer, out_bytes) } )
-        .and_then( move |(writer, _buf)|
-        {
-            trace!("Reading buffer size for peer info");
-            let mut size_bytes = BytesMut::new();
-            size_bytes.resize( mem::size_of_val(&bufsize), 0 );
-            io::read_exact(reader, size_bytes)
-                .map( |(reader, buf)| (reader, writer, buf) )
-        } )
-        .and_then( |(reader, writer, buf)|
+    let write_fut = io::write_all(writer, size_out_bytes)
+        .and_then( move |(writer, _buf)| io::write_all(writer, out_bytes) )
+        .map( |(writer, _buf)| writer )
+        .map_err( |e| e.context(ErrorKind::TlsHandshakeFailed).into() );
+    Box::new(write_fut)
+}
+
+
+fn read_message<R,T>(reader: R) -> AsyncResult<(R, T), Error>
+where R: std::io::Read + tokio_io::AsyncRead + 'static,
+      T: serde::de::DeserializeOwned + 'static
+{
+    let mut size_bytes = BytesMut::new();
+    size_bytes.resize( mem::size_of::<u32>(), 0 );
+    let read_fut = io::read_exact(reader, size_bytes)
+        .and_then( |(reader, buf)|
         {
-            trace!("Reading peer info, size: {:?}", buf);
             let size_in_bytes = buf.into_buf().get_u32_le();
+            if size_in_bytes > MAX_HANDSHAKE_MESSAGE_SIZE {
+                return future::Either::A( future::err( std::io::Error::new(
+                    std::io::ErrorKind::InvalidData, "Handshake message is too large") ) );
+            }
             let mut in_bytes = BytesMut::new();
             in_bytes.resize(size_in_bytes as usize, 0);
-            io::read_exact(reader, in_bytes)
-                .map( |(reader, buf)| (reader, writer, buf) )
-        } )        
-        .and_then( |(reader, writer, buf)|
+            future::Either::B( io::read_exact(reader, in_bytes) )
+        } )
+        .and_then( |(reader, buf)|
         {
-            trace!("Processing peer info received");
-            let peer_auth: AuthenticationInfo = from_slice(&buf)
-                .map_err( |e| std::io::Error::new( std::io::ErrorKind::Other, e) )?;
-            trace!("Received peer identity: {:?}", peer_auth);
-            let peer_ctx = PeerContext::new( signer, peer_auth.public_key, peer_auth.profile_id );
-            debug!("Handshake succeeded");
-            Ok( (reader, writer, peer_ctx) )
+            let message = from_slice(&buf)
+                .map_err( |e| std::io::Error::new( std::io::ErrorKind::InvalidData, e) )?;
+            Ok( (reader, message) )
         } )
-        .map_err(|err| err.context(ErrorKind::TlsHandshakeFailed).into()); 
-    Box::new(handshake_fut)
+        .map_err( |e| e.context(ErrorKind::TlsHandshakeFailed).into() );
+    Box::new(read_fut)
 }
 
 
+// Order identities by their ephemeral key so both sides compute the very same transcript
+fn ordered<'a>(mine: &'a AuthenticationInfo, peer: &'a AuthenticationInfo)
+    -> (&'a AuthenticationInfo, &'a AuthenticationInfo)
+    { if mine.ephemeral_key < peer.ephemeral_key { (mine, peer) } else { (peer, mine) } }
 
-pub fn temp_tcp_handshake_until_tls_is_implemented(socket: TcpStream, signer: Rc<Signer>)
-    -> AsyncResult<(impl std::io::Read, impl std::io::Write, PeerContext), Error>
+
+fn transcript_hash(mine: &AuthenticationInfo, peer: &AuthenticationInfo) -> Vec<u8>
+{
+    let (first, second) = ordered(mine, peer);
+    let mut encoder = CanonicalEncoder::new(DOMAIN_HANDSHAKE);
+    encoder.bytes(HANDSHAKE_PROTOCOL_ID);
+    for auth in &[first, second]
+        { encoder.bytes(&auth.ephemeral_key).item(&auth.public_key).item(&auth.profile_id); }
+    let mut hasher = Sha256::new();
+    hasher.input( &encoder.finish() );
+    hasher.result().to_vec()
+}
+
+
+// Signing the transcript together with our own ephemeral key makes reflecting our signature back to us impossible
+fn signable_transcript(transcript: &[u8], signer_auth: &AuthenticationInfo) -> Vec<u8>
+{
+    CanonicalEncoder::new(DOMAIN_HANDSHAKE)
+        .bytes(transcript).bytes(&signer_auth.ephemeral_key)
+        .finish()
+}
+
+
+fn derive_session_keys(my_secret: EphemeralSecret, mine: &AuthenticationInfo,
+                       peer: &AuthenticationInfo, transcript: &[u8])
+    -> Result<(Vec<u8>, Vec<u8>), Error>
+{
+    if peer.ephemeral_key.len() != 32 || peer.ephemeral_key == mine.ephemeral_key
+        { Err(ErrorKind::TlsHandshakeFailed)? }
+
+    let mut peer_key_bytes = [0u8; 32];
+    peer_key_bytes.copy_from_slice(&peer.ephemeral_key);
+    let shared_secret = my_secret.diffie_hellman( &DhPublicKey::from(peer_key_bytes) );
+
+    let kdf = Hkdf::<Sha256>::new( Some(transcript), shared_secret.as_bytes() );
+    let mut okm = [0u8; 64];
+    kdf.expand(HANDSHAKE_PROTOCOL_ID, &mut okm)
+        .map_err( |_e| ErrorKind::TlsHandshakeFailed )?;
+
+    // First half of the output encrypts traffic sent by the side with the lower ephemeral key
+    let (lower_key, higher_key) = okm.split_at(32);
+    if mine.ephemeral_key < peer.ephemeral_key
+         { Ok( (lower_key.to_vec(), higher_key.to_vec()) ) }
+    else { Ok( (higher_key.to_vec(), lower_key.to_vec()) ) }
+}
+
+
+fn frame_nonce(counter: u64) -> [u8; 12]
+{
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice( &counter.to_le_bytes() );
+    nonce
+}
+
+fn crypto_error(msg: &str) -> std::io::Error
+    { std::io::Error::new(std::io::ErrorKind::InvalidData, msg) }
+
+
+
+/// Read half of an encrypted channel created by `ecdh_handshake()`.
+/// Frames are prefixed with their encrypted size as u32 in little endian.
+pub struct EncryptedReader<R>
+{
+    inner:      R,
+    cipher:     ChaCha20Poly1305,
+    counter:    u64,
+    encrypted:  Vec<u8>,
+    plain:      Vec<u8>,
+    plain_pos:  usize,
+}
+
+impl<R: Read> EncryptedReader<R>
+{
+    fn new(inner: R, key: &[u8]) -> Self
+    {
+        Self{ inner, cipher: ChaCha20Poly1305::new( Key::from_slice(key) ), counter: 0,
+              encrypted: Vec::new(), plain: Vec::new(), plain_pos: 0 }
+    }
+
+    // Decrypt the next frame if it was already fully received, returns false otherwise
+    fn decrypt_buffered_frame(&mut self) -> std::io::Result<bool>
+    {
+        if self.encrypted.len() < FRAME_HEADER_SIZE
+            { return Ok(false); }
+
+        let frame_size = (&self.encrypted[..FRAME_HEADER_SIZE]).into_buf().get_u32_le() as usize;
+        if frame_size > MAX_FRAME_PAYLOAD_SIZE + FRAME_TAG_SIZE
+            { return Err( crypto_error("Encrypted frame is too large") ); }
+        if self.encrypted.len() < FRAME_HEADER_SIZE + frame_size
+            { return Ok(false); }
+
+        let frame: Vec<u8> = self.encrypted.drain( ..FRAME_HEADER_SIZE + frame_size ).skip(FRAME_HEADER_SIZE).collect();
+        self.plain = self.cipher.decrypt( Nonce::from_slice( &frame_nonce(self.counter) ), frame.as_slice() )
+            .map_err( |_e| crypto_error("Failed to decrypt frame") )?;
+        self.plain_pos = 0;
+        self.counter += 1;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for EncryptedReader<R>
+{
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize>
+    {
+        loop
+        {
+            if self.plain_pos < self.plain.len() {
+                let size = cmp::min( buf.len(), self.plain.len() - self.plain_pos );
+                buf[..size].copy_from_slice( &self.plain[self.plain_pos .. self.plain_pos + size] );
+                self.plain_pos += size;
+                return Ok(size);
+            }
+
+            if self.decrypt_buffered_frame()?
+                { continue; }
+
+            let mut chunk = [0u8; 4096];
+            let size = self.inner.read(&mut chunk)?;
+            if size == 0 {
+                if self.encrypted.is_empty() { return Ok(0); }
+                return Err( std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "Connection closed within a frame") );
+            }
+            self.encrypted.extend_from_slice(&chunk[..size]);
+        }
+    }
+}
+
+impl<R: tokio_io::AsyncRead> tokio_io::AsyncRead for EncryptedReader<R> {}
+
+
+
+/// Write half of an encrypted channel created by `ecdh_handshake()`.
+pub struct EncryptedWriter<W>
+{
+    inner:      W,
+    cipher:     ChaCha20Poly1305,
+    counter:    u64,
+    pending:    Vec<u8>,
+}
+
+impl<W: Write> EncryptedWriter<W>
 {
+    fn new(inner: W, key: &[u8]) -> Self
+        { Self{ inner, cipher: ChaCha20Poly1305::new( Key::from_slice(key) ), counter: 0, pending: Vec::new() } }
+
+    fn write_pending(&mut self) -> std::io::Result<()>
+    {
+        while ! self.pending.is_empty()
+        {
+            let size = self.inner.write(&self.pending)?;
+            if size == 0
+                { return Err( std::io::Error::new(std::io::ErrorKind::WriteZero, "Failed to write encrypted frame") ); }
+            self.pending.drain(..size);
+        }
+        Ok( () )
+    }
+}
+
+impl<W: Write> Write for EncryptedWriter<W>
+{
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize>
+    {
+        // Refuse new data until the previous frame is fully sent to keep memory usage bounded
+        self.write_pending()?;
+
+        let size = cmp::min( buf.len(), MAX_FRAME_PAYLOAD_SIZE );
+        let frame = self.cipher.encrypt( Nonce::from_slice( &frame_nonce(self.counter) ), &buf[..size] )
+            .map_err( |_e| crypto_error("Failed to encrypt frame") )?;
+        self.counter += 1;
+
+        let mut header = BytesMut::with_capacity(FRAME_HEADER_SIZE);
+        header.put_u32_le( frame.len() as u32 );
+        self.pending.extend_from_slice(&header);
+        self.pending.extend_from_slice(&frame);
+
+        // The frame is already accepted, delivery errors will be reported by the next write() or flush()
+        match self.write_pending() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {},
+            Err(e) => return Err(e),
+            Ok( () ) => {},
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()>
+    {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: tokio_io::AsyncWrite> tokio_io::AsyncWrite for EncryptedWriter<W>
+{
+    fn shutdown(&mut self) -> Poll<(), std::io::Error>
+    {
+        match self.write_pending() {
+            Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+            Err(e) => return Err(e),
+            Ok( () ) => {},
+        }
+        self.inner.shutdown()
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crypto::Ed25519Signer;
+    use tokio_core::net::TcpListener;
+    use tokio_core::reactor;
     use tokio_io::AsyncRead;
 
-    match socket.set_nodelay(true) {
-        Ok(_) => {},
-        Err(e) => return Box::new( future::err(e.context(ErrorKind::TlsHandshakeFailed).into())),
-    };
+    fn signer(seed: u8) -> Rc<Signer>
+        { Rc::new( Ed25519Signer::new( &PrivateKey( vec![seed; 32] ) ).unwrap() ) }
 
-    let (reader, writer) = socket.split();
-    temp_handshake_until_tls_is_implemented(reader, writer, signer)
+    #[test]
+    fn test_handshake_authenticates_and_encrypts()
+    {
+        let mut reactor = reactor::Core::new().unwrap();
+        let handle = reactor.handle();
+        let (server_signer, client_signer) = (signer(1), signer(2));
+
+        let listener = TcpListener::bind( &"127.0.0.1:0".parse().unwrap(), &handle ).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server_fut = listener.incoming().into_future()
+            .map_err( |(e,_incoming)| e.context(ErrorKind::TlsHandshakeFailed).into() )
+            .and_then( { let server_signer = server_signer.clone(); move |(conn, _incoming)| {
+                let (socket, _addr) = conn.unwrap();
+                let (reader, writer) = socket.split();
+                ecdh_handshake(reader, writer, server_signer)
+            } } )
+            .and_then( |(reader, writer, ctx)|
+                io::read_exact(reader, vec![0u8; 5])
+                    .and_then( |(_reader, msg)| io::write_all(writer, msg) )
+                    .map( move |_| ctx )
+                    .map_err( |e| e.context(ErrorKind::TlsHandshakeFailed).into() ) );
+
+        let client_fut = TcpStream::connect(&addr, &handle)
+            .map_err( |e| e.context(ErrorKind::TlsHandshakeFailed).into() )
+            .and_then( { let client_signer = client_signer.clone(); move |socket| {
+                let (reader, writer) = socket.split();
+                ecdh_handshake(reader, writer, client_signer)
+            } } )
+            .and_then( |(reader, writer, ctx)|
+                io::write_all(writer, b"hello".to_vec())
+                    .and_then( |(writer, _buf)| io::flush(writer) )
+                    .and_then( |_writer| io::read_exact(reader, vec![0u8; 5]) )
+                    .map( move |(_reader, echo)| (ctx, echo) )
+                    .map_err( |e| e.context(ErrorKind::TlsHandshakeFailed).into() ) );
+
+        let (server_ctx, (client_ctx, echo)) = reactor.run( server_fut.join(client_fut) ).unwrap();
+        assert_eq!( server_ctx.peer_id(), client_signer.profile_id() );
+        assert_eq!( client_ctx.peer_id(), server_signer.profile_id() );
+        assert_eq!( echo, b"hello".to_vec() );
+    }
+
+    #[test]
+    fn test_frame_roundtrip_and_tampering()
+    {
+        let key = [7u8; 32];
+        let mut writer = EncryptedWriter::new( Vec::new(), &key );
+        writer.write_all(b"some secret payload").unwrap();
+        let mut wire = writer.inner.clone();
+        assert!( ! wire.windows(6).any( |w| w == b"secret" ) );
+
+        let mut reader = EncryptedReader::new( wire.as_slice(), &key );
+        let mut plain = Vec::new();
+        reader.read_to_end(&mut plain).unwrap();
+        assert_eq!( plain, b"some secret payload".to_vec() );
+
+        let last = wire.len() - 1;
+        wire[last] ^= 1;
+        let mut reader = EncryptedReader::new( wire.as_slice(), &key );
+        assert!( reader.read_to_end(&mut Vec::new()).is_err() );
+    }
 }
@@ -3,28 +3,37 @@ extern crate bytes;
 extern crate capnp;
 #[macro_use]
 extern crate capnp_rpc;
+extern crate chacha20poly1305;
 extern crate ed25519_dalek;
 #[macro_use]
 extern crate failure;
 extern crate futures;
+extern crate hkdf;
 #[macro_use]
 extern crate log;
 extern crate multiaddr;
 extern crate multibase;
 extern crate multihash;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
+extern crate rand;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;
+extern crate sha2;
 extern crate signatory;
 extern crate signatory_dalek;
 extern crate structopt;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate toml;
+extern crate x25519_dalek;
 
 
 
+pub mod canonical;
 pub mod crypto;
 pub mod error;
 pub mod future;
@@ -34,14 +43,17 @@ pub mod util;
 
 
 
-use std::{rc::Rc, str};
+use std::{rc::Rc, str, time::Duration};
 
 use bincode::serialize;
+use failure::Fail;
 use futures::{Future, sync::mpsc};
+use rand::{RngCore, rngs::OsRng};
 use multiaddr::{Multiaddr, ToMultiaddr};
 use serde::{Deserialize, Deserializer, Serializer};
 use serde::{de::Error as DeSerError, ser::SerializeSeq};
 
+use canonical::*;
 use crypto::{ProfileValidator, SignatureValidator};
 use ::error::*;
 
@@ -50,7 +62,7 @@ use ::error::*;
 pub const CHANNEL_CAPACITY: usize = 1;
 
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct ProfileId(pub Vec<u8>); // NOTE multihash::encode() output
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
@@ -59,7 +71,7 @@ pub struct PublicKey(pub Vec<u8>);
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 pub struct PrivateKey(pub Vec<u8>);
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 pub struct Signature(pub Vec<u8>);
 
 
@@ -71,7 +83,7 @@ pub trait Signer
     fn profile_id(&self) -> &ProfileId;
 
     fn public_key(&self) -> &PublicKey;
-    // NOTE the data to be signed ideally will be the output from Mudlee's multicodec lib
+    // NOTE the data to be signed is produced by `canonical::CanonicalEncoder`, except for legacy relation proofs
     fn sign(&self, data: &[u8]) -> Signature;
 }
 
@@ -81,8 +93,7 @@ pub trait Signer
 pub struct PersonaFacet
 {
     /// `homes` contain items with `relation_type` "home", with proofs included.
-    /// Current implementation supports only a single home stored in `homes[0]`,
-    /// Support for multiple homes will be implemented in a future release.
+    /// Homes are listed in order of preference, clients try them one after the other on failure.
     pub homes:  Vec<RelationProof>,
     pub data:   Vec<u8>,
 }
@@ -97,9 +108,42 @@ pub struct HomeFacet
     #[serde(serialize_with = "serialize_multiaddr_vec")]
     #[serde(deserialize_with = "deserialize_multiaddr_vec")]
     pub addrs:  Vec<Multiaddr>,
+    /// JSON encoded `HomeFacetData`, empty if there is nothing to add to `addrs`
     pub data:   Vec<u8>,
 }
 
+
+/// Details of a home not fitting into other fields of its facet
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct HomeFacetData
+{
+    /// Onion service addresses like "/onion3/<service id>:<port>"
+    // NOTE the pinned multiaddr version cannot represent /onion3, so these cannot be listed in `addrs`
+    #[serde(default)]
+    pub onion_addrs:    Vec<String>,
+}
+
+
+impl HomeFacet
+{
+    pub fn home_data(&self) -> Result<HomeFacetData, Error>
+    {
+        if self.data.is_empty()
+            { return Ok( HomeFacetData::default() ); }
+        let home_data = serde_json::from_slice(&self.data)
+            .map_err( |e| e.context(ErrorKind::ProfileValidationFailed) )?;
+        Ok(home_data)
+    }
+
+
+    pub fn set_home_data(&mut self, home_data: &HomeFacetData) -> Result<(), Error>
+    {
+        self.data = if *home_data == HomeFacetData::default() { Vec::new() }
+            else { serde_json::to_vec(home_data).map_err( |e| e.context(ErrorKind::ProfileUpdateFailed) )? };
+        Ok( () )
+    }
+}
+
 // NOTE Given for each SUPPORTED app, not currently available (checked in) app, checkins are managed differently
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 pub struct ApplicationFacet
@@ -133,7 +177,15 @@ pub struct Profile
     /// Public key used for validating the identity of the profile.
     pub public_key: PublicKey,
     pub facet:      ProfileFacet, // TODO consider redesigning facet Rust types/storage
-    // TODO consider having a signature of the profile data here
+
+    /// Incremented by the owner on every change, documents older than an already known version are rejected.
+    // NOTE missing from profiles serialized before signing was introduced
+    #[serde(default)]
+    pub version:    u64,
+
+    /// Signature of the profile key over all other fields, empty if the document is not signed yet.
+    #[serde(default)]
+    pub signature:  Signature,
 }
 
 
@@ -154,28 +206,62 @@ pub type AsyncResult<T,E> = Box< Future<Item=T, Error=E> >;
 pub type AsyncStream<Elem, RemoteErr> = mpsc::Receiver< std::result::Result<Elem, RemoteErr> >;
 pub type AsyncSink<Elem, RemoteErr>   = mpsc::Sender< std::result::Result<Elem, RemoteErr> >;
 
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+pub enum FacetType
+{
+    Home,
+    Persona,
+    Application,
+}
+
+
+/// Criteria for `ProfileRepo::list()`, an empty (default) filter matches all profiles.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+pub struct ProfileListFilter
+{
+    pub facet_type: Option<FacetType>,
+    /// Match only profiles with an ApplicationFacet of this application
+    pub app_id:     Option<ApplicationId>,
+    /// Number of matching profiles to skip, used for paging
+    pub offset:     u32,
+    /// Maximum number of profiles returned, `None` means no limit.
+    /// Servers may return fewer profiles than requested to keep responses small, clients then page with `offset`.
+    pub limit:      Option<u32>,
+}
+
+
 /// Potentially a whole network of nodes with internal routing and sharding
 pub trait ProfileRepo
 {
-//    /// List all profiles that can be load()'ed or resolve()'d.
-//    fn list(&self, /* TODO what filter criteria should we have here? */ ) ->
-//        HomeStream<Profile,String>;
+    /// List all profiles that can be load()'ed or resolve()'d, ordered by their ids.
+    fn list(&self, filter: &ProfileListFilter) -> AsyncResult<Vec<Profile>, Error>;
 
     /// Look for specified `id` and return. This might involve searching for the latest version
     /// of the profile in the dht, but if it's the profile's home server, could come from memory, too.
     fn load(&self, id: &ProfileId) -> AsyncResult<Profile, Error>;
 
-//    /// Same as load(), but also contains hints for resolution, therefore it's more efficient than load(id)
-//    ///
-//    /// The `url` may contain
-//    /// * ProfileID (mandatory)
-//    /// * some profile metadata (for user experience enhancement) (big fat warning should be thrown if it does not match the latest info)
-//    /// * ProfileID of its home server
-//    /// * last known multiaddress(es) of its home server
-//    fn resolve(&self, url: &str) ->
-//        AsyncResult<Profile, Error>;
+    /// Same as load(), but also contains hints for resolution, therefore it's more efficient than load(id)
+    ///
+    /// The `url` is in the format of `ProfileUrl` and may contain
+    /// * ProfileID (mandatory)
+    /// * ProfileID of its home server
+    /// * last known multiaddress(es) of its home server
+    fn resolve(&self, url: &str) -> AsyncResult<Profile, Error>;
 
-    // TODO notifications on profile updates should be possible
+    // NOTE notifications on profile updates are available from the home of the profile, see Home::subscribe()
+}
+
+
+
+/// A shareable link to a profile, in the format of
+/// `mercury:<profile_id>?home=<home_profile_id>&addr=<home_multiaddr>&addr=<home_multiaddr>`,
+/// where all parts but the profile id are optional hints to find the profile faster.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ProfileUrl
+{
+    pub profile_id: ProfileId,
+    pub home_id:    Option<ProfileId>,
+    pub home_addrs: Vec<Multiaddr>,
 }
 
 
@@ -199,17 +285,31 @@ impl OwnProfile
 
 
 
-// NOTE the binary blob to be signed is rust-specific: Strings are serialized to a u64 (size) and the encoded string itself.
-// TODO consider if this is platform-agnostic enough, especially when combined with capnproto
+/// The part of a relation half proof covered by the signature, see `canonical` for the signed bytes.
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 pub struct RelationSignablePart {
     pub relation_type: String,
     pub signer_id: ProfileId,
     pub peer_id: ProfileId,
-    // TODO is a nonce needed?
+    pub metadata: RelationMetadata,
 }
 
 
+/// Details signed together with both halves of a relation to prevent replaying them.
+/// Proofs signed before versioning was introduced have the default value, i.e. `VERSION_LEGACY`,
+/// their signatures cover only the relation type and the profile ids.
+#[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+pub struct RelationMetadata
+{
+    pub version:        u8,
+    /// Random bytes making each signed relation unique
+    pub nonce:          Vec<u8>,
+    /// Seconds since the unix epoch
+    pub created_at:     u64,
+    /// Seconds since the unix epoch, the relation is not valid after this time
+    pub expires_at:     Option<u64>,
+}
+
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
 pub struct RelationHalfProof
@@ -218,17 +318,28 @@ pub struct RelationHalfProof
     pub signer_id:      ProfileId,
     pub peer_id:        ProfileId,
     pub signature:      Signature,
-    // TODO is a nonce needed?
+    // NOTE missing from proofs serialized before versioning was introduced
+    #[serde(default)]
+    pub metadata:       RelationMetadata,
 }
 
 impl RelationHalfProof
 {
+    /// A half proof for a relation that does not expire.
     pub fn new(relation_type: &str, peer_id: &ProfileId, signer: &Signer) -> Self
+        { Self::with_metadata( relation_type, peer_id, RelationMetadata::new(None), signer ) }
+
+    /// A half proof for a relation that expires after `validity`.
+    pub fn new_expiring(relation_type: &str, peer_id: &ProfileId, validity: Duration, signer: &Signer) -> Self
+        { Self::with_metadata( relation_type, peer_id, RelationMetadata::new( Some(validity) ), signer ) }
+
+    fn with_metadata(relation_type: &str, peer_id: &ProfileId, metadata: RelationMetadata, signer: &Signer) -> Self
     {
         let mut result = Self{ relation_type: relation_type.to_owned(),
                                signer_id: signer.profile_id().to_owned(),
                                peer_id: peer_id.to_owned(),
-                               signature: Signature( Vec::new() ) };
+                               signature: Signature( Vec::new() ),
+                               metadata };
         result.signature = RelationSignablePart::from(&result).sign(signer);
         result
     }
@@ -243,7 +354,25 @@ pub struct RelationProof
     pub a_signature:    Signature,
     pub b_id:           ProfileId,
     pub b_signature:    Signature,
-    // TODO is a nonce needed?
+    /// Shared by both halves, the remaining half is signed with the metadata of the initiator's half proof
+    // NOTE missing from proofs serialized before versioning was introduced
+    #[serde(default)]
+    pub metadata:       RelationMetadata,
+}
+
+
+
+/// A statement signed by either party of a relation that the relation is not valid anymore.
+/// It is delivered to and stored by the homes of both parties, so calls carrying the revoked proof are refused.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+pub struct RelationRevocation
+{
+    pub relation:   RelationProof,
+    pub revoker_id: ProfileId,
+    /// Seconds since the unix epoch
+    pub revoked_at: u64,
+    /// The signature of the revoker
+    pub signature:  Signature,
 }
 
 
@@ -254,19 +383,74 @@ pub struct HomeInvitation
 {
     pub home_id:    ProfileId,
 
-    /// A unique string that identifies the invitation
+    /// A unique random string that identifies the invitation, a home accepts each voucher only once
     pub voucher:    String,
 
+    /// Seconds since the unix epoch, the invitation cannot be used after this time
+    pub expires_at: u64,
+
     /// The signature of the home
     pub signature:  Signature,
-    // TODO is a nonce needed?
-    // TODO is an expiration time needed?
 }
 
+/// A forwarding record issued and signed by the old home of a profile that moved to new home(s).
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct ProfileRedirect
+{
+    pub profile_id:     ProfileId,
+    /// The profile after migration, its persona facet lists the new home(s)
+    pub new_profile:    Profile,
+    pub old_home_id:    ProfileId,
+    /// Seconds since the unix epoch, the old home serves the redirect until this time
+    pub expires_at:     u64,
+    /// The signature of the old home
+    pub signature:      Signature,
+}
+
+
+
 impl HomeInvitation
 {
-    pub fn new(home_id: &ProfileId, voucher: &str, signature: &Signature) -> Self
-        { Self{ home_id: home_id.to_owned(), voucher: voucher.to_owned(), signature: signature.to_owned() } }
+    pub fn new(home_id: &ProfileId, voucher: &str, expires_at: u64, signature: &Signature) -> Self
+    {
+        Self{ home_id: home_id.to_owned(), voucher: voucher.to_owned(),
+              expires_at, signature: signature.to_owned() }
+    }
+
+    /// Create a new invitation with a random voucher, signed by the home.
+    pub fn issue(validity: Duration, home_signer: &Signer) -> Self
+    {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let voucher = ::multibase::encode(::multibase::Base::Base64url, &nonce);
+        let mut result = Self::new( home_signer.profile_id(), &voucher,
+            unix_timestamp() + validity.as_secs(), &Signature( Vec::new() ) );
+        result.signature = home_signer.sign( &result.signable_part() );
+        result
+    }
+
+    fn signable_part(&self) -> Vec<u8>
+    {
+        CanonicalEncoder::new(DOMAIN_INVITATION)
+            .item(&self.home_id).text(&self.voucher).uint(self.expires_at)
+            .finish()
+    }
+
+    pub fn is_expired(&self) -> bool
+        { self.expires_at <= unix_timestamp() }
+
+    /// Check that the invitation was issued by the given home and is still valid.
+    /// Note that whether the voucher was already used can be checked only by the home itself.
+    pub fn validate(&self, validator: &Validator, home_id: &ProfileId, home_pubkey: &PublicKey) -> Result<(), Error>
+    {
+        if self.home_id != *home_id
+            { Err(ErrorKind::InvalidInvitation)? }
+        if self.is_expired()
+            { Err(ErrorKind::InvitationExpired)? }
+        if ! validator.validate_signature(home_pubkey, &self.signable_part(), &self.signature)?
+            { Err(ErrorKind::InvalidInvitation)? }
+        Ok( () )
+    }
 }
 
 
@@ -281,11 +465,53 @@ pub type AppMsgStream = AsyncStream<AppMessageFrame, String>;
 pub type AppMsgSink   = AsyncSink<AppMessageFrame, String>;
 
 
+/// Random identifier chosen by the caller, so a call can be hung up even while it is still ringing.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+pub struct CallId(pub Vec<u8>);
+
+impl CallId
+{
+    /// Homes refuse calls with longer ids, generated ids are 16 bytes long
+    pub const MAX_LEN: usize = 64;
+
+    pub fn generate() -> Self
+    {
+        let mut id = vec![0u8; 16];
+        OsRng.fill_bytes(&mut id);
+        CallId(id)
+    }
+
+    /// False for empty or too long ids, e.g. read from a request that did not set the id at all
+    pub fn is_valid(&self) -> bool
+        { ! self.0.is_empty() && self.0.len() <= Self::MAX_LEN }
+}
+
+
+/// Lifecycle of a call, tracked by the home of the callee.
+/// A ringing call is either answered, rejected or cancelled (i.e. hung up by the caller or timed out),
+/// an answered call is ended by a hangup of either party.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum CallState
+{
+    Ringing,
+    Answered,
+    Rejected,
+    Cancelled,
+    Ended,
+}
+
+
 /// A struct that is passed from the caller to the callee. The callee can examine this
 /// before answering the call.
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CallRequestDetails
 {
+    /// Identifies the call when hanging up, see `Home::hangup()`.
+    pub id:             CallId,
+
+    /// The call is cancelled if not answered within this period. Homes may limit it to a shorter period.
+    pub ring_timeout:   Duration,
+
     /// Proof for the home server that the caller is authorized to call the callee.
     /// The callee can find out who's calling by looking at `relation`.
     pub relation:       RelationProof,
@@ -299,6 +525,31 @@ pub struct CallRequestDetails
     pub to_caller:      Option<AppMsgSink>,
 }
 
+impl CallRequestDetails
+{
+    pub const DEFAULT_RING_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// A call request with a new random id and the default ring timeout.
+    pub fn new(relation: RelationProof, init_payload: AppMessageFrame, to_caller: Option<AppMsgSink>) -> Self
+    {
+        Self{ id: CallId::generate(), ring_timeout: Self::DEFAULT_RING_TIMEOUT,
+              relation, init_payload, to_caller }
+    }
+}
+
+
+/// The decision of the callee on an incoming call, returned to the caller by `Home::call()`.
+#[derive(Debug)]
+pub enum CallAnswer
+{
+    /// The call was accepted, messages to the callee can be sent into the sink.
+    Accepted(AppMsgSink),
+    /// A single reply to `init_payload`, the callee does not receive further messages.
+    Replied(AppMessageFrame),
+    /// The call was rejected with a reason code defined by the application.
+    Rejected(u32),
+}
+
 
 // Interface to a single home server.
 // NOTE authentication is already done when the connection is built,
@@ -313,7 +564,9 @@ pub trait Home: ProfileRepo
     fn register(&self, own_prof: OwnProfile, half_proof: RelationHalfProof, invite: Option<HomeInvitation>) ->
         AsyncResult<OwnProfile, (OwnProfile,Error)>;
 
-    /// By calling this method, any active session of the same profile is closed.
+    /// Depending on the session policy of the home, any active session of the same profile is either
+    /// closed with a notice on its event stream, or kept alive as the session of another device.
+    /// Devices logged in concurrently all receive profile events and are all rung by incoming calls.
     fn login(&self, proof_of_home: &RelationProof) -> AsyncResult<Rc<HomeSession>, Error>;
 
     /// The peer in `half_proof` must be hosted on this home server.
@@ -325,27 +578,57 @@ pub trait Home: ProfileRepo
 
     // NOTE initiating a real P2P connection (vs a single frame push notification),
     //      the caller must fill in some message channel to itself.
-    //      A successful call returns a channel to callee.
+    //      A successful call returns the answer of the callee, e.g. a channel to the callee if accepted.
+    //      Calls not answered in time fail with `ErrorKind::CallTimedOut`.
     fn call(&self, app: ApplicationId, call_req: CallRequestDetails) ->
-        AsyncResult<Option<AppMsgSink>, Error>;
-
-// TODO consider how to do this in a later milestone
-//    fn presence(&self, rel: Relation, app: ApplicationId) ->
-//        AsyncResult<Option<AppMessageFrame>, Error>;
+        AsyncResult<CallAnswer, Error>;
+
+    /// Called by either party of a call on the home of the callee. Hanging up a ringing call cancels it,
+    /// so `call()` fails with `ErrorKind::CallCancelled`, an answered call is ended.
+    /// Both parties are notified by the end of their incoming message streams. Dropping the sink of a call
+    /// also ends the incoming stream of the other party, the call is over when both directions are closed.
+    fn hangup(&self, call: CallId) -> AsyncResult<(), Error>;
+
+    /// The revoker must be a party of the revoked relation. Called on the homes of both parties,
+    /// the home of the peer notifies the peer with a `ProfileEvent::RelationRevoked` event.
+    fn revoke_relation(&self, revocation: RelationRevocation) -> AsyncResult<(), Error>;
+
+    /// Returns where a profile formerly hosted here has moved to. Requests to a moved profile
+    /// fail with `ErrorKind::ProfileMoved` and callers are expected to follow this redirect.
+    fn redirect(&self, profile: &ProfileId) -> AsyncResult<ProfileRedirect, Error>;
+
+    /// Watch the profile of a contact hosted on this home, the caller may be hosted on any home.
+    /// `relation` must be a valid relation between the caller and the contact.
+    /// Whenever the contact publishes a new version of its profile, e.g. after joining or leaving a home,
+    /// a `ProfileEvent::ProfileUpdated` event is pushed into the returned stream.
+    /// The subscription lasts until the stream or the connection to this home is dropped.
+    fn subscribe(&self, relation: RelationProof) -> AsyncStream<ProfileEvent, String>;
+
+    /// Presence published by the peer of `rel` when checking in `app`, None if the app is not checked in.
+    /// `rel` must be a valid relation between the caller and a profile hosted on this home.
+    fn presence(&self, rel: RelationProof, app: ApplicationId) ->
+        AsyncResult<Option<AppMessageFrame>, Error>;
+
+    /// Same as presence(), but pushes the current presence and then all its changes into the returned stream.
+    fn subscribe_presence(&self, rel: RelationProof, app: ApplicationId) ->
+        AsyncStream<Option<AppMessageFrame>, String>;
 }
 
 
-#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, PartialOrd, Serialize)]
+// NOTE Profile cannot be ordered because of its multiaddresses, so neither can events
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum ProfileEvent
 {
     Unknown(Vec<u8>), // forward compatibility for protocol extension
     PairingRequest(RelationHalfProof),
     // TODO do we want to distinguish "rejected" and "notYetApproved" states for pairing, i.e. need an explicit rejected response?
     PairingResponse(RelationProof),
+    RelationRevoked(RelationRevocation),
+    /// A new signed version of a watched contact profile, see `Home::subscribe()`
+    ProfileUpdated(Profile),
 // TODO are these events needed? What others?
 //    HomeBroadcast,
 //    HomeHostingExpiry,
-//    ProfileUpdated, // from a different client instance/session
 }
 
 
@@ -357,13 +640,11 @@ pub trait IncomingCall
     fn request_details(&self) -> &CallRequestDetails;
 
     // NOTE this assumes boxed trait objects, if Rc of something else is needed, this must be revised
-    // TODO consider offering the possibility to somehow send back a single AppMessageFrame
-    //      as a reply to init_payload without a to_callee sink,
-    //      either included into this function or an additional method
-    /// Indicate to the caller that the call was answered.
+    /// Send the decision on the call back to the caller, where it is returned by `call()`.
     /// If the callee wishes to receive messages from the caller, it has to create a channel
-    /// and pass the created sink to `answer()`, which is returned by `call()` on the caller side.
-    fn answer(self: Box<Self>, to_callee: Option<AppMsgSink>) -> CallRequestDetails;
+    /// and pass the created sink in `CallAnswer::Accepted`.
+    /// If the call was cancelled meanwhile, the answer is dropped and `to_caller` is closed.
+    fn answer(self: Box<Self>, answer: CallAnswer) -> CallRequestDetails;
 }
 
 
@@ -371,25 +652,26 @@ pub trait HomeSession
 {
     fn update(&self, own_prof: OwnProfile) -> AsyncResult<(), Error>;
 
-    // NOTE newhome is a profile that contains at least one HomeFacet different than this home
+    // NOTE newhome is the persona profile after migration, it must contain at least one home
+    //      different from this one. The home then serves a signed `ProfileRedirect` to it for a retention period.
     // TODO should we return a modified OwnProfile here with this home removed from the homes of persona facet in profile?
     fn unregister(&self, newhome: Option<Profile>) -> AsyncResult<(), Error>;
 
 
     fn events(&self) -> AsyncStream<ProfileEvent, String>;
 
+    /// Contacts can query `presence` of the app until it checks in again, or its calls stream or this session is dropped.
     // TODO some kind of proof might be needed that the AppId given really belongs to the caller
-    // TODO add argument in a later milestone, presence: Option<AppMessageFrame>) ->
-    fn checkin_app(&self, app: &ApplicationId) -> AsyncStream<Box<IncomingCall>, String>;
+    fn checkin_app(&self, app: &ApplicationId, presence: Option<AppMessageFrame>) -> AsyncStream<Box<IncomingCall>, String>;
 
     // TODO remove this after testing
     fn ping(&self, txt: &str) -> AsyncResult<String, Error>;
 
 
-// TODO ban features are delayed to a later milestone
-//    fn banned_profiles(&self) -> AsyncResult<Vec<ProfileId>, Error>;
-//    fn ban(&self, profile: &ProfileId) -> AsyncResult<(), Error>;
-//    fn unban(&self, profile: &ProfileId) -> AsyncResult<(), Error>;
+    /// Pairing requests and calls from banned profiles are refused by the home before reaching the session.
+    fn banned_profiles(&self) -> AsyncResult<Vec<ProfileId>, Error>;
+    fn ban(&self, profile: &ProfileId) -> AsyncResult<(), Error>;
+    fn unban(&self, profile: &ProfileId) -> AsyncResult<(), Error>;
 }
 
 
@@ -503,10 +785,118 @@ fn deserialize_multiaddr_vec<'de, D>(deserializer: D) -> std::result::Result<Vec
 
 
 
+impl FacetType
+{
+    pub fn of(facet: &ProfileFacet) -> Option<Self>
+    {
+        match facet {
+            ProfileFacet::Home(_)           => Some(FacetType::Home),
+            ProfileFacet::Persona(_)        => Some(FacetType::Persona),
+            ProfileFacet::Application(_)    => Some(FacetType::Application),
+            ProfileFacet::Unknown(_)        => None,
+        }
+    }
+}
+
+
+
+impl ProfileListFilter
+{
+    pub fn matches(&self, profile: &Profile) -> bool
+    {
+        let facet_type_ok = self.facet_type.map_or( true, |facet_type|
+            FacetType::of(&profile.facet) == Some(facet_type) );
+        let app_ok = self.app_id.as_ref().map_or( true, |app_id|
+            match profile.facet {
+                ProfileFacet::Application(ref facet) => facet.id == *app_id,
+                _ => false,
+            } );
+        facet_type_ok && app_ok
+    }
+
+    /// Keep matching profiles only, sort them by id and cut the requested page.
+    pub fn apply(&self, mut profiles: Vec<Profile>) -> Vec<Profile>
+    {
+        profiles.retain( |profile| self.matches(profile) );
+        profiles.sort_by( |a, b| a.id.0.cmp(&b.id.0) );
+        let limit = self.limit.map_or( profiles.len(), |limit| limit as usize );
+        profiles.into_iter().skip(self.offset as usize).take(limit).collect()
+    }
+}
+
+
+
+impl ProfileUrl
+{
+    pub const SCHEME: &'static str = "mercury";
+
+    pub fn new(profile_id: ProfileId, home_id: Option<ProfileId>, home_addrs: Vec<Multiaddr>) -> Self
+        { Self{ profile_id, home_id, home_addrs } }
+
+    pub fn parse(url: &str) -> Result<Self, Error>
+    {
+        let prefix = format!("{}:", Self::SCHEME);
+        if ! url.starts_with(&prefix)
+            { Err(ErrorKind::FailedToResolveUrl)? }
+
+        let mut parts = url[prefix.len()..].splitn(2, '?');
+        let profile_id = parts.next()
+            .ok_or(ErrorKind::FailedToResolveUrl)
+            .and_then( |id_str| ProfileId::try_from(id_str).map_err( |_e| ErrorKind::FailedToResolveUrl ) )?;
+
+        let mut result = Self::new( profile_id, None, Vec::new() );
+        for param in parts.next().unwrap_or("").split('&').filter( |param| ! param.is_empty() )
+        {
+            let mut keyval = param.splitn(2, '=');
+            match ( keyval.next(), keyval.next() )
+            {
+                ( Some("home"), Some(home_str) ) => result.home_id = Some( ProfileId::try_from(home_str)
+                    .map_err( |_e| ErrorKind::FailedToResolveUrl )? ),
+                ( Some("addr"), Some(addr_str) ) => result.home_addrs.push( addr_str.to_multiaddr()
+                    .map_err( |_e| ErrorKind::MultiaddrDeserializationFailed )? ),
+                // NOTE unknown parameters are ignored for forward compatibility
+                _ => debug!("Ignoring unknown profile url parameter {}", param),
+            }
+        }
+        Ok(result)
+    }
+
+    /// A profile of the home server built from hints of the url, if there's enough info for connecting to it.
+    /// Note that its public key is unknown until connected, the profile id will be proven by the handshake.
+    pub fn home_hint(&self) -> Option<Profile>
+    {
+        match self.home_id {
+            Some(ref home_id) if ! self.home_addrs.is_empty() => Some( Profile::new( home_id, &PublicKey( Vec::new() ),
+                &ProfileFacet::Home( HomeFacet{ addrs: self.home_addrs.clone(), data: Vec::new() } ) ) ),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ProfileUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", Self::SCHEME, self.profile_id)?;
+        let mut params = Vec::new();
+        if let Some(ref home_id) = self.home_id
+            { params.push( format!("home={}", home_id) ); }
+        for addr in &self.home_addrs
+            { params.push( format!("addr={}", addr) ); }
+        if ! params.is_empty()
+            { write!(f, "?{}", params.join("&"))?; }
+        Ok( () )
+    }
+}
+
+
+
 impl Profile
 {
+    /// An unsigned document with version 0, use sign_next_version() before publishing it.
     pub fn new(id: &ProfileId, public_key: &PublicKey, facet: &ProfileFacet) -> Self
-        { Self{ id: id.to_owned(), public_key: public_key.to_owned(), facet: facet.to_owned() } }
+    {
+        Self{ id: id.to_owned(), public_key: public_key.to_owned(), facet: facet.to_owned(),
+              version: 0, signature: Signature::default() }
+    }
 
     pub fn new_home(id: ProfileId, public_key: PublicKey, address: Multiaddr) -> Self
     {
@@ -515,7 +905,46 @@ impl Profile
             data: vec![],
         };
 
-        Self { id, public_key, facet: ProfileFacet::Home(facet) }
+        Self::new( &id, &public_key, &ProfileFacet::Home(facet) )
+    }
+
+    fn signable_part(&self) -> Vec<u8>
+    {
+        CanonicalEncoder::new(DOMAIN_PROFILE)
+            .item(&self.id).item(&self.public_key).item(&self.facet).uint(self.version)
+            .finish()
+    }
+
+    pub fn is_signed(&self) -> bool
+        { ! self.signature.0.is_empty() }
+
+    /// Increment the version and sign the document, must be called after every change.
+    pub fn sign_next_version(&mut self, signer: &Signer)
+    {
+        self.version += 1;
+        self.signature = signer.sign( &self.signable_part() );
+    }
+
+    /// Check that the document is signed by the key the profile id was derived from.
+    pub fn validate(&self, validator: &Validator) -> Result<(), Error>
+    {
+        if ! self.is_signed()
+            { Err(ErrorKind::ProfileNotSigned)? }
+        validator.validate_profile(&self.public_key, &self.id)
+            .and_then( |valid| if valid { Ok( () ) } else { Err( ErrorKind::ProfileValidationFailed )? } )?;
+        // NOTE the validator returns an error for some invalid signatures instead of false
+        if ! validator.validate_signature(&self.public_key, &self.signable_part(), &self.signature).unwrap_or(false)
+            { Err(ErrorKind::InvalidProfileSignature)? }
+        Ok( () )
+    }
+
+    /// Refuse rolling back to an older version of the document than `known`.
+    /// Republishing the very same document is accepted.
+    pub fn ensure_not_older_than(&self, known: &Profile) -> Result<(), Error>
+    {
+        if self.version < known.version || ( self.version == known.version && self != known )
+            { Err(ErrorKind::StaleProfileVersion)? }
+        Ok( () )
     }
 }
 
@@ -545,16 +974,22 @@ impl PeerContext
 
 impl RelationSignablePart
 {
-    fn new(relation_type: &str, signer_id: &ProfileId, peer_id: &ProfileId) -> Self
+    fn new(relation_type: &str, signer_id: &ProfileId, peer_id: &ProfileId, metadata: &RelationMetadata) -> Self
         { Self{ relation_type: relation_type.to_owned(),
-                signer_id: signer_id.to_owned(), peer_id: peer_id.to_owned() } }
+                signer_id: signer_id.to_owned(), peer_id: peer_id.to_owned(), metadata: metadata.to_owned() } }
 
     fn serialized(&self) -> Vec<u8> {
-        // TODO unwrap() can fail here in some special cases: when there is a limit set and it's exceeded - or when .len() is
-        //      not supported for the types to be serialized. Neither is possible here, so the unwrap will not fail.
-        //      But still, to be on the safe side, this serialization shoule be swapped later with a call that cannot fail.
-        // TODO consider using unwrap_or( Vec::new() ) instead
-        serialize(self).unwrap()
+        let meta = &self.metadata;
+        if meta.version == RelationMetadata::VERSION_LEGACY {
+            // NOTE this must produce exactly the same bytes as before versioning to keep existing signatures valid.
+            //      The bincode blob is rust-specific: strings are serialized to a u64 (size) and the encoded string itself.
+            //      Serializing these types cannot fail, so the unwrap is safe.
+            return serialize( &(&self.relation_type, &self.signer_id, &self.peer_id) ).unwrap()
+        }
+        CanonicalEncoder::new(DOMAIN_RELATION)
+            .uint( u64::from(meta.version) ).text(&self.relation_type).item(&self.signer_id).item(&self.peer_id)
+            .bytes(&meta.nonce).uint(meta.created_at).option( meta.expires_at.as_ref() )
+            .finish()
     }
 
     fn sign(&self, signer: &Signer) -> Signature
@@ -562,35 +997,171 @@ impl RelationSignablePart
 }
 
 
+impl ProfileRedirect
+{
+    pub fn new(new_profile: &Profile, retention: Duration, home_signer: &Signer) -> Self
+    {
+        let mut result = Self{ profile_id: new_profile.id.to_owned(), new_profile: new_profile.to_owned(),
+                               old_home_id: home_signer.profile_id().to_owned(),
+                               expires_at: unix_timestamp() + retention.as_secs(),
+                               signature: Signature( Vec::new() ) };
+        result.signature = home_signer.sign( &result.signable_part() );
+        result
+    }
+
+    fn signable_part(&self) -> Vec<u8>
+    {
+        CanonicalEncoder::new(DOMAIN_REDIRECT)
+            .item(&self.profile_id).item(&self.new_profile).item(&self.old_home_id).uint(self.expires_at)
+            .finish()
+    }
+
+    pub fn is_expired(&self) -> bool
+        { self.expires_at <= unix_timestamp() }
+
+    /// Check that the redirect was issued by the old home for `profile` and is still in effect.
+    pub fn validate(&self, validator: &Validator, profile: &Profile, old_home_pubkey: &PublicKey) -> Result<(), Error>
+    {
+        if self.profile_id != profile.id || self.new_profile.id != profile.id
+            { Err(ErrorKind::ProfileMismatch)? }
+        if self.new_profile.public_key != profile.public_key
+            { Err(ErrorKind::PublicKeyMismatch)? }
+        self.new_profile.validate(validator)?;
+        self.new_profile.ensure_not_older_than(profile)?;
+        if self.is_expired()
+            { Err(ErrorKind::RedirectExpired)? }
+        validator.validate_profile(old_home_pubkey, &self.old_home_id)
+            .and_then( |valid| if valid { Ok( () ) } else { Err( ErrorKind::ProfileValidationFailed )? } )?;
+        if ! validator.validate_signature(old_home_pubkey, &self.signable_part(), &self.signature)?
+            { Err(ErrorKind::InvalidSignature)? }
+        Ok( () )
+    }
+}
+
+
+impl RelationRevocation
+{
+    pub fn new(relation: &RelationProof, signer: &Signer) -> Result<Self, Error>
+    {
+        relation.peer_id( signer.profile_id() )?;
+        let mut result = Self{ relation: relation.to_owned(), revoker_id: signer.profile_id().to_owned(),
+                               revoked_at: unix_timestamp(), signature: Signature( Vec::new() ) };
+        result.signature = signer.sign( &result.signable_part() );
+        Ok(result)
+    }
+
+    fn signable_part(&self) -> Vec<u8>
+    {
+        CanonicalEncoder::new(DOMAIN_REVOCATION)
+            .item(&self.relation).item(&self.revoker_id).uint(self.revoked_at)
+            .finish()
+    }
+
+    /// The other party of the revoked relation.
+    pub fn peer_id(&self) -> Result<&ProfileId, Error>
+        { self.relation.peer_id(&self.revoker_id) }
+
+    /// Check that the revocation was signed by a party of the relation.
+    pub fn validate(&self, validator: &Validator, revoker_pubkey: &PublicKey) -> Result<(), Error>
+    {
+        self.peer_id()?;
+        if ! validator.validate_signature(revoker_pubkey, &self.signable_part(), &self.signature)?
+            { Err(ErrorKind::InvalidSignature)? }
+        Ok( () )
+    }
+}
+
+
+pub fn unix_timestamp() -> u64
+{
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+        .map( |elapsed| elapsed.as_secs() )
+        .unwrap_or(0)
+}
+
+
 impl<'a> From<&'a RelationHalfProof> for RelationSignablePart {
     fn from(src: &'a RelationHalfProof) -> Self {
         RelationSignablePart{
             relation_type: src.relation_type.clone(),
             signer_id: src.signer_id.clone(),
             peer_id: src.peer_id.clone(),
+            metadata: src.metadata.clone(),
         }
     }
 }
 
 
 
+impl RelationMetadata
+{
+    pub const VERSION_LEGACY:   u8 = 0;
+    pub const VERSION_CURRENT:  u8 = 1;
+
+    // TODO legacy proofs are accepted only during a transition period, set this to false afterwards
+    pub const ACCEPT_LEGACY:    bool = true;
+    /// Tolerated difference between the clocks of the signer and the validator
+    pub const MAX_CLOCK_SKEW:   Duration = Duration::from_secs(5 * 60);
+
+    pub fn new(validity: Option<Duration>) -> Self
+    {
+        let mut nonce = vec![0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let created_at = unix_timestamp();
+        Self{ version: Self::VERSION_CURRENT, nonce, created_at,
+              expires_at: validity.map( |validity| created_at + validity.as_secs() ) }
+    }
+
+    /// Check that the relation was not created in the future and did not expire yet.
+    /// If `max_age` is given, relations created earlier than that are also rejected.
+    pub fn validate_freshness(&self, max_age: Option<Duration>) -> Result<(), Error>
+    {
+        if self.version == Self::VERSION_LEGACY {
+            if Self::ACCEPT_LEGACY { return Ok( () ) }
+            Err(ErrorKind::UnsupportedRelationVersion)?
+        }
+        if self.version > Self::VERSION_CURRENT
+            { Err(ErrorKind::UnsupportedRelationVersion)? }
+
+        let now = unix_timestamp();
+        if self.created_at > now + Self::MAX_CLOCK_SKEW.as_secs()
+            { Err(ErrorKind::RelationNotYetValid)? }
+        if let Some(expires_at) = self.expires_at {
+            if expires_at <= now { Err(ErrorKind::RelationExpired)? }
+        }
+        if let Some(max_age) = max_age {
+            if self.created_at + max_age.as_secs() + Self::MAX_CLOCK_SKEW.as_secs() < now
+                { Err(ErrorKind::RelationExpired)? }
+        }
+        Ok( () )
+    }
+}
+
+
+
 impl RelationProof
 {
     pub const RELATION_TYPE_HOSTED_ON_HOME:         &'static str = "hosted_on_home";
     pub const RELATION_TYPE_ENABLE_CALLS_BETWEEN:   &'static str = "enable_call_between";
 
+    /// Pairing requests are accepted only within this time after the half proof was signed
+    pub const HALF_PROOF_MAX_AGE: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
     pub fn new(relation_type: &str,
                a_id: &ProfileId, a_signature: &Signature,
-               b_id: &ProfileId, b_signature: &Signature) -> Self
+               b_id: &ProfileId, b_signature: &Signature,
+               metadata: &RelationMetadata) -> Self
     {
         if a_id < b_id
             { Self{ relation_type: relation_type.to_owned(),
                     a_id: a_id.to_owned(), a_signature: a_signature.to_owned(),
-                    b_id: b_id.to_owned(), b_signature: b_signature.to_owned() } }
+                    b_id: b_id.to_owned(), b_signature: b_signature.to_owned(),
+                    metadata: metadata.to_owned() } }
         // TODO decide on inverting relation_type if needed, e.g. `a_is_home_of_b` vs `b_is_home_of_a`
         else{ Self{ relation_type: relation_type.to_owned(),
                     a_id: b_id.to_owned(), a_signature: b_signature.to_owned(),
-                    b_id: a_id.to_owned(), b_signature: a_signature.to_owned() } }
+                    b_id: a_id.to_owned(), b_signature: a_signature.to_owned(),
+                    metadata: metadata.to_owned() } }
     }
 
     pub fn sign_remaining_half(half_proof: &RelationHalfProof, signer: &Signer) -> Result<Self, Error>
@@ -600,9 +1171,9 @@ impl RelationProof
             { Err(ErrorKind::RelationSigningFailed)? }
 
         let signable = RelationSignablePart::new(
-            &half_proof.relation_type, &my_profile_id, &half_proof.signer_id);
+            &half_proof.relation_type, &my_profile_id, &half_proof.signer_id, &half_proof.metadata);
         Ok( Self::new( &half_proof.relation_type, &half_proof.signer_id, &half_proof.signature,
-                       &my_profile_id, &signable.sign(signer) ) )
+                       &my_profile_id, &signable.sign(signer), &half_proof.metadata ) )
     }
 
     // TODO relation-type should be more sophisticated once we have a proper metainfo schema there
@@ -622,6 +1193,18 @@ impl RelationProof
         if self.b_id == *my_id { return Ok(&self.a_signature) }
         Err(ErrorKind::PeerIdRetreivalFailed)?
     }
+
+    /// Check only the half of the proof signed by `signer_id`, e.g. when the public key of the peer is not known.
+    pub fn validate_half(&self, validator: &Validator, signer_id: &ProfileId, signer_pubkey: &PublicKey) -> Result<(), Error>
+    {
+        self.metadata.validate_freshness(None)?;
+        let peer_id = self.peer_id(signer_id)?;
+        let signature = self.peer_signature(peer_id)?;
+        let signable = RelationSignablePart::new(&self.relation_type, signer_id, peer_id, &self.metadata);
+        if ! validator.validate_signature( signer_pubkey, &signable.serialized(), signature )?
+            { Err(ErrorKind::InvalidSignature)? }
+        Ok( () )
+    }
 }
 
 
@@ -629,6 +1212,7 @@ impl RelationProof
 pub trait Validator: ProfileValidator + SignatureValidator
 {
     fn validate_half_proof(&self, half_proof: &RelationHalfProof, signer_pubkey: &PublicKey) -> Result<(), Error> {
+        half_proof.metadata.validate_freshness( Some(RelationProof::HALF_PROOF_MAX_AGE) )?;
         self.validate_signature(signer_pubkey,
             &RelationSignablePart::from(half_proof).serialized(), &half_proof.signature)?;
         Ok(())
@@ -642,17 +1226,21 @@ pub trait Validator: ProfileValidator + SignatureValidator
         id_2: &ProfileId,
         public_key_2: &PublicKey
     ) -> Result<(), Error> {
+        relation_proof.metadata.validate_freshness(None)?;
+
         // TODO consider inverting relation_type for different directions
         let signable_a = RelationSignablePart::new(
             &relation_proof.relation_type,
             &relation_proof.a_id,
             &relation_proof.b_id,
+            &relation_proof.metadata,
         ).serialized();
 
         let signable_b = RelationSignablePart::new(
             &relation_proof.relation_type,
             &relation_proof.b_id,
             &relation_proof.a_id,
+            &relation_proof.metadata,
         ).serialized();
 
         let peer_of_id_1 = relation_proof.peer_id(&id_1)?;
@@ -742,4 +1330,120 @@ mod tests
         assert_eq!( recv_vec.len(), 1 );
         assert_eq!( recv_vec[0], item );
     }
+
+
+    #[test]
+    fn test_profile_url()
+    {
+        use multiaddr::ToMultiaddr;
+        use super::*;
+
+        let url = ProfileUrl::new( ProfileId( b"persona".to_vec() ), Some( ProfileId( b"home".to_vec() ) ),
+            vec![ "/ip4/127.0.0.1/tcp/2077".to_multiaddr().unwrap() ] );
+        let parsed = ProfileUrl::parse( &url.to_string() ).unwrap();
+        assert_eq!(parsed, url);
+        assert_eq!( parsed.home_hint().unwrap().id, ProfileId( b"home".to_vec() ) );
+
+        let bare = ProfileUrl::parse( &format!( "mercury:{}?unknown=1", ProfileId( b"persona".to_vec() ) ) ).unwrap();
+        assert_eq!( bare, ProfileUrl::new( ProfileId( b"persona".to_vec() ), None, Vec::new() ) );
+        assert!( bare.home_hint().is_none() );
+
+        assert!( ProfileUrl::parse("http://example.com").is_err() );
+    }
+
+
+    #[test]
+    fn test_profile_list_filter()
+    {
+        use super::*;
+
+        let app = |id: &[u8], app: &str| Profile::new( &ProfileId( id.to_vec() ), &PublicKey( Vec::new() ),
+            &ProfileFacet::Application( ApplicationFacet{ id: ApplicationId( app.to_owned() ), data: Vec::new() } ) );
+        let persona = Profile::new( &ProfileId( b"b".to_vec() ), &PublicKey( Vec::new() ),
+            &ProfileFacet::Persona( PersonaFacet{ homes: Vec::new(), data: Vec::new() } ) );
+        let profiles = vec![ app(b"c", "chat"), persona.clone(), app(b"a", "chat"), app(b"d", "game") ];
+
+        let all = ProfileListFilter::default().apply( profiles.clone() );
+        let ids: Vec<_> = all.iter().map( |p| p.id.0.clone() ).collect();
+        assert_eq!( ids, vec![ b"a".to_vec(), b"b".to_vec(), b"c".to_vec(), b"d".to_vec() ] );
+
+        let personas = ProfileListFilter{ facet_type: Some(FacetType::Persona), ..Default::default() };
+        assert_eq!( personas.apply( profiles.clone() ), vec![persona] );
+
+        let chat_page = ProfileListFilter{ app_id: Some( ApplicationId( "chat".to_owned() ) ),
+            offset: 1, limit: Some(1), ..Default::default() };
+        assert_eq!( chat_page.apply(profiles), vec![ app(b"c", "chat") ] );
+    }
+
+
+    #[test]
+    fn test_profile_signature()
+    {
+        use super::*;
+        use crypto::{CompositeValidator, Ed25519Signer};
+
+        let signer = Ed25519Signer::new( &PrivateKey( vec![1; 32] ) ).unwrap();
+        let validator = CompositeValidator::default();
+        let facet = ProfileFacet::Persona( PersonaFacet{ homes: Vec::new(), data: Vec::new() } );
+        let mut profile = Profile::new( signer.profile_id(), signer.public_key(), &facet );
+        assert_eq!( profile.validate(&validator).unwrap_err().kind(), ErrorKind::ProfileNotSigned );
+
+        profile.sign_next_version(&signer);
+        assert_eq!(profile.version, 1);
+        assert!( profile.validate(&validator).is_ok() );
+
+        let mut forged = profile.clone();
+        forged.facet = ProfileFacet::Persona( PersonaFacet{ homes: Vec::new(), data: b"forged".to_vec() } );
+        assert_eq!( forged.validate(&validator).unwrap_err().kind(), ErrorKind::InvalidProfileSignature );
+
+        let mut next = forged.clone();
+        next.sign_next_version(&signer);
+        assert!( next.ensure_not_older_than(&profile).is_ok() );
+        assert!( profile.ensure_not_older_than(&profile).is_ok() );
+        assert_eq!( profile.ensure_not_older_than(&next).unwrap_err().kind(), ErrorKind::StaleProfileVersion );
+        assert_eq!( forged.ensure_not_older_than(&profile).unwrap_err().kind(), ErrorKind::StaleProfileVersion );
+    }
+
+
+    #[test]
+    fn test_relation_metadata()
+    {
+        use super::*;
+        use crypto::{CompositeValidator, Ed25519Signer};
+
+        let signer_a = Ed25519Signer::new( &PrivateKey( vec![1; 32] ) ).unwrap();
+        let signer_b = Ed25519Signer::new( &PrivateKey( vec![2; 32] ) ).unwrap();
+        let validator = CompositeValidator::default();
+        let (a_id, b_id) = ( signer_a.profile_id().to_owned(), signer_b.profile_id().to_owned() );
+
+        let half_proof = RelationHalfProof::new("friend", &b_id, &signer_a);
+        assert!( validator.validate_half_proof( &half_proof, signer_a.public_key() ).is_ok() );
+        let proof = RelationProof::sign_remaining_half(&half_proof, &signer_b).unwrap();
+        assert!( validator.validate_relation_proof( &proof, &a_id, signer_a.public_key(), &b_id, signer_b.public_key() ).is_ok() );
+
+        // The signature covers the metadata
+        let mut replayed = half_proof.clone();
+        replayed.metadata.nonce = vec![0; 16];
+        assert!( validator.validate_half_proof( &replayed, signer_a.public_key() ).is_err() );
+
+        // Proofs signed before versioning are still accepted, also when deserialized without metadata
+        let mut legacy = half_proof.clone();
+        legacy.metadata = RelationMetadata::default();
+        legacy.signature = signer_a.sign( &serialize( &("friend", &a_id, &b_id) ).unwrap() );
+        assert!( validator.validate_half_proof( &legacy, signer_a.public_key() ).is_ok() );
+        let mut legacy_json = ::serde_json::to_value(&legacy).unwrap();
+        legacy_json.as_object_mut().unwrap().remove("metadata");
+        assert_eq!( ::serde_json::from_value::<RelationHalfProof>(legacy_json).unwrap(), legacy );
+
+        let now = unix_timestamp();
+        let expired = RelationMetadata{ expires_at: Some(now - 1), ..RelationMetadata::new(None) };
+        let expired_proof = RelationProof::sign_remaining_half(
+            &RelationHalfProof::with_metadata("friend", &b_id, expired, &signer_a), &signer_b ).unwrap();
+        let expired_res = validator.validate_relation_proof( &expired_proof, &a_id, signer_a.public_key(), &b_id, signer_b.public_key() );
+        assert_eq!( expired_res.unwrap_err().kind(), ErrorKind::RelationExpired );
+
+        let stale = RelationMetadata{ created_at: now - RelationProof::HALF_PROOF_MAX_AGE.as_secs() - 3600, ..RelationMetadata::new(None) };
+        let stale_half_proof = RelationHalfProof::with_metadata("friend", &b_id, stale, &signer_a);
+        assert!( validator.validate_half_proof( &stale_half_proof, signer_a.public_key() ).is_err() );
+    }
 }
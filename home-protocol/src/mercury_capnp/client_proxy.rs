@@ -1,17 +1,19 @@
-use std::time::Duration;
-
 use capnp::capability::Promise;
-use failure::Fail;
 use futures::prelude::*;
 use futures::sync::{mpsc, oneshot};
 use tokio_core::reactor;
 use tokio_core::net::TcpStream;
+use std::time::Duration;
 
 use ::*;
 use ::mercury_capnp::*;
 
 
 
+/// Period of checking whether an app dropped the stream of its incoming calls
+const CFG_CALLS_STREAM_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+
 pub struct HomeClientCapnProto
 {
     repo:    profile_repo::Client,
@@ -58,12 +60,22 @@ impl HomeClientCapnProto
 
 impl ProfileRepo for HomeClientCapnProto
 {
-//    fn list(&self, /* TODO what filter criteria should we have here? */ ) ->
-//        HomeStream<Profile, String>
-//    {
-//        // TODO properly implement this
-//        unimplemented!()
-//    }
+    fn list(&self, filter: &ProfileListFilter) -> AsyncResult<Vec<Profile>, Error>
+    {
+        let mut request = self.repo.list_request();
+        request.get().init_filter().fill_from(filter);
+
+        let resp_fut = request.send().promise
+            .and_then( |resp|
+                resp.get()
+                    .and_then( |res| res.get_profiles() )
+                    .and_then( |profiles_capnp| profiles_capnp.iter()
+                        .map( |profile_capnp| Profile::try_from(profile_capnp) )
+                        .collect::<Result<Vec<_>,_>>() ) )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToListProfiles) );
+
+        Box::new(resp_fut)
+    }
 
 
     fn load(&self, id: &ProfileId) -> AsyncResult<Profile, Error>
@@ -78,28 +90,29 @@ impl ProfileRepo for HomeClientCapnProto
                 let profile = Profile::try_from(profile_capnp);
                 Promise::result(profile)
             } )
-            .map_err( |e| e.context(ErrorKind::FailedToLoadProfile).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToLoadProfile) );
 
         Box::new(resp_fut)
     }
 
-//    // NOTE should be more efficient than load(id) because URL is supposed to contain hints for resolution
-//    fn resolve(&self, url: &str) -> AsyncResult<Profile, Error>
-//    {
-//        let mut request = self.repo.resolve_request();
-//        request.get().set_profile_url(url);
-//
-//        let resp_fut = request.send().promise
-//            .and_then( |resp|
-//            {
-//                let profile_capnp = pry!( pry!( resp.get() ).get_profile() );
-//                let profile = Profile::try_from(profile_capnp);
-//                Promise::result(profile)
-//            } )
-//            .map_err( |e| e.context(ErrorKind::FailedToResolveUrl).into() );
-//
-//        Box::new(resp_fut)
-//    }
+
+    // NOTE should be more efficient than load(id) because URL is supposed to contain hints for resolution
+    fn resolve(&self, url: &str) -> AsyncResult<Profile, Error>
+    {
+        let mut request = self.repo.resolve_request();
+        request.get().set_profile_url(url);
+
+        let resp_fut = request.send().promise
+            .and_then( |resp|
+            {
+                let profile_capnp = pry!( pry!( resp.get() ).get_profile() );
+                let profile = Profile::try_from(profile_capnp);
+                Promise::result(profile)
+            } )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToResolveUrl) );
+
+        Box::new(resp_fut)
+    }
 }
 
 
@@ -116,7 +129,7 @@ impl Home for HomeClientCapnProto
                 resp.get()
                     .and_then( |res| res.get_own_profile() )
                     .and_then( |own_prof_capnp| OwnProfile::try_from(own_prof_capnp) ) )
-            .map_err( |e| e.context(ErrorKind::FailedToClaimProfile).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToClaimProfile) );
 
         Box::new(resp_fut)
     }
@@ -136,7 +149,7 @@ impl Home for HomeClientCapnProto
                 resp.get()
                     .and_then( |res| res.get_own_profile() )
                     .and_then( |own_prof_capnp| OwnProfile::try_from(own_prof_capnp) ) )
-            .map_err( move |e| (own_profile, e.context(ErrorKind::RegisterFailed).into() ) );
+            .map_err( move |e| (own_profile, mercury_capnp::from_capnp_error(e, ErrorKind::RegisterFailed) ) );
 
         Box::new(resp_fut)
     }
@@ -156,7 +169,7 @@ impl Home for HomeClientCapnProto
                     .map( |session_client| Rc::new(
                         HomeSessionClientCapnProto::new(session_client, handle_clone) ) as Rc<HomeSession> )
             } )
-            .map_err( |e| e.context(ErrorKind::FailedToCreateSession).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToCreateSession) );
 
         Box::new(resp_fut)
     }
@@ -171,7 +184,7 @@ impl Home for HomeClientCapnProto
 
         let resp_fut = request.send().promise
             .map( |_resp| () )
-            .map_err( |e| e.context(ErrorKind::PairRequestFailed).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::PairRequestFailed) );
 
         Box::new(resp_fut)
     }
@@ -185,19 +198,21 @@ impl Home for HomeClientCapnProto
 
         let resp_fut = request.send().promise
             .map( |_resp| () )
-            .map_err( |e| e.context(ErrorKind::PairResponseFailed).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::PairResponseFailed) );
 
         Box::new(resp_fut)
     }
 
 
     fn call(&self, app: ApplicationId, call_req: CallRequestDetails) ->
-        AsyncResult<Option<AppMsgSink>, Error>
+        AsyncResult<CallAnswer, Error>
     {
         let mut request = self.home.call_request();
         request.get().init_relation().fill_from(&call_req.relation);
         request.get().set_app( (&app).into() );
         request.get().set_init_payload( (&call_req.init_payload).into() );
+        request.get().set_call_id( (&call_req.id).into() );
+        request.get().set_ring_timeout_ms( mercury_capnp::duration_to_millis(call_req.ring_timeout) );
 
         if let Some(send) = call_req.to_caller
         {
@@ -210,15 +225,120 @@ impl Home for HomeClientCapnProto
         let handle_clone = self.handle.clone();
         let resp_fut = request.send().promise
             .and_then( |resp| resp.get()
-                .map( |res| res.get_to_callee()
-                    .map( |to_callee_capnp| mercury_capnp::fwd_appmsg(to_callee_capnp, handle_clone) )
-                    .ok()
-                )
-            )
-            .map_err( |e| e.context(ErrorKind::CallFailed).into() );
+                .and_then( |res| res.get_answer() )
+                .and_then( |answer| mercury_capnp::read_call_answer(answer, handle_clone) ) )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::CallFailed) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn hangup(&self, call: CallId) -> AsyncResult<(), Error>
+    {
+        let mut request = self.home.hangup_request();
+        request.get().set_call_id( (&call).into() );
+
+        let resp_fut = request.send().promise
+            .map( |_resp| () )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::HangupFailed) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn redirect(&self, profile: &ProfileId) -> AsyncResult<ProfileRedirect, Error>
+    {
+        let mut request = self.home.redirect_request();
+        request.get().set_profile_id( profile.into() );
+
+        let resp_fut = request.send().promise
+            .and_then( |resp|
+                resp.get()
+                    .and_then( |res| res.get_redirect() )
+                    .and_then( |redirect_capnp| ProfileRedirect::try_from(redirect_capnp) ) )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToGetRedirect) );
 
         Box::new(resp_fut)
     }
+
+
+    fn revoke_relation(&self, revocation: RelationRevocation) -> AsyncResult<(), Error>
+    {
+        let mut request = self.home.revoke_relation_request();
+        request.get().init_revocation().fill_from(&revocation);
+
+        let resp_fut = request.send().promise
+            .map( |_resp| () )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::RevokeRelationFailed) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn subscribe(&self, relation: RelationProof) -> AsyncStream<ProfileEvent, String>
+    {
+        let (send, recv) = mpsc::channel(1);
+        let listener = ProfileEventDispatcherCapnProto::new( send.clone() );
+        let listener_capnp = mercury_capnp::profile_event_listener::ToClient::new(listener)
+            .into_client::<::capnp_rpc::Server>();
+
+        let mut request = self.home.subscribe_request();
+        request.get().init_relation().fill_from(&relation);
+        request.get().set_event_listener(listener_capnp);
+
+        self.handle.spawn(
+            request.send().promise
+                .map( |_resp| () )
+                .or_else( move |e|
+                    send.send( Err( format!("Subscription failed: {}", e) ) )
+                        .map( |_sink| () )
+                        .map_err( |_err| () ) )
+        );
+
+        recv
+    }
+
+
+    fn presence(&self, rel: RelationProof, app: ApplicationId) -> AsyncResult<Option<AppMessageFrame>, Error>
+    {
+        let mut request = self.home.presence_request();
+        request.get().init_relation().fill_from(&rel);
+        request.get().set_app( (&app).into() );
+
+        let resp_fut = request.send().promise
+            .and_then( |resp|
+                resp.get()
+                    .and_then( |res| res.get_presence() )
+                    .and_then( |presence_capnp| Option::<AppMessageFrame>::try_from(presence_capnp) ) )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToGetPresence) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn subscribe_presence(&self, rel: RelationProof, app: ApplicationId) -> AsyncStream<Option<AppMessageFrame>, String>
+    {
+        let (send, recv) = mpsc::channel(1);
+        let listener = AppPresenceDispatcherCapnProto::new( send.clone() );
+        let listener_capnp = mercury_capnp::app_presence_listener::ToClient::new(listener)
+            .into_client::<::capnp_rpc::Server>();
+
+        let mut request = self.home.subscribe_presence_request();
+        request.get().init_relation().fill_from(&rel);
+        request.get().set_app( (&app).into() );
+        request.get().set_presence_listener(listener_capnp);
+
+        self.handle.spawn(
+            request.send().promise
+                .map( |_resp| () )
+                .or_else( move |e|
+                    send.send( Err( format!("Presence subscription failed: {}", e) ) )
+                        .map( |_sink| () )
+                        .map_err( |_err| () ) )
+        );
+
+        recv
+    }
 }
 
 
@@ -264,6 +384,47 @@ impl mercury_capnp::profile_event_listener::Server for ProfileEventDispatcherCap
 
 
 
+struct AppPresenceDispatcherCapnProto
+{
+    sender: AsyncSink<Option<AppMessageFrame>, String>,
+}
+
+impl AppPresenceDispatcherCapnProto
+{
+    fn new(sender: AsyncSink<Option<AppMessageFrame>, String>) -> Self
+        { Self{ sender: sender } }
+}
+
+
+impl mercury_capnp::app_presence_listener::Server for AppPresenceDispatcherCapnProto
+{
+    fn receive(&mut self, params: mercury_capnp::app_presence_listener::ReceiveParams,
+               _results: mercury_capnp::app_presence_listener::ReceiveResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let presence_capnp = pry!( pry!( params.get() ).get_presence() );
+        let presence = pry!( Option::<AppMessageFrame>::try_from(presence_capnp) );
+        let recv_fut = self.sender.clone().send( Ok(presence) )
+            .map( |_sink| () )
+            .map_err( |e| ::capnp::Error::failed( format!("Failed to delegate presence: {}", e) ) );
+        Promise::from_future(recv_fut)
+    }
+
+
+    fn error(&mut self, params: mercury_capnp::app_presence_listener::ErrorParams,
+              _results: mercury_capnp::app_presence_listener::ErrorResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let error = pry!( pry!( params.get() ).get_error() ).into();
+        let recv_fut = self.sender.clone().send( Err(error) )
+            .map( |_sink| () )
+            .map_err( |e| ::capnp::Error::failed( format!("Failed to delegate presence error: {}", e) ) );
+        Promise::from_future(recv_fut)
+    }
+}
+
+
+
 pub struct HomeSessionClientCapnProto
 {
     session: mercury_capnp::home_session::Client,
@@ -288,7 +449,7 @@ impl HomeSession for HomeSessionClientCapnProto
 
         let resp_fut = request.send().promise
             .map( |_resp| () )
-            .map_err(  |e| e.context(ErrorKind::ProfileUpdateFailed).into());
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::ProfileUpdateFailed) );
 
         Box::new(resp_fut)
     }
@@ -303,7 +464,7 @@ impl HomeSession for HomeSessionClientCapnProto
 
         let resp_fut = request.send().promise
             .map( |_resp| () )
-            .map_err( |e| e.context(ErrorKind::UnregisterFailed).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::UnregisterFailed) );
 
         Box::new(resp_fut)
     }
@@ -334,7 +495,7 @@ impl HomeSession for HomeSessionClientCapnProto
     }
 
 
-    fn checkin_app(&self, app: &ApplicationId) -> AsyncStream<Box<IncomingCall>, String>
+    fn checkin_app(&self, app: &ApplicationId, presence: Option<AppMessageFrame>) -> AsyncStream<Box<IncomingCall>, String>
     {
         // Send a call dispatcher proxy to remote home through which we'll accept incoming calls
         let (send, recv) = mpsc::channel(1);
@@ -346,9 +507,14 @@ impl HomeSession for HomeSessionClientCapnProto
         let mut request = self.session.checkin_app_request();
         request.get().set_app( app.into() );
         request.get().set_call_listener(listener_capnp);
+        if let Some(ref presence) = presence
+            { request.get().set_presence( presence.into() ); }
 
         // We can either return Future<Stream> or
-        // return the stream directly and spawn sending the request in another fiber
+        // return the stream directly and spawn sending the request in another fiber.
+        // NOTE the request is pending while the home delivers calls, cancelling it when the app
+        //      dropped its stream lets the home know that the app is not listening anymore
+        let dropped_fut = ::future::receiver_dropped( send.clone(), CFG_CALLS_STREAM_CHECK_INTERVAL, &self.handle );
         self.handle.spawn(
             request.send().promise
                 .map( |_resp| () )
@@ -357,6 +523,9 @@ impl HomeSession for HomeSessionClientCapnProto
                         .map( |_sink| () )
                         // TODO what to do if failed to send error?
                         .map_err( |_err| () ) )
+                .select(dropped_fut)
+                .map( |_done| () )
+                .map_err( |_err| () )
         );
 
         recv
@@ -375,15 +544,58 @@ impl HomeSession for HomeSessionClientCapnProto
                     .and_then( |res| res.get_pong() )
                     .map( |pong| pong.to_owned() )
             } )
-            .map_err( |e| e.context(ErrorKind::PingFailed).into() );
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::PingFailed) );
 
         Box::new(resp_fut)
     }
-}
 
 
+    fn banned_profiles(&self) -> AsyncResult<Vec<ProfileId>, Error>
+    {
+        let request = self.session.banned_profiles_request();
+
+        let resp_fut = request.send().promise
+            .and_then( |resp|
+            {
+                let profiles_capnp = resp.get()?.get_profiles()?;
+                let mut profiles = Vec::new();
+                for profile_res in profiles_capnp.iter()
+                    { profiles.push( ProfileId::from( profile_res? ) ); }
+                Ok(profiles)
+            } )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::FailedToGetBannedProfiles) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn ban(&self, profile: &ProfileId) -> AsyncResult<(), Error>
+    {
+        let mut request = self.session.ban_request();
+        request.get().set_profile( profile.into() );
+
+        let resp_fut = request.send().promise
+            .map( |_resp| () )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::BanFailed) );
+
+        Box::new(resp_fut)
+    }
+
+
+    fn unban(&self, profile: &ProfileId) -> AsyncResult<(), Error>
+    {
+        let mut request = self.session.unban_request();
+        request.get().set_profile( profile.into() );
+
+        let resp_fut = request.send().promise
+            .map( |_resp| () )
+            .map_err( |e| mercury_capnp::from_capnp_error(e, ErrorKind::UnbanFailed) );
+
+        Box::new(resp_fut)
+    }
+}
+
 
-const CALL_TIMEOUT_SECS: u32 = 30;
 
 struct CallDispatcherCapnProto
 {
@@ -401,7 +613,7 @@ impl CallDispatcherCapnProto
 impl mercury_capnp::call_listener::Server for CallDispatcherCapnProto
 {
     // Receive notification on an incoming call request and
-    // send back a message channel if answering the call
+    // send back the answer of the callee, e.g. a message channel if accepting the call
     fn receive(&mut self, params: mercury_capnp::call_listener::ReceiveParams,
                mut results: mercury_capnp::call_listener::ReceiveResults)
         -> Promise<(), ::capnp::Error>
@@ -417,25 +629,17 @@ impl mercury_capnp::call_listener::Server for CallDispatcherCapnProto
             .ok();
 
         let (one_send, one_recv) = oneshot::channel();
-        let answer_fut = one_recv.map( |to_callee_opt: Option<AppMsgSink>|
-        {
-            // If the call is accepted then set up a to_callee channel and send it back in the response
-            to_callee_opt.map( move |to_callee|
-            {
-                let listener = AppMessageDispatcherCapnProto::new(to_callee);
-                // TODO consider how to drop/unregister this object from capnp if the stream is dropped
-                let listener_capnp = mercury_capnp::app_message_listener::ToClient::new(listener)
-                    .into_client::<::capnp_rpc::Server>();
-                results.get().set_to_callee(listener_capnp);
-            } );
-        } )
-        .map_err( |e| ::capnp::Error::failed( format!("Failed to get answer from callee: {:?}", e) ) ); // TODO should we send an error back to the caller?
-
-        // TODO make this timeout period user-configurable
-        let timeout_res = reactor::Timeout::new(
-            Duration::from_secs( CALL_TIMEOUT_SECS.into() ), &self.handle );
+        // If the call is accepted then a to_callee channel is set up and sent back in the response
+        let answer_fut = one_recv
+            .map( move |answer: CallAnswer| mercury_capnp::fill_call_answer( results.get().init_answer(), answer ) )
+            .map_err( |e| ::capnp::Error::failed( format!("Failed to get answer from callee: {:?}", e) ) ); // TODO should we send an error back to the caller?
+
+        // NOTE the home of the callee cancels the call after the same period
+        let timeout_res = reactor::Timeout::new(call.ring_timeout, &self.handle);
         let timeout_fut = pry!(timeout_res)
-            .map_err( |e| ::capnp::Error::failed( format!("Call timed out without answer: {:?}", e) ) ); // TODO should we send an error back to the caller?
+            .map_err( |e| ::capnp::Error::failed( format!("Call timeout failed: {:?}", e) ) )
+            // NOTE an empty answer would be misread as an accepted call, so the caller gets an error instead
+            .and_then( |()| Err::<(),_>( ::capnp::Error::failed( "Call timed out without answer".to_owned() ) ) );
 
         // Call will time out if not answered in a given period
         let answer_or_timeout_fut = answer_fut.select(timeout_fut)
@@ -474,12 +678,12 @@ impl mercury_capnp::call_listener::Server for CallDispatcherCapnProto
 struct IncomingCallCapnProto
 {
     request:    CallRequestDetails,
-    sender:     oneshot::Sender< Option<AppMsgSink> >,
+    sender:     oneshot::Sender<CallAnswer>,
 }
 
 impl IncomingCallCapnProto
 {
-    fn new(request: CallRequestDetails, sender: oneshot::Sender< Option<AppMsgSink> >) -> Self
+    fn new(request: CallRequestDetails, sender: oneshot::Sender<CallAnswer>) -> Self
         { Self{ request: request, sender: sender } }
 }
 
@@ -487,11 +691,11 @@ impl IncomingCall for IncomingCallCapnProto
 {
     fn request_details(&self) -> &CallRequestDetails { &self.request }
 
-    fn answer(self: Box<Self>, to_callee: Option<AppMsgSink>) -> CallRequestDetails
+    fn answer(self: Box<Self>, answer: CallAnswer) -> CallRequestDetails
     {
         // NOTE needed to dereference Box because otherwise the whole self is moved at its first dereference
         let this = *self;
-        match this.sender.send(to_callee)
+        match this.sender.send(answer)
         {
             Ok( () ) => {},
             Err(_e) => {}, // TODO what to do with the error? Only log or can we handle it somehow?
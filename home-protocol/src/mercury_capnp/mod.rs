@@ -1,10 +1,17 @@
+use std::time::Duration;
+
 use capnp;
 use capnp::capability::Promise;
+use capnp::serialize;
+use failure::Fail;
 use futures::prelude::*;
 use futures::{future, Sink, sync::mpsc};
 use tokio_core::reactor;
 
+use multiaddr::ToMultiaddr;
+
 use ::{AppMessageFrame, AppMsgSink, AsyncResult, TryFrom};
+use ::error::{Error, ErrorKind};
 
 
 
@@ -31,6 +38,21 @@ pub trait FillFrom<T>
     fn fill_from(self, source: &T);
 }
 
+
+// NOTE durations are sent in milliseconds, longer ones are cut to u32::MAX, i.e. about 49 days
+fn duration_to_millis(duration: Duration) -> u32
+{
+    let millis = duration.as_secs() * 1000 + u64::from( duration.subsec_millis() );
+    if millis > u64::from( u32::max_value() ) { u32::max_value() } else { millis as u32 }
+}
+
+// NOTE a missing ring timeout reads as 0, it means the default instead of an immediate timeout
+pub fn ring_timeout_from_millis(millis: u32) -> Duration
+{
+    if millis == 0 { ::CallRequestDetails::DEFAULT_RING_TIMEOUT }
+    else { Duration::from_millis( millis.into() ) }
+}
+
 impl<'a> From<&'a ::PublicKey> for &'a [u8] {
     fn from(public_key: &'a ::PublicKey) -> Self {
         public_key.0.as_ref()
@@ -50,6 +72,18 @@ impl<'a> From<&'a ::AppMessageFrame> for &'a [u8]
         { &src.0 }
 }
 
+impl<'a> From<&'a [u8]> for ::CallId
+{
+    fn from(src: &'a [u8]) -> Self
+        { ::CallId( src.to_owned() ) }
+}
+
+impl<'a> From<&'a ::CallId> for &'a [u8]
+{
+    fn from(src: &'a ::CallId) -> Self
+        { &src.0 }
+}
+
 
 impl<'a> From<&'a str> for ::ApplicationId
 {
@@ -73,29 +107,32 @@ impl<'a> TryFrom<profile::Reader<'a>> for ::Profile
         let profile_id = ::ProfileId( src.get_id()?.to_owned() );
         let public_key = ::PublicKey( src.get_public_key()?.to_owned() );
 
-        let facet_res = match src.get_facet().which() {
-            Ok(profile::facet::Which::Persona(r)) => {
-                if let Some(proof_reader) = r.get_homes()?.iter().next() {  // only 0 or 1 home is supported in the current impl
-                    let home_relation = ::RelationProof::try_from(proof_reader)?;
-                    Ok(::ProfileFacet::Persona(::PersonaFacet{homes: vec![home_relation], data: vec![]}))
-                } else {
-                    Ok(::ProfileFacet::Persona(::PersonaFacet{homes: vec![], data: vec![]}))
-                }
+        let facet = match src.get_facet().which()? {
+            profile::facet::Which::Persona(persona) => {
+                let homes = persona.get_homes()?.iter()
+                    .map( |proof_capnp| ::RelationProof::try_from(proof_capnp) )
+                    .collect::<Result<Vec<_>,_>>()?;
+                ::ProfileFacet::Persona( ::PersonaFacet{ homes, data: persona.get_data()?.to_owned() } )
+            },
+            profile::facet::Which::Home(home) => {
+                let addrs = home.get_addresses()?.iter()
+                    .map( |addr_res| addr_res.and_then( |addr|
+                        addr.to_multiaddr().map_err( |e| capnp::Error::failed( format!("Invalid multiaddr {}: {}", addr, e) ) ) ) )
+                    .collect::<Result<Vec<_>,_>>()?;
+                ::ProfileFacet::Home( ::HomeFacet{ addrs, data: home.get_data()?.to_owned() } )
+            },
+            profile::facet::Which::Application(app) => {
+                let id = ::ApplicationId::from( app.get_id()? );
+                ::ProfileFacet::Application( ::ApplicationFacet{ id, data: app.get_data()?.to_owned() } )
             },
-            // TODO finish this implementation to be able to send HomeProfiles, too
-            // Ok(profile::facet::Which::Home(r)) => {
-            //     let addrs = r.get_addresses()?.iter().map(|addr| ::Multiaddr::from(addr?));
-            //     Ok(::ProfileFacet::Home(::HomeFacet{addrs, data: vec![]}))
-            // },
-            _ => {
-                Err("Unimplemented")
-            }
+            profile::facet::Which::Unknown(raw) =>
+                ::ProfileFacet::Unknown( ::RawFacet{ data: raw.get_data()?.to_owned() } ),
         };
 
-        match facet_res {
-            Ok(facet) => Ok(::Profile::new(&profile_id, &public_key, &facet) ),
-            Err(e) => Err(::capnp::Error::failed(e.to_owned())),
-        }
+        let mut profile = ::Profile::new(&profile_id, &public_key, &facet);
+        profile.version = src.get_version();
+        profile.signature = ::Signature( src.get_signature()?.to_owned() );
+        Ok(profile)
     }
 }
 
@@ -105,22 +142,78 @@ impl<'a> FillFrom<::Profile> for profile::Builder<'a>
     {
         self.set_id( (&src.id).into() );
         self.set_public_key( (&src.public_key).into() );
+        self.set_version(src.version);
+        self.set_signature(&src.signature.0);
         match src.facet {
             ::ProfileFacet::Persona(ref facet) => {
-                let persona_builder = self.init_facet().init_persona();
+                let mut persona_builder = self.init_facet().init_persona();
+                persona_builder.set_data(&facet.data);
                 let mut homes = persona_builder.init_homes(facet.homes.len() as u32);
                 for (i, home) in facet.homes.iter().enumerate() {
                     homes.reborrow().get(i as u32).fill_from(&home);
                 }
-            }
-            _ => {
-                panic!("Unimplemented");  // TODO implement home and application facets
-            }
+            },
+            ::ProfileFacet::Home(ref facet) => {
+                let mut home_builder = self.init_facet().init_home();
+                home_builder.set_data(&facet.data);
+                let mut addrs = home_builder.init_addresses(facet.addrs.len() as u32);
+                for (i, addr) in facet.addrs.iter().enumerate() {
+                    addrs.set( i as u32, &addr.to_string() );
+                }
+            },
+            ::ProfileFacet::Application(ref facet) => {
+                let mut app_builder = self.init_facet().init_application();
+                app_builder.set_id( (&facet.id).into() );
+                app_builder.set_data(&facet.data);
+            },
+            ::ProfileFacet::Unknown(ref facet) => {
+                self.init_facet().init_unknown().set_data(&facet.data);
+            },
         }
     }
 }
 
 
+impl<'a> TryFrom<profile_list_filter::Reader<'a>> for ::ProfileListFilter
+{
+    type Error = capnp::Error;
+
+    fn try_from(src: profile_list_filter::Reader) -> Result<Self, Self::Error>
+    {
+        let facet_type = match src.get_facet_type()? {
+            FacetType::Any          => None,
+            FacetType::Home         => Some(::FacetType::Home),
+            FacetType::Persona      => Some(::FacetType::Persona),
+            FacetType::Application  => Some(::FacetType::Application),
+        };
+        let app_id = if src.has_app_id() && ! src.get_app_id()?.is_empty()
+            { Some( ::ApplicationId::from( src.get_app_id()? ) ) } else { None };
+        let limit = match src.get_limit() {
+            0 => None,
+            limit => Some(limit),
+        };
+        Ok( ::ProfileListFilter{ facet_type, app_id, offset: src.get_offset(), limit } )
+    }
+}
+
+impl<'a> FillFrom<::ProfileListFilter> for profile_list_filter::Builder<'a>
+{
+    fn fill_from(mut self, src: &::ProfileListFilter)
+    {
+        self.set_facet_type( match src.facet_type {
+            None                                => FacetType::Any,
+            Some(::FacetType::Home)             => FacetType::Home,
+            Some(::FacetType::Persona)          => FacetType::Persona,
+            Some(::FacetType::Application)      => FacetType::Application,
+        } );
+        if let Some(ref app_id) = src.app_id
+            { self.set_app_id( app_id.into() ); }
+        self.set_offset(src.offset);
+        self.set_limit( src.limit.unwrap_or(0) );
+    }
+}
+
+
 impl<'a> TryFrom<own_profile::Reader<'a>> for ::OwnProfile
 {
     type Error = capnp::Error;
@@ -143,24 +236,85 @@ impl<'a> FillFrom<::OwnProfile> for own_profile::Builder<'a>
 }
 
 
+impl<'a> TryFrom<profile_redirect::Reader<'a>> for ::ProfileRedirect
+{
+    type Error = capnp::Error;
+
+    fn try_from(src: profile_redirect::Reader) -> Result<Self, Self::Error>
+    {
+        Ok( ::ProfileRedirect{
+            profile_id:     ::ProfileId( src.get_profile_id()?.to_owned() ),
+            new_profile:    ::Profile::try_from( src.get_new_profile()? )?,
+            old_home_id:    ::ProfileId( src.get_old_home_id()?.to_owned() ),
+            expires_at:     src.get_expires_at(),
+            signature:      ::Signature( src.get_signature()?.to_owned() ),
+        } )
+    }
+}
+
+impl<'a> FillFrom<::ProfileRedirect> for profile_redirect::Builder<'a>
+{
+    fn fill_from(mut self, src: &::ProfileRedirect)
+    {
+        self.set_profile_id( (&src.profile_id).into() );
+        self.reborrow().init_new_profile().fill_from(&src.new_profile);
+        self.set_old_home_id( (&src.old_home_id).into() );
+        self.set_expires_at(src.expires_at);
+        self.set_signature(&src.signature.0);
+    }
+}
+
+
 impl<'a> TryFrom<home_invitation::Reader<'a>> for ::HomeInvitation
 {
     type Error = capnp::Error;
 
-    fn try_from(_src: home_invitation::Reader) -> Result<Self, Self::Error>
+    fn try_from(src: home_invitation::Reader) -> Result<Self, Self::Error>
     {
-        // TODO
-        Ok( ::HomeInvitation::new( &::ProfileId("TODO".as_bytes().to_owned()),
-                                   &"TODO", &::Signature("TODO".as_bytes().to_owned() ) ) )
+        Ok( ::HomeInvitation::new( &::ProfileId( src.get_home_id()?.to_owned() ),
+            src.get_voucher()?, src.get_expires_at(), &::Signature( src.get_signature()?.to_owned() ) ) )
     }
 }
 
 impl<'a> FillFrom<::HomeInvitation> for home_invitation::Builder<'a>
 {
-    // fn fill_from(mut self, src: &::HomeInvitation)
-    fn fill_from(self, _src: &::HomeInvitation)
+    fn fill_from(mut self, src: &::HomeInvitation)
+    {
+        self.set_home_id( (&src.home_id).into() );
+        self.set_voucher(&src.voucher);
+        self.set_expires_at(src.expires_at);
+        self.set_signature(&src.signature.0);
+    }
+}
+
+
+impl<'a> TryFrom<relation_metadata::Reader<'a>> for ::RelationMetadata
+{
+    type Error = capnp::Error;
+
+    fn try_from(src: relation_metadata::Reader) -> Result<Self, Self::Error>
     {
-        // TODO
+        let expires_at = match src.get_expires_at() {
+            0 => None,
+            expires_at => Some(expires_at),
+        };
+        Ok(::RelationMetadata {
+            version: src.get_version(),
+            nonce: src.get_nonce()?.to_owned(),
+            created_at: src.get_created_at(),
+            expires_at,
+        })
+    }
+}
+
+impl<'a> FillFrom<::RelationMetadata> for relation_metadata::Builder<'a>
+{
+    fn fill_from(mut self, src: &::RelationMetadata)
+    {
+        self.set_version(src.version);
+        self.set_nonce(&src.nonce);
+        self.set_created_at(src.created_at);
+        self.set_expires_at( src.expires_at.unwrap_or(0) );
     }
 }
 
@@ -176,6 +330,7 @@ impl<'a> TryFrom<relation_half_proof::Reader<'a>> for ::RelationHalfProof
             signer_id: ::ProfileId(src.get_signer_id()?.to_owned()),
             peer_id: ::ProfileId(src.get_peer_id()?.to_owned()),
             signature: ::Signature(src.get_signature()?.to_owned()),
+            metadata: ::RelationMetadata::try_from(src.get_metadata()?)?,
         })
     }
 }
@@ -188,6 +343,7 @@ impl<'a> FillFrom<::RelationHalfProof> for relation_half_proof::Builder<'a>
         self.set_signer_id(&src.signer_id.0);
         self.set_peer_id(&src.peer_id.0);
         self.set_signature(&src.signature.0);
+        self.init_metadata().fill_from(&src.metadata);
     }
 }
 
@@ -204,6 +360,7 @@ impl<'a> TryFrom<relation_proof::Reader<'a>> for ::RelationProof
             a_signature: ::Signature(src.get_a_signature()?.to_owned()),
             b_id: ::ProfileId(src.get_b_id()?.to_owned()),
             b_signature: ::Signature(src.get_b_signature()?.to_owned()),
+            metadata: ::RelationMetadata::try_from(src.get_metadata()?)?,
         })
     }
 }
@@ -217,6 +374,35 @@ impl<'a> FillFrom<::RelationProof> for relation_proof::Builder<'a>
         self.set_a_signature(&src.a_signature.0);
         self.set_b_id(&src.b_id.0);
         self.set_b_signature(&src.b_signature.0);
+        self.init_metadata().fill_from(&src.metadata);
+    }
+}
+
+
+
+impl<'a> TryFrom<relation_revocation::Reader<'a>> for ::RelationRevocation
+{
+    type Error = capnp::Error;
+
+    fn try_from(src: relation_revocation::Reader) -> Result<Self, Self::Error>
+    {
+        Ok( ::RelationRevocation{
+            relation:   ::RelationProof::try_from( src.get_relation()? )?,
+            revoker_id: ::ProfileId( src.get_revoker_id()?.to_owned() ),
+            revoked_at: src.get_revoked_at(),
+            signature:  ::Signature( src.get_signature()?.to_owned() ),
+        } )
+    }
+}
+
+impl<'a> FillFrom<::RelationRevocation> for relation_revocation::Builder<'a>
+{
+    fn fill_from(mut self, src: &::RelationRevocation)
+    {
+        self.reborrow().init_relation().fill_from(&src.relation);
+        self.set_revoker_id( (&src.revoker_id).into() );
+        self.set_revoked_at(src.revoked_at);
+        self.set_signature(&src.signature.0);
     }
 }
 
@@ -232,6 +418,8 @@ impl<'a> TryFrom<profile_event::Reader<'a>> for ::ProfileEvent
             profile_event::Which::Unknown(data) => Ok(::ProfileEvent::Unknown(Vec::from(data?))),
             profile_event::Which::PairingRequest(half_proof) => Ok(::ProfileEvent::PairingRequest(::RelationHalfProof::try_from(half_proof?)?)),
             profile_event::Which::PairingResponse(proof) => Ok(::ProfileEvent::PairingResponse(::RelationProof::try_from(proof?)?)),
+            profile_event::Which::RelationRevoked(revocation) => Ok(::ProfileEvent::RelationRevoked(::RelationRevocation::try_from(revocation?)?)),
+            profile_event::Which::ProfileUpdated(profile) => Ok(::ProfileEvent::ProfileUpdated(::Profile::try_from(profile?)?)),
         }
     }
 }
@@ -249,6 +437,14 @@ impl<'a> FillFrom<::ProfileEvent> for profile_event::Builder<'a>
                 let mut builder = self.init_pairing_response();
                 builder.reborrow().fill_from(proof);
             },
+            ::ProfileEvent::RelationRevoked(revocation) => {
+                let mut builder = self.init_relation_revoked();
+                builder.reborrow().fill_from(revocation);
+            },
+            ::ProfileEvent::ProfileUpdated(profile) => {
+                let mut builder = self.init_profile_updated();
+                builder.reborrow().fill_from(profile);
+            },
             ::ProfileEvent::Unknown(data) => {
                 let _builder = self.init_unknown(data.len() as u32);
                 // TODO fill with data
@@ -260,6 +456,31 @@ impl<'a> FillFrom<::ProfileEvent> for profile_event::Builder<'a>
 
 
 
+impl<'a> TryFrom<app_presence::Reader<'a>> for Option<::AppMessageFrame>
+{
+    type Error = capnp::Error;
+
+    fn try_from(src: app_presence::Reader) -> Result<Self, Self::Error>
+    {
+        match src.which()? {
+            app_presence::Which::Offline(()) => Ok(None),
+            app_presence::Which::Online(presence) => Ok( Some( presence?.into() ) ),
+        }
+    }
+}
+
+impl<'a> FillFrom<Option<::AppMessageFrame>> for app_presence::Builder<'a>
+{
+    fn fill_from(mut self, src: &Option<::AppMessageFrame>)
+    {
+        match *src {
+            None => self.set_offline( () ),
+            Some(ref presence) => self.set_online( presence.into() ),
+        }
+    }
+}
+
+
 impl<'a> TryFrom<call_request::Reader<'a>> for ::CallRequestDetails
 {
     type Error = capnp::Error;
@@ -269,8 +490,10 @@ impl<'a> TryFrom<call_request::Reader<'a>> for ::CallRequestDetails
     {
         let relation = ::RelationProof::try_from( src.get_relation()? )?;
         let init_payload = src.get_init_payload()?.into();
+        let id = src.get_call_id()?.into();
+        let ring_timeout = ring_timeout_from_millis( src.get_ring_timeout_ms() );
 
-        Ok( ::CallRequestDetails { relation: relation, init_payload: init_payload, to_caller: None } )
+        Ok( ::CallRequestDetails { id, ring_timeout, relation, init_payload, to_caller: None } )
     }
 }
 
@@ -280,23 +503,115 @@ impl<'a> FillFrom<::CallRequestDetails> for call_request::Builder<'a>
     {
         self.set_init_payload( (&src.init_payload).into() );
         self.init_relation().fill_from(&src.relation);
+        self.set_call_id( (&src.id).into() );
+        self.set_ring_timeout_ms( duration_to_millis(src.ring_timeout) );
         // TODO set up channel to caller: is it possible here without external context?
         // self.set_to_caller( TODO );
     }
 }
 
 
+// NOTE like for call requests, setting up the channel of an accepted call needs outer context
+pub fn read_call_answer(src: call_answer::Reader, handle: reactor::Handle) -> Result<::CallAnswer, capnp::Error>
+{
+    match src.which()? {
+        call_answer::Which::Accepted(to_callee) => Ok( ::CallAnswer::Accepted( fwd_appmsg(to_callee?, handle) ) ),
+        call_answer::Which::Replied(reply) => Ok( ::CallAnswer::Replied( reply?.into() ) ),
+        call_answer::Which::Rejected(reason) => Ok( ::CallAnswer::Rejected(reason) ),
+    }
+}
+
+pub fn fill_call_answer(mut dst: call_answer::Builder, src: ::CallAnswer)
+{
+    match src {
+        ::CallAnswer::Accepted(to_callee) => {
+            // TODO consider how to drop/unregister this object from capnp if the stream is dropped
+            let listener = AppMessageDispatcherCapnProto::new(to_callee);
+            let listener_capnp = app_message_listener::ToClient::new(listener)
+                .into_client::<::capnp_rpc::Server>();
+            dst.set_accepted(listener_capnp);
+        },
+        ::CallAnswer::Replied(ref reply) => dst.set_replied( reply.into() ),
+        ::CallAnswer::Rejected(reason) => dst.set_rejected(reason),
+    }
+}
+
+
+
+const REMOTE_ERROR_PREFIX: &'static str = "mercury-error:";
+
+/// Exception carrying an error of the API to the remote side, see the RemoteError struct in the schema
+pub fn to_capnp_error(error: &Error) -> capnp::Error
+{
+    let mut context = error.to_string();
+    let mut cause = error.cause();
+    while let Some(inner) = cause {
+        context.push_str(": ");
+        context.push_str( &inner.to_string() );
+        cause = inner.cause();
+    }
+
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let mut remote_error = message.init_root::<remote_error::Builder>();
+        remote_error.set_code( error.kind().code() );
+        remote_error.set_context(&context);
+    }
+    let mut buffer = Vec::new();
+    if let Err(e) = serialize::write_message(&mut buffer, &message)
+        { return e; } // NOTE cannot happen when writing into memory
+    let encoded = ::multibase::encode(::multibase::Base::Base64url, &buffer);
+    capnp::Error::failed( format!("{}{} {}", REMOTE_ERROR_PREFIX, encoded, context) )
+}
+
+/// The error returned by the remote side, or a local error of the given kind caused by
+/// the capnp error if it is not a remote error, e.g. the connection was lost.
+pub fn from_capnp_error(error: capnp::Error, local_kind: ErrorKind) -> Error
+{
+    match read_remote_error(&error.description) {
+        // NOTE kinds unknown to us, e.g. added in a newer protocol version, are reported as a local failure
+        Some( (code, context) ) => ::failure::err_msg(context)
+            .context( ErrorKind::from_code(code).unwrap_or(local_kind) )
+            .into(),
+        None => error.context(local_kind).into(),
+    }
+}
+
+fn read_remote_error(description: &str) -> Option<(u16, String)>
+{
+    // NOTE capnp-rpc adds its own prefix to the reason of remote exceptions
+    let start = description.find(REMOTE_ERROR_PREFIX)? + REMOTE_ERROR_PREFIX.len();
+    let encoded = description[start..].split_whitespace().next()?;
+    let (_base, buffer) = ::multibase::decode(encoded).ok()?;
+    let message = serialize::read_message( &mut &buffer[..], capnp::message::ReaderOptions::new() ).ok()?;
+    let remote_error = message.get_root::<remote_error::Reader>().ok()?;
+    Some( ( remote_error.get_code(), String::from( remote_error.get_context().ok()? ) ) )
+}
+
+
 
 // TODO consider using a single generic imlementation for all kinds of Dispatchers
 pub struct AppMessageDispatcherCapnProto
 {
-    sender: AppMsgSink,
+    sender: Option<AppMsgSink>, // NOTE dropped when closed by the remote side
 }
 
 impl AppMessageDispatcherCapnProto
 {
     pub fn new(sender: AppMsgSink) -> Self
-        { Self{ sender: sender } }
+        { Self{ sender: Some(sender) } }
+
+    fn send(&self, message: Result<AppMessageFrame, String>) -> Promise<(), ::capnp::Error>
+    {
+        let sender = match self.sender {
+            Some(ref sender) => sender.clone(),
+            None => return Promise::err( ::capnp::Error::failed( "Message channel was closed".to_owned() ) ),
+        };
+        let send_fut = sender.send(message)
+            .map(  |_sink| () )
+            .map_err( |e| ::capnp::Error::failed( format!("Failed to send event: {:?}",e ) ) );
+        Promise::from_future(send_fut)
+    }
 }
 
 impl app_message_listener::Server for AppMessageDispatcherCapnProto
@@ -306,10 +621,7 @@ impl app_message_listener::Server for AppMessageDispatcherCapnProto
         -> Promise<(), ::capnp::Error>
     {
         let message = pry!( pry!( params.get() ).get_message() );
-        let recv_fut = self.sender.clone().send( Ok( message.into() ) )
-            .map(  |_sink| () )
-            .map_err( |e| ::capnp::Error::failed( format!("Failed to send event: {:?}",e ) ) );
-        Promise::from_future(recv_fut)
+        self.send( Ok( message.into() ) )
     }
 
 
@@ -318,10 +630,17 @@ impl app_message_listener::Server for AppMessageDispatcherCapnProto
         -> Promise<(), ::capnp::Error>
     {
         let error = pry!( pry!( params.get() ).get_error() ).into();
-        let recv_fut = self.sender.clone().send( Err(error) )
-            .map(  |_sink| () )
-            .map_err( |e| ::capnp::Error::failed( format!("Failed to send event: {:?}",e ) ) );
-        Promise::from_future(recv_fut)
+        self.send( Err(error) )
+    }
+
+
+    fn close(&mut self, _params: app_message_listener::CloseParams,
+             _results: app_message_listener::CloseResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        // NOTE the receiver stream ends as soon as all senders are dropped, also this one
+        self.sender.take();
+        Promise::ok( () )
     }
 }
 
@@ -331,6 +650,7 @@ pub fn fwd_appmsg(to_callee: app_message_listener::Client, handle: reactor::Hand
 {
     let (send, recv) = mpsc::channel::<Result<AppMessageFrame, String>>(1);
 
+    let closer = to_callee.clone();
     handle.spawn(
         recv.for_each( move |message|
         {
@@ -353,6 +673,10 @@ pub fn fwd_appmsg(to_callee: app_message_listener::Client, handle: reactor::Hand
             };
             capnp_fut.map_err(  |_e| () ) // TODO what to do here with the network capnp error?
         } )
+        // Propagate dropping the channel so the remote stream ends as well
+        .then( move |_res| closer.close_request().send().promise
+            .map( |_resp| () )
+            .map_err( |_e| () ) )
     );
 
     send
@@ -372,6 +696,8 @@ mod tests
             signer_id: ::ProfileId(Vec::from("me")),
             peer_id: ProfileId(Vec::from("you")),
             signature: Signature(Vec::from("i signed")),
+            metadata: RelationMetadata{ version: RelationMetadata::VERSION_CURRENT, nonce: Vec::from("nonce"),
+                                        created_at: 1_500_000_000, expires_at: Some(1_600_000_000) },
         };
         let mut message = capnp::message::Builder::new_default();
         {
@@ -386,4 +712,137 @@ mod tests
         let recoded = RelationHalfProof::try_from(obj_reader).unwrap();
         assert_eq!(recoded, relation_half_proof);
     }
+
+    #[test]
+    fn home_invitation_encoding() {
+        let invitation = HomeInvitation::new( &ProfileId(Vec::from("home")), "voucher",
+            1_500_000_000, &Signature(Vec::from("home signed")) );
+        let mut message = capnp::message::Builder::new_default();
+        message.init_root::<mercury_capnp::home_invitation::Builder>().fill_from(&invitation);
+        let mut buffer = vec![];
+        serialize::write_message(&mut buffer, &message).unwrap();
+        // -- 8< --
+        let message_reader = serialize::read_message(&mut &buffer[..], ::capnp::message::ReaderOptions::new()).unwrap();
+        let obj_reader = message_reader.get_root::<mercury_capnp::home_invitation::Reader>().unwrap();
+        let recoded = HomeInvitation::try_from(obj_reader).unwrap();
+        assert_eq!(recoded, invitation);
+    }
+
+
+    #[test]
+    fn remote_error_encoding() {
+        use failure::Fail;
+        use error::{Error, ErrorKind};
+
+        let error: Error = ErrorKind::InvalidSignature.context(ErrorKind::SignatureValidationFailed)
+            .context(ErrorKind::AlreadyRegistered).into();
+        let capnp_error = mercury_capnp::to_capnp_error(&error);
+        // -- 8< --
+        let transferred = ::capnp::Error::failed( format!("remote exception: {}", capnp_error.description) );
+        let decoded = mercury_capnp::from_capnp_error(transferred, ErrorKind::RegisterFailed);
+        assert_eq!( decoded.kind(), ErrorKind::AlreadyRegistered );
+        assert_eq!( decoded.cause().unwrap().to_string(),
+            "already registered: signature validation failed: invalid signature" );
+
+        let local = mercury_capnp::from_capnp_error( ::capnp::Error::disconnected( "connection lost".to_owned() ), ErrorKind::RegisterFailed );
+        assert_eq!( local.kind(), ErrorKind::RegisterFailed );
+        assert!( local.is_home_failure() );
+        assert!( ! decoded.is_home_failure() );
+    }
+
+
+    #[test]
+    fn ring_timeout_decoding() {
+        assert_eq!( mercury_capnp::ring_timeout_from_millis(0), CallRequestDetails::DEFAULT_RING_TIMEOUT );
+        assert_eq!( mercury_capnp::ring_timeout_from_millis(1500), ::std::time::Duration::from_millis(1500) );
+    }
+
+
+    fn capnp_roundtrip(profile: &Profile) -> Profile
+    {
+        let mut message = capnp::message::Builder::new_default();
+        message.init_root::<mercury_capnp::profile::Builder>().fill_from(profile);
+        let mut buffer = vec![];
+        serialize::write_message(&mut buffer, &message).unwrap();
+        // -- 8< --
+        let message_reader = serialize::read_message(&mut &buffer[..], ::capnp::message::ReaderOptions::new()).unwrap();
+        let obj_reader = message_reader.get_root::<mercury_capnp::profile::Reader>().unwrap();
+        Profile::try_from(obj_reader).unwrap()
+    }
+
+    fn serde_roundtrip(profile: &Profile) -> Profile
+    {
+        let buffer = ::serde_json::to_vec(profile).unwrap();
+        ::serde_json::from_slice(&buffer).unwrap()
+    }
+
+
+    mod strategies
+    {
+        use proptest::prelude::*;
+        use multiaddr::ToMultiaddr;
+        use ::*;
+
+        fn bytes() -> BoxedStrategy< Vec<u8> >
+            { prop::collection::vec( any::<u8>(), 0..32 ).boxed() }
+
+        fn multiaddr() -> BoxedStrategy<Multiaddr>
+        {
+            prop_oneof![
+                ( any::<[u8;4]>(), any::<u16>() ).prop_map( |(ip, port)|
+                    format!( "/ip4/{}.{}.{}.{}/tcp/{}", ip[0], ip[1], ip[2], ip[3], port ) ),
+                any::<u16>().prop_map( |port| format!("/ip6/::1/tcp/{}", port) ),
+            ].prop_map( |addr| addr.to_multiaddr().unwrap() ).boxed()
+        }
+
+        fn relation_metadata() -> BoxedStrategy<RelationMetadata>
+        {
+            prop_oneof![
+                Just( RelationMetadata::default() ),
+                ( bytes(), any::<u64>(), prop::option::of(1..u64::max_value()) ).prop_map(
+                    |(nonce, created_at, expires_at)| RelationMetadata{
+                        version: RelationMetadata::VERSION_CURRENT, nonce, created_at, expires_at } ),
+            ].boxed()
+        }
+
+        fn relation_proof() -> BoxedStrategy<RelationProof>
+        {
+            ( "[a-z_]{1,10}", bytes(), bytes(), bytes(), bytes(), relation_metadata() ).prop_map(
+                |(relation_type, a_id, a_signature, b_id, b_signature, metadata)| RelationProof{ relation_type,
+                    a_id: ProfileId(a_id), a_signature: Signature(a_signature),
+                    b_id: ProfileId(b_id), b_signature: Signature(b_signature), metadata } ).boxed()
+        }
+
+        fn facet() -> BoxedStrategy<ProfileFacet>
+        {
+            prop_oneof![
+                ( prop::collection::vec( relation_proof(), 0..4 ), bytes() )
+                    .prop_map( |(homes, data)| ProfileFacet::Persona( PersonaFacet{homes, data} ) ),
+                ( prop::collection::vec( multiaddr(), 0..4 ), bytes() )
+                    .prop_map( |(addrs, data)| ProfileFacet::Home( HomeFacet{addrs, data} ) ),
+                ( "[a-z-]{1,16}", bytes() ).prop_map( |(id, data)|
+                    ProfileFacet::Application( ApplicationFacet{ id: ApplicationId(id), data } ) ),
+                bytes().prop_map( |data| ProfileFacet::Unknown( RawFacet{data} ) ),
+            ].boxed()
+        }
+
+        pub fn profile() -> BoxedStrategy<Profile>
+        {
+            ( bytes(), bytes(), facet(), any::<u64>(), bytes() ).prop_map( |(id, public_key, facet, version, signature)|
+                Profile{ version, signature: Signature(signature),
+                         ..Profile::new( &ProfileId(id), &PublicKey(public_key), &facet ) } ).boxed()
+        }
+    }
+
+
+    proptest!
+    {
+        #[test]
+        fn profile_encoding(ref profile in strategies::profile())
+        {
+            let capnp_recoded = capnp_roundtrip(profile);
+            prop_assert_eq!( &capnp_recoded, profile );
+            prop_assert_eq!( capnp_recoded, serde_roundtrip(profile) );
+        }
+    }
 }
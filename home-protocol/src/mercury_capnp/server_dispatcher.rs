@@ -56,12 +56,23 @@ impl Drop for HomeDispatcherCapnProto
 
 impl profile_repo::Server for HomeDispatcherCapnProto
 {
-//    fn list(&mut self, _params: profile_repo::ListParams, _results: profile_repo::ListResults)
-//        -> Promise<(), ::capnp::Error>
-//    {
-//        // TODO properly implement this
-//        unimplemented!()
-//    }
+    fn list(&mut self, params: profile_repo::ListParams,
+            mut results: profile_repo::ListResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let filter_capnp = pry!( pry!( params.get() ).get_filter() );
+        let filter = pry!( ProfileListFilter::try_from(filter_capnp) );
+        let list_fut = self.home.list(&filter)
+            .map( move |profiles|
+            {
+                let mut profiles_capnp = results.get().init_profiles( profiles.len() as u32 );
+                for (i, profile) in profiles.iter().enumerate()
+                    { profiles_capnp.reborrow().get(i as u32).fill_from(profile); }
+            } )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(list_fut)
+    }
 
 
     fn load(&mut self, params: profile_repo::LoadParams,
@@ -71,23 +82,23 @@ impl profile_repo::Server for HomeDispatcherCapnProto
         let profile_id_capnp = pry!( pry!( params.get() ).get_profile_id() );
         let load_fut = self.home.load( &profile_id_capnp.into() )
             .map( move |profile| results.get().init_profile().fill_from(&profile) )
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to load profile id: {:?}",e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(load_fut)
     }
 
 
-//    fn resolve(&mut self, params: profile_repo::ResolveParams,
-//               mut results: profile_repo::ResolveResults)
-//        -> Promise<(), ::capnp::Error>
-//    {
-//        let profile_url = pry!( pry!( params.get() ).get_profile_url() );
-//        let res_fut = self.home.resolve(profile_url)
-//            .map( move |profile| results.get().init_profile().fill_from(&profile) )
-//            .map_err( | e| ::capnp::Error::failed( format!("Failed to resolve url: {:?}",e ) ) );
-//
-//        Promise::from_future(res_fut)
-//    }
+    fn resolve(&mut self, params: profile_repo::ResolveParams,
+               mut results: profile_repo::ResolveResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let profile_url = pry!( pry!( params.get() ).get_profile_url() );
+        let res_fut = self.home.resolve(profile_url)
+            .map( move |profile| results.get().init_profile().fill_from(&profile) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(res_fut)
+    }
 }
 
 
@@ -100,7 +111,7 @@ impl home::Server for HomeDispatcherCapnProto
     {
         let profile_id_capnp = pry!( pry!( params.get() ).get_profile_id() );
         let claim_fut = self.home.claim( profile_id_capnp.into() )
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to claim profile: {:?}", e) ) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) )
             .map( move |own_profile|
                 results.get().init_own_profile().fill_from(&own_profile) );
 
@@ -118,13 +129,13 @@ impl home::Server for HomeDispatcherCapnProto
         let half_proof_capnp = pry!( pry!(params.get()).get_half_proof() );
         let half_proof = pry!( RelationHalfProof::try_from(half_proof_capnp) );
 
-        let inv_capnp_res = pry!( params.get() ).get_invite();
-        let invite_opt = inv_capnp_res
-            .and_then( |inv_capnp| HomeInvitation::try_from(inv_capnp) )
-            .ok();
+        let invite_opt = if pry!( params.get() ).has_invite() {
+            let inv_capnp = pry!( pry!( params.get() ).get_invite() );
+            Some( pry!( HomeInvitation::try_from(inv_capnp) ) )
+        } else { None };
 
         let reg_fut = self.home.register(own_prof, half_proof, invite_opt)
-            .map_err( |e| ::capnp::Error::failed( format!("Failed to register profile: {:?}", e) ) )
+            .map_err( |(_own_profile, e)| mercury_capnp::to_capnp_error(&e) )
             .map( move |own_profile|
                 results.get().init_own_profile().fill_from(&own_profile) );
 
@@ -149,7 +160,7 @@ impl home::Server for HomeDispatcherCapnProto
                 results.get().set_session(session);
                 ()
             } )
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to login: {:?}", e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(session_fut)
     }
@@ -163,7 +174,7 @@ impl home::Server for HomeDispatcherCapnProto
         let half_proof = pry!( RelationHalfProof::try_from(half_proof_capnp) );
 
         let pair_req_fut = self.home.pair_request(half_proof)
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to request pairing {:?}", e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(pair_req_fut)
     }
@@ -177,7 +188,7 @@ impl home::Server for HomeDispatcherCapnProto
         let proof = pry!( RelationProof::try_from(proof_capnp) );
 
         let pair_resp_fut = self.home.pair_response(proof)
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to send pairing response: {:?}", e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(pair_resp_fut)
     }
@@ -199,28 +210,162 @@ impl home::Server for HomeDispatcherCapnProto
         let relation = pry!( RelationProof::try_from(rel_capnp) );
         let app = ApplicationId::from(app_capnp);
         let init_payload = AppMessageFrame::from(init_payload_capnp);
+        let id = CallId::from( pry!( opts.get_call_id() ) );
+        let ring_timeout = mercury_capnp::ring_timeout_from_millis( opts.get_ring_timeout_ms() );
 
-        let call_req = CallRequestDetails { relation: relation, init_payload: init_payload,
-            to_caller: to_caller};
+        let call_req = CallRequestDetails { id: id, ring_timeout: ring_timeout,
+            relation: relation, init_payload: init_payload, to_caller: to_caller};
         let call_fut = self.home.call(app, call_req)
-            .map( |to_callee_opt|
+            .map( move |answer| mercury_capnp::fill_call_answer( results.get().init_answer(), answer ) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(call_fut)
+    }
+
+
+    fn hangup(&mut self, params: home::HangupParams,
+              _results: home::HangupResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let call_id_capnp = pry!( pry!( params.get() ).get_call_id() );
+        let hangup_fut = self.home.hangup( call_id_capnp.into() )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(hangup_fut)
+    }
+
+
+    fn redirect(&mut self, params: home::RedirectParams,
+                mut results: home::RedirectResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let profile_id_capnp = pry!( pry!( params.get() ).get_profile_id() );
+        let redirect_fut = self.home.redirect( &profile_id_capnp.into() )
+            .map( move |redirect| results.get().init_redirect().fill_from(&redirect) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(redirect_fut)
+    }
+
+
+    fn revoke_relation(&mut self, params: home::RevokeRelationParams,
+                       mut _results: home::RevokeRelationResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let revocation_capnp = pry!( pry!( params.get() ).get_revocation() );
+        let revocation = pry!( RelationRevocation::try_from(revocation_capnp) );
+
+        let revoke_fut = self.home.revoke_relation(revocation)
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(revoke_fut)
+    }
+
+
+    fn subscribe(&mut self, params: home::SubscribeParams,
+                 mut _results: home::SubscribeResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let params = pry!( params.get() );
+        let relation = pry!( RelationProof::try_from( pry!( params.get_relation() ) ) );
+        let callback = pry!( params.get_event_listener() );
+
+        let events = self.home.subscribe(relation)
+            .map_err( | e| ::capnp::Error::failed( format!("Failed to get profile updates: {:?}", e) ) );
+        Promise::from_future( forward_events(events, callback) )
+    }
+
+
+    fn presence(&mut self, params: home::PresenceParams,
+                mut results: home::PresenceResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let params = pry!( params.get() );
+        let relation = pry!( RelationProof::try_from( pry!( params.get_relation() ) ) );
+        let app = ApplicationId::from( pry!( params.get_app() ) );
+
+        let presence_fut = self.home.presence(relation, app)
+            .map( move |presence| results.get().init_presence().fill_from(&presence) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+
+        Promise::from_future(presence_fut)
+    }
+
+
+    fn subscribe_presence(&mut self, params: home::SubscribePresenceParams,
+                          mut _results: home::SubscribePresenceResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let params = pry!( params.get() );
+        let relation = pry!( RelationProof::try_from( pry!( params.get_relation() ) ) );
+        let app = ApplicationId::from( pry!( params.get_app() ) );
+        let callback = pry!( params.get_presence_listener() );
+
+        let forward_fut = self.home.subscribe_presence(relation, app)
+            .map_err( | e| ::capnp::Error::failed( format!("Failed to get presence updates: {:?}", e) ) )
+            .for_each( move |item|
             {
-                to_callee_opt.map( move |to_callee|
+                match item
                 {
-                    let to_callee_dispatch = mercury_capnp::AppMessageDispatcherCapnProto::new(to_callee);
-                    let to_callee_capnp = mercury_capnp::app_message_listener::ToClient::new(to_callee_dispatch)
-                        .into_client::<::capnp_rpc::Server>();
-                    results.get().set_to_callee(to_callee_capnp);
-                } );
-            } )
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to call profile: {:?}", e) ) );
+                    Ok(presence) =>
+                    {
+                        let mut request = callback.receive_request();
+                        request.get().init_presence().fill_from(&presence);
+                        let fut = request.send().promise
+                            .map( | _resp| () );
+                        Box::new(fut) as AsyncResult<(), ::capnp::Error>
+                    },
+                    Err(err) =>
+                    {
+                        let mut request = callback.error_request();
+                        request.get().set_error(&err);
+                        let fut = request.send().promise
+                            .map( | _resp| () );
+                        Box::new(fut)
+                    }
+                }
+            } );
 
-        Promise::from_future(call_fut)
+        Promise::from_future(forward_fut)
     }
 }
 
 
 
+/// Deliver events from a stream to a remote listener until the stream ends
+fn forward_events<S>(events: S, callback: profile_event_listener::Client)
+    -> AsyncResult<(), ::capnp::Error>
+    where S: Stream<Item=Result<ProfileEvent, String>, Error=::capnp::Error> + 'static
+{
+    let forward_fut = events.for_each( move |item|
+    {
+        match item
+        {
+            Ok(event) =>
+            {
+                let mut request = callback.receive_request();
+                request.get().init_event().fill_from(&event);
+                let fut = request.send().promise
+                    .map( | _resp| () );
+                // TODO .map_err() what to do here in case of an error?
+                Box::new(fut) as AsyncResult<(), ::capnp::Error>
+            },
+            Err(err) =>
+            {
+                let mut request = callback.error_request();
+                request.get().set_error(&err);
+                let fut = request.send().promise
+                    .map( | _resp| () );
+                // TODO .map_err() what to do here in case of an error?
+                Box::new(fut)
+            }
+        }
+    } );
+    Box::new(forward_fut)
+}
+
+
+
 pub struct HomeSessionDispatcherCapnProto
 {
     session:    Rc<HomeSession>,
@@ -247,7 +392,7 @@ impl home_session::Server for HomeSessionDispatcherCapnProto
         let own_profile = pry!( OwnProfile::try_from(own_profile_capnp) );
 
         let upd_fut = self.session.update(own_profile)
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to update profile: {:?}", e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(upd_fut)
     }
@@ -263,7 +408,7 @@ impl home_session::Server for HomeSessionDispatcherCapnProto
             .ok();
 
         let upd_fut = self.session.unregister(new_home_opt)
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to unregister profile: {:?}", e) ) );
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
 
         Promise::from_future(upd_fut)
     }
@@ -275,45 +420,57 @@ impl home_session::Server for HomeSessionDispatcherCapnProto
     {
         let txt = pry!( pry!( params.get() ).get_txt() );
         let ping_fut = self.session.ping(txt)
-            .map_err( | e| ::capnp::Error::failed( format!("Failed ping: {:?}", e) ) )
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) )
             .map( move |pong| results.get().set_pong(&pong) );
         Promise::from_future(ping_fut)
     }
 
 
+    fn banned_profiles(&mut self, _params: home_session::BannedProfilesParams,
+                       mut results: home_session::BannedProfilesResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let banned_fut = self.session.banned_profiles()
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) )
+            .map( move |profiles| {
+                let mut profiles_capnp = results.get().init_profiles( profiles.len() as u32 );
+                for (idx, profile) in profiles.iter().enumerate()
+                    { profiles_capnp.set( idx as u32, &profile.0 ); }
+            } );
+        Promise::from_future(banned_fut)
+    }
+
+
+    fn ban(&mut self, params: home_session::BanParams,
+           mut _results: home_session::BanResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let profile = ProfileId::from( pry!( pry!( params.get() ).get_profile() ) );
+        let ban_fut = self.session.ban(&profile)
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+        Promise::from_future(ban_fut)
+    }
+
+
+    fn unban(&mut self, params: home_session::UnbanParams,
+             mut _results: home_session::UnbanResults)
+        -> Promise<(), ::capnp::Error>
+    {
+        let profile = ProfileId::from( pry!( pry!( params.get() ).get_profile() ) );
+        let unban_fut = self.session.unban(&profile)
+            .map_err( |e| mercury_capnp::to_capnp_error(&e) );
+        Promise::from_future(unban_fut)
+    }
+
+
     fn events(&mut self, params: home_session::EventsParams,
               mut _results: home_session::EventsResults)
         -> Promise<(), ::capnp::Error>
     {
         let callback = pry!( pry!( params.get() ).get_event_listener() );
-        let events_fut = self.session.events()
-            .map_err( | e| ::capnp::Error::failed( format!("Failed to get profile events: {:?}", e) ) )
-            .for_each( move |item|
-            {
-                match item
-                {
-                    Ok(event) =>
-                    {
-                        let mut request = callback.receive_request();
-                        request.get().init_event().fill_from(&event);
-                        let fut = request.send().promise
-                            .map( | _resp| () );
-                        // TODO .map_err() what to do here in case of an error?
-                        Box::new(fut) as AsyncResult<(), ::capnp::Error>
-                    },
-                    Err(err) =>
-                    {
-                        let mut request = callback.error_request();
-                        request.get().set_error(&err);
-                        let fut = request.send().promise
-                            .map( | _resp| () );
-                        // TODO .map_err() what to do here in case of an error?
-                        Box::new(fut)
-                    }
-                }
-            } );
-
-        Promise::from_future(events_fut)
+        let events = self.session.events()
+            .map_err( | e| ::capnp::Error::failed( format!("Failed to get profile events: {:?}", e) ) );
+        Promise::from_future( forward_events(events, callback) )
     }
 
 
@@ -325,10 +482,12 @@ impl home_session::Server for HomeSessionDispatcherCapnProto
         let params = pry!( params.get() );
         let app_id = pry!( params.get_app() );
         let call_listener = pry!( params.get_call_listener() );
+        let presence = if params.has_presence()
+            { Some( pry!( params.get_presence() ).into() ) } else { None };
 
         // Forward incoming calls from business logic into capnp proxy stub of client
         let handle_clone = self.handle.clone();
-        let calls_fut = self.session.checkin_app( &app_id.into() )
+        let calls_fut = self.session.checkin_app( &app_id.into(), presence )
             .map_err( | e| ::capnp::Error::failed( format!("Failed to checkin app: {:?}", e) ) )
             .for_each( move |item|
             {
@@ -355,12 +514,12 @@ impl home_session::Server for HomeSessionDispatcherCapnProto
                             .map( move |resp|
                             {
                                 let answer = resp.get()
-                                    .and_then( |res| res.get_to_callee() )
-                                    .map( |to_callee_capnp|
-                                        fwd_appmsg( to_callee_capnp, handle_clone ) )
-                                    .map_err( |e| e ) // TODO should we something about errors here?
-                                    .ok();
-                                incoming_call.answer(answer);
+                                    .and_then( |res| res.get_answer() )
+                                    .and_then( |answer| read_call_answer(answer, handle_clone) );
+                                // NOTE the caller gets an error if the call is dropped without a valid answer
+                                // TODO should we do anything else about errors here?
+                                if let Ok(answer) = answer
+                                    { incoming_call.answer(answer); }
                             } );
                         Box::new(fut) as AsyncResult<(), ::capnp::Error>
                     },
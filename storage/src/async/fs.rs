@@ -20,6 +20,17 @@ pub type FileStore = BlockingFileStore;
 
 
 
+// NOTE a missing file means that the key holds no value, callers must be able to tell that from real failures
+fn io_error(e: ::std::io::Error) -> StorageError
+{
+    match e.kind() {
+        ::std::io::ErrorKind::NotFound => StorageError::InvalidKey,
+        _ => StorageError::StringError( e.description().to_owned() ),
+    }
+}
+
+
+
 pub struct BlockingFileStore
 {
     base_path:  PathBuf,
@@ -33,11 +44,16 @@ impl BlockingFileStore
 
     fn set_bytes(&self, key: String, value: &[u8]) -> Result<(),::std::io::Error>
     {
-        use std::{io::Write, fs::{create_dir_all, File}};
-        let file_path = self.base_path.join(key);
+        use std::{io::Write, fs::{create_dir_all, rename, File}};
+        let file_path = self.base_path.join(&key);
+        // NOTE write a temporary file first and replace the old one by renaming,
+        //      so an interrupted write cannot leave a truncated file behind
+        let temp_path = self.base_path.join( format!("{}.tmp", key) );
         create_dir_all(&self.base_path)?;
-        let mut file = File::create(file_path)?;
+        let mut file = File::create(&temp_path)?;
         file.write_all(value)?;
+        file.sync_all()?;
+        rename(temp_path, file_path)?;
         Ok( () )
     }
 
@@ -72,7 +88,7 @@ impl<V> KeyValueStore<String, V> for BlockingFileStore
     {
         let bytes = match self.get_bytes(key) {
             Ok(bytes) => bytes,
-            Err(e) => return Box::new( Err( StorageError::StringError( e.description().to_owned() ) ).into_future() ),
+            Err(e) => return Box::new( Err( io_error(e) ).into_future() ),
         };
 
         let res = serde_json::from_slice(&bytes)
@@ -83,7 +99,7 @@ impl<V> KeyValueStore<String, V> for BlockingFileStore
     fn clear_local(&mut self, key: String) -> Box< Future<Item=(), Error=StorageError> + Send >
     {
         let res = ::std::fs::remove_file( self.base_path.join(key) )
-            .map_err( |e| StorageError::StringError( e.description().to_owned() ) );
+            .map_err(io_error);
         Box::new( res.into_future() )
     }
 }
@@ -157,7 +173,7 @@ impl<V> KeyValueStore<String, V> for AsyncFileStore
             .inspect( |_| trace!("File opened for read") )
             .and_then( |file| ::tokio_io::io::read_to_end( file, Vec::new() ) )
             .inspect( |(_file,bytes)| trace!("Read {} bytes from file", bytes.len()) )
-            .map_err( |e| StorageError::StringError( e.description().to_owned())  )
+            .map_err(io_error)
             .and_then( |(_file,bytes)| serde_json::from_slice(&bytes)
                 .map_err( |e| { debug!("Failed to read file: {:?}", e); StorageError::StringError( e.description().to_owned() ) } ) );
         Box::new( self.schedule(fut) )
@@ -166,7 +182,7 @@ impl<V> KeyValueStore<String, V> for AsyncFileStore
     fn clear_local(&mut self, key: String) -> Box< Future<Item=(), Error=StorageError> + Send >
     {
         let fut = ::tokio_fs::remove_file( self.base_path.join(key) )
-            .map_err( |e| StorageError::StringError( e.description().to_owned() ) );
+            .map_err(io_error);
         Box::new( self.schedule(fut) )
     }
 }
@@ -197,4 +213,8 @@ fn test_file_store()
         assert_eq!(read, content);
 
     }
+    match reactor.run( storage.get( 0.to_string() ) ) {
+        Err(StorageError::InvalidKey) => {},
+        res => panic!("Expected missing key, got {:?}", res),
+    }
 }
@@ -42,6 +42,17 @@ pub trait KeyValueStore<KeyType, ValueType>
 }
 
 
+/// Turn the result of a get() into None if the key holds no value, keeping all other failures as errors
+pub fn optional<V>(get_res: Result<V, StorageError>) -> Result<Option<V>, StorageError>
+{
+    match get_res {
+        Ok(value) => Ok( Some(value) ),
+        Err(StorageError::InvalidKey) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
 
 pub struct ModularHashSpace<SerializedType, BinaryHashType, ReadableHashType>
 {
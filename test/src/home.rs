@@ -1,9 +1,16 @@
+use std::time::Duration;
+
 use futures::{Future, Sink, Stream};
 use tokio_core::reactor;
 
+use mercury_connect::{Contact, DAppSession, profile::{HomeConnector, MyProfile}, sdk::DAppSessionImpl};
+
 use mercury_home_protocol::*;
+use mercury_home_protocol::error::ErrorKind;
 use mercury_home_protocol::mercury_capnp::{client_proxy::HomeClientCapnProto, server_dispatcher::HomeDispatcherCapnProto};
+use mercury_home_node::metrics;
 use mercury_home_node::server::*;
+use mercury_storage::error::StorageError;
 
 use super::*;
 
@@ -157,7 +164,13 @@ fn register_client(setup: &mut TestSetup, client: &TestClient) -> OwnProfile
     let half_proof = RelationHalfProof::new(RelationProof::RELATION_TYPE_HOSTED_ON_HOME,
         setup.testclient.home_context.peer_id(), client.home_context.my_signer());
     let reg_fut = client.home_connection.register(client.ownprofile.clone(), half_proof, None);
-    setup.reactor.run(reg_fut).unwrap()
+    let mut ownprofile = setup.reactor.run(reg_fut).unwrap();
+
+    // The home added its proof to the profile, the new version has to be signed and published by the client
+    ownprofile.profile.sign_next_version( client.home_context.my_signer() );
+    let session = setup.reactor.run( client.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    setup.reactor.run( session.update( ownprofile.clone() ) ).unwrap();
+    ownprofile
 }
 
 fn register_client_from_setup(setup: &mut TestSetup) -> OwnProfile
@@ -226,6 +239,342 @@ fn test_home_events(mut setup: TestSetup)
     }
 }
 
+fn test_home_offline_events(mut setup: TestSetup)
+{
+    let _ownprofile1 = register_client_from_setup(&mut setup);
+
+    let (ownprofile2, signer2) = generate_persona();
+    let home_server_clone = setup.home_server.clone();
+    let home_signer_clone = setup.home_signer.clone();
+    let home_profile_clone = setup.home_profile.clone();
+    let testclient2 = TestClient::new(
+        setup.mode.clone(),
+        ownprofile2, Rc::new(signer2),
+        home_server_clone, home_signer_clone, &home_profile_clone,
+        setup.reactor.handle()
+    );
+    let ownprofile2 = register_client(&mut setup, &testclient2);
+
+    // Pairing request is sent while the peer has no session
+    let half_proof = RelationHalfProof::new("friend", &ownprofile2.profile.id, setup.testclient.home_context.my_signer());
+    setup.reactor.run( setup.testclient.home_connection.pair_request( half_proof.clone() ) ).unwrap();
+
+    let session2 = setup.reactor.run(testclient2.home_connection.login(first_home_of(&ownprofile2))).unwrap();
+    let events_fut = session2.events().take(1).collect();
+    let events = setup.reactor.run(events_fut).unwrap();
+    match events[0] {
+        Ok( ProfileEvent::PairingRequest(ref received) ) => assert_eq!(*received, half_proof),
+        _ => panic!("not a PairingRequest"),
+    }
+}
+
+fn test_home_list_resolve(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+
+    let personas = ProfileListFilter{ facet_type: Some(FacetType::Persona), ..Default::default() };
+    let listed = setup.reactor.run( setup.testclient.home_connection.list(&personas) ).unwrap();
+    assert_eq!( listed, vec![ ownprofile.profile.clone() ] );
+
+    let next_page = ProfileListFilter{ offset: 1, ..personas.clone() };
+    let listed = setup.reactor.run( setup.testclient.home_connection.list(&next_page) ).unwrap();
+    assert!( listed.is_empty() );
+
+    let homes = ProfileListFilter{ facet_type: Some(FacetType::Home), ..Default::default() };
+    let listed = setup.reactor.run( setup.testclient.home_connection.list(&homes) ).unwrap();
+    assert!( listed.is_empty() );
+
+    let url = ProfileUrl::new( ownprofile.profile.id.clone(), Some( setup.home_profile.id.clone() ), Vec::new() );
+    let resolved = setup.reactor.run( setup.testclient.home_connection.resolve( &url.to_string() ) ).unwrap();
+    assert_eq!( resolved, ownprofile.profile );
+
+    assert!( setup.reactor.run( setup.testclient.home_connection.resolve("invalid url") ).is_err() );
+}
+
+#[test]
+fn test_resolve_with_hints_wrong_profile()
+{
+    let mut reactor = reactor::Core::new().unwrap();
+    let public_dht = Rc::new( RefCell::new( InMemoryStore::<ProfileId, Profile>::new() ) );
+    let home_server = Rc::new( home_server_with_dht( &reactor.handle(), public_dht.clone(),
+        RegistrationPolicy::Open, SessionLimits::default(), SessionPolicy::Single ) );
+    let (home_profile, home_signer) = generate_home();
+    let mut homes = HashMap::new();
+    homes.insert( home_profile.id.clone(), ( home_server, Rc::new(home_signer) as Rc<Signer> ) );
+    let connector = Rc::new( DirectHomeConnector{ homes, unreachable: Default::default() } );
+
+    // The home answers with another validly signed profile than the requested one
+    let (requested, _requested_signer) = generate_persona();
+    let (other, other_signer) = generate_persona();
+    reactor.run( public_dht.borrow_mut().set( requested.profile.id.clone(), other.profile.clone() ) ).unwrap();
+
+    let repo = SimpleProfileRepo::default();
+    let home_addr = "/ip4/127.0.0.1/tcp/2077".parse().unwrap();
+    let url = ProfileUrl::new( requested.profile.id.clone(), Some( home_profile.id.clone() ), vec![home_addr] );
+    let resolve_fut = repo.resolve_with_hints( &url.to_string(), connector, Rc::new(other_signer) );
+    assert_eq!( reactor.run(resolve_fut).unwrap_err().kind(), ErrorKind::FailedToResolveUrl );
+    assert!( reactor.run( repo.load(&requested.profile.id) ).is_err() );
+}
+
+fn register_persona_with(setup: &mut TestSetup, home_server: Rc<HomeServer>, invite: Option<HomeInvitation>)
+    -> Result<OwnProfile, ErrorKind>
+{
+    let (ownprofile, signer) = generate_persona();
+    let signer = Rc::new(signer);
+    let client = TestClient::new( setup.mode.clone(), ownprofile.clone(), signer.clone(), home_server,
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    let half_proof = RelationHalfProof::new( RelationProof::RELATION_TYPE_HOSTED_ON_HOME, &setup.home_profile.id, &*signer );
+    let reg_fut = client.home_connection.register(ownprofile, half_proof, invite);
+    setup.reactor.run(reg_fut).map_err( |(_ownprofile, e)| e.kind() )
+}
+
+fn test_home_invite_only(mut setup: TestSetup)
+{
+    let home_server = Rc::new( home_server_with( &setup.reactor.handle(),
+        RegistrationPolicy::InviteOnly, SessionLimits::default(), SessionPolicy::Single ) );
+    let validity = Duration::from_secs(60);
+    let (_other_home, other_home_signer) = generate_home();
+    let invitation = HomeInvitation::issue( validity, &*setup.home_signer );
+    let expired = HomeInvitation::issue( Duration::from_secs(0), &*setup.home_signer );
+    let foreign = HomeInvitation::issue( validity, &other_home_signer );
+
+    let expect_err = |setup: &mut TestSetup, invite: Option<HomeInvitation>, kind: ErrorKind| {
+        let res = register_persona_with( setup, home_server.clone(), invite );
+        assert_eq!( res.unwrap_err(), kind );
+    };
+
+    expect_err( &mut setup, None, ErrorKind::InvitationRequired );
+    expect_err( &mut setup, Some(expired), ErrorKind::InvitationExpired );
+    expect_err( &mut setup, Some(foreign), ErrorKind::InvalidInvitation );
+
+    let ownprofile = register_persona_with( &mut setup, home_server.clone(), Some( invitation.clone() ) ).unwrap();
+    match ownprofile.profile.facet {
+        ProfileFacet::Persona(ref persona) => assert_eq!( persona.homes.len(), 1 ),
+        _ => panic!("Persona expected"),
+    }
+
+    expect_err( &mut setup, Some(invitation), ErrorKind::InvitationAlreadyUsed );
+}
+
+fn test_home_event_buffer_limits(mut setup: TestSetup)
+{
+    let limits = SessionLimits{ event_buffer_size: 1, overflow: OverflowPolicy::RejectNew, ..SessionLimits::default() };
+    setup.home_server = Rc::new( home_server_with( &setup.reactor.handle(), RegistrationPolicy::Open, limits, SessionPolicy::Single ) );
+    let new_client = |setup: &TestSetup| {
+        let (ownprofile, signer) = generate_persona();
+        TestClient::new( setup.mode.clone(), ownprofile, Rc::new(signer), setup.home_server.clone(),
+            setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() )
+    };
+    setup.testclient = new_client(&setup);
+    let _ownprofile1 = register_client_from_setup(&mut setup);
+    let testclient2 = new_client(&setup);
+    let ownprofile2 = register_client(&mut setup, &testclient2);
+
+    // The session is online but does not listen to events yet, so only the first request fits into its buffer
+    let session2 = setup.reactor.run( testclient2.home_connection.login( first_home_of(&ownprofile2) ) ).unwrap();
+    let half_proof = RelationHalfProof::new("friend", &ownprofile2.profile.id, setup.testclient.home_context.my_signer());
+    setup.reactor.run( setup.testclient.home_connection.pair_request( half_proof.clone() ) ).unwrap();
+    let overflow_res = setup.reactor.run( setup.testclient.home_connection.pair_request( half_proof.clone() ) );
+    assert_eq!( overflow_res.unwrap_err().kind(), ErrorKind::SessionBufferFull );
+    assert_eq!( setup.home_server.buffer_metrics().rejected_events, 1 );
+
+    let events = setup.reactor.run( session2.events().take(1).collect() ).unwrap();
+    match events[0] {
+        Ok( ProfileEvent::PairingRequest(ref received) ) => assert_eq!(*received, half_proof),
+        _ => panic!("not a PairingRequest"),
+    }
+}
+
+fn test_home_login_sessions(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let old_session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    let old_events = old_session.events();
+    let new_session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+
+    // The replaced session is notified, events are delivered to the new one
+    let (notice, _old_events) = setup.reactor.run( old_events.into_future() ).ok().unwrap();
+    assert!( match notice { Some( Err(_) ) => true, _ => false } );
+
+    let (peer_ownprofile, peer_signer) = generate_persona();
+    let peer_testclient = TestClient::new( setup.mode.clone(), peer_ownprofile, Rc::new(peer_signer), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    let _peer_ownprofile = register_client(&mut setup, &peer_testclient);
+    let half_proof = RelationHalfProof::new("friend", &ownprofile.profile.id, peer_testclient.home_context.my_signer());
+    setup.reactor.run( peer_testclient.home_connection.pair_request( half_proof.clone() ) ).unwrap();
+
+    let events = setup.reactor.run( new_session.events().take(1).collect() ).unwrap();
+    assert_eq!( events[0], Ok( ProfileEvent::PairingRequest(half_proof) ) );
+}
+
+fn test_home_multi_device(mut setup: TestSetup)
+{
+    setup.home_server = Rc::new( home_server_with( &setup.reactor.handle(), RegistrationPolicy::Open,
+        SessionLimits::default(), SessionPolicy::MultiDevice ) );
+    let new_client = |setup: &TestSetup| {
+        let (ownprofile, signer) = generate_persona();
+        TestClient::new( setup.mode.clone(), ownprofile, Rc::new(signer), setup.home_server.clone(),
+            setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() )
+    };
+    setup.testclient = new_client(&setup);
+    let callee_ownprofile = register_client_from_setup(&mut setup);
+    let caller_testclient = new_client(&setup);
+    let _caller_ownprofile = register_client(&mut setup, &caller_testclient);
+
+    let app = ApplicationId::from("chat");
+    let phone = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&callee_ownprofile) ) ).unwrap();
+    let laptop = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&callee_ownprofile) ) ).unwrap();
+    let phone_calls = phone.checkin_app(&app, None);
+    let laptop_calls = laptop.checkin_app(&app, None);
+
+    // Events are delivered to all devices
+    let half_proof = RelationHalfProof::new("friend", &callee_ownprofile.profile.id, caller_testclient.home_context.my_signer());
+    setup.reactor.run( caller_testclient.home_connection.pair_request( half_proof.clone() ) ).unwrap();
+    for session in vec![&phone, &laptop] {
+        let events = setup.reactor.run( session.events().take(1).collect() ).unwrap();
+        assert_eq!( events[0], Ok( ProfileEvent::PairingRequest( half_proof.clone() ) ) );
+    }
+
+    // Calls ring all devices, the laptop answers while the phone keeps ringing
+    let relation = RelationProof::sign_remaining_half( &half_proof, &*setup.testclient.home_context.my_signer() ).unwrap();
+    let (to_caller, from_callee) = app_channel(1);
+    let call_details = CallRequestDetails::new( relation, AppMessageFrame( Vec::new() ), Some(to_caller) );
+    let call_fut = caller_testclient.home_connection.call( app.clone(), call_details );
+
+    let (_phone_call, _phone_calls) = setup.reactor.run( phone_calls.into_future() ).ok().unwrap();
+    let (to_callee, _from_caller) = app_channel(1);
+    let answer_fut = laptop_calls.into_future()
+        .map_err( |_e| ErrorKind::CallFailed.into() )
+        .map( move |(call, _calls)| call.unwrap().unwrap().answer( CallAnswer::Accepted(to_callee) ).to_caller.unwrap() );
+    let (answer, laptop_to_caller) = setup.reactor.run( call_fut.join(answer_fut) ).unwrap();
+    assert!( match answer { CallAnswer::Accepted(_) => true, _ => false } );
+
+    let hello = AppMessageFrame( Vec::from("hello from the laptop") );
+    setup.reactor.run( laptop_to_caller.send( Ok( hello.clone() ) ) ).unwrap();
+    let messages = setup.reactor.run( from_callee.take(1).collect() ).unwrap();
+    assert_eq!( messages, vec![ Ok(hello) ] );
+}
+
+fn test_home_admin(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let profile_id = ownprofile.profile.id.clone();
+    let home_id = setup.home_signer.profile_id().to_owned();
+    let hosted = setup.reactor.run( setup.home_server.hosted_profiles(&home_id) ).unwrap();
+    assert!( hosted.contains(&profile_id) );
+
+    // Events of the offline profile are queued in its mailbox
+    let (peer_ownprofile, peer_signer) = generate_persona();
+    let peer_testclient = TestClient::new( setup.mode.clone(), peer_ownprofile, Rc::new(peer_signer), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    let _peer_ownprofile = register_client(&mut setup, &peer_testclient);
+    let half_proof = RelationHalfProof::new("friend", &profile_id, peer_testclient.home_context.my_signer());
+    setup.reactor.run( peer_testclient.home_connection.pair_request( half_proof.clone() ) ).unwrap();
+    let queued = setup.reactor.run( setup.home_server.queued_events(&profile_id) ).unwrap();
+    assert_eq!( queued, vec![ ProfileEvent::PairingRequest(half_proof) ] );
+
+    // Logging out closes the streams of the session with a notice
+    let session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    let (_pairing_request, events) = setup.reactor.run( session.events().into_future() ).ok().unwrap();
+    assert!( setup.home_server.logout(&profile_id) >= 1 );
+    let (notice, _events) = setup.reactor.run( events.into_future() ).ok().unwrap();
+    assert!( match notice { Some( Err(_) ) => true, _ => false } );
+
+    setup.reactor.run( HomeServer::force_unregister( setup.home_server.clone(), home_id.clone(), profile_id.clone() ) ).unwrap();
+    let hosted = setup.reactor.run( setup.home_server.hosted_profiles(&home_id) ).unwrap();
+    assert!( ! hosted.contains(&profile_id) );
+    let unregister_res = setup.reactor.run( HomeServer::force_unregister( setup.home_server.clone(), home_id, profile_id ) );
+    assert_eq!( unregister_res.unwrap_err().kind(), ErrorKind::PeerNotHostedHere );
+}
+
+fn test_home_metrics(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let registry = setup.home_server.metrics_registry();
+    let sessions_opened = registry.value(&metrics::SESSIONS_OPENED, &[]);
+    let _session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    assert_eq!( registry.value(&metrics::SESSIONS_OPENED, &[]), sessions_opened + 1.0 );
+
+    let rendered = setup.home_server.render_metrics();
+    assert!( rendered.contains("# TYPE mercury_home_sessions_opened_total counter\n") );
+    assert!( rendered.contains("mercury_home_buffer_overflows_total{item=\"event\",action=\"rejected\"} 0\n") );
+
+    let probes = setup.reactor.run( setup.home_server.check_storage() ).unwrap();
+    assert_eq!( probes.len(), 8 );
+    assert!( probes.iter().all( |&(_name, healthy)| healthy ) );
+
+    // A storage failing right away is unhealthy, too, not only one that does not respond
+    let failing_server = home_server_with_dht( &setup.reactor.handle(), Rc::new( RefCell::new(FailingStore) ),
+        RegistrationPolicy::Open, SessionLimits::default(), SessionPolicy::Single );
+    let probes = setup.reactor.run( failing_server.check_storage() ).unwrap();
+    assert!( probes.iter().all( |&(name, healthy)| healthy == (name != "public-profiles") ) );
+}
+
+/// Storage failing all operations like an unreadable disk
+struct FailingStore;
+
+impl<K, V: Send + 'static> KeyValueStore<K, V> for FailingStore
+{
+    fn set(&mut self, _key: K, _value: V) -> Box< Future<Item=(), Error=StorageError> + Send >
+        { Box::new( futures::future::err( StorageError::StringError( "I/O error".to_owned() ) ) ) }
+
+    fn get(&self, _key: K) -> Box< Future<Item=V, Error=StorageError> + Send >
+        { Box::new( futures::future::err( StorageError::StringError( "I/O error".to_owned() ) ) ) }
+
+    fn clear_local(&mut self, _key: K) -> Box< Future<Item=(), Error=StorageError> + Send >
+        { Box::new( futures::future::err( StorageError::StringError( "I/O error".to_owned() ) ) ) }
+}
+
+fn test_home_publish_addresses(mut setup: TestSetup)
+{
+    use multiaddr::ToMultiaddr;
+
+    let addrs = vec![ "/ip4/192.0.2.1/tcp/2077".to_multiaddr().unwrap(), "/ip6/2001:db8::1/tcp/2077".to_multiaddr().unwrap() ];
+    let publish_fut = HomeServer::publish_home_profile( setup.home_server.clone(), setup.home_signer.clone(), addrs.clone() );
+    let published = setup.reactor.run(publish_fut).unwrap();
+    assert_eq!( published.facet, ProfileFacet::Home( HomeFacet{ addrs: addrs.clone(), data: Vec::new() } ) );
+    assert!( published.validate( &crypto::CompositeValidator::default() ).is_ok() );
+
+    // NOTE publishing the same addresses again keeps the published version
+    let republish_fut = HomeServer::publish_home_profile( setup.home_server.clone(), setup.home_signer.clone(), addrs );
+    assert_eq!( setup.reactor.run(republish_fut).unwrap(), published );
+
+    let moved_addrs = vec![ "/ip4/192.0.2.2/tcp/2077".to_multiaddr().unwrap() ];
+    let moved_fut = HomeServer::publish_home_profile( setup.home_server.clone(), setup.home_signer.clone(), moved_addrs.clone() );
+    let moved = setup.reactor.run(moved_fut).unwrap();
+    assert!( moved.version > published.version );
+    assert_eq!( moved.facet, ProfileFacet::Home( HomeFacet{ addrs: moved_addrs, data: Vec::new() } ) );
+
+    // NOTE publishing no addresses at all, e.g. when listening only on unspecified ones, keeps the last profile
+    let empty_fut = HomeServer::publish_home_profile( setup.home_server.clone(), setup.home_signer.clone(), Vec::new() );
+    assert_eq!( setup.reactor.run(empty_fut).unwrap_err().kind(), ErrorKind::ProfileUpdateFailed );
+    let home_id = setup.home_signer.profile_id().to_owned();
+    assert_eq!( setup.reactor.run( setup.testclient.home_connection.load(&home_id) ).unwrap(), moved );
+
+    // A restarted home not knowing its last publication still publishes a newer version
+    ::std::thread::sleep( Duration::from_millis(10) );
+    let restarted_server = Rc::new( default_home_server( &setup.reactor.handle() ) );
+    let restarted_addrs = vec![ "/ip4/192.0.2.3/tcp/2077".to_multiaddr().unwrap() ];
+    let restarted_fut = HomeServer::publish_home_profile( restarted_server, setup.home_signer.clone(), restarted_addrs );
+    let restarted = setup.reactor.run(restarted_fut).unwrap();
+    assert!( restarted.ensure_not_older_than(&moved).is_ok() );
+    assert!( restarted.version > moved.version );
+}
+
+fn test_home_shutdown(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    let events = session.events();
+
+    setup.reactor.run( HomeServer::shutdown( setup.home_server.clone(), Duration::from_secs(1) ) ).unwrap();
+    let (notice, _events) = setup.reactor.run( events.into_future() ).ok().unwrap();
+    assert!( match notice { Some( Err(_) ) => true, _ => false } );
+
+    let login_res = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) );
+    assert_eq!( login_res.err().unwrap().kind(), ErrorKind::ShuttingDown );
+}
+
 fn test_home_login(mut setup: TestSetup)
 {
     let ownprofile = register_client_from_setup(&mut setup);
@@ -265,6 +614,58 @@ fn test_home_register(mut setup: TestSetup)
     }
 }
 
+fn test_home_update(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let client = setup.testclient.clone();
+    let session = setup.reactor.run( client.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+
+    let mut updated = ownprofile.clone();
+    if let ProfileFacet::Persona(ref mut persona) = updated.profile.facet
+        { persona.data = b"updated".to_vec(); }
+    let unsigned_res = setup.reactor.run( session.update( updated.clone() ) );
+    assert_eq!( unsigned_res.unwrap_err().kind(), ErrorKind::InvalidProfileSignature );
+
+    updated.profile.sign_next_version( client.home_context.my_signer() );
+    setup.reactor.run( session.update( updated.clone() ) ).unwrap();
+    let loaded = setup.reactor.run( client.home_connection.load(&updated.profile.id) ).unwrap();
+    assert_eq!(loaded, updated.profile);
+
+    // Rolling back to a previous version is refused
+    let stale_res = setup.reactor.run( session.update(ownprofile) );
+    assert_eq!( stale_res.unwrap_err().kind(), ErrorKind::StaleProfileVersion );
+}
+
+fn test_home_ban(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+    let session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+
+    let (spammer_ownprofile, spammer_signer) = generate_persona();
+    let spammer_signer = Rc::new(spammer_signer);
+    let spammer_id = spammer_ownprofile.profile.id.clone();
+    let spammer = TestClient::new( setup.mode.clone(), spammer_ownprofile, spammer_signer.clone(), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    register_client(&mut setup, &spammer);
+
+    setup.reactor.run( session.ban(&spammer_id) ).unwrap();
+    setup.reactor.run( session.ban(&spammer_id) ).unwrap();
+    assert_eq!( setup.reactor.run( session.banned_profiles() ).unwrap(), vec![ spammer_id.clone() ] );
+
+    let half_proof = RelationHalfProof::new( "friend", &ownprofile.profile.id, &*spammer_signer );
+    let pair_res = setup.reactor.run( spammer.home_connection.pair_request( half_proof.clone() ) );
+    assert_eq!( pair_res.unwrap_err().kind(), ErrorKind::ProfileBanned );
+
+    let relation = RelationProof::sign_remaining_half( &half_proof, &*setup.testclient.home_context.my_signer() ).unwrap();
+    let call_details = CallRequestDetails::new( relation, AppMessageFrame( Vec::new() ), None );
+    let call_res = setup.reactor.run( spammer.home_connection.call( ApplicationId::from("chat"), call_details ) );
+    assert!( call_res.is_err() );
+
+    setup.reactor.run( session.unban(&spammer_id) ).unwrap();
+    assert!( setup.reactor.run( session.banned_profiles() ).unwrap().is_empty() );
+    setup.reactor.run( spammer.home_connection.pair_request(half_proof) ).unwrap();
+}
+
 fn test_home_call(mut setup: TestSetup)
 {
     let callee_ownprofile = register_client_from_setup(&mut setup);
@@ -276,7 +677,7 @@ fn test_home_call(mut setup: TestSetup)
 
     let app = ApplicationId::from("chat");
     let callee_session = setup.reactor.run(setup.testclient.home_connection.login(first_home_of(&callee_ownprofile))).unwrap();
-    let callee_calls = callee_session.checkin_app(&app);
+    let callee_calls = callee_session.checkin_app(&app, None);
 
     let relation_type = "friend";
     let relation_half_proof = RelationHalfProof::new(relation_type, &callee_ownprofile.profile.id, &*caller_signer);
@@ -287,11 +688,7 @@ fn test_home_call(mut setup: TestSetup)
     let (forward_sink, forward_stream) = app_channel(1);  // forward channel (caller -> callee)
     let (backwards_sink, backwards_stream) = app_channel(1);  // backwards channel (callee -> caller)
 
-    let call_details = CallRequestDetails {
-        relation,
-        init_payload: init_payload.clone(),
-        to_caller: Some(backwards_sink)
-    };
+    let call_details = CallRequestDetails::new( relation, init_payload.clone(), Some(backwards_sink) );
     let forward_sink_fut = caller_testclient.home_connection.call(app, call_details);
 
     // NOTE: an AppMessageFrame can be sent even before answer() is called
@@ -304,7 +701,7 @@ fn test_home_call(mut setup: TestSetup)
                     println!("call received");
                     assert_eq!( call.request_details().relation.relation_type, relation_type );
                     assert_eq!( call.request_details().init_payload, init_payload );
-                    call.answer( Some(forward_sink.clone()) ).to_caller.unwrap()
+                    call.answer( CallAnswer::Accepted( forward_sink.clone() ) ).to_caller.unwrap()
                 },
                 Err(_) => panic!(),
             }
@@ -318,7 +715,11 @@ fn test_home_call(mut setup: TestSetup)
 
     // Testing forward channel (caller -> callee)
     let banana = AppMessageFrame(Vec::from("banana"));
-    let send_banana_fut = forward_sink_returned.unwrap().send(Ok(banana.clone()));
+    let forward_sink_returned = match forward_sink_returned {
+        CallAnswer::Accepted(sink) => sink,
+        answer => panic!("Call should have been accepted: {:?}", answer),
+    };
+    let send_banana_fut = forward_sink_returned.send(Ok(banana.clone()));
     setup.reactor.run(send_banana_fut).unwrap();
 
     let read_banana_fut = forward_stream.take(1).collect();
@@ -347,6 +748,155 @@ fn test_home_call(mut setup: TestSetup)
     setup.reactor.run(read_orange_fut).unwrap();
 }
 
+fn test_home_call_answers(mut setup: TestSetup)
+{
+    let callee_ownprofile = register_client_from_setup(&mut setup);
+
+    let (caller_ownprofile, caller_signer) = generate_persona();
+    let caller_signer = Rc::new(caller_signer);
+    let caller = TestClient::new( setup.mode.clone(), caller_ownprofile, caller_signer.clone(), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    register_client(&mut setup, &caller);
+
+    let app = ApplicationId::from("chat");
+    let callee_session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&callee_ownprofile) ) ).unwrap();
+    let callee_calls = callee_session.checkin_app(&app, None);
+
+    let half_proof = RelationHalfProof::new( "friend", &callee_ownprofile.profile.id, &*caller_signer );
+    let relation = RelationProof::sign_remaining_half( &half_proof, &*setup.testclient.home_context.my_signer() ).unwrap();
+
+    // The callee answers the first call with a single reply, then rejects the second one
+    let reply = AppMessageFrame( Vec::from("busy, call me later") );
+    let answers = vec![ CallAnswer::Replied( reply.clone() ), CallAnswer::Rejected(42) ];
+    let answer_fut = callee_calls
+        .zip( futures::stream::iter_ok(answers) )
+        .for_each( |(call, answer)| { call.unwrap().answer(answer); Ok(()) } );
+    setup.reactor.handle().spawn(answer_fut);
+
+    // Calls without an id, e.g. from clients not setting it, are refused without ringing the callee
+    let mut call_details = CallRequestDetails::new( relation.clone(), AppMessageFrame( Vec::new() ), None );
+    call_details.id = CallId( Vec::new() );
+    let call_res = setup.reactor.run( caller.home_connection.call( app.clone(), call_details ) );
+    assert_eq!( call_res.unwrap_err().kind(), ErrorKind::InvalidCallId );
+
+    let call_details = CallRequestDetails::new( relation.clone(), AppMessageFrame( Vec::new() ), None );
+    match setup.reactor.run( caller.home_connection.call( app.clone(), call_details ) ).unwrap() {
+        CallAnswer::Replied(frame) => assert_eq!(frame, reply),
+        answer => panic!("Call should have been replied: {:?}", answer),
+    }
+
+    let call_details = CallRequestDetails::new( relation, AppMessageFrame( Vec::new() ), None );
+    match setup.reactor.run( caller.home_connection.call( app, call_details ) ).unwrap() {
+        CallAnswer::Rejected(reason) => assert_eq!(reason, 42),
+        answer => panic!("Call should have been rejected: {:?}", answer),
+    }
+}
+
+fn test_home_call_hangup(mut setup: TestSetup)
+{
+    let callee_ownprofile = register_client_from_setup(&mut setup);
+
+    let (caller_ownprofile, caller_signer) = generate_persona();
+    let caller_signer = Rc::new(caller_signer);
+    let caller = TestClient::new( setup.mode.clone(), caller_ownprofile, caller_signer.clone(), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    register_client(&mut setup, &caller);
+
+    let app = ApplicationId::from("chat");
+    let callee_session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&callee_ownprofile) ) ).unwrap();
+    let callee_calls = callee_session.checkin_app(&app, None);
+
+    let half_proof = RelationHalfProof::new( "friend", &callee_ownprofile.profile.id, &*caller_signer );
+    let relation = RelationProof::sign_remaining_half( &half_proof, &*setup.testclient.home_context.my_signer() ).unwrap();
+
+    // The caller cancels the call while it is still ringing
+    let (to_caller, _from_callee) = app_channel(1);
+    let call_req = CallRequestDetails::new( relation.clone(), AppMessageFrame( Vec::new() ), Some(to_caller) );
+    let call_id = call_req.id.clone();
+    let call_fut = caller.home_connection.call( app.clone(), call_req )
+        .then( |res| Ok::<_, ::mercury_home_protocol::error::Error>(res) );
+    let caller_home = caller.home_connection.clone();
+    let cancel_fut = callee_calls.into_future()
+        .map_err( |_e| ErrorKind::CallFailed.into() )
+        .and_then( move |(call, calls)| caller_home.hangup(call_id).map( move |()| (call, calls) ) );
+    let (call_res, (call, callee_calls)) = setup.reactor.run( call_fut.join(cancel_fut) ).unwrap();
+    assert_eq!( call_res.unwrap_err().kind(), ErrorKind::CallCancelled );
+
+    // Answering a cancelled call has no effect, its channels are closed
+    let (to_callee, from_caller) = app_channel(1);
+    call.unwrap().unwrap().answer( CallAnswer::Accepted(to_callee) );
+    assert!( setup.reactor.run( from_caller.collect() ).unwrap().is_empty() );
+
+    // The callee hangs up an answered call, ending the incoming streams of both parties
+    let (to_caller, from_callee) = app_channel(1);
+    let call_req = CallRequestDetails::new( relation, AppMessageFrame( Vec::new() ), Some(to_caller) );
+    let call_id = call_req.id.clone();
+    let (to_callee, from_caller) = app_channel(1);
+    let answer_fut = callee_calls.into_future()
+        .map( |(call, _calls)| call.unwrap().unwrap().answer( CallAnswer::Accepted(to_callee) ).to_caller )
+        .map_err( |_e| ErrorKind::CallFailed.into() );
+    let (answer, _to_caller) = setup.reactor.run( caller.home_connection.call( app, call_req ).join(answer_fut) ).unwrap();
+    let _to_callee = match answer {
+        CallAnswer::Accepted(to_callee) => to_callee,
+        answer => panic!("Call should have been accepted: {:?}", answer),
+    };
+
+    setup.reactor.run( setup.testclient.home_connection.hangup( call_id.clone() ) ).unwrap();
+    assert!( setup.reactor.run( from_callee.collect() ).unwrap().is_empty() );
+    assert!( setup.reactor.run( from_caller.collect() ).unwrap().is_empty() );
+    assert!( setup.reactor.run( caller.home_connection.hangup(call_id) ).is_err() );
+}
+
+fn test_home_presence(mut setup: TestSetup)
+{
+    let ownprofile = register_client_from_setup(&mut setup);
+
+    let (contact_ownprofile, contact_signer) = generate_persona();
+    let contact_signer = Rc::new(contact_signer);
+    let contact = TestClient::new( setup.mode.clone(), contact_ownprofile, contact_signer.clone(), setup.home_server.clone(),
+        setup.home_signer.clone(), &setup.home_profile, setup.reactor.handle() );
+    register_client(&mut setup, &contact);
+
+    let half_proof = RelationHalfProof::new( "friend", &ownprofile.profile.id, &*contact_signer );
+    let relation = RelationProof::sign_remaining_half( &half_proof, &*setup.testclient.home_context.my_signer() ).unwrap();
+    let app = ApplicationId::from("chat");
+    let available = AppMessageFrame( Vec::from("available") );
+
+    let session = setup.reactor.run( setup.testclient.home_connection.login( first_home_of(&ownprofile) ) ).unwrap();
+    let updates = contact.home_connection.subscribe_presence( relation.clone(), app.clone() );
+    let (current, updates) = setup.reactor.run( updates.into_future() ).ok().unwrap();
+    assert_eq!( current, Some( Ok(None) ) );
+
+    let calls = session.checkin_app( &app, Some( available.clone() ) );
+    let (changed, updates) = setup.reactor.run( updates.into_future() ).ok().unwrap();
+    assert_eq!( changed, Some( Ok( Some( available.clone() ) ) ) );
+    let presence = setup.reactor.run( contact.home_connection.presence( relation.clone(), app.clone() ) ).unwrap();
+    assert_eq!( presence, Some( available.clone() ) );
+
+    let _calls = session.checkin_app(&app, None);
+    let (changed, updates) = setup.reactor.run( updates.into_future() ).ok().unwrap();
+    assert_eq!( changed, Some( Ok(None) ) );
+
+    // Presence is cleared as soon as the app drops its calls stream
+    drop(calls);
+    let calls = session.checkin_app( &app, Some( available.clone() ) );
+    let (changed, updates) = setup.reactor.run( updates.into_future() ).ok().unwrap();
+    assert_eq!( changed, Some( Ok( Some( available.clone() ) ) ) );
+    drop(calls);
+    let (changed, updates) = setup.reactor.run( updates.into_future() ).ok().unwrap();
+    assert_eq!( changed, Some( Ok(None) ) );
+    let presence = setup.reactor.run( contact.home_connection.presence( relation.clone(), app.clone() ) ).unwrap();
+    assert_eq!(presence, None);
+
+    // ... or the whole session goes away
+    if let TestMode::Direct = setup.mode {
+        let _calls = session.checkin_app( &app, Some( available.clone() ) );
+        drop(session);
+        let changes = setup.reactor.run( updates.take(2).collect() ).unwrap();
+        assert_eq!( changes, vec![ Ok( Some(available) ), Ok(None) ] );
+    }
+}
+
 fn do_test(test_fn: &Fn(TestSetup) -> ()) {
     println!("> Direct mode");
     test_fn(TestSetup::init(TestMode::Direct));
@@ -372,18 +922,320 @@ fn test_home_events_configs()
     do_test(&test_home_events);
 }
 
+#[test]
+fn test_home_offline_events_configs()
+{
+    do_test(&test_home_offline_events);
+}
+
+#[test]
+fn test_home_list_resolve_configs()
+{
+    do_test(&test_home_list_resolve);
+}
+
+#[test]
+fn test_home_invite_only_configs()
+{
+    do_test(&test_home_invite_only);
+}
+
+#[test]
+fn test_home_update_configs()
+{
+    do_test(&test_home_update);
+}
+
+#[test]
+fn test_home_ban_configs()
+{
+    do_test(&test_home_ban);
+}
+
 #[test]
 fn test_home_call_configs()
 {
     do_test(&test_home_call);
 }
 
+#[test]
+fn test_home_call_answers_configs()
+{
+    do_test(&test_home_call_answers);
+}
+
+#[test]
+fn test_home_call_hangup_configs()
+{
+    do_test(&test_home_call_hangup);
+}
+
+#[test]
+fn test_home_presence_configs()
+{
+    do_test(&test_home_presence);
+}
+
+#[test]
+fn test_home_event_buffer_limits_configs()
+{
+    do_test(&test_home_event_buffer_limits);
+}
+
+#[test]
+fn test_home_login_sessions_configs()
+{
+    do_test(&test_home_login_sessions);
+}
+
+#[test]
+fn test_home_multi_device_configs()
+{
+    do_test(&test_home_multi_device);
+}
+
+#[test]
+fn test_home_admin_configs()
+{
+    do_test(&test_home_admin);
+}
+
+#[test]
+fn test_home_metrics_configs()
+{
+    do_test(&test_home_metrics);
+}
+
+#[test]
+fn test_home_publish_addresses_configs()
+{
+    do_test(&test_home_publish_addresses);
+}
+
+#[test]
+fn test_home_shutdown_configs()
+{
+    do_test(&test_home_shutdown);
+}
+
 #[test]
 fn test_home_login_configs()
 {
     do_test(&test_home_login);
 }
 
+#[test]
+fn test_profile_multi_home()
+{
+    let mut setup = TwoPersonasSetup::new();
+    for home in &setup.homes
+        { setup.reactor.run( setup.alice.join_home( home.id.clone(), None ) ).unwrap(); }
+    assert_eq!( setup.reactor.run( setup.alice.homes() ).unwrap().len(), 2 );
+    setup.exchange_profiles();
+
+    let alice_session = setup.reactor.run( setup.alice.login() ).unwrap();
+    let alice_events = alice_session.events();
+
+    // Pairing request fails over to the second home while the first one is down
+    setup.connector.unreachable.borrow_mut().insert( setup.homes[0].id.clone() );
+    setup.reactor.run( setup.bob.initiate_relation("friend", &setup.alice_id) ).unwrap();
+    setup.connector.unreachable.borrow_mut().clear();
+
+    // The very same request delivered through both homes must not be seen twice
+    let half_proof = RelationHalfProof::new("colleague", &setup.alice_id, &*setup.bob_signer);
+    for home_profile in &setup.homes {
+        let home = setup.reactor.run( setup.connector.connect( home_profile, setup.bob_signer.clone() ) ).unwrap();
+        setup.reactor.run( home.pair_request( half_proof.clone() ) ).unwrap();
+    }
+
+    setup.reactor.run( setup.bob.initiate_relation("acquaintance", &setup.alice_id) ).unwrap();
+
+    let events = setup.reactor.run( alice_events.take(3).collect() ).unwrap();
+    match ( &events[0], &events[1], &events[2] ) {
+        ( ProfileEvent::PairingRequest(ref failed_over), ProfileEvent::PairingRequest(ref first),
+          ProfileEvent::PairingRequest(ref next) ) => {
+            assert_eq!(failed_over.relation_type, "friend");
+            assert_eq!(*first, half_proof);
+            assert_eq!(next.relation_type, "acquaintance");
+        },
+        _ => panic!("PairingRequests expected"),
+    }
+}
+
+
+#[test]
+fn test_profile_migration()
+{
+    let mut setup = TwoPersonasSetup::new();
+    let (old_home, new_home) = ( setup.homes[0].clone(), setup.homes[1].clone() );
+    let alice_id = setup.alice_id.clone();
+    setup.reactor.run( setup.alice.join_home( old_home.id.clone(), None ) ).unwrap();
+
+    // Bob only knows where Alice lived before moving
+    let stale_alice = setup.reactor.run( setup.alice_repo.load(&alice_id) ).unwrap();
+    setup.reactor.run( setup.bob_repo.insert(stale_alice) ).unwrap();
+
+    setup.reactor.run( setup.alice.leave_home( old_home.id.clone(), Some( new_home.clone() ) ) ).unwrap();
+    let homes = setup.reactor.run( setup.alice.homes() ).unwrap();
+    assert_eq!(homes.len(), 1);
+    assert_eq!( *homes[0].peer_id(&alice_id).unwrap(), new_home.id );
+
+    let old_home_conn = setup.reactor.run( setup.connector.connect( &old_home, setup.bob_signer.clone() ) ).unwrap();
+
+    // The old home serves the new profile and refuses requests to the moved profile
+    let moved_alice = setup.reactor.run( old_home_conn.load(&alice_id) ).unwrap();
+    assert_eq!( moved_alice.facet, ProfileFacet::Persona( PersonaFacet{ homes: homes.clone(), data: Vec::new() } ) );
+    let half_proof = RelationHalfProof::new("friend", &alice_id, &*setup.bob_signer);
+    let pair_res = setup.reactor.run( old_home_conn.pair_request(half_proof) );
+    assert_eq!( pair_res.unwrap_err().kind(), mercury_home_protocol::error::ErrorKind::ProfileMoved );
+
+    // Bob follows the redirect of the old home
+    let alice_session = setup.reactor.run( setup.alice.login() ).unwrap();
+    let alice_events = alice_session.events();
+    setup.reactor.run( setup.bob.initiate_relation("friend", &alice_id) ).unwrap();
+
+    let events = setup.reactor.run( alice_events.take(1).collect() ).unwrap();
+    match events[0] {
+        ProfileEvent::PairingRequest(ref half_proof) => assert_eq!( half_proof.signer_id, setup.bob_id ),
+        _ => panic!("PairingRequest expected"),
+    }
+}
+
+
+#[test]
+fn test_relation_revocation()
+{
+    let mut setup = TwoPersonasSetup::new();
+    setup.reactor.run( setup.alice.join_home( setup.homes[0].id.clone(), None ) ).unwrap();
+    setup.reactor.run( setup.bob.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+    setup.exchange_profiles();
+
+    let alice_id = setup.alice_id.clone();
+    let half_proof = RelationHalfProof::new( "friend", &alice_id, &*setup.bob_signer );
+    let proof = setup.reactor.run( setup.alice.accept_relation(&half_proof) ).unwrap();
+    assert_eq!( setup.alice.relations(), vec![ proof.clone() ] );
+
+    let bob_session = setup.reactor.run( setup.bob.login() ).unwrap();
+    let bob_events = bob_session.events();
+
+    setup.reactor.run( setup.alice.revoke_relation(&proof) ).unwrap();
+    assert!( setup.alice.relations().is_empty() );
+
+    let events = setup.reactor.run( bob_events.take(2).collect() ).unwrap();
+    match ( &events[0], &events[1] ) {
+        ( ProfileEvent::PairingResponse(ref accepted), ProfileEvent::RelationRevoked(ref revocation) ) => {
+            assert_eq!(*accepted, proof);
+            assert_eq!(revocation.relation, proof);
+            assert_eq!(revocation.revoker_id, alice_id);
+        },
+        _ => panic!("PairingResponse and RelationRevoked expected"),
+    }
+
+    // Homes of both parties refuse calls with the revoked relation
+    let app = ApplicationId::from("chat");
+    let call_res = setup.reactor.run( setup.bob.call( app.clone(), CallRequestDetails::new( proof.clone(), AppMessageFrame( Vec::new() ), None ) ) );
+    assert_eq!( call_res.unwrap_err().home_error_kind(), Some(ErrorKind::RelationRevoked) );
+    let call_res = setup.reactor.run( setup.alice.call( app, CallRequestDetails::new( proof, AppMessageFrame( Vec::new() ), None ) ) );
+    assert_eq!( call_res.unwrap_err().home_error_kind(), Some(ErrorKind::RelationRevoked) );
+}
+
+
+#[test]
+fn test_dapp_call_close()
+{
+    let mut setup = TwoPersonasSetup::new();
+    setup.reactor.run( setup.alice.join_home( setup.homes[0].id.clone(), None ) ).unwrap();
+    setup.reactor.run( setup.bob.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+    setup.reactor.run( setup.bob.join_home( setup.homes[0].id.clone(), None ) ).unwrap();
+    setup.exchange_profiles();
+
+    let app = ApplicationId::from("chat");
+    let half_proof = RelationHalfProof::new( &app.0, &setup.alice_id, &*setup.bob_signer );
+    setup.reactor.run( setup.alice.accept_relation(&half_proof) ).unwrap();
+
+    let bob_session = setup.reactor.run( setup.bob.login_home( setup.homes[0].id.clone() ) ).unwrap();
+    let bob_calls = bob_session.session().checkin_app(&app, None);
+
+    // The call fails over to the second home of Bob while his first one is down
+    setup.connector.unreachable.borrow_mut().insert( setup.homes[1].id.clone() );
+    let alice_dapp = DAppSessionImpl::new( Rc::new( setup.alice.clone() ), app.clone() );
+    let contacts = setup.reactor.run( alice_dapp.contacts() ).unwrap();
+    let (call_id, call_fut) = contacts[0].call( AppMessageFrame( b"hello".to_vec() ) );
+
+    let (to_bob, _from_alice) = app_channel(1);
+    let answer_fut = bob_calls.take(1).collect()
+        .map( move |mut calls| { calls.pop().unwrap().answer( CallAnswer::Accepted(to_bob) ); } )
+        .map_err( |_e| mercury_connect::ErrorKind::CallFailed.into() );
+    let (call, ()) = setup.reactor.run( call_fut.join(answer_fut) ).unwrap();
+    assert_eq!(call.id, call_id);
+    setup.connector.unreachable.borrow_mut().clear();
+
+    // Closing the call hangs it up on the home hosting it, even if the first home of Bob
+    // is back and does not know the call, so it is gone from all homes
+    setup.reactor.run( call.close() ).unwrap();
+    let hangup_res = setup.reactor.run( setup.alice.hangup( &setup.bob_id, call_id ) );
+    assert_eq!( hangup_res.unwrap_err().home_error_kind(), Some(ErrorKind::CallNotFound) );
+}
+
+
+#[test]
+fn test_profile_watch()
+{
+    let mut setup = TwoPersonasSetup::new();
+    setup.reactor.run( setup.alice.join_home( setup.homes[0].id.clone(), None ) ).unwrap();
+    setup.reactor.run( setup.bob.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+    // Both parties know each other's profile, e.g. from a shared profile url
+    setup.exchange_profiles();
+
+    let half_proof = RelationHalfProof::new( "friend", &setup.alice_id, &*setup.bob_signer );
+    let proof = setup.reactor.run( setup.alice.accept_relation(&half_proof) ).unwrap();
+
+    // Bob is hosted elsewhere, but is notified by Alice's home when she joins another home
+    let alice_updates = setup.reactor.run( setup.bob.watch_profile(&proof) ).unwrap();
+    setup.reactor.run( setup.alice.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+
+    let events = setup.reactor.run( alice_updates.take(1).collect() ).unwrap();
+    let updated_alice = match events[0] {
+        ProfileEvent::ProfileUpdated(ref profile) => profile.to_owned(),
+        _ => panic!("ProfileUpdated expected"),
+    };
+    assert_eq!( updated_alice.facet, ProfileFacet::Persona( PersonaFacet{
+        homes: setup.reactor.run( setup.alice.homes() ).unwrap(), data: Vec::new() } ) );
+    assert_eq!( setup.reactor.run( setup.bob_repo.load(&setup.alice_id) ).unwrap(), updated_alice );
+
+    // Others cannot watch Alice with a relation they are not a party of
+    let (_eve_profile, eve_signer) = generate_persona();
+    let home = setup.reactor.run( setup.connector.connect( &setup.homes[0], Rc::new(eve_signer) ) ).unwrap();
+    let eve_updates = setup.reactor.run( home.subscribe(proof).take(1).collect() ).unwrap();
+    assert!( eve_updates[0].is_err() );
+}
+
+
+#[test]
+fn test_profile_watch_revoked()
+{
+    let mut setup = TwoPersonasSetup::new();
+    setup.reactor.run( setup.alice.join_home( setup.homes[0].id.clone(), None ) ).unwrap();
+    setup.reactor.run( setup.bob.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+    setup.exchange_profiles();
+
+    let half_proof = RelationHalfProof::new( "friend", &setup.alice_id, &*setup.bob_signer );
+    let proof = setup.reactor.run( setup.alice.accept_relation(&half_proof) ).unwrap();
+
+    // An update is received first, so the subscription is surely in place before the revocation
+    let alice_updates = setup.reactor.run( setup.bob.watch_profile(&proof) ).unwrap();
+    setup.reactor.run( setup.alice.join_home( setup.homes[1].id.clone(), None ) ).unwrap();
+    let alice_updates = match setup.reactor.run( alice_updates.into_future() ) {
+        Ok( ( Some( ProfileEvent::ProfileUpdated(_) ), alice_updates ) ) => alice_updates,
+        _ => panic!("ProfileUpdated expected"),
+    };
+
+    // Alice's home drops the subscription of Bob with the revoked relation, so his stream ends
+    setup.reactor.run( setup.alice.revoke_relation(&proof) ).unwrap();
+    let events = setup.reactor.run( alice_updates.collect() ).unwrap();
+    assert!( events.is_empty() );
+}
+
 #[ignore]
 #[test]
 fn test_generate_key_files() 
@@ -16,16 +16,20 @@ extern crate rand;
 extern crate sha2;
 extern crate base64;
 
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, rc::Rc, sync::Arc};
+use std::collections::{HashMap, HashSet};
 
+use futures::future;
 use rand::rngs::OsRng;
 use sha2::Sha512;
 use tokio_core::reactor;
 
+use mercury_connect::{SimpleProfileRepo, profile::{HomeConnector, MyProfile, MyProfileImpl}};
 use mercury_home_protocol::*;
 use mercury_home_protocol::crypto::*;
-use mercury_home_node::server::HomeServer;
-use mercury_storage::async::imp::InMemoryStore;
+use mercury_home_node::metrics::MetricsRegistry;
+use mercury_home_node::server::{HomeConnectionServer, HomeServer, RegistrationPolicy, SessionLimits, SessionPolicy};
+use mercury_storage::async::{KeyValueStore, imp::InMemoryStore};
 
 
 pub mod dummy; // TODO this will not be needed with real components ready and tested
@@ -50,7 +54,8 @@ pub fn generate_ownprofile(facet: ProfileFacet, private_data: Vec<u8>)
 {
     let (private_key, _public_key) = generate_keypair();
     let signer = Ed25519Signer::new(&private_key).expect("TODO: this should not be able to fail");
-    let profile = Profile::new( &signer.profile_id(), &signer.public_key(), &facet );
+    let mut profile = Profile::new( &signer.profile_id(), &signer.public_key(), &facet );
+    profile.sign_next_version(&signer);
     let own_profile = OwnProfile::new(&profile, &private_data);
     (own_profile, signer)
 }
@@ -74,10 +79,32 @@ pub fn generate_home() -> (Profile, Ed25519Signer)
 }
 
 pub fn default_home_server(handle: &reactor::Handle) -> HomeServer {
+    home_server_with( handle, RegistrationPolicy::Open, SessionLimits::default(), SessionPolicy::Single )
+}
+
+pub fn home_server_with(handle: &reactor::Handle, registration: RegistrationPolicy,
+                        limits: SessionLimits, session_policy: SessionPolicy) -> HomeServer {
+    home_server_with_dht( handle, Rc::new( RefCell::new( InMemoryStore::new() ) ), registration, limits, session_policy )
+}
+
+/// Home server publishing profiles into the given storage, e.g. to tamper with it in tests
+pub fn home_server_with_dht(handle: &reactor::Handle, public_dht: Rc<RefCell< KeyValueStore<ProfileId, Profile> >>,
+                            registration: RegistrationPolicy, limits: SessionLimits, session_policy: SessionPolicy)
+    -> HomeServer {
     HomeServer::new( handle,
         Rc::new( CompositeValidator::default() ),
+        public_dht,
+        Rc::new( RefCell::new( InMemoryStore::new() ) ),
+        Rc::new( RefCell::new( InMemoryStore::new() ) ),
+        Rc::new( RefCell::new( InMemoryStore::new() ) ),
         Rc::new( RefCell::new( InMemoryStore::new() ) ),
         Rc::new( RefCell::new( InMemoryStore::new() ) ),
+        Rc::new( RefCell::new( InMemoryStore::new() ) ),
+        Rc::new( RefCell::new( InMemoryStore::new() ) ),
+        registration,
+        limits,
+        session_policy,
+        Arc::new( MetricsRegistry::default() ),
     )
 }
 
@@ -91,3 +118,86 @@ pub fn first_home_of(own_profile: &OwnProfile) -> &RelationProof
     }
 }
 
+
+
+/// Connects to in-process home servers directly, homes can be marked unreachable to test failover.
+pub struct DirectHomeConnector
+{
+    pub homes:          HashMap<ProfileId, (Rc<HomeServer>, Rc<Signer>)>,
+    pub unreachable:    RefCell< HashSet<ProfileId> >,
+}
+
+impl HomeConnector for DirectHomeConnector
+{
+    fn connect(&self, home_profile: &Profile, signer: Rc<Signer>)
+        -> AsyncResult<Rc<Home>, mercury_connect::Error>
+    {
+        if self.unreachable.borrow().contains(&home_profile.id)
+            { return Box::new( future::err( mercury_connect::ErrorKind::ConnectionToHomeFailed.into() ) ) }
+
+        let (server, home_signer) = self.homes.get(&home_profile.id).unwrap().clone();
+        let context = Rc::new( PeerContext::new( home_signer, signer.public_key().to_owned(), signer.profile_id().to_owned() ) );
+        let home = Rc::new( HomeConnectionServer::new(context, server).unwrap() ) as Rc<Home>;
+        Box::new( future::ok(home) )
+    }
+}
+
+
+
+/// Two personas, Alice and Bob, with their own profile repositories and two in-process homes
+/// known by both repositories. The personas are not registered on any of the homes yet.
+pub struct TwoPersonasSetup
+{
+    pub reactor:    reactor::Core,
+    pub connector:  Rc<DirectHomeConnector>,
+    pub homes:      Vec<Profile>,
+    pub alice_repo: Rc<SimpleProfileRepo>,
+    pub alice:      MyProfileImpl,
+    pub alice_id:   ProfileId,
+    pub bob_repo:   Rc<SimpleProfileRepo>,
+    pub bob:        MyProfileImpl,
+    pub bob_id:     ProfileId,
+    pub bob_signer: Rc<Signer>,
+}
+
+impl TwoPersonasSetup
+{
+    pub fn new() -> Self
+    {
+        let mut reactor = reactor::Core::new().unwrap();
+        let handle = reactor.handle();
+
+        let alice_repo = Rc::new( SimpleProfileRepo::default() );
+        let bob_repo = Rc::new( SimpleProfileRepo::default() );
+        let mut home_servers = HashMap::new();
+        let mut homes = Vec::new();
+        for _ in 0..2 {
+            let (home_profile, home_signer) = generate_home();
+            reactor.run( alice_repo.insert( home_profile.clone() ) ).unwrap();
+            reactor.run( bob_repo.insert( home_profile.clone() ) ).unwrap();
+            home_servers.insert( home_profile.id.clone(),
+                ( Rc::new( default_home_server(&handle) ), Rc::new(home_signer) as Rc<Signer> ) );
+            homes.push(home_profile);
+        }
+        let connector = Rc::new( DirectHomeConnector{ homes: home_servers, unreachable: Default::default() } );
+
+        let (alice_profile, alice_signer) = generate_persona();
+        let alice = MyProfileImpl::new( alice_profile, Rc::new(alice_signer), alice_repo.clone(), connector.clone(), handle.clone() );
+        let (bob_profile, bob_signer) = generate_persona();
+        let bob_signer = Rc::new(bob_signer) as Rc<Signer>;
+        let bob = MyProfileImpl::new( bob_profile, bob_signer.clone(), bob_repo.clone(), connector.clone(), handle );
+
+        let alice_id = alice.signer().profile_id().to_owned();
+        let bob_id = bob.signer().profile_id().to_owned();
+        Self{ reactor, connector, homes, alice_repo, alice, alice_id, bob_repo, bob, bob_id, bob_signer }
+    }
+
+    /// Make the current public profiles of both personas known to each other, e.g. as if shared by a profile url
+    pub fn exchange_profiles(&mut self)
+    {
+        let bob_public = self.reactor.run( self.bob_repo.load(&self.bob_id) ).unwrap();
+        self.reactor.run( self.alice_repo.insert(bob_public) ).unwrap();
+        let alice_public = self.reactor.run( self.alice_repo.load(&self.alice_id) ).unwrap();
+        self.reactor.run( self.bob_repo.insert(alice_public) ).unwrap();
+    }
+}